@@ -8,6 +8,7 @@ layout(set = 0, binding = 1) uniform sampler colorsampler;
 
 layout(std140, set = 0, binding = 2) uniform Args {
     vec4 exposure_numframes_xx;
+    uvec4 passthrough_xxx;
 };
 
 layout(location = 0) out vec4 color;
@@ -62,6 +63,11 @@ void main() {
     vec2 uv = f_uv;
     uv.y = 1.0 - uv.y;
     vec3 tex_color = texture(sampler2D(colormap, colorsampler), uv).rgb;// / exposure_numframes_xx.g;
+    if (passthrough_xxx.x != 0) {
+        // debug view of the raw linear buffer
+        color = vec4(clamp(tex_color, 0, 1), 1.0);
+        return;
+    }
     tex_color *= exposure_numframes_xx.r;
     //tex_color = tex_color / (1 + tex_color); // reinhard tonemap
     tex_color = ACESFitted(tex_color);
@@ -0,0 +1,58 @@
+// Baseline timings for the core trace loop, run with `cargo bench`. Everything is built from fixed seeds so
+// runs are comparable, each benchmark reports the rays traced per second on a single thread.
+extern crate path_tracer;
+
+use path_tracer::Config;
+use path_tracer::bench::{self, Hitable};
+use path_tracer::math::AABB;
+use path_tracer::math::Vec3;
+use std::time::{Duration, Instant};
+
+const SEED: u64 = 1412;
+// each benchmark repeats until it has run for at least this long
+const MIN_BENCH_TIME: Duration = Duration::from_secs(2);
+
+fn duration_in_secs(duration: &Duration) -> f64 {
+    duration.as_secs() as f64 + duration.subsec_nanos() as f64 * 1e-9
+}
+
+// Runs the workload, which returns the rays it traced, once to warm up then repeatedly for MIN_BENCH_TIME
+fn bench<F: FnMut() -> usize>(name: &str, mut workload: F) {
+    workload();
+
+    let timer = Instant::now();
+    let mut iterations = 0;
+    let mut rays_traced = 0;
+    while timer.elapsed() < MIN_BENCH_TIME {
+        rays_traced += workload();
+        iterations += 1;
+    }
+    let secs = duration_in_secs(&timer.elapsed());
+    println!("{:<24} {:>10.3} Mrays/s ({} rays in {} iterations, {:.3}ms per iteration)",
+             name, rays_traced as f64 / secs * 1e-6, rays_traced, iterations, secs * 1000.0 / iterations as f64);
+}
+
+fn main() {
+    let rays = bench::random_rays(100_000, SEED);
+
+    let args: Vec<String> = vec!["bench", "-offline", "-spp=4"].into_iter().map(String::from).collect();
+    let config = Config::from_cmdline(&args);
+    // the glass sphere and the floor beneath it
+    bench("color cornell crop", || bench::trace_cornell_crop(&config, (256, 256), ((48, 16), (64, 64))));
+
+    let bvh = bench::random_sphere_bvh(1000, SEED);
+    let mut hits = 0;
+    bench("BvhNode::hit", || {
+        hits += rays.iter().filter(|ray| bvh.hit(ray, 0.001, std::f64::MAX).is_some()).count();
+        rays.len()
+    });
+
+    let aabb = AABB::new(Vec3::from_float(-10.0), Vec3::from_float(10.0));
+    bench("AABB::hit", || {
+        hits += rays.iter().filter(|ray| aabb.hit(ray, 0.001, std::f64::MAX)).count();
+        rays.len()
+    });
+
+    // keeps the hit tests from being optimised away
+    println!("{} hits", hits);
+}
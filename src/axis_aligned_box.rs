@@ -38,4 +38,80 @@ impl Hitable for AxisAlignedBox {
         AABB::new(self.pmin, self.pmax)
     }
 
-}
\ No newline at end of file
+    fn is_emissive(&self) -> bool {
+        self.list.is_emissive()
+    }
+
+    fn primitive_count(&self) -> usize {
+        self.list.primitive_count()
+    }
+}
+
+// Box that only registers hits within edge_thickness of its 12 edges, rays through the faces pass
+// straight through so the box renders as a wireframe
+pub struct WireframeBox {
+    aabb: AxisAlignedBox,
+    edge_thickness: f64,
+}
+
+impl WireframeBox {
+    pub fn new(pmin: Vec3, pmax: Vec3, edge_thickness: f64, material: Arc<dyn Material + Send + Sync>) -> Self {
+        Self {
+            aabb: AxisAlignedBox::new(pmin, pmax, material),
+            edge_thickness,
+        }
+    }
+
+    fn is_near_edge(&self, point: &Vec3) -> bool {
+        // a point on a face is on an edge when it is also close to a face along another axis
+        let mut num_faces_near = 0;
+        for axis in 0..3 {
+            if (point[axis] - self.aabb.pmin[axis]).abs() <= self.edge_thickness || (point[axis] - self.aabb.pmax[axis]).abs() <= self.edge_thickness {
+                num_faces_near += 1;
+            }
+        }
+        num_faces_near >= 2
+    }
+}
+
+impl Hitable for WireframeBox {
+    fn hit(&self, ray: &Ray, t_min: f64, t_max: f64) -> Option<HitRecord> {
+        // walk through the faces until one is hit near an edge, a box has at most two faces along a ray
+        let mut t_min = t_min;
+        while let Some(hit_record) = self.aabb.hit(ray, t_min, t_max) {
+            if self.is_near_edge(&hit_record.p) {
+                return Some(hit_record);
+            }
+            t_min = hit_record.t + 0.0001;
+        }
+        None
+    }
+
+    fn bounding_box(&self, t0: f64, t1: f64) -> AABB {
+        self.aabb.bounding_box(t0, t1)
+    }
+
+    fn is_emissive(&self) -> bool {
+        self.aabb.is_emissive()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+
+    use super::*;
+    use material::DummyMaterial;
+
+    #[test]
+    fn wireframe_hits_edges_but_not_faces() {
+        let wireframe = WireframeBox::new(Vec3::new_zero_vector(), Vec3::from_float(1.0), 0.05, Arc::new(DummyMaterial::new()));
+        let direction = Vec3::new(0.0, 0.0, 1.0);
+
+        let through_face_centre = Ray::new(Vec3::new(0.5, 0.5, -1.0), direction, 0.0);
+        assert!(wireframe.hit(&through_face_centre, 0.001, std::f64::MAX).is_none());
+
+        let through_edge = Ray::new(Vec3::new(0.02, 0.5, -1.0), direction, 0.0);
+        let hit_record = wireframe.hit(&through_edge, 0.001, std::f64::MAX).expect("ray along an edge should hit");
+        assert!(hit_record.t > 0.99 && hit_record.t < 1.01);
+    }
+}
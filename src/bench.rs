@@ -0,0 +1,55 @@
+use std::sync::Arc;
+use math::*;
+use hitable::ThreadsafeHitable;
+use material::Lambertian;
+use texture::ConstantTexture;
+use sphere::Sphere;
+use trace::trace_single_threaded;
+use Config;
+
+// Fixed workloads for the benchmarks in benches/. The scene modules are private so what the benchmarks need is
+// re-exported here, this is not meant as a stable API.
+pub use bvh::BvhNode;
+pub use hitable::Hitable;
+
+// Half the size of the cube the random spheres and rays are placed in
+const FIELD_EXTENT: f64 = 50.0;
+
+fn random_point_in_field() -> Vec3 {
+    Vec3::new(random::rand() - 0.5, random::rand() - 0.5, random::rand() - 0.5) * (2.0 * FIELD_EXTENT)
+}
+
+// Spheres scattered through a cube around the origin. Built from the seeded generator as the BVH picks its split
+// axes randomly, so every run traverses the same tree.
+pub fn random_sphere_bvh(num_spheres: usize, seed: u64) -> BvhNode {
+    random::seed_thread_rng(seed);
+    let material = Arc::new(Lambertian::new(Arc::new(ConstantTexture::new(Vec3::from_float(0.5))), 0.0));
+    let list: Vec<Arc<ThreadsafeHitable>> = (0..num_spheres)
+        .map(|_| Arc::new(Sphere::new(random_point_in_field(), 0.5 + random::rand(), material.clone())) as Arc<ThreadsafeHitable>)
+        .collect();
+    let bvh = BvhNode::from_list(list, 0.0, 1.0);
+    random::clear_thread_rng_seed();
+    bvh
+}
+
+// Rays between random points of the same cube, some pass through the spheres and some miss them all
+pub fn random_rays(num_rays: usize, seed: u64) -> Vec<Ray> {
+    random::seed_thread_rng(seed);
+    let rays = (0..num_rays)
+        .map(|_| {
+            let origin = random_point_in_field();
+            let direction = &random_point_in_field() - &origin;
+            Ray::new(origin, direction, 0.0)
+        })
+        .collect();
+    random::clear_thread_rng_seed();
+    rays
+}
+
+// Traces the crop (start, size) of the Cornell box through color() on the calling thread with a fixed seed,
+// returning the number of rays traced
+pub fn trace_cornell_crop(config: &Config, image_size: (u32, u32), crop: ((u32, u32), (u32, u32))) -> usize {
+    let (world, cam) = ::cornell_box((image_size.0 as f64)/(image_size.1 as f64), config.shutter);
+    let scene_output = trace_single_threaded(world, cam, config, image_size, crop);
+    scene_output.stats.rays_traced()
+}
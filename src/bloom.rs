@@ -0,0 +1,127 @@
+// Bloom applied to the linear float buffer before tonemapping. Channels above the threshold are
+// downsampled to half resolution, blurred and added back scaled by the intensity so bright lights
+// bleed into their surroundings.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Bloom {
+    pub threshold: f32,
+    pub intensity: f32,
+}
+
+// gaussian blur on the half resolution image, in half resolution pixels
+const BLUR_RADIUS: i64 = 4;
+const BLUR_SIGMA: f32 = 2.0;
+
+impl Bloom {
+    pub fn new(threshold: f32, intensity: f32) -> Self {
+        Bloom {
+            threshold,
+            intensity,
+        }
+    }
+
+    // Returns a copy of the RGBA buffer with the bloom added, alpha is left untouched
+    pub fn apply(&self, buffer: &[f32], image_size: (u32, u32)) -> Vec<f32> {
+        let (width, height) = (image_size.0 as i64, image_size.1 as i64);
+        let half_size = ((width + 1) / 2, (height + 1) / 2);
+
+        // bright pass and 2x2 box downsample, summed rather than averaged so no energy is lost
+        let mut bright = vec![[0.0_f32; 3]; (half_size.0 * half_size.1) as usize];
+        for y in 0..height {
+            for x in 0..width {
+                let index = ((y * width + x) * 4) as usize;
+                let half_pixel = &mut bright[((y / 2) * half_size.0 + x / 2) as usize];
+                for channel in 0..3 {
+                    half_pixel[channel] += (buffer[index + channel] - self.threshold).max(0.0);
+                }
+            }
+        }
+
+        let kernel: Vec<f32> = (-BLUR_RADIUS..=BLUR_RADIUS)
+            .map(|offset| (-((offset * offset) as f32) / (2.0 * BLUR_SIGMA * BLUR_SIGMA)).exp())
+            .collect();
+        let kernel_sum: f32 = kernel.iter().sum();
+        let kernel: Vec<f32> = kernel.iter().map(|weight| weight / kernel_sum).collect();
+
+        let blurred = blur_1d(&bright, half_size, &kernel, (1, 0));
+        let blurred = blur_1d(&blurred, half_size, &kernel, (0, 1));
+
+        // spread each half resolution pixel back over the 2x2 block it came from
+        let mut output = buffer.to_vec();
+        for y in 0..height {
+            for x in 0..width {
+                let index = ((y * width + x) * 4) as usize;
+                let half_pixel = &blurred[((y / 2) * half_size.0 + x / 2) as usize];
+                for channel in 0..3 {
+                    output[index + channel] += self.intensity * half_pixel[channel] * 0.25;
+                }
+            }
+        }
+        output
+    }
+}
+
+// Blurs along one axis, energy that would fall outside the image is dropped
+fn blur_1d(pixels: &[[f32; 3]], size: (i64, i64), kernel: &[f32], direction: (i64, i64)) -> Vec<[f32; 3]> {
+    let mut output = vec![[0.0_f32; 3]; pixels.len()];
+    for y in 0..size.1 {
+        for x in 0..size.0 {
+            let pixel = &pixels[(y * size.0 + x) as usize];
+            for (tap, weight) in kernel.iter().enumerate() {
+                let offset = tap as i64 - BLUR_RADIUS;
+                let (tx, ty) = (x + offset * direction.0, y + offset * direction.1);
+                if tx < 0 || ty < 0 || tx >= size.0 || ty >= size.1 {
+                    continue;
+                }
+                let target = &mut output[(ty * size.0 + tx) as usize];
+                for channel in 0..3 {
+                    target[channel] += pixel[channel] * weight;
+                }
+            }
+        }
+    }
+    output
+}
+
+// threshold,intensity
+impl std::str::FromStr for Bloom {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let values: Vec<f32> = s.split(',')
+            .map(|value| value.trim().parse::<f32>())
+            .collect::<Result<_, _>>()
+            .map_err(|_| format!("Invalid bloom '{}', expected threshold,intensity", s))?;
+        match values.as_slice() {
+            [threshold, intensity] => Ok(Bloom::new(*threshold, *intensity)),
+            _ => Err(format!("Invalid bloom '{}', expected threshold,intensity", s)),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+
+    use super::*;
+
+    #[test]
+    fn bright_pixel_spreads_with_bounded_energy() {
+        let image_size = (32, 32);
+        let mut buffer = vec![0.1_f32; (image_size.0 * image_size.1 * 4) as usize];
+        let centre = ((16 * image_size.0 + 16) * 4) as usize;
+        buffer[centre..centre + 3].copy_from_slice(&[10.0, 10.0, 10.0]);
+
+        let bloom: Bloom = "1.0,0.5".parse().unwrap();
+        let output = bloom.apply(&buffer, image_size);
+
+        let neighbour = ((16 * image_size.0 + 19) * 4) as usize;
+        assert!(output[neighbour] > buffer[neighbour]);
+        let far_corner = 0;
+        assert_eq!(output[far_corner], buffer[far_corner]);
+
+        let total = |pixels: &[f32]| pixels.chunks(4).map(|pixel| (pixel[0] + pixel[1] + pixel[2]) as f64).sum::<f64>();
+        let increase = total(&output) - total(&buffer);
+        // at most the energy above the threshold scaled by the intensity
+        let bound = 0.5 * 3.0 * (10.0 - 1.0);
+        assert!(increase > 0.0 && increase <= bound + 1e-4, "increase = {}", increase);
+    }
+}
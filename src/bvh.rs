@@ -1,6 +1,7 @@
 
 use hitable::*;
 use math::*;
+use material::Material;
 use std::sync::Arc;
 
 pub struct BvhNode {
@@ -67,6 +68,13 @@ impl BvhNode {
             bounding_box    
         }
     }
+
+    // bounding boxes of the root and every node or primitive up to max_depth levels below it
+    pub fn collect_boxes(&self, max_depth: u32) -> Vec<AABB> {
+        let mut boxes = vec![];
+        self.append_debug_boxes(max_depth, &mut boxes);
+        boxes
+    }
 }
 
 impl Hitable for BvhNode {
@@ -79,6 +87,11 @@ impl Hitable for BvhNode {
             let left_hit = self.left.hit(ray, t_min, t_max);
             //let right_hit = self.right.hit(ray, t_min, t_max);
 
+            // nodes holding a single primitive store it on both sides
+            if Arc::ptr_eq(&self.left, &self.right) {
+                return left_hit;
+            }
+
             record = match left_hit {
                 // if left hit, try right hit with t_max as left_hit.t as no point testing hits beyond this point
                 Some(left_hit_u) => {
@@ -98,7 +111,231 @@ impl Hitable for BvhNode {
         return record;
     }
 
+    fn hit_any(&self, ray: &Ray, t_min: f64, t_max: f64) -> bool {
+        self.bounding_box.hit(ray, t_min, t_max) &&
+            (self.left.hit_any(ray, t_min, t_max) || (!Arc::ptr_eq(&self.left, &self.right) && self.right.hit_any(ray, t_min, t_max)))
+    }
+
     fn bounding_box(&self, _t0: f64, _t1: f64) -> AABB {
         self.bounding_box.clone()
     }
+
+    fn is_emissive(&self) -> bool {
+        self.left.is_emissive() || self.right.is_emissive()
+    }
+
+    fn append_lights(&self, lights: &mut Vec<Arc<ThreadsafeHitable>>) {
+        append_child_lights(&self.left, lights);
+        if !Arc::ptr_eq(&self.left, &self.right) {
+            append_child_lights(&self.right, lights);
+        }
+    }
+
+    fn append_primitives(&self, primitives: &mut Vec<Arc<ThreadsafeHitable>>) {
+        append_child_primitives(&self.left, primitives);
+        if !Arc::ptr_eq(&self.left, &self.right) {
+            append_child_primitives(&self.right, primitives);
+        }
+    }
+
+    fn append_debug_boxes(&self, max_depth: u32, boxes: &mut Vec<AABB>) {
+        boxes.push(self.bounding_box.clone());
+        if max_depth > 0 {
+            self.left.append_debug_boxes(max_depth - 1, boxes);
+            // single element nodes share the same child on both sides
+            if !Arc::ptr_eq(&self.left, &self.right) {
+                self.right.append_debug_boxes(max_depth - 1, boxes);
+            }
+        }
+    }
+
+    fn primitive_count(&self) -> usize {
+        if Arc::ptr_eq(&self.left, &self.right) {self.left.primitive_count()} else {self.left.primitive_count() + self.right.primitive_count()}
+    }
+}
+
+// Edges of debug boxes are drawn this fraction of the box's largest side wide
+const DEBUG_EDGE_WIDTH: f64 = 0.01;
+
+// Renders the edges of a bvh's boxes as a wireframe, useful for checking how well the tree fits the scene. The faces
+// are left open so the boxes of deeper levels show through their parents
+pub struct BvhDebugBoxes {
+    boxes: Vec<AABB>,
+    material: Arc<dyn Material + Send + Sync + 'static>,
+    bounding_box: AABB,
+}
+
+impl BvhDebugBoxes {
+    pub fn new(bvh: &BvhNode, max_depth: u32, material: Arc<dyn Material + Send + Sync + 'static>) -> Self {
+        Self {
+            boxes: bvh.collect_boxes(max_depth),
+            material,
+            bounding_box: bvh.bounding_box.clone(),
+        }
+    }
+
+    // Closest of the ray's entry and exit points of the box that lies on one of its edges
+    fn edge_hit(bounding_box: &AABB, ray: &Ray, t_min: f64, t_max: f64) -> Option<f64> {
+        let (min, max) = (bounding_box.min(), bounding_box.max());
+        let (mut t_enter, mut t_exit) = (f64::NEG_INFINITY, f64::INFINITY);
+        for axis in 0..3 {
+            let inv_d = 1.0 / ray.direction()[axis];
+            let t0 = (min[axis] - ray.origin()[axis]) * inv_d;
+            let t1 = (max[axis] - ray.origin()[axis]) * inv_d;
+            t_enter = t_enter.max(t0.min(t1));
+            t_exit = t_exit.min(t0.max(t1));
+        }
+        if t_enter > t_exit {
+            return None;
+        }
+
+        let width = DEBUG_EDGE_WIDTH * (*max - *min).max_component();
+        [t_enter, t_exit].iter().cloned().filter(|t| *t > t_min && *t < t_max).find(|t| {
+            // points on a face are next to an edge when they're also close to a second pair of faces
+            let p = ray.at(*t);
+            let num_faces_near = (0..3).filter(|&axis| (p[axis] - min[axis]).abs() < width || (max[axis] - p[axis]).abs() < width).count();
+            num_faces_near >= 2
+        })
+    }
+}
+
+impl Hitable for BvhDebugBoxes {
+    fn hit(&self, ray: &Ray, t_min: f64, t_max: f64) -> Option<HitRecord> {
+        let mut closest: Option<f64> = None;
+        for bounding_box in self.boxes.iter() {
+            if let Some(t) = BvhDebugBoxes::edge_hit(bounding_box, ray, t_min, closest.unwrap_or(t_max)) {
+                closest = Some(t);
+            }
+        }
+        // edges have no well defined normal, face them towards the ray so they're always lit from the camera side
+        closest.map(|t| HitRecord::new(t, 0.0, 0.0, ray.at(t), -Vec3::new_unit_vector(ray.direction()), self.material.clone()))
+    }
+
+    fn bounding_box(&self, _t0: f64, _t1: f64) -> AABB {
+        self.bounding_box.clone()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+
+    use super::*;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use material::DummyMaterial;
+    use sphere::Sphere;
+    use axis_aligned_box::AxisAlignedBox;
+    use stats;
+
+    // counts calls to hit so tests can compare how much work a query did
+    struct CountingHitable {
+        child: Arc<dyn Hitable + Send + Sync + 'static>,
+        num_hit_calls: Arc<AtomicUsize>,
+    }
+
+    impl Hitable for CountingHitable {
+        fn hit(&self, r: &Ray, t_min: f64, t_max: f64) -> Option<HitRecord> {
+            self.num_hit_calls.fetch_add(1, Ordering::SeqCst);
+            self.child.hit(r, t_min, t_max)
+        }
+        fn bounding_box(&self, t0: f64, t1: f64) -> AABB {
+            self.child.bounding_box(t0, t1)
+        }
+    }
+
+    // spheres lined up along -z so a ray down the axis passes through all of them
+    fn spheres_along_axis(num_hit_calls: &Arc<AtomicUsize>) -> Vec<Arc<dyn Hitable + Send + Sync + 'static>> {
+        (1..=5).map(|i| {
+            let sphere = Arc::new(Sphere::new(Vec3::new(0.0, 0.0, -2.0 * i as f64), 0.5, Arc::new(DummyMaterial::new())));
+            Arc::new(CountingHitable { child: sphere, num_hit_calls: num_hit_calls.clone() }) as Arc<dyn Hitable + Send + Sync + 'static>
+        }).collect()
+    }
+
+    #[test]
+    fn hit_any_stops_at_first_primitive_hit() {
+        let ray = Ray::new(Vec3::new_zero_vector(), Vec3::new(0.0, 0.0, -1.0), 0.0);
+        let num_hit_calls = Arc::new(AtomicUsize::new(0));
+        let list = HitableList::new(spheres_along_axis(&num_hit_calls));
+        let bvh = BvhNode::from_list(spheres_along_axis(&num_hit_calls), 0.0, 1.0);
+
+        for hitable in [&list as &dyn Hitable, &bvh as &dyn Hitable].iter() {
+            num_hit_calls.store(0, Ordering::SeqCst);
+            assert!(hitable.hit(&ray, 0.001, std::f64::MAX).is_some());
+            let closest_hit_calls = num_hit_calls.swap(0, Ordering::SeqCst);
+
+            assert!(hitable.hit_any(&ray, 0.001, std::f64::MAX));
+            assert_eq!(num_hit_calls.load(Ordering::SeqCst), 1);
+            assert!(closest_hit_calls >= 1);
+            assert!(!hitable.hit_any(&Ray::new(Vec3::new_zero_vector(), Vec3::new(0.0, 1.0, 0.0), 0.0), 0.001, std::f64::MAX));
+        }
+        // the list has to test every primitive to be sure it found the closest
+        num_hit_calls.store(0, Ordering::SeqCst);
+        list.hit(&ray, 0.001, std::f64::MAX);
+        assert_eq!(num_hit_calls.load(Ordering::SeqCst), 5);
+    }
+
+    #[test]
+    fn two_primitives_yield_leaf_boxes_and_root() {
+        let material: Arc<dyn Material + Send + Sync + 'static> = Arc::new(DummyMaterial::new());
+        let list: Vec<Arc<dyn Hitable + Send + Sync + 'static>> = vec![
+            Arc::new(Sphere::new(Vec3::new(-2.0, 0.0, 0.0), 1.0, material.clone())),
+            Arc::new(Sphere::new(Vec3::new(2.0, 0.0, 0.0), 1.0, material.clone())),
+        ];
+        let bvh = BvhNode::from_list(list, 0.0, 1.0);
+        assert_eq!(bvh.collect_boxes(0).len(), 1);
+        let boxes = bvh.collect_boxes(8);
+        assert_eq!(boxes.len(), 3);
+        assert_eq!(boxes[0].min().x, -3.0);
+        assert_eq!(boxes[0].max().x, 3.0);
+
+        let debug_boxes = BvhDebugBoxes::new(&bvh, 8, material);
+        // through the middle of the faces of the root and left leaf, nothing to see
+        assert!(debug_boxes.hit(&Ray::new(Vec3::new(-2.0, 0.0, -10.0), Vec3::new(0.0, 0.0, 1.0), 0.0), 0.001, std::f64::MAX).is_none());
+        // the root's face doesn't hide the edge of the left leaf behind it
+        let hit_record = debug_boxes.hit(&Ray::new(Vec3::new(-1.001, 0.0, -10.0), Vec3::new(0.0, 0.0, 1.0), 0.0), 0.001, std::f64::MAX).unwrap();
+        assert_eq!(hit_record.t, 9.0);
+        // and the far edge is found when the near one is out of range
+        let hit_record = debug_boxes.hit(&Ray::new(Vec3::new(-1.001, 0.0, -10.0), Vec3::new(0.0, 0.0, 1.0), 0.0), 10.0, std::f64::MAX).unwrap();
+        assert_eq!(hit_record.t, 11.0);
+    }
+
+    #[test]
+    fn primitive_count_counts_spheres_and_box_rects() {
+        let material: Arc<dyn Material + Send + Sync + 'static> = Arc::new(DummyMaterial::new());
+        let mut list: Vec<Arc<dyn Hitable + Send + Sync + 'static>> = (0..5)
+            .map(|i| Arc::new(Sphere::new(Vec3::new(2.0 * i as f64, 0.0, 0.0), 0.5, material.clone())) as Arc<dyn Hitable + Send + Sync + 'static>)
+            .collect();
+        list.push(Arc::new(AxisAlignedBox::new(Vec3::new(-1.0, 2.0, -1.0), Vec3::new(1.0, 4.0, 1.0), material.clone())));
+        assert_eq!(HitableList::new(list.clone()).primitive_count(), 11);
+        assert_eq!(BvhNode::from_list(list, 0.0, 1.0).primitive_count(), 11);
+    }
+
+    #[test]
+    fn bvh_holds_every_primitive_once() {
+        let num_hit_calls = Arc::new(AtomicUsize::new(0));
+        for num_primitives in 1..=5 {
+            let list = spheres_along_axis(&num_hit_calls).into_iter().take(num_primitives).collect();
+            assert_eq!(BvhNode::from_list(list, 0.0, 1.0).primitive_count(), num_primitives);
+        }
+    }
+
+    #[test]
+    fn single_sphere_is_tested_once_per_ray_at_its_box() {
+        let sphere: Arc<dyn Hitable + Send + Sync + 'static> = Arc::new(Sphere::new(Vec3::new(0.0, 0.0, -3.0), 0.5, Arc::new(DummyMaterial::new())));
+        let bvh = BvhNode::from_list(vec![sphere], 0.0, 1.0);
+        let sphere_box = bvh.bounding_box(0.0, 1.0);
+
+        // primary rays fanning out over a 16x16 grid, only some of which point at the sphere
+        let rays: Vec<Ray> = (0..16 * 16).map(|i| {
+            let (x, y) = ((i % 16) as f64 / 7.5 - 1.0, (i / 16) as f64 / 7.5 - 1.0);
+            Ray::new(Vec3::new_zero_vector(), Vec3::new(x, y, -1.0), 0.0)
+        }).collect();
+        let rays_at_sphere = rays.iter().filter(|ray| sphere_box.hit(ray, 0.001, std::f64::MAX)).count();
+        assert!(rays_at_sphere > 0 && rays_at_sphere < rays.len());
+
+        stats::reset_primitive_hit_tests();
+        for ray in rays.iter() {
+            bvh.hit(ray, 0.001, std::f64::MAX);
+        }
+        assert_eq!(stats::primitive_hit_tests(), rays_at_sphere);
+    }
 }
\ No newline at end of file
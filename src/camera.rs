@@ -1,7 +1,14 @@
 use std::f64::consts::PI;
 use math::*;
+#[cfg(feature = "winit")]
 use crate::input;
 
+// looking straight up or down would make the view direction parallel to world up and flip the basis
+const MAX_PITCH: f64 = 89.0;
+
+// Right handed basis where u is right, v is up and w points backwards away from the look at point, so the image
+// plane sits at -w. get_forward returns -w and everything built from the basis (get_ray, set_origin, update)
+// uses the same convention.
 #[allow(dead_code)]
 pub struct Camera {
     origin: Vec3,
@@ -18,7 +25,12 @@ pub struct Camera {
     half_width: f64,
     half_height: f64, 
     focus_dist: f64,
-    world_up: Vec3
+    world_up: Vec3,
+    // look direction in degrees, yaw about y from -z towards +x and pitch up from the xz plane. Mouse-look
+    // turns these and rebuilds look_at at look_distance so repeated small turns don't drift
+    yaw: f64,
+    pitch: f64,
+    look_distance: f64,
 }
 
 impl Camera {
@@ -28,10 +40,10 @@ impl Camera {
         let theta = vfov * PI / 180.0;
         let half_height = (theta/2.0).tan();
         let half_width = aspect * half_height;
-        let w = Vec3::new_unit_vector(&(origin - look_at)); // TODO(SS): This produces negative forward vector..
+        let w = Vec3::new_unit_vector(&(origin - look_at));
         let u = Vec3::new_unit_vector(&vec3::cross(&vup, &w));
         let v = vec3::cross(&w,&u);
-        Camera {
+        let mut cam = Camera {
             origin: origin.clone(),
             look_at: look_at.clone(),
             lower_left_corner: origin - (u*half_width*focus_dist) - (v*half_height*focus_dist) - (w*focus_dist),
@@ -46,8 +58,33 @@ impl Camera {
             half_width,
             half_height, 
             focus_dist,
-            world_up: vup
-        }
+            world_up: vup,
+            yaw: 0.0,
+            pitch: 0.0,
+            look_distance: 1.0,
+        };
+        cam.sync_angles();
+        cam
+    }
+
+    // Lens described as a focal length and sensor height in mm, e.g. 50mm on a 36mm sensor, instead of a vfov
+    pub fn from_focal_length(origin: Vec3, look_at: Vec3, vup: Vec3, focal_mm: f64, sensor_mm: f64, aspect: f64, 
+                             aperture: f64, focus_dist: f64, time0: f64, time1: f64) -> Camera {
+        Camera::new(origin, look_at, vup, focal_length_to_vfov(focal_mm, sensor_mm), aspect, aperture, focus_dist, time0, time1)
+    }
+
+    // Changes the vertical field of view in degrees keeping the aspect ratio
+    pub fn set_vfov(&mut self, vfov: f64) {
+        let aspect = self.half_width / self.half_height;
+        self.half_height = (vfov * PI / 180.0 / 2.0).tan();
+        self.half_width = aspect * self.half_height;
+        self.update();
+    }
+
+    // Changes the width over height of the image keeping the vertical field of view, e.g. after a resize
+    pub fn set_aspect(&mut self, aspect: f64) {
+        self.half_width = aspect * self.half_height;
+        self.update();
     }
 
     pub fn get_ray(&self, s: f64, t: f64) -> Ray {
@@ -58,6 +95,24 @@ impl Camera {
         Ray::new(&self.origin + &offset, direction, time)
     }
 
+    // get_ray with the lens position and time taken from samples in [0, 1) instead of drawn at random,
+    // so they can follow a low discrepancy sequence
+    pub fn get_ray_sampled(&self, s: f64, t: f64, lens_sample: (f64, f64), time_sample: f64) -> Ray {
+        let rd = concentric_sample_disk(lens_sample.0, lens_sample.1)*self.lens_radius;
+        let offset = self.u*rd.x + self.v*rd.y;
+        let time = self.time0 + time_sample*(self.time1 - self.time0);
+        let direction = self.lower_left_corner + self.horizontal*s + self.vertical*t - self.origin - offset;
+        Ray::new(self.origin + offset, direction, time)
+    }
+
+    // Ray for a single colour channel (0 = red, 1 = green, 2 = blue) with chromatic aberration. Red and blue are
+    // scaled in and out from the image centre by the aberration amount so the fringing grows towards the edges.
+    pub fn get_channel_ray(&self, s: f64, t: f64, channel: usize, aberration: f64) -> Ray {
+        let (s, t) = channel_st(s, t, channel, aberration);
+        self.get_ray(s, t)
+    }
+
+    // towards the look at point, w points the other way
     pub fn get_forward(&self) -> Vec3 {
         -self.w.clone()
     }
@@ -79,6 +134,9 @@ impl Camera {
             self.look_at += &origin - &self.origin;
         }
         self.origin = origin;
+        if !update_look_at {
+            self.sync_angles();
+        }
         self.lower_left_corner = &self.origin - &(&self.u*self.half_width*self.focus_dist) - &(&self.v*self.half_height*self.focus_dist) - &(&self.w*self.focus_dist)
     }
 
@@ -95,17 +153,58 @@ impl Camera {
         self.vertical = &self.v*2.0*self.half_height*self.focus_dist;
     }
 
-    pub fn set_look_at(&mut self, look_at: Vec3, maintain_distance: bool) {
-        let mut look_at = look_at;
-        if maintain_distance {
-             // minimise drift - ensure new look is same distance apart
-            let look_at_dist = (&self.origin - &self.look_at).length();
-            let new_look_at_dist = (&self.origin - &look_at).length();
-            look_at *= look_at_dist / new_look_at_dist;
-        }
+    // Rotates the camera origin around the look at point about the world up axis, keeping it facing the look at point
+    pub fn orbit(&mut self, degrees: f64) {
+        let radians = degrees * PI / 180.0;
+        let (sin_theta, cos_theta) = radians.sin_cos();
+        let axis = Vec3::new_unit_vector(&self.world_up);
+        let offset = &self.origin - &self.look_at;
+        // Rodrigues' rotation formula
+        let rotated = &(&(&offset * cos_theta) + &(&vec3::cross(&axis, &offset) * sin_theta)) + &(&axis * (vec3::dot(&axis, &offset) * (1.0 - cos_theta)));
+        self.origin = &self.look_at + &rotated;
+        self.sync_angles();
+        self.update();
+    }
+
+    // Keeps the view direction and moves the camera back from the centre of bbox until the box's bounding sphere
+    // fits inside the narrower of the vertical and horizontal fields of view
+    pub fn frame_scene(&mut self, bbox: &AABB, aspect: f64) {
+        self.half_width = aspect * self.half_height;
+        let centre = (bbox.min() + bbox.max()) * 0.5;
+        let radius = ((bbox.max() - bbox.min()).length() * 0.5).max(1e-3);
+        let half_fov = self.half_height.atan().min(self.half_width.atan());
+        let distance = radius / half_fov.sin();
+        let forward = self.get_forward();
+        self.look_at = centre;
+        self.origin = &self.look_at - &(&forward * distance);
+        self.sync_angles();
+        self.update();
+    }
+
+    pub fn set_look_at(&mut self, look_at: Vec3) {
         self.look_at = look_at;
+        self.sync_angles();
+    }
+
+    // Turns the look direction by the given degrees, pitch is clamped to +-MAX_PITCH. Call update to rebuild the basis
+    pub fn rotate(&mut self, yaw_degrees: f64, pitch_degrees: f64) {
+        self.yaw = (self.yaw + yaw_degrees) % 360.0;
+        self.pitch = clamp(&(self.pitch + pitch_degrees), &-MAX_PITCH, &MAX_PITCH);
+        let (yaw, pitch) = (self.yaw.to_radians(), self.pitch.to_radians());
+        let direction = Vec3::new(pitch.cos() * yaw.sin(), pitch.sin(), -pitch.cos() * yaw.cos());
+        self.look_at = &self.origin + &(&direction * self.look_distance);
+    }
+
+    // recovers the angles after look_at or origin are set directly
+    fn sync_angles(&mut self) {
+        let to_look_at = &self.look_at - &self.origin;
+        self.look_distance = to_look_at.length();
+        let direction = &to_look_at / self.look_distance;
+        self.yaw = direction.x.atan2(-direction.z).to_degrees();
+        self.pitch = direction.y.max(-1.0).min(1.0).asin().to_degrees();
     }
     
+    #[cfg(feature = "winit")]
     pub fn update_from_input(
         &mut self, 
         user_input: &input::UserInput, 
@@ -114,7 +213,9 @@ impl Camera {
         use winit::event::*;
 
         const CAM_SPEED: f64 = 40.0;
-        const MOUSE_LOOK_SPEED: f64 = 1.0;
+        // degrees per second
+        const LOOK_SPEED: f64 = 60.0;
+        const MOUSE_LOOK_SPEED: f64 = 5.0;
 
         let mut camera_moved = false;
 
@@ -168,47 +269,26 @@ impl Camera {
         }
         
         if user_input.keys_held.contains(&VirtualKeyCode::Right) {
-            let cam_look_at = self.get_look_at();
-            let cam_right = self.get_right();
-            self.set_look_at(cam_look_at + cam_right * CAM_SPEED * frame_time, true);
+            self.rotate(LOOK_SPEED * frame_time, 0.0);
             camera_moved = true;
         }
         if user_input.keys_held.contains(&VirtualKeyCode::Left) {
-            let cam_look_at = self.get_look_at();
-            let cam_right = self.get_right();
-            self.set_look_at(cam_look_at + -cam_right * CAM_SPEED * frame_time, true);
+            self.rotate(-LOOK_SPEED * frame_time, 0.0);
             camera_moved = true;
         }
         if user_input.keys_held.contains(&VirtualKeyCode::Up) {
-            let cam_look_at = self.get_look_at();
-            let cam_up = self.get_up();
-            self.set_look_at(cam_look_at + cam_up * CAM_SPEED * frame_time, true);
+            self.rotate(0.0, LOOK_SPEED * frame_time);
             camera_moved = true;
         }
         if user_input.keys_held.contains(&VirtualKeyCode::Down) {
-            let cam_look_at = self.get_look_at();
-            let cam_up = self.get_up();
-            self.set_look_at(cam_look_at + -cam_up * CAM_SPEED * frame_time, true);
+            self.rotate(0.0, -LOOK_SPEED * frame_time);
             camera_moved = true;
         }
         if user_input.mouse_delta != (0.0,0.0) {
-            let mouse_x_delta = user_input.mouse_delta.0;
-            let mouse_y_delta = user_input.mouse_delta.1;
-            if mouse_x_delta != 0.0 || mouse_y_delta != 0.0
-            { 
-                let mut cam_look_at = self.get_look_at();
-                let cam_right = self.get_right();
-                let cam_up = self.get_up();
-                if mouse_x_delta != 0.0 {
-                    cam_look_at += cam_right * MOUSE_LOOK_SPEED * frame_time * mouse_x_delta
-                }
-                if mouse_y_delta != 0.0 {
-                    cam_look_at += cam_up * MOUSE_LOOK_SPEED * frame_time * mouse_y_delta;
-                }
-
-                self.set_look_at(cam_look_at, true);
-                camera_moved = true;
-            }
+            let mouse_x_delta = user_input.mouse_delta.0 as f64;
+            let mouse_y_delta = user_input.mouse_delta.1 as f64;
+            self.rotate(MOUSE_LOOK_SPEED * frame_time * mouse_x_delta, MOUSE_LOOK_SPEED * frame_time * mouse_y_delta);
+            camera_moved = true;
         }
 
         camera_moved
@@ -217,12 +297,178 @@ impl Camera {
     //pub fn get_look
 }
 
+// Vertical field of view in degrees of a lens focused at infinity, sensor_mm is the sensor height
+pub fn focal_length_to_vfov(focal_mm: f64, sensor_mm: f64) -> f64 {
+    2.0 * (sensor_mm / (2.0 * focal_mm)).atan() * 180.0 / PI
+}
+
+// Image coordinates a colour channel's ray goes through with chromatic aberration, see Camera::get_channel_ray
+pub fn channel_st(s: f64, t: f64, channel: usize, aberration: f64) -> (f64, f64) {
+    let scale = 1.0 + aberration * (channel as f64 - 1.0);
+    (0.5 + (s - 0.5) * scale, 0.5 + (t - 0.5) * scale)
+}
+
+#[cfg(test)]
+mod tests {
 
-fn random_in_unit_disk() -> Vec3 {
-    let mut new_vector = Vec3::new(random::rand(), random::rand(), 0.0)*2.0 - Vec3::new(1.0,1.0,0.0);
-    while vec3::dot(&new_vector,&new_vector) >= 1.0 {
-        new_vector = Vec3::new(random::rand(), random::rand(), 0.0)*2.0 - Vec3::new(1.0,1.0,0.0);
-    } 
+    use super::*;
+    use std::sync::Arc;
+    use hitable::Hitable;
+    use sphere::MovingSphere;
+    use material::DummyMaterial;
 
-    new_vector
+    // Spread of the moving sphere's centre recovered from hits of camera rays through the centre of the image
+    fn hit_centre_spread(shutter: f64) -> f64 {
+        let radius = 1.0;
+        // moves 0.5 along x over time 0..1
+        let sphere = MovingSphere::new(Vec3::new_zero_vector(), Vec3::new(0.5, 0.0, 0.0), 0.0, 1.0, radius, Arc::new(DummyMaterial::new()));
+        let cam = Camera::new(Vec3::new(0.0, 0.0, 5.0), Vec3::new_zero_vector(), Vec3::new(0.0, 1.0, 0.0), 40.0, 1.0, 0.0, 1.0, 0.0, shutter);
+
+        let (mut min_x, mut max_x) = (std::f64::MAX, std::f64::MIN);
+        for _ in 0..1000 {
+            let hit = sphere.hit(&cam.get_ray(0.5, 0.5), 0.001, std::f64::MAX).unwrap();
+            let centre = &hit.p - &(&hit.normal * radius);
+            min_x = min_x.min(centre.x);
+            max_x = max_x.max(centre.x);
+        }
+        max_x - min_x
+    }
+
+    #[test]
+    fn chromatic_aberration_grows_from_centre() {
+        let cam = Camera::new(Vec3::new_zero_vector(), Vec3::new(0.0, 0.0, -1.0), Vec3::new(0.0, 1.0, 0.0), 40.0, 1.0, 0.0, 1.0, 0.0, 1.0);
+        let channel_separation = |s: f64, t: f64| {
+            let red = Vec3::new_unit_vector(&cam.get_channel_ray(s, t, 0, 0.02).direction);
+            let blue = Vec3::new_unit_vector(&cam.get_channel_ray(s, t, 2, 0.02).direction);
+            (&red - &blue).length()
+        };
+
+        assert_eq!(channel_separation(0.5, 0.5), 0.0);
+        let mid = channel_separation(0.75, 0.5);
+        let edge = channel_separation(1.0, 0.5);
+        let corner = channel_separation(1.0, 1.0);
+        assert!(mid > 0.0 && edge > mid && corner > edge, "mid = {}, edge = {}, corner = {}", mid, edge, corner);
+
+        // green is the reference channel and is unaffected
+        let green = cam.get_channel_ray(0.9, 0.1, 1, 0.02).direction;
+        let reference = cam.get_ray(0.9, 0.1).direction;
+        assert!((&green - &reference).length() < 1e-12);
+    }
+
+    #[test]
+    fn framed_scene_corners_are_inside_frustum() {
+        let aspect = 2.0;
+        let mut cam = Camera::new(Vec3::new(3.0, 1.0, 2.0), Vec3::new(0.0, 0.0, -1.0), Vec3::new(0.0, 1.0, 0.0), 40.0, aspect, 0.0, 1.0, 0.0, 1.0);
+        let bbox = AABB::new(Vec3::new(-20.0, 5.0, 10.0), Vec3::new(40.0, 12.0, 25.0));
+        cam.frame_scene(&bbox, aspect);
+
+        for corner in 0..8 {
+            let point = Vec3::new(
+                if corner & 1 == 0 {bbox.min().x} else {bbox.max().x},
+                if corner & 2 == 0 {bbox.min().y} else {bbox.max().y},
+                if corner & 4 == 0 {bbox.min().z} else {bbox.max().z});
+            let to_corner = &point - &cam.get_origin();
+            let depth = vec3::dot(&to_corner, &cam.get_forward());
+            assert!(depth > 0.0, "corner {} is behind the camera", point);
+            assert!(vec3::dot(&to_corner, &cam.get_right()).abs() / depth <= cam.half_width, "corner {} is outside horizontally", point);
+            assert!(vec3::dot(&to_corner, &cam.get_up()).abs() / depth <= cam.half_height, "corner {} is outside vertically", point);
+        }
+    }
+
+    #[test]
+    fn basis_agrees_with_look_at_and_rays() {
+        let (origin, look_at) = (Vec3::new(3.0, 1.0, 2.0), Vec3::new(-1.0, 2.0, -4.0));
+        let mut cam = Camera::new(origin, look_at, Vec3::new(0.0, 1.0, 0.0), 40.0, 1.5, 0.0, 1.0, 0.0, 1.0);
+        for _ in 0..2 {
+            let to_look_at = &cam.get_look_at() - &cam.get_origin();
+            assert!(vec3::dot(&cam.get_forward(), &to_look_at) > 0.0);
+            // the centre ray looks straight ahead, rays towards the right and top of the image lean that way
+            let centre = Vec3::new_unit_vector(&cam.get_ray(0.5, 0.5).direction);
+            assert!((&centre - &cam.get_forward()).length() < 1e-9);
+            assert!(vec3::dot(&cam.get_ray(1.0, 0.5).direction, &cam.get_right()) > 0.0);
+            assert!(vec3::dot(&cam.get_ray(0.5, 1.0).direction, &cam.get_up()) > 0.0);
+            assert!(vec3::dot(&cam.get_up(), &Vec3::new(0.0, 1.0, 0.0)) > 0.0);
+            // right handed, right x up is backwards
+            assert!((&vec3::cross(&cam.get_right(), &cam.get_up()) + &cam.get_forward()).length() < 1e-9);
+
+            // and still agree after moving
+            cam.set_origin(Vec3::new(-5.0, 0.0, 6.0), false);
+            cam.update();
+        }
+    }
+
+    #[test]
+    fn full_turn_in_small_steps_returns_to_start() {
+        let mut cam = Camera::new(Vec3::new(278.0, 278.0, -800.0), Vec3::new(278.0, 250.0, 0.0), Vec3::new(0.0, 1.0, 0.0), 40.0, 1.0, 0.0, 1.0, 0.0, 1.0);
+        let (start_forward, start_distance) = (cam.get_forward(), (&cam.get_look_at() - &cam.get_origin()).length());
+        for _ in 0..3600 {
+            cam.rotate(0.1, 0.0);
+            cam.update();
+        }
+        assert!((&cam.get_forward() - &start_forward).length() < 1e-9, "{} != {}", cam.get_forward(), start_forward);
+        assert!(((&cam.get_look_at() - &cam.get_origin()).length() - start_distance).abs() < 1e-9);
+
+        // moving keeps the direction
+        cam.set_origin(&cam.get_origin() + &Vec3::new(10.0, 0.0, 5.0), true);
+        cam.rotate(45.0, 0.0);
+        cam.rotate(-45.0, 0.0);
+        cam.update();
+        assert!((&cam.get_forward() - &start_forward).length() < 1e-9);
+    }
+
+    #[test]
+    fn pitch_is_clamped_before_the_camera_flips() {
+        let mut cam = Camera::new(Vec3::new(0.0, 0.0, 5.0), Vec3::new_zero_vector(), Vec3::new(0.0, 1.0, 0.0), 40.0, 1.0, 0.0, 1.0, 0.0, 1.0);
+        for pitch_step in [1.0, -1.0].iter() {
+            let mut previous_forward_y = cam.get_forward().y;
+            for _ in 0..200 {
+                cam.rotate(0.0, *pitch_step);
+                cam.update();
+                // keeps turning the same way until the limit then stops, rather than going over the top
+                assert!((cam.get_forward().y - previous_forward_y) * pitch_step >= -1e-12);
+                assert!(vec3::dot(&cam.get_up(), &Vec3::new(0.0, 1.0, 0.0)) > 0.0);
+                assert!(cam.get_forward().z < 0.0);
+                previous_forward_y = cam.get_forward().y;
+            }
+            assert_eq!(cam.pitch, MAX_PITCH * pitch_step);
+            assert!((cam.get_forward().y - (MAX_PITCH * pitch_step).to_radians().sin()).abs() < 1e-9);
+        }
+    }
+
+    #[test]
+    fn focal_length_gives_expected_vfov() {
+        let vfov = focal_length_to_vfov(50.0, 36.0);
+        assert!((vfov - 39.6).abs() < 0.05, "vfov = {}", vfov);
+        // longer lenses zoom in
+        assert!(focal_length_to_vfov(85.0, 36.0) < vfov);
+
+        let cam = Camera::from_focal_length(Vec3::new_zero_vector(), Vec3::new(0.0, 0.0, -1.0), Vec3::new(0.0, 1.0, 0.0), 50.0, 36.0, 1.5, 0.0, 1.0, 0.0, 1.0);
+        let reference = Camera::new(Vec3::new_zero_vector(), Vec3::new(0.0, 0.0, -1.0), Vec3::new(0.0, 1.0, 0.0), vfov, 1.5, 0.0, 1.0, 0.0, 1.0);
+        assert!((cam.half_height - reference.half_height).abs() < 1e-12);
+        assert!((cam.half_width - reference.half_width).abs() < 1e-12);
+
+        let mut cam = Camera::new(Vec3::new_zero_vector(), Vec3::new(0.0, 0.0, -1.0), Vec3::new(0.0, 1.0, 0.0), 90.0, 1.5, 0.0, 1.0, 0.0, 1.0);
+        cam.set_vfov(vfov);
+        assert!((cam.get_ray(0.0, 0.0).direction - reference.get_ray(0.0, 0.0).direction).length() < 1e-12);
+    }
+
+    #[test]
+    fn aspect_widens_the_view_but_keeps_its_height() {
+        let mut cam = Camera::new(Vec3::new(1.0, 2.0, 3.0), Vec3::new(0.0, 0.0, -1.0), Vec3::new(0.0, 1.0, 0.0), 40.0, 1.0, 0.0, 2.0, 0.0, 1.0);
+        let (horizontal, vertical) = (cam.horizontal.clone(), cam.vertical.clone());
+        cam.set_aspect(16.0 / 9.0);
+        assert!((cam.horizontal.length() - horizontal.length() * 16.0 / 9.0).abs() < 1e-12);
+        assert!(cam.vertical.approx_eq(&vertical, 1e-12));
+        // the view stays centred on the same point
+        assert!(Vec3::new_unit_vector(&cam.get_ray(0.5, 0.5).direction).approx_eq(&cam.get_forward(), 1e-12));
+    }
+
+    #[test]
+    fn motion_blur_is_proportional_to_shutter() {
+        for shutter in [0.25, 0.5, 1.0].iter() {
+            let spread = hit_centre_spread(*shutter);
+            assert!((spread - 0.5 * shutter).abs() < 0.02 * shutter, "shutter = {}, spread = {}", shutter, spread);
+        }
+        assert_eq!(hit_centre_spread(0.0), 0.0);
+    }
 }
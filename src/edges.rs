@@ -0,0 +1,54 @@
+// Sobel gradient above which a pixel is considered to lie on an edge. Luminance is compressed
+// with l / (1 + l) first so the threshold behaves the same for bright and dark regions.
+pub const EDGE_THRESHOLD: f64 = 0.1;
+
+fn compressed_luminance(buffer: &[f32], image_size: (u32, u32), x: i64, y: i64) -> f64 {
+    // clamp to the border so edge pixels of the image aren't flagged
+    let x = x.max(0).min(image_size.0 as i64 - 1) as usize;
+    let y = y.max(0).min(image_size.1 as i64 - 1) as usize;
+    let index = (y * image_size.0 as usize + x) * 4;
+    let luminance = 0.2126 * buffer[index] as f64 + 0.7152 * buffer[index + 1] as f64 + 0.0722 * buffer[index + 2] as f64;
+    luminance / (1.0 + luminance)
+}
+
+// Runs a sobel filter over the luminance of an RGBA buffer and returns a per-pixel mask, laid out
+// like the buffer, of pixels whose gradient magnitude exceeds the threshold
+pub fn sobel_edge_mask(buffer: &[f32], image_size: (u32, u32), threshold: f64) -> Vec<bool> {
+    let mut mask = vec![false; (image_size.0 * image_size.1) as usize];
+    for y in 0..image_size.1 as i64 {
+        for x in 0..image_size.0 as i64 {
+            let l = |dx: i64, dy: i64| compressed_luminance(buffer, image_size, x + dx, y + dy);
+            let gradient_x = (l(1, -1) + 2.0 * l(1, 0) + l(1, 1)) - (l(-1, -1) + 2.0 * l(-1, 0) + l(-1, 1));
+            let gradient_y = (l(-1, 1) + 2.0 * l(0, 1) + l(1, 1)) - (l(-1, -1) + 2.0 * l(0, -1) + l(1, -1));
+            let magnitude = (gradient_x * gradient_x + gradient_y * gradient_y).sqrt();
+            mask[(y * image_size.0 as i64 + x) as usize] = magnitude > threshold;
+        }
+    }
+    mask
+}
+
+#[cfg(test)]
+mod tests {
+
+    use super::*;
+
+    #[test]
+    fn edges_found_along_boundary_only() {
+        let image_size = (16, 8);
+        let mut buffer = vec![0.0_f32; (image_size.0 * image_size.1 * 4) as usize];
+        for (pixel_index, pixel) in buffer.chunks_mut(4).enumerate() {
+            if pixel_index as u32 % image_size.0 >= image_size.0 / 2 {
+                pixel[0] = 1.0;
+                pixel[1] = 1.0;
+                pixel[2] = 1.0;
+            }
+        }
+
+        let mask = sobel_edge_mask(&buffer, image_size, EDGE_THRESHOLD);
+        for (pixel_index, is_edge) in mask.iter().enumerate() {
+            let x = pixel_index as u32 % image_size.0;
+            let near_boundary = x == image_size.0 / 2 - 1 || x == image_size.0 / 2;
+            assert_eq!(*is_edge, near_boundary, "pixel {} at x = {}", pixel_index, x);
+        }
+    }
+}
@@ -0,0 +1,205 @@
+use std::f64::consts::PI;
+use math::*;
+
+// Equirectangular HDR environment map. Rows run from the bottom of the sky (v = 0, looking straight
+// down) to the top (v = 1) and columns wrap around the y axis, matching Vec3::to_spherical.
+// Keeps a 2D CDF over luminance so directions can be importance sampled towards bright regions.
+// Lookups are bilinearly filtered so mirrors don't show blocky texels, sampling stays piecewise constant.
+pub struct EnvironmentMap {
+    width: usize,
+    height: usize,
+    pixels: Vec<Vec3>,
+    distribution: Distribution2D, // luminance scaled by the solid angle of each pixel
+}
+
+// the pixels are too many to print
+impl std::fmt::Debug for EnvironmentMap {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        write!(f, "EnvironmentMap({}x{})", self.width, self.height)
+    }
+}
+
+impl PartialEq for EnvironmentMap {
+    fn eq(&self, other: &Self) -> bool {
+        self.width == other.width && self.height == other.height && self.pixels == other.pixels
+    }
+}
+
+impl EnvironmentMap {
+    pub fn new(width: usize, height: usize, pixels: Vec<Vec3>) -> Self {
+        assert_eq!(pixels.len(), width * height, "environment map pixels don't match its size");
+
+        let weights: Vec<f64> = pixels.iter().enumerate().map(|(index, pixel)| {
+            // rows near the poles cover less solid angle
+            let latitude = (((index / width) as f64 + 0.5) / height as f64 - 0.5) * PI;
+            pixel.luminance().max(0.0) * latitude.cos()
+        }).collect();
+
+        Self {
+            width,
+            height,
+            pixels,
+            distribution: Distribution2D::new(width, height, &weights),
+        }
+    }
+
+    // Linear rgb from the first layer of an OpenEXR file, the top row of the file is the top of the sky
+    pub fn load(path: &str) -> Result<Self, String> {
+        let image = exr::prelude::read_first_rgba_layer_from_file(path,
+            |resolution, _| (resolution.width(), vec![Vec3::new_zero_vector(); resolution.width() * resolution.height()]),
+            |(width, pixels): &mut (usize, Vec<Vec3>), position, (r, g, b, _): (f32, f32, f32, f32)| {
+                pixels[position.y() * *width + position.x()] = Vec3::new(r as f64, g as f64, b as f64);
+            })
+            .map_err(|e| format!("Could not read environment map {}: {}", path, e))?;
+        let (width, pixels) = image.layer_data.channel_data.pixels;
+        let height = pixels.len() / width;
+        // flipped so rows run from the bottom up
+        let pixels = pixels.chunks(width).rev().flatten().cloned().collect();
+        Ok(Self::new(width, height, pixels))
+    }
+
+    fn direction_to_uv(direction: &Vec3) -> (f64, f64) {
+        let (theta, phi) = Vec3::new_unit_vector(direction).to_spherical();
+        ((phi + PI) / (2.0 * PI), (theta + 0.5 * PI) / PI)
+    }
+
+    // pixel the direction falls in
+    fn pixel_coordinates(&self, direction: &Vec3) -> (usize, usize) {
+        let (u, v) = Self::direction_to_uv(direction);
+        let column = ((u * self.width as f64) as usize).min(self.width - 1);
+        let row = ((v * self.height as f64) as usize).min(self.height - 1);
+        (column, row)
+    }
+
+    // bilinear between the four nearest pixel centres, wrapping around the y axis and clamping at the poles
+    pub fn value(&self, direction: &Vec3) -> Vec3 {
+        let (u, v) = Self::direction_to_uv(direction);
+        let x = u * self.width as f64 - 0.5;
+        let y = (v * self.height as f64 - 0.5).max(0.0).min((self.height - 1) as f64);
+        let (x0, y0) = (x.floor(), y.floor());
+        let (tx, ty) = (x - x0, y - y0);
+
+        let width = self.width as i64;
+        let column = |offset: i64| ((x0 as i64 + offset).rem_euclid(width)) as usize;
+        let row = |offset: usize| (y0 as usize + offset).min(self.height - 1);
+        let pixel = |column: usize, row: usize| &self.pixels[row * self.width + column];
+
+        let bottom = lerp(pixel(column(0), row(0)), pixel(column(1), row(0)), tx);
+        let top = lerp(pixel(column(0), row(1)), pixel(column(1), row(1)), tx);
+        lerp(&bottom, &top, ty)
+    }
+
+    // Picks a direction with probability proportional to the map's luminance, returns it with its pdf
+    pub fn sample_direction(&self) -> (Vec3, f64) {
+        let (column, row) = self.distribution.sample();
+        let u = (column as f64 + random::rand()) / self.width as f64;
+        let v = (row as f64 + random::rand()) / self.height as f64;
+        let direction = Vec3::from_spherical((v - 0.5) * PI, u * 2.0 * PI - PI);
+        (direction, self.pdf_at(column, row, v))
+    }
+
+    // Probability density per unit solid angle of sample_direction returning direction
+    pub fn pdf(&self, direction: &Vec3) -> f64 {
+        let (column, row) = self.pixel_coordinates(direction);
+        let (theta, _) = Vec3::new_unit_vector(direction).to_spherical();
+        self.pdf_at(column, row, (theta + 0.5 * PI) / PI)
+    }
+
+    fn pdf_at(&self, column: usize, row: usize, v: f64) -> f64 {
+        // uniform within the pixel in uv, converted to solid angle where d(omega) = 2 pi^2 cos(latitude) du dv
+        let pdf_uv = self.distribution.probability(column, row) * (self.width * self.height) as f64;
+        let cos_latitude = ((v - 0.5) * PI).cos();
+        if cos_latitude <= 0.0 {
+            return 0.0;
+        }
+        pdf_uv / (2.0 * PI * PI * cos_latitude)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+
+    use super::*;
+
+    fn bright_spot_map() -> EnvironmentMap {
+        let (width, height) = (64, 32);
+        let mut pixels = vec![Vec3::from_float(0.01); width * height];
+        pixels[20 * width + 48] = Vec3::from_float(1000.0);
+        EnvironmentMap::new(width, height, pixels)
+    }
+
+    #[test]
+    fn samples_concentrate_on_bright_spot() {
+        let map = bright_spot_map();
+        random::seed_thread_rng(1379);
+        let num_samples = 2000;
+        let num_bright = (0..num_samples).filter(|_| {
+            let (direction, pdf) = map.sample_direction();
+            assert!(pdf > 0.0);
+            map.value(&direction).x > 1.0
+        }).count();
+        random::clear_thread_rng_seed();
+        assert!(num_bright as f64 > 0.9 * num_samples as f64, "{} of {} samples hit the bright spot", num_bright, num_samples);
+    }
+
+    #[test]
+    fn mirror_reflection_of_gradient_is_smooth() {
+        use std::sync::Arc;
+        use hitable::Hitable;
+        use material::{Material, Metal};
+        use rect::{AxisAlignedRect, AxisAlignedRectAxis};
+
+        // brightness increases one unit per column around the y axis
+        let (width, height) = (8, 4);
+        let pixels = (0..width * height).map(|index| Vec3::from_float((index % width) as f64)).collect();
+        let map = EnvironmentMap::new(width, height, pixels);
+
+        let mirror = AxisAlignedRect::new(-10.0, 10.0, -10.0, 10.0, 0.0, AxisAlignedRectAxis::Y, Arc::new(Metal::new(Vec3::from_float(1.0), 0.0)));
+        let num_rays = 400;
+        let reflections: Vec<f64> = (0..num_rays).map(|ray_index| {
+            let x = -1.0 + 2.0 * ray_index as f64 / (num_rays - 1) as f64;
+            let ray = Ray::new(Vec3::new(0.0, 1.0, 0.0), Vec3::new(x, -1.0, -1.0), 0.0);
+            let hit_record = mirror.hit(&ray, 0.001, std::f64::MAX).unwrap();
+            let reflected = hit_record.mat.scatter(&ray, &hit_record).unwrap().specular_ray;
+            map.value(&reflected.direction).x
+        }).collect();
+
+        // nearest neighbour would hold a texel then jump a whole unit to the next
+        let largest_step = reflections.windows(2).map(|pair| (pair[1] - pair[0]).abs()).fold(0.0, f64::max);
+        assert!(largest_step < 0.05, "largest step = {}", largest_step);
+        let range = reflections[num_rays - 1] - reflections[0];
+        assert!(range > 1.0, "range = {}", range);
+    }
+
+    #[test]
+    fn loaded_exr_is_used_as_the_background() {
+        use trace::BackgroundMode;
+
+        // a 2x2 map, bright across the top row of the file
+        let path = std::env::temp_dir().join("path_tracer_tests").join("environment.exr");
+        std::fs::create_dir_all(path.parent().unwrap()).unwrap();
+        exr::prelude::write_rgba_file(&path, 2, 2, |_, y| if y == 0 {(4.0_f32, 4.0_f32, 4.0_f32, 1.0_f32)} else {(0.5, 0.5, 0.5, 1.0)}).unwrap();
+
+        let background: BackgroundMode = path.to_str().unwrap().parse().unwrap();
+        let environment = background.environment().unwrap();
+        assert_eq!(environment.value(&Vec3::new(0.0, 1.0, 0.0)), Vec3::from_float(4.0));
+        assert_eq!(background.colour(&Vec3::new(0.0, -1.0, 0.0), 1.0), Vec3::from_float(0.5));
+        assert!("missing.exr".parse::<BackgroundMode>().is_err());
+    }
+
+    #[test]
+    fn pdf_integrates_to_one_over_sphere() {
+        let map = bright_spot_map();
+        let (num_theta, num_phi) = (512, 1024);
+        let (d_theta, d_phi) = (PI / num_theta as f64, 2.0 * PI / num_phi as f64);
+        let mut integral = 0.0;
+        for i in 0..num_theta {
+            let theta = -0.5 * PI + (i as f64 + 0.5) * d_theta;
+            for j in 0..num_phi {
+                let phi = -PI + (j as f64 + 0.5) * d_phi;
+                integral += map.pdf(&Vec3::from_spherical(theta, phi)) * theta.cos() * d_theta * d_phi;
+            }
+        }
+        assert!((integral - 1.0).abs() < 0.01, "integral = {}", integral);
+    }
+}
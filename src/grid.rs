@@ -0,0 +1,274 @@
+use hitable::*;
+use math::*;
+use std::sync::Arc;
+
+// Cells per primitive the grid aims for, more cells means fewer primitives tested per cell but more cells stepped through
+const CELLS_PER_PRIMITIVE: f64 = 3.0;
+const MAX_CELLS_PER_AXIS: usize = 64;
+
+// Structure the world's primitives are traced through, selected with -accel=
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Acceleration {
+    Bvh,
+    Grid,
+}
+
+impl std::str::FromStr for Acceleration {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "bvh" => Ok(Acceleration::Bvh),
+            "grid" => Ok(Acceleration::Grid),
+            _ => Err(format!("Unknown acceleration structure '{}', expected bvh or grid", s)),
+        }
+    }
+}
+
+// Scenes are built as BVHs, a grid is rebuilt from the primitives beneath the world. Worlds that are a single
+// primitive are left as they are. Moving primitives are bucketed over the shutter interval time0 to time1
+pub fn accelerate(world: Box<ThreadsafeHitable>, acceleration: Acceleration, time0: f64, time1: f64) -> Box<ThreadsafeHitable> {
+    match acceleration {
+        Acceleration::Bvh => world,
+        Acceleration::Grid => {
+            let mut primitives = vec![];
+            world.append_primitives(&mut primitives);
+            if primitives.is_empty() {
+                world
+            } else {
+                Box::new(UniformGrid::new(primitives, time0, time1))
+            }
+        }
+    }
+}
+
+// Buckets primitives into a grid of equally sized voxels over the scene, a ray steps through the voxels it passes
+// with a 3D-DDA and only tests the primitives in them. Suits scenes of many similar sized, evenly spread
+// primitives where it skips the tree descent a BVH needs. Primitives are listed in every voxel their box overlaps
+pub struct UniformGrid {
+    primitives: Vec<Arc<ThreadsafeHitable>>,
+    cells: Vec<Vec<usize>>,
+    resolution: [usize; 3],
+    cell_size: Vec3,
+    bounding_box: AABB,
+}
+
+impl UniformGrid {
+    pub fn new(primitives: Vec<Arc<ThreadsafeHitable>>, time0: f64, time1: f64) -> UniformGrid {
+        let boxes: Vec<AABB> = primitives.iter().map(|primitive| primitive.bounding_box(time0, time1)).collect();
+        let bounding_box = boxes.iter().skip(1).fold(boxes[0].clone(), |union, next| AABB::get_union(&union, next));
+
+        // cells are roughly cubes, with enough of them for CELLS_PER_PRIMITIVE on average
+        let extent = bounding_box.max() - bounding_box.min();
+        let max_extent = extent.max_component().max(1e-6);
+        let cells_per_unit = (CELLS_PER_PRIMITIVE * primitives.len() as f64).cbrt() / max_extent;
+        let mut resolution = [1; 3];
+        let mut cell_size = Vec3::from_float(1.0);
+        for axis in 0..3 {
+            resolution[axis] = ((extent[axis] * cells_per_unit).round() as usize).clamp(1, MAX_CELLS_PER_AXIS);
+            // flat scenes still get a cell with some depth
+            cell_size[axis] = extent[axis].max(1e-6) / resolution[axis] as f64;
+        }
+
+        let mut grid = UniformGrid {
+            primitives: vec![],
+            cells: vec![vec![]; resolution[0] * resolution[1] * resolution[2]],
+            resolution,
+            cell_size,
+            bounding_box,
+        };
+        for (primitive_index, primitive_box) in boxes.iter().enumerate() {
+            let (min_cell, max_cell) = (grid.cell_at(primitive_box.min()), grid.cell_at(primitive_box.max()));
+            for z in min_cell[2]..=max_cell[2] {
+                for y in min_cell[1]..=max_cell[1] {
+                    for x in min_cell[0]..=max_cell[0] {
+                        let cell_index = grid.cell_index([x, y, z]);
+                        grid.cells[cell_index].push(primitive_index);
+                    }
+                }
+            }
+        }
+        grid.primitives = primitives;
+        grid
+    }
+
+    // cell containing point, clamped into the grid
+    fn cell_at(&self, point: &Vec3) -> [usize; 3] {
+        let mut cell = [0; 3];
+        for axis in 0..3 {
+            let offset = (point[axis] - self.bounding_box.min()[axis]) / self.cell_size[axis];
+            cell[axis] = (offset.max(0.0) as usize).min(self.resolution[axis] - 1);
+        }
+        cell
+    }
+
+    fn cell_index(&self, cell: [usize; 3]) -> usize {
+        (cell[2] * self.resolution[1] + cell[1]) * self.resolution[0] + cell[0]
+    }
+
+    // Range of t the ray spends inside the grid's box, limited to t_min..t_max
+    fn clip(&self, ray: &Ray, t_min: f64, t_max: f64) -> Option<(f64, f64)> {
+        let (mut t_enter, mut t_exit) = (t_min, t_max);
+        for axis in 0..3 {
+            let inv_d = 1.0 / ray.direction()[axis];
+            let mut t0 = (self.bounding_box.min()[axis] - ray.origin()[axis]) * inv_d;
+            let mut t1 = (self.bounding_box.max()[axis] - ray.origin()[axis]) * inv_d;
+            if inv_d < 0.0 {
+                std::mem::swap(&mut t0, &mut t1);
+            }
+            t_enter = t_enter.max(t0);
+            t_exit = t_exit.min(t1);
+            if t_exit < t_enter {
+                return None;
+            }
+        }
+        Some((t_enter, t_exit))
+    }
+
+    // Calls visit with each cell the ray passes through in order along with the t it leaves the cell at, stopping
+    // when visit returns true
+    fn traverse<F: FnMut(&[usize], f64) -> bool>(&self, ray: &Ray, t_min: f64, t_max: f64, mut visit: F) {
+        let (t_enter, t_exit) = match self.clip(ray, t_min, t_max) {
+            Some(range) => range,
+            None => return,
+        };
+        let mut cell = self.cell_at(&ray.at(t_enter));
+        let mut step = [0_i64; 3];
+        let mut t_next = [f64::INFINITY; 3];
+        let mut t_delta = [f64::INFINITY; 3];
+        for axis in 0..3 {
+            let direction = ray.direction()[axis];
+            let cell_min = self.bounding_box.min()[axis] + cell[axis] as f64 * self.cell_size[axis];
+            if direction > 0.0 {
+                step[axis] = 1;
+                t_next[axis] = (cell_min + self.cell_size[axis] - ray.origin()[axis]) / direction;
+                t_delta[axis] = self.cell_size[axis] / direction;
+            } else if direction < 0.0 {
+                step[axis] = -1;
+                t_next[axis] = (cell_min - ray.origin()[axis]) / direction;
+                t_delta[axis] = -self.cell_size[axis] / direction;
+            }
+        }
+
+        loop {
+            let axis = if t_next[0] < t_next[1] {if t_next[0] < t_next[2] {0} else {2}} else if t_next[1] < t_next[2] {1} else {2};
+            let t_leave = t_next[axis].min(t_exit);
+            if visit(&self.cells[self.cell_index(cell)], t_leave) || t_next[axis] > t_exit {
+                return;
+            }
+            let next_cell = cell[axis] as i64 + step[axis];
+            if next_cell < 0 || next_cell >= self.resolution[axis] as i64 {
+                return;
+            }
+            cell[axis] = next_cell as usize;
+            t_next[axis] += t_delta[axis];
+        }
+    }
+}
+
+impl Hitable for UniformGrid {
+    fn hit(&self, ray: &Ray, t_min: f64, t_max: f64) -> Option<HitRecord> {
+        let mut closest: Option<HitRecord> = None;
+        self.traverse(ray, t_min, t_max, |cell, t_leave| {
+            for primitive_index in cell {
+                let closest_t = closest.as_ref().map_or(t_max, |hit| hit.t);
+                if let Some(hit) = self.primitives[*primitive_index].hit(ray, t_min, closest_t) {
+                    closest = Some(hit);
+                }
+            }
+            // primitives overlapping several cells can be hit beyond this one, a later cell may still hold something nearer
+            closest.as_ref().is_some_and(|hit| hit.t <= t_leave)
+        });
+        closest
+    }
+
+    fn hit_any(&self, ray: &Ray, t_min: f64, t_max: f64) -> bool {
+        let mut hit_any = false;
+        self.traverse(ray, t_min, t_max, |cell, _| {
+            hit_any = cell.iter().any(|primitive_index| self.primitives[*primitive_index].hit_any(ray, t_min, t_max));
+            hit_any
+        });
+        hit_any
+    }
+
+    fn bounding_box(&self, _t0: f64, _t1: f64) -> AABB {
+        self.bounding_box.clone()
+    }
+
+    fn is_emissive(&self) -> bool {
+        self.primitives.iter().any(|primitive| primitive.is_emissive())
+    }
+
+    fn append_lights(&self, lights: &mut Vec<Arc<ThreadsafeHitable>>) {
+        self.primitives.iter().for_each(|primitive| append_child_lights(primitive, lights));
+    }
+
+    fn append_primitives(&self, primitives: &mut Vec<Arc<ThreadsafeHitable>>) {
+        self.primitives.iter().for_each(|primitive| append_child_primitives(primitive, primitives));
+    }
+
+    fn primitive_count(&self) -> usize {
+        self.primitives.iter().map(|primitive| primitive.primitive_count()).sum()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+
+    use super::*;
+    use bvh::BvhNode;
+    use material::{Material, DummyMaterial};
+    use sphere::{Sphere, MovingSphere};
+
+    fn random_point(scale: f64) -> Vec3 {
+        Vec3::new(random::rand() - 0.5, random::rand() - 0.5, random::rand() - 0.5) * scale
+    }
+
+    #[test]
+    fn grid_finds_the_same_nearest_hit_as_the_bvh() {
+        random::seed_thread_rng(1437);
+        let material: Arc<dyn Material + Send + Sync + 'static> = Arc::new(DummyMaterial::new());
+        let spheres: Vec<Arc<ThreadsafeHitable>> = (0..200)
+            .map(|_| Arc::new(Sphere::new(random_point(20.0), 0.2 + random::rand(), material.clone())) as Arc<ThreadsafeHitable>)
+            .collect();
+        let bvh: Box<ThreadsafeHitable> = Box::new(BvhNode::from_list(spheres.clone(), 0.0, 1.0));
+        let grid = UniformGrid::new(spheres, 0.0, 1.0);
+        assert_eq!(grid.primitive_count(), bvh.primitive_count());
+
+        let mut num_hits = 0;
+        for _ in 0..2000 {
+            // rays starting both inside and outside the grid
+            let ray = Ray::new(random_point(40.0), random_point(2.0), 0.0);
+            let (expected, found) = (bvh.hit(&ray, 0.001, std::f64::MAX), grid.hit(&ray, 0.001, std::f64::MAX));
+            assert_eq!(expected.is_some(), found.is_some());
+            if let (Some(expected), Some(found)) = (expected, found) {
+                assert!((expected.t - found.t).abs() < 1e-9, "bvh hit at {} but grid at {}", expected.t, found.t);
+                assert!(expected.p.approx_eq(&found.p, 1e-9));
+                num_hits += 1;
+            }
+            assert_eq!(bvh.hit_any(&ray, 0.001, 10.0), grid.hit_any(&ray, 0.001, 10.0));
+        }
+        assert!(num_hits > 100, "only {} rays hit", num_hits);
+
+        // the world built by a scene is rebuilt as a grid over the same primitives
+        let regridded = accelerate(bvh, Acceleration::Grid, 0.0, 1.0);
+        assert_eq!(regridded.primitive_count(), grid.primitive_count());
+        random::clear_thread_rng_seed();
+    }
+
+    #[test]
+    fn grid_covers_moving_primitives_over_the_shutter_interval() {
+        let material: Arc<dyn Material + Send + Sync + 'static> = Arc::new(DummyMaterial::new());
+        let spheres: Vec<Arc<ThreadsafeHitable>> = vec![
+            Arc::new(Sphere::new(Vec3::new_zero_vector(), 0.5, material.clone())),
+            Arc::new(MovingSphere::new(Vec3::new_zero_vector(), Vec3::new(10.0, 0.0, 0.0), 0.0, 10.0, 0.5, material)),
+        ];
+        let world: Box<ThreadsafeHitable> = Box::new(BvhNode::from_list(spheres, 9.0, 10.0));
+        let grid = accelerate(world, Acceleration::Grid, 9.0, 10.0);
+
+        // the moving sphere is at x = 10 by the end of the shutter, well outside where it was over 0 to 1
+        let ray = Ray::new(Vec3::new(10.0, 0.0, -5.0), Vec3::new(0.0, 0.0, 1.0), 10.0);
+        let hit_record = grid.hit(&ray, 0.001, f64::MAX).unwrap();
+        assert!((hit_record.t - 4.5).abs() < 1e-9, "t = {}", hit_record.t);
+    }
+}
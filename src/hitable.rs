@@ -1,5 +1,5 @@
 use math::*;
-use material::Material;
+use material::{Material, ThreadsafeMaterial};
 use std::sync::Arc;
 
 pub struct HitRecord {
@@ -27,16 +27,67 @@ impl HitRecord {
 pub trait Hitable {
     fn hit(&self, r: &Ray, t_min: f64, t_max: f64) -> Option<HitRecord>;
     fn bounding_box(&self, t0: f64, t1: f64) -> AABB;
+    // occlusion test for shadow rays, true if anything is hit in the interval. Aggregates override this
+    // to return on the first hit found rather than searching for the closest
+    fn hit_any(&self, r: &Ray, t_min: f64, t_max: f64) -> bool {
+        self.hit(r, t_min, t_max).is_some()
+    }
     fn pdf_value(&self, _origin: &Vec3, _direction: &Vec3) -> f64 {
         0.0
     }
     fn random(&self, _origin: &Vec3) -> Vec3 {
         Vec3::new(1.0, 0.0, 0.0)
     }
+    // true when the hitable has an emissive material, used to gather the shapes to importance sample
+    fn is_emissive(&self) -> bool {
+        false
+    }
+    // appends the emissive hitables beneath an aggregate, used as the lights for next event estimation.
+    // Anything else is a leaf and is appended by its parent, see append_child_lights
+    fn append_lights(&self, _lights: &mut Vec<Arc<ThreadsafeHitable>>) {
+    }
+    // appends the primitives beneath an aggregate so they can be rebuilt into another acceleration structure.
+    // Anything else is a leaf and is appended by its parent, see append_child_primitives
+    fn append_primitives(&self, _primitives: &mut Vec<Arc<ThreadsafeHitable>>) {
+    }
+    // appends the bounding box of this hitable and, for acceleration structures, the boxes beneath it down to max_depth more levels
+    fn append_debug_boxes(&self, _max_depth: u32, boxes: &mut Vec<AABB>) {
+        boxes.push(self.bounding_box(0.0, 0.0));
+    }
+    // number of leaf primitives beneath this hitable, used to report the size of the scene and check BVH builds.
+    // Aggregates, including boxes of rects, sum their children and wrappers report the hitable they wrap
+    fn primitive_count(&self) -> usize {
+        1
+    }
 }
 
 pub type ThreadsafeHitable = dyn Hitable + Send + Sync;
 
+// Appends the lights beneath child, or child itself when it is an emissive leaf
+pub fn append_child_lights(child: &Arc<ThreadsafeHitable>, lights: &mut Vec<Arc<ThreadsafeHitable>>) {
+    let num_lights = lights.len();
+    child.append_lights(lights);
+    if lights.len() == num_lights && child.is_emissive() {
+        lights.push(child.clone());
+    }
+}
+
+// Appends the primitives beneath child, or child itself when it is a leaf
+pub fn append_child_primitives(child: &Arc<ThreadsafeHitable>, primitives: &mut Vec<Arc<ThreadsafeHitable>>) {
+    let num_primitives = primitives.len();
+    child.append_primitives(primitives);
+    if primitives.len() == num_primitives {
+        primitives.push(child.clone());
+    }
+}
+
+// Emissive hitables in the world, an emissive world that isn't an aggregate can't be shared so isn't included
+pub fn collect_lights(world: &ThreadsafeHitable) -> Vec<Arc<ThreadsafeHitable>> {
+    let mut lights = vec![];
+    world.append_lights(&mut lights);
+    lights
+}
+
 pub struct HitableList {
     list: Vec<Arc<dyn Hitable + Send + Sync + 'static>>
 }
@@ -60,8 +111,16 @@ impl Hitable for HitableList {
 
         hitrecord
     }
-    fn bounding_box(&self, _t0: f64, _t1: f64) -> AABB {
-        unreachable!(); 
+    fn hit_any(&self, r: &Ray, t_min: f64, t_max: f64) -> bool {
+        self.list.iter().any(|object| object.hit_any(r, t_min, t_max))
+    }
+    // union of the children's boxes, so the scene root can be queried for the extent of the whole scene
+    fn bounding_box(&self, t0: f64, t1: f64) -> AABB {
+        let mut boxes = self.list.iter().map(|hitable| hitable.bounding_box(t0, t1));
+        match boxes.next() {
+            Some(first) => boxes.fold(first, |union, next| AABB::get_union(&union, &next)),
+            None => AABB::new(Vec3::new_zero_vector(), Vec3::new_zero_vector()),
+        }
     }
     fn pdf_value(&self, origin: &Vec3, direction: &Vec3) -> f64 {
         let weight = 1.0 / self.list.len() as f64;
@@ -71,6 +130,18 @@ impl Hitable for HitableList {
         }
         sum
     }
+    fn is_emissive(&self) -> bool {
+        self.list.iter().any(|hitable| hitable.is_emissive())
+    }
+    fn append_lights(&self, lights: &mut Vec<Arc<ThreadsafeHitable>>) {
+        self.list.iter().for_each(|hitable| append_child_lights(hitable, lights));
+    }
+    fn append_primitives(&self, primitives: &mut Vec<Arc<ThreadsafeHitable>>) {
+        self.list.iter().for_each(|hitable| append_child_primitives(hitable, primitives));
+    }
+    fn primitive_count(&self) -> usize {
+        self.list.iter().map(|hitable| hitable.primitive_count()).sum()
+    }
     fn random(&self, origin: &Vec3) -> Vec3 {
         let index = (self.list.len() as f64 * random::rand()) as usize;
         self.list[index].random(origin)
@@ -99,9 +170,81 @@ impl Hitable for FlipNormals {
         None
     }
 
+    fn hit_any(&self, r: &Ray, t_min: f64, t_max: f64) -> bool {
+        self.child.hit_any(r, t_min, t_max)
+    }
+
+    fn bounding_box(&self, t0: f64, t1: f64) -> AABB {
+        self.child.bounding_box(t0, t1)
+    }
+
+    // flipping normals doesn't change where the surface is so light sampling is unchanged
+    fn pdf_value(&self, origin: &Vec3, direction: &Vec3) -> f64 {
+        self.child.pdf_value(origin, direction)
+    }
+
+    fn random(&self, origin: &Vec3) -> Vec3 {
+        self.child.random(origin)
+    }
+
+    fn is_emissive(&self) -> bool {
+        self.child.is_emissive()
+    }
+
+    fn primitive_count(&self) -> usize {
+        self.child.primitive_count()
+    }
+}
+
+// Replaces the material of everything beneath child, e.g. to recolour a loaded mesh without rebuilding it
+pub struct MaterialOverride {
+    child: Arc<ThreadsafeHitable>,
+    mat: Arc<ThreadsafeMaterial>,
+}
+
+impl MaterialOverride {
+    pub fn new(child: Arc<ThreadsafeHitable>, mat: Arc<ThreadsafeMaterial>) -> Self {
+        Self {
+            child,
+            mat,
+        }
+    }
+}
+
+impl Hitable for MaterialOverride {
+    fn hit(&self, r: &Ray, t_min: f64, t_max: f64) -> Option<HitRecord> {
+        if let Some(mut hit_record) = self.child.hit(r, t_min, t_max) {
+            hit_record.mat = self.mat.clone();
+            return Some(hit_record);
+        }
+
+        None
+    }
+
+    fn hit_any(&self, r: &Ray, t_min: f64, t_max: f64) -> bool {
+        self.child.hit_any(r, t_min, t_max)
+    }
+
     fn bounding_box(&self, t0: f64, t1: f64) -> AABB {
         self.child.bounding_box(t0, t1)
     }
+
+    fn pdf_value(&self, origin: &Vec3, direction: &Vec3) -> f64 {
+        self.child.pdf_value(origin, direction)
+    }
+
+    fn random(&self, origin: &Vec3) -> Vec3 {
+        self.child.random(origin)
+    }
+
+    // the child's own materials are hidden so whether this is a light depends only on the override
+    fn is_emissive(&self) -> bool {
+        self.mat.is_emissive()
+    }
+
+    fn primitive_count(&self) -> usize {
+        self.child.primitive_count()
+    }
 }
 
 pub struct Translate {
@@ -135,6 +278,14 @@ impl Hitable for Translate {
         bounding_box.add_translation(self.translation);
         bounding_box
     }
+
+    fn is_emissive(&self) -> bool {
+        self.hittable.is_emissive()
+    }
+
+    fn primitive_count(&self) -> usize {
+        self.hittable.primitive_count()
+    }
 }
 
 pub struct RotateY {
@@ -149,27 +300,8 @@ impl RotateY {
         let radians = angle.to_radians();
         let sin_theta = radians.sin();
         let cos_theta = radians.cos();
-        let bounding_box = hittable.bounding_box(0.0, 1.0);
-        let mut min = Vec3::from_float(std::f64::MAX);
-        let mut max = Vec3::from_float(-std::f64::MAX);
-
-        for i in 0..2 {
-            for j in 0..2 {
-                for k in 0..2 {
-                    let (i_f64, j_f64, k_f64) = (i as f64, j as f64, k as f64);
-                    let x = i_f64 * bounding_box.max().x + (1.0 - i_f64) * bounding_box.min().x;
-                    let y = j_f64 * bounding_box.max().y + (1.0 - j_f64) * bounding_box.min().y;
-                    let z = k_f64 * bounding_box.max().z + (1.0 - k_f64) * bounding_box.min().z;
-                    let new_x =  cos_theta * x + sin_theta * z;
-                    let new_z = -sin_theta * x + cos_theta * z;
-                    let new_axis = Vec3::new(new_x, y, new_z);
-                    min = vec3::min(&new_axis, &min);
-                    max = vec3::max(&new_axis, &max);
-                }
-            }
-        }
-
-        let bounding_box = AABB::new(min, max);
+        let bounding_box = hittable.bounding_box(0.0, 1.0)
+            .transformed_by(|p| Vec3::new(cos_theta * p.x + sin_theta * p.z, p.y, -sin_theta * p.x + cos_theta * p.z));
 
         Self {
             hittable,
@@ -200,6 +332,14 @@ impl Hitable for RotateY {
         self.bounding_box.clone()
     }
 
+    fn is_emissive(&self) -> bool {
+        self.hittable.is_emissive()
+    }
+
+    fn primitive_count(&self) -> usize {
+        self.hittable.primitive_count()
+    }
+
     fn hit(&self, r: &Ray, t_min: f64, t_max: f64) -> Option<HitRecord> {
         let origin = self.unrotate_vector(&r.origin);
         let direction = self.unrotate_vector(&r.direction);
@@ -213,4 +353,42 @@ impl Hitable for RotateY {
             None => None
         }
     }
-}
\ No newline at end of file
+}
+
+#[cfg(test)]
+mod tests {
+
+    use super::*;
+    use material::DummyMaterial;
+    use sphere::Sphere;
+    use bvh::BvhNode;
+
+    #[test]
+    fn translated_sphere_box_is_shifted() {
+        let sphere: Arc<ThreadsafeHitable> = Arc::new(Sphere::new(Vec3::new(1.0, 2.0, 3.0), 0.5, Arc::new(DummyMaterial::new())));
+        let translation = Vec3::new(-4.0, 0.5, 10.0);
+        let translated = Translate::new(sphere.clone(), translation);
+        let expected = AABB::new(Vec3::new(-3.5, 2.0, 12.5), Vec3::new(-2.5, 3.0, 13.5));
+        let bounding_box = translated.bounding_box(0.0, 1.0);
+        assert!(bounding_box.approx_eq(&expected, 1e-9), "{:?} != {:?}", bounding_box, expected);
+        // the child's box is untouched
+        assert!(sphere.bounding_box(0.0, 1.0).approx_eq(&AABB::new(Vec3::new(0.5, 1.5, 2.5), Vec3::new(1.5, 2.5, 3.5)), 1e-9));
+    }
+
+    #[test]
+    fn primitive_count_sums_the_leaves_beneath_aggregates_and_wrappers() {
+        let material: Arc<dyn Material + Send + Sync + 'static> = Arc::new(DummyMaterial::new());
+        let sphere = |x: f64| Arc::new(Sphere::new(Vec3::new(x, 0.0, 0.0), 0.5, material.clone())) as Arc<ThreadsafeHitable>;
+        let bvh = BvhNode::from_list(vec![sphere(0.0), sphere(2.0), sphere(4.0)], 0.0, 1.0);
+        // a single element node has the same child on both sides
+        let single = BvhNode::from_list(vec![sphere(6.0)], 0.0, 1.0);
+        let list = HitableList::new(vec![
+            Arc::new(bvh),
+            Arc::new(single),
+            Arc::new(Translate::new(Arc::new(FlipNormals::new(sphere(8.0))), Vec3::new(0.0, 1.0, 0.0))),
+            Arc::new(RotateY::new(sphere(10.0), 45.0)),
+        ]);
+        assert_eq!(list.primitive_count(), 6);
+        assert_eq!(HitableList::new(vec![]).primitive_count(), 0);
+    }
+}
@@ -0,0 +1,45 @@
+// Compares two RGBA buffers of the given size, returning the RMSE over the colour channels and a
+// buffer of per-channel absolute differences laid out like the inputs so it can be exported as a heatmap.
+// Alpha is ignored as the tracer doesn't write it.
+pub fn image_diff(a: &[f32], b: &[f32], image_size: (u32, u32)) -> (f64, Vec<f32>) {
+    let num_elements = (image_size.0 * image_size.1 * 4) as usize;
+    assert_eq!(a.len(), num_elements, "first image doesn't match the image size");
+    assert_eq!(b.len(), num_elements, "second image doesn't match the image size");
+
+    let mut difference = vec![0.0_f32; num_elements];
+    let mut sum_squared_error = 0.0;
+    for (pixel_index, (pixel_a, pixel_b)) in a.chunks(4).zip(b.chunks(4)).enumerate() {
+        for channel in 0..3 {
+            let error = pixel_a[channel] as f64 - pixel_b[channel] as f64;
+            sum_squared_error += error * error;
+            difference[pixel_index * 4 + channel] = error.abs() as f32;
+        }
+    }
+
+    let num_values = (image_size.0 * image_size.1 * 3) as f64;
+    ((sum_squared_error / num_values).sqrt(), difference)
+}
+
+#[cfg(test)]
+mod tests {
+
+    use super::*;
+
+    #[test]
+    fn rmse_of_identical_and_single_pixel_change() {
+        let image_size = (4, 2);
+        let a: Vec<f32> = (0..image_size.0 * image_size.1 * 4).map(|i| i as f32 * 0.1).collect();
+
+        let (rmse, difference) = image_diff(&a, &a, image_size);
+        assert_eq!(rmse, 0.0);
+        assert!(difference.iter().all(|value| *value == 0.0));
+
+        let mut b = a.clone();
+        b[5 * 4 + 1] += 0.6;
+        let (rmse, difference) = image_diff(&a, &b, image_size);
+        let expected = (0.6_f64 * 0.6 / 24.0).sqrt();
+        assert!((rmse - expected).abs() < 1e-6, "rmse = {}, expected = {}", rmse, expected);
+        assert!((difference[5 * 4 + 1] - 0.6).abs() < 1e-6);
+        assert_eq!(difference.iter().filter(|value| **value > 0.0).count(), 1);
+    }
+}
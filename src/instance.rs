@@ -0,0 +1,153 @@
+use math::*;
+use hitable::*;
+use std::sync::Arc;
+
+// Uniform scale, then rotation about y, then translation
+#[derive(Clone)]
+pub struct Transform {
+    translation: Vec3,
+    sin_theta: f64,
+    cos_theta: f64,
+    scale: f64,
+}
+
+impl Transform {
+    pub fn new() -> Self {
+        Self {
+            translation: Vec3::new_zero_vector(),
+            sin_theta: 0.0,
+            cos_theta: 1.0,
+            scale: 1.0,
+        }
+    }
+
+    pub fn with_translation(&mut self, translation: Vec3) -> &mut Self {
+        self.translation = translation;
+        self
+    }
+
+    pub fn with_rotation_y(&mut self, angle: f64) -> &mut Self {
+        let radians = angle.to_radians();
+        self.sin_theta = radians.sin();
+        self.cos_theta = radians.cos();
+        self
+    }
+
+    pub fn with_scale(&mut self, scale: f64) -> &mut Self {
+        self.scale = scale;
+        self
+    }
+
+    fn rotate_vector(&self, v: &Vec3) -> Vec3 {
+        Vec3::new(self.cos_theta * v.x + self.sin_theta * v.z, v.y, -self.sin_theta * v.x + self.cos_theta * v.z)
+    }
+
+    fn unrotate_vector(&self, v: &Vec3) -> Vec3 {
+        Vec3::new(self.cos_theta * v.x - self.sin_theta * v.z, v.y, self.sin_theta * v.x + self.cos_theta * v.z)
+    }
+
+    pub fn transform_point(&self, p: &Vec3) -> Vec3 {
+        self.rotate_vector(&(p * self.scale)) + self.translation
+    }
+
+    pub fn inverse_transform_point(&self, p: &Vec3) -> Vec3 {
+        self.unrotate_vector(&((p - &self.translation) / self.scale))
+    }
+
+    pub fn transform_vector(&self, v: &Vec3) -> Vec3 {
+        self.rotate_vector(&(v * self.scale))
+    }
+
+    pub fn inverse_transform_vector(&self, v: &Vec3) -> Vec3 {
+        self.unrotate_vector(&(v / self.scale))
+    }
+
+    // the scale is uniform so normals only need rotating
+    pub fn transform_normal(&self, n: &Vec3) -> Vec3 {
+        self.rotate_vector(n)
+    }
+
+    pub fn transform_aabb(&self, bounding_box: &AABB) -> AABB {
+        bounding_box.transformed_by(|p| self.transform_point(p))
+    }
+}
+
+// Places a shared hitable in the scene with its own transform, so a heavy sub-tree such as a
+// mesh bvh can be placed many times without duplicating it
+pub struct Instance {
+    hitable: Arc<ThreadsafeHitable>,
+    transform: Transform,
+    bounding_box: AABB,
+}
+
+impl Instance {
+    pub fn new(hitable: Arc<ThreadsafeHitable>, transform: Transform) -> Self {
+        let bounding_box = transform.transform_aabb(&hitable.bounding_box(0.0, 1.0));
+        Self {
+            hitable,
+            transform,
+            bounding_box,
+        }
+    }
+}
+
+impl Hitable for Instance {
+    fn hit(&self, ray: &Ray, t_min: f64, t_max: f64) -> Option<HitRecord> {
+        // the transform is affine so the ray parameter is the same in local and world space
+        let local_ray = Ray::new(self.transform.inverse_transform_point(&ray.origin), self.transform.inverse_transform_vector(&ray.direction), ray.time);
+        match self.hitable.hit(&local_ray, t_min, t_max) {
+            Some(mut hit_record) => {
+                hit_record.p = self.transform.transform_point(&hit_record.p);
+                hit_record.normal = self.transform.transform_normal(&hit_record.normal);
+                Some(hit_record)
+            },
+            None => None
+        }
+    }
+
+    fn bounding_box(&self, _t0: f64, _t1: f64) -> AABB {
+        self.bounding_box.clone()
+    }
+
+    fn is_emissive(&self) -> bool {
+        self.hitable.is_emissive()
+    }
+
+    fn primitive_count(&self) -> usize {
+        self.hitable.primitive_count()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+
+    use super::*;
+    use bvh::BvhNode;
+    use material::DummyMaterial;
+    use sphere::Sphere;
+
+    #[test]
+    fn instances_share_geometry_at_different_positions() {
+        let material = Arc::new(DummyMaterial::new());
+        let cluster_list: Vec<Arc<ThreadsafeHitable>> = vec![
+            Arc::new(Sphere::new(Vec3::new(-1.0, 0.0, 0.0), 0.5, material.clone())),
+            Arc::new(Sphere::new(Vec3::new(1.0, 0.0, 0.0), 0.5, material.clone())),
+        ];
+        let cluster: Arc<ThreadsafeHitable> = Arc::new(BvhNode::from_list(cluster_list, 0.0, 1.0));
+
+        let left = Instance::new(cluster.clone(), Transform::new().with_translation(Vec3::new(-10.0, 0.0, 0.0)).clone());
+        let right = Instance::new(cluster.clone(), Transform::new().with_translation(Vec3::new(10.0, 0.0, 0.0)).with_rotation_y(90.0).clone());
+        assert_eq!(Arc::strong_count(&cluster), 3);
+
+        let down_z = Vec3::new(0.0, 0.0, 1.0);
+        let left_hit = left.hit(&Ray::new(Vec3::new(-11.0, 0.0, -5.0), down_z, 0.0), 0.001, std::f64::MAX).expect("left instance should be hit");
+        assert!((left_hit.p - Vec3::new(-11.0, 0.0, -0.5)).length() < 1e-6);
+        assert!(left.hit(&Ray::new(Vec3::new(-10.0, 0.0, -5.0), down_z, 0.0), 0.001, std::f64::MAX).is_none());
+
+        // rotated by 90 degrees the spheres now sit along z, so a ray down z through the centre hits the nearer one
+        let right_hit = right.hit(&Ray::new(Vec3::new(10.0, 0.0, -5.0), down_z, 0.0), 0.001, std::f64::MAX).expect("right instance should be hit");
+        assert!((right_hit.p - Vec3::new(10.0, 0.0, -1.5)).length() < 1e-6);
+        assert!((right_hit.normal - Vec3::new(0.0, 0.0, -1.0)).length() < 1e-6);
+        assert!(left.bounding_box(0.0, 1.0).max().x < right.bounding_box(0.0, 1.0).min().x);
+    }
+}
@@ -4,11 +4,14 @@ use std::sync::Arc;
 use parking_lot::{RwLock, Condvar, Mutex};
 use std::collections::VecDeque;
 use lazy_static::lazy_static;
-use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
 use std::cell::UnsafeCell;
 
+// Number of times an idle job thread polls the queue before parking
+pub const DEFAULT_SPINS_BEFORE_SLEEP: u32 = 20;
+
 lazy_static! {
-    static ref THREAD_POOL: ThreadPool = ThreadPool::new();
+    static ref THREAD_POOL: ThreadPool = ThreadPool::new(num_cpus::get());
 }
 
 pub struct Jobs {}
@@ -30,6 +33,11 @@ impl Jobs {
     pub fn job_queue_empty() -> bool {
         THREAD_POOL.job_queue.is_empty()
     }
+
+    // stops the global pool, jobs still queued are skipped but their counters are completed so waiters return
+    pub fn shutdown() {
+        THREAD_POOL.shutdown();
+    }
 }
 
 pub trait JobTask {
@@ -63,37 +71,47 @@ impl JobCounter {
     }
 }
 
-struct ThreadPool {
-    job_threads: Vec<JobThreadHandle>,
+pub struct ThreadPool {
+    job_threads: Mutex<Vec<JobThreadHandle>>,
     job_queue: JobQueue,
     thread_wake_event: ThreadWakeEvent,
+    is_shut_down: AtomicBool,
 }
 
 impl ThreadPool {
-    pub fn new() -> ThreadPool {
-        let num_cores = num_cpus::get();
-        println!("Thread pool: Spooling up {} threads", num_cores);
+    pub fn new(num_threads: usize) -> ThreadPool {
+        ThreadPool::with_spins_before_sleep(num_threads, DEFAULT_SPINS_BEFORE_SLEEP)
+    }
+
+    // fewer spins saves cpu when idle, more spins picks up new work faster, e.g. between realtime frames
+    pub fn with_spins_before_sleep(num_threads: usize, spins_before_sleep: u32) -> ThreadPool {
+        println!("Thread pool: Spooling up {} threads", num_threads);
         
         let job_queue = JobQueue::new();
         let thread_wake_event = ThreadWakeEvent::new();
         let mut job_threads = vec![];
-        for i in 0..num_cores {
-            let job_thread = JobThread::new(i, job_queue.clone(), thread_wake_event.clone());
+        for i in 0..num_threads {
+            let job_thread = JobThread::new(i, job_queue.clone(), thread_wake_event.clone(), spins_before_sleep);
             job_threads.push(job_thread);
         }
 
         ThreadPool {
-            job_threads,
+            job_threads: Mutex::new(job_threads),
             job_queue,
             thread_wake_event,
+            is_shut_down: AtomicBool::new(false),
         }
     }
 
+    pub fn num_threads(&self) -> usize {
+        self.job_threads.lock().len()
+    }
+
     pub fn push_job(&self, job_task: Arc<RwLock<dyn JobTask + Send + Sync + 'static>>) -> Arc<JobCounter> {
         //self.thread_wake_event.wake_threads(); // notify threads to wake
         let job_counter = Arc::new(JobCounter::new(1));
         let job_descriptor = JobDescriptor::new(job_task, job_counter.clone());
-        self.job_queue.push(job_descriptor);
+        self.push_descriptor(job_descriptor);
         self.thread_wake_event.wake_threads(); // notify threads to wake
         job_counter
     }
@@ -103,23 +121,50 @@ impl ThreadPool {
         let job_counter = Arc::new(JobCounter::new(job_tasks.len()));
         for job in job_tasks {
             let job_descriptor = JobDescriptor::new(job.clone(), job_counter.clone());
-            self.job_queue.push(job_descriptor);
+            self.push_descriptor(job_descriptor);
         }
         self.thread_wake_event.wake_threads(); // notify threads to wake
         job_counter
     }
 
-    fn destroy(&mut self) {
+    fn push_descriptor(&self, job_descriptor: JobDescriptor) {
+        if self.is_shut_down.load(Ordering::Acquire) {
+            // no threads left to run it, complete it straight away so nobody waits on it forever
+            job_descriptor.job_counter.decrement();
+        } else {
+            self.job_queue.push(job_descriptor);
+            // shutdown may have drained the queue between the check and the push
+            if self.is_shut_down.load(Ordering::Acquire) {
+                self.cancel_queued_jobs();
+            }
+        }
+    }
+
+    fn cancel_queued_jobs(&self) {
+        while let Some(job_descriptor) = self.job_queue.pop() {
+            job_descriptor.job_counter.decrement();
+        }
+    }
+
+    // Stops and joins every thread, jobs already running finish but queued jobs are skipped. The counters of
+    // skipped jobs are still decremented so no wait_for_counter call can deadlock. Safe to call more than once.
+    pub fn shutdown(&self) {
+        self.is_shut_down.store(true, Ordering::Release);
+        let mut job_threads = self.job_threads.lock();
         // stop each thread before waiting for them all to join
-        self.job_threads.iter().for_each(|thread| thread.stop());
+        job_threads.iter().for_each(|thread| thread.stop());
+        // wake any sleeping threads so they see they've been stopped
+        self.thread_wake_event.wake_threads();
         // drain all threads and wait for them to join
-        self.job_threads.drain(..).for_each( move |thread| thread.join());
+        job_threads.drain(..).for_each( move |thread| thread.join());
+
+        self.cancel_queued_jobs();
     }
 }
 
 impl Drop for ThreadPool {
     fn drop(&mut self) {
-        self.destroy();
+        self.shutdown();
     }
 }
 
@@ -206,10 +251,15 @@ impl ThreadWakeEvent {
         condvar.notify_all();
     }
 
-    fn sleep_thread(&self) {
+    fn sleep_thread(&self, is_running: &RwLock<bool>, queue: &JobQueue) {
         let &(ref lock, ref condvar) = &*self.value;
-        // sleep on event, this may wake spuriously but we don't really care
-        condvar.wait(&mut lock.lock());
+        let mut wake = lock.lock();
+        // checked under the lock, pushes and stops wake threads after taking the same lock so a job
+        // pushed or a stop requested since the last pop can't be missed
+        if *is_running.read() && queue.is_empty() {
+            // sleep on event, this may wake spuriously but we don't really care
+            condvar.wait(&mut wake);
+        }
     }
 }
 
@@ -218,16 +268,18 @@ struct JobThread {
     is_running: Arc<RwLock<bool>>,
     queue: JobQueue,
     wake_event: ThreadWakeEvent,
+    spins_before_sleep: u32,
 }
 
 impl JobThread {
-    fn new(thread_pool_index: usize, queue: JobQueue, wake_event: ThreadWakeEvent) -> JobThreadHandle {
+    fn new(thread_pool_index: usize, queue: JobQueue, wake_event: ThreadWakeEvent, spins_before_sleep: u32) -> JobThreadHandle {
         let is_running = Arc::new(RwLock::new(true));
         let job_thread = JobThread {
             thread_pool_index,
             is_running: is_running.clone(),
             queue,
             wake_event,
+            spins_before_sleep,
         };
 
         let thread_handle = thread::spawn( move || {
@@ -243,7 +295,6 @@ impl JobThread {
     fn run(&self) {
         println!("Job Thread {} started..", self.thread_pool_index);
         
-        const SPINS_BEFORE_SLEEP: i32 = 20;
         let mut spins = 0;
         while *self.is_running.read() {
             match self.queue.pop() {
@@ -258,8 +309,9 @@ impl JobThread {
             };
 
             // sleep if we've no work
-            if spins > SPINS_BEFORE_SLEEP {
-                self.wake_event.sleep_thread();
+            if spins > self.spins_before_sleep {
+                self.wake_event.sleep_thread(&self.is_running, &self.queue);
+                spins = 0;
             }
 
         }
@@ -274,8 +326,10 @@ pub struct MultiSliceReadWriteLock<T> {
     data: Arc<UnsafeCell<T>>
 }
 
-unsafe impl<T> Send for MultiSliceReadWriteLock<T> {}
-unsafe impl<T> Sync for MultiSliceReadWriteLock<T> {}
+// Callers must only write disjoint slices from each thread, the bounds at least match RwLock's so a non
+// thread safe T can't be shared through it
+unsafe impl<T: Send> Send for MultiSliceReadWriteLock<T> {}
+unsafe impl<T: Send + Sync> Sync for MultiSliceReadWriteLock<T> {}
 
 impl<T> MultiSliceReadWriteLock<T> {
     
@@ -294,4 +348,66 @@ impl<T> MultiSliceReadWriteLock<T> {
         // TODO(SS): Ensure no one can read when write is checked out?
         unsafe {  & *self.data.get() }
     }
-}
\ No newline at end of file
+}
+
+#[cfg(test)]
+mod tests {
+
+    use super::*;
+    use std::sync::mpsc;
+    use std::time::Duration;
+
+    struct SleepJob;
+
+    impl JobTask for SleepJob {
+        fn run(&mut self) {
+            thread::sleep(Duration::from_millis(20));
+        }
+    }
+
+    struct EmptyJob;
+
+    impl JobTask for EmptyJob {
+        fn run(&mut self) {}
+    }
+
+    #[test]
+    fn jobs_pushed_while_threads_park_all_run() {
+        // no spinning so threads park as soon as the queue is empty, right as the next job is pushed
+        let thread_pool = ThreadPool::with_spins_before_sleep(4, 0);
+        let job: Arc<RwLock<dyn JobTask + Send + Sync + 'static>> = Arc::new(RwLock::new(EmptyJob));
+        for _ in 0..2000 {
+            let job_counter = thread_pool.push_job(job.clone());
+            let timeout = std::time::Instant::now() + Duration::from_secs(5);
+            while job_counter.counter.lock().load(Ordering::SeqCst) != 0 {
+                assert!(std::time::Instant::now() < timeout, "job was never picked up");
+                thread::yield_now();
+            }
+        }
+    }
+
+    #[test]
+    fn shutdown_with_pending_jobs_releases_waiters() {
+        let thread_pool = Arc::new(ThreadPool::new(1));
+        let jobs: Vec<Arc<RwLock<dyn JobTask + Send + Sync + 'static>>> = (0..50).map(|_| {
+            let job: Arc<RwLock<dyn JobTask + Send + Sync + 'static>> = Arc::new(RwLock::new(SleepJob));
+            job
+        }).collect();
+        let job_counter = thread_pool.push_job_array(&jobs);
+
+        let (sender, receiver) = mpsc::channel();
+        let waiter_counter = job_counter.clone();
+        thread::spawn(move || {
+            Jobs::wait_for_counter(&waiter_counter, 0);
+            sender.send(()).unwrap();
+        });
+
+        thread::sleep(Duration::from_millis(30));
+        thread_pool.shutdown();
+        assert!(receiver.recv_timeout(Duration::from_secs(5)).is_ok(), "waiter still blocked after shutdown");
+        assert_eq!(thread_pool.num_threads(), 0);
+
+        // pushing after shutdown completes immediately rather than hanging
+        Jobs::wait_for_counter(&thread_pool.push_job_array(&jobs), 0);
+    }
+}
@@ -0,0 +1,155 @@
+use math::{Vec3, parse_vec3};
+use math::spline::catmull_rom;
+use camera::Camera;
+
+// Camera keyframes for fly-throughs, loaded with -keyframes=. One keyframe per line as time, origin, look at and
+// vertical field of view in degrees separated by whitespace, in increasing time order. # starts a comment.
+//
+//   0.0  278,278,-800  278,278,0  40
+//   2.5  400,300,-600  278,278,0  35
+#[derive(Debug, Clone, PartialEq)]
+pub struct CameraKeyframe {
+    pub time: f64,
+    pub origin: Vec3,
+    pub look_at: Vec3,
+    pub vfov: f64,
+}
+
+impl CameraKeyframe {
+    pub fn apply(&self, cam: &mut Camera) {
+        cam.set_origin(self.origin, false);
+        cam.set_look_at(self.look_at);
+        // rebuilds the basis for the new origin and look at too
+        cam.set_vfov(self.vfov);
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum KeyframeInterpolation {
+    Linear,
+    CatmullRom,
+}
+
+impl std::str::FromStr for KeyframeInterpolation {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "linear" => Ok(KeyframeInterpolation::Linear),
+            "catmull-rom" => Ok(KeyframeInterpolation::CatmullRom),
+            _ => Err(format!("Unknown interpolation '{}', expected linear or catmull-rom", s)),
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct CameraKeyframes {
+    keyframes: Vec<CameraKeyframe>,
+}
+
+impl CameraKeyframes {
+    pub fn load(path: &str) -> Result<Self, String> {
+        let contents = std::fs::read_to_string(path)
+            .map_err(|e| format!("Could not read keyframe file {}: {}", path, e))?;
+        contents.parse()
+    }
+
+    pub fn start_time(&self) -> f64 {
+        self.keyframes[0].time
+    }
+
+    pub fn end_time(&self) -> f64 {
+        self.keyframes[self.keyframes.len() - 1].time
+    }
+
+    // The camera at time, held at the first and last keyframes outside of them. Catmull-Rom passes through every
+    // keyframe like linear does but without the sudden turns at them
+    pub fn sample(&self, time: f64, interpolation: KeyframeInterpolation) -> CameraKeyframe {
+        let last = self.keyframes.len() - 1;
+        let segment = self.keyframes.iter().rposition(|keyframe| keyframe.time <= time).unwrap_or(0).min(last.saturating_sub(1));
+        let next = (segment + 1).min(last);
+        let (from, to) = (&self.keyframes[segment], &self.keyframes[next]);
+        let duration = to.time - from.time;
+        let t = if duration > 0.0 {((time - from.time) / duration).clamp(0.0, 1.0)} else {0.0};
+
+        let (origin, look_at, vfov) = match interpolation {
+            KeyframeInterpolation::Linear => (from.origin.lerp(&to.origin, t), from.look_at.lerp(&to.look_at, t), from.vfov + (to.vfov - from.vfov) * t),
+            KeyframeInterpolation::CatmullRom => {
+                // the end keyframes are repeated to give their segments a neighbour
+                let before = &self.keyframes[segment.saturating_sub(1)];
+                let after = &self.keyframes[(next + 1).min(last)];
+                (catmull_rom(&before.origin, &from.origin, &to.origin, &after.origin, t),
+                 catmull_rom(&before.look_at, &from.look_at, &to.look_at, &after.look_at, t),
+                 catmull_rom(&before.vfov, &from.vfov, &to.vfov, &after.vfov, t))
+            },
+        };
+        CameraKeyframe { time, origin, look_at, vfov }
+    }
+}
+
+impl std::str::FromStr for CameraKeyframes {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let mut keyframes: Vec<CameraKeyframe> = vec![];
+        for (line_index, line) in s.lines().enumerate() {
+            let line = line.split('#').next().unwrap().trim();
+            if line.is_empty() {
+                continue;
+            }
+            let invalid_line = || format!("Invalid keyframe file line {}: '{}', expected time origin look_at vfov", line_index + 1, line);
+            let fields: Vec<&str> = line.split_whitespace().collect();
+            let keyframe = match fields.as_slice() {
+                [time, origin, look_at, vfov] => CameraKeyframe {
+                    time: time.parse().map_err(|_| invalid_line())?,
+                    origin: parse_vec3(origin).ok_or_else(invalid_line)?,
+                    look_at: parse_vec3(look_at).ok_or_else(invalid_line)?,
+                    vfov: vfov.parse().map_err(|_| invalid_line())?,
+                },
+                _ => return Err(invalid_line()),
+            };
+            if keyframes.last().is_some_and(|previous| keyframe.time <= previous.time) {
+                return Err(format!("Keyframe on line {} is not after the one before it", line_index + 1));
+            }
+            keyframes.push(keyframe);
+        }
+        if keyframes.is_empty() {
+            return Err(String::from("Keyframe file has no keyframes"));
+        }
+        Ok(CameraKeyframes { keyframes })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+
+    use super::*;
+
+    const KEYFRAMES: &str = "# fly in\n0 0,0,5 0,0,0 40\n1 2,1,4 0,0,0 35 # turn\n3 3,2,0 0,1,0 30\n4 0,1,-5 0,0,0 60\n";
+
+    #[test]
+    fn interpolation_at_a_keyframe_time_is_that_keyframe() {
+        let keyframes: CameraKeyframes = KEYFRAMES.parse().unwrap();
+        for interpolation in [KeyframeInterpolation::Linear, KeyframeInterpolation::CatmullRom].iter() {
+            for keyframe in keyframes.keyframes.iter() {
+                let sampled = keyframes.sample(keyframe.time, *interpolation);
+                assert!(sampled.origin.approx_eq(&keyframe.origin, 1e-12), "{:?} origin at {}", interpolation, keyframe.time);
+                assert!(sampled.look_at.approx_eq(&keyframe.look_at, 1e-12), "{:?} look at at {}", interpolation, keyframe.time);
+                assert!((sampled.vfov - keyframe.vfov).abs() < 1e-12, "{:?} vfov at {}", interpolation, keyframe.time);
+            }
+        }
+        // held outside the keyframes
+        assert_eq!(keyframes.sample(-1.0, KeyframeInterpolation::CatmullRom).origin, Vec3::new(0.0, 0.0, 5.0));
+        assert_eq!(keyframes.sample(5.0, KeyframeInterpolation::Linear).origin, Vec3::new(0.0, 1.0, -5.0));
+        assert_eq!(keyframes.sample(0.5, KeyframeInterpolation::Linear).origin, Vec3::new(1.0, 0.5, 4.5));
+
+        let mut cam = Camera::new(Vec3::new(1.0, 1.0, 1.0), Vec3::new_zero_vector(), Vec3::new(0.0, 1.0, 0.0), 90.0, 1.0, 0.0, 1.0, 0.0, 1.0);
+        keyframes.sample(1.0, KeyframeInterpolation::CatmullRom).apply(&mut cam);
+        let reference = Camera::new(Vec3::new(2.0, 1.0, 4.0), Vec3::new_zero_vector(), Vec3::new(0.0, 1.0, 0.0), 35.0, 1.0, 0.0, 1.0, 0.0, 1.0);
+        assert!(cam.get_ray(0.25, 0.75).direction.approx_eq(&reference.get_ray(0.25, 0.75).direction, 1e-9));
+
+        assert!("0 0,0,5 0,0,0".parse::<CameraKeyframes>().is_err());
+        assert!("1 0,0,5 0,0,0 40\n0 0,0,4 0,0,0 40".parse::<CameraKeyframes>().is_err());
+        assert!("# nothing".parse::<CameraKeyframes>().is_err());
+    }
+}
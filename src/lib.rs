@@ -32,7 +32,10 @@ pub type Backend = rendy::vulkan::Backend;
 #[cfg(feature = "empty")]
 pub type Backend = rendy::empty::Backend;
 
+// graphics dependencies are optional so the cpu feature can build without them
+#[cfg(feature = "rendy")]
 extern crate rendy;
+#[cfg(feature = "rendy")]
 use rendy::{
     graph::{present::PresentNode, render::*, GraphBuilder},
     resource::{BufferInfo, Buffer, Escape},
@@ -41,8 +44,16 @@ use rendy::{
 
 //use rendy::init::winit;
 //use ::winit;
+#[cfg(feature = "winit")]
 extern crate winit;
+// -software presents through softbuffer outside Windows
+#[cfg(all(feature = "winit", not(target_os = "windows")))]
+extern crate softbuffer;
+#[cfg(all(feature = "winit", not(target_os = "windows")))]
+extern crate raw_window_handle;
+#[cfg(feature = "rendy")]
 use rendy::hal;
+#[cfg(feature = "winit")]
 use winit::{ 
     event::{VirtualKeyCode},
     window::WindowBuilder,
@@ -50,7 +61,7 @@ use winit::{
 };
 
 // module imports
-mod math;
+pub mod math;
 mod hitable;
 mod material;
 mod texture;
@@ -58,15 +69,33 @@ mod camera;
 mod sphere;
 mod bvh;
 mod trace;
+#[cfg(feature = "winit")]
 mod winit_utils;
 mod jobs;
+#[cfg(feature = "rendy")]
 mod node;
+#[cfg(feature = "winit")]
 mod input;
 mod rect;
 mod axis_aligned_box;
+mod instance;
+mod renderer;
 mod scene;
 mod volume;
 mod onb;
+mod stats;
+mod tiles;
+mod edges;
+mod bloom;
+mod denoise;
+mod environment;
+mod scene_file;
+mod keyframes;
+mod grid;
+pub mod image_diff;
+// only public so benches/ can reach it, kept out of the docs as it isn't part of the API
+#[doc(hidden)]
+pub mod bench;
 
 use math::*;
 use hitable::*;
@@ -78,12 +107,20 @@ use axis_aligned_box::*;
 use sphere::{Sphere, MovingSphere};
 use bvh::BvhNode;
 use trace::*;
-use jobs::{Jobs, JobTask, MultiSliceReadWriteLock};
+use jobs::{JobTask, MultiSliceReadWriteLock};
+use renderer::Renderer;
+use tiles::TileOrder;
+use bloom::Bloom;
+use keyframes::KeyframeInterpolation;
+use grid::Acceleration;
 
 // For tracking multithreading bugs
 const RUN_SINGLE_THREADED: bool = false;
 const OUTPUT_IMAGE_ON_CLOSE: bool = false;
 const FRAMES_IN_FLIGHT: u32 = 3;
+// auto exposure scales the log average luminance to this key, moving this fraction of the way there per second
+const AUTO_EXPOSURE_KEY: f64 = 0.18;
+const AUTO_EXPOSURE_SPEED: f64 = 2.0;
 
 // Returns the cargo manifest directory when running the executable with cargo
 // or the directory in which the executable resides otherwise,
@@ -106,11 +143,53 @@ pub fn application_root_dir() -> String {
     }
 }
 
-#[derive(Clone, Copy)]
+#[derive(Clone, Debug)]
 pub struct Config {
     realtime: bool,
     max_depth: i32,
     spp: u32, // samples per pixel
+    spp_per_pass: u32, // offline renders accumulate spp over passes of this many samples
+    filter: ReconstructionFilter,
+    gamma: f64,
+    transfer: TransferFunction,
+    output_path: Option<String>, // when set the image is written here instead of the rotating output{n}.ppm files
+    write_stats: bool, // write render statistics to stats.json on completion
+    max_seconds: Option<f64>, // time budget for offline renders, whatever has been traced is exported when it runs out
+    tile_order: TileOrder,
+    aa_edges: bool, // offline renders trace the first pass at 1spp then only supersample pixels on detected edges
+    num_threads: Option<usize>, // defaults to one per core
+    num_frames: u32, // more than one renders an offline image sequence instead
+    orbit: bool, // image sequences turn the camera a full revolution around the look at point
+    shutter: f64, // time the shutter is open for, camera rays sample times in 0..shutter so larger values blur moving objects more
+    background: BackgroundMode, // radiance for rays that miss the scene
+    seed: Option<u64>, // base seed for reproducible renders, each tile derives its own stream from it
+    bloom: Option<Bloom>, // applied to exported images before tonemapping
+    aberration: f64, // chromatic aberration, red and blue rays are scaled this much from the image centre
+    vignette: f64, // strength of the darkening towards the corners of exported images, 0 disables it
+    nee: bool, // next event estimation, diffuse bounces sample the world's lights directly with a shadow ray
+    min_bounces: i32, // bounces before russian roulette can end a path
+    termination: Termination, // fixed depth disables russian roulette
+    ray_epsilon: f64, // hits closer than this are ignored to stop surfaces shadowing themselves, should suit the scene's scale
+    clear_colour: [f32; 4], // window images are cleared to this before the first trace
+    scene_file: Option<String>, // render settings were loaded from this file, command line flags override them
+    render_mode: RenderMode, // beauty or an AOV of the camera rays' first hits
+    guides: bool, // headless renders also export albedo and normal AOVs next to the image for denoising
+    software: bool, // tonemap on the CPU and blit to the window instead of presenting with rendy, no GPU needed
+    max_accumulation: u32, // realtime pixels stop being traced after accumulating this many frames, until the camera moves
+    focal_length: Option<f64>, // in mm, replaces the scene camera's vfov when set
+    sensor_size: f64, // sensor height in mm used with focal_length
+    info: bool, // print the config, tile layout, threads and scene stats then exit without rendering
+    light_mix: LightMix, // chance of sampling towards the importance sampled shapes instead of the material's pdf
+    clay: bool, // shade every surface but the lights with a plain grey material to review lighting and geometry
+    review: bool, // offline windows stay open once done so exposure and tonemapping can be adjusted and the image re-saved
+    exposure: f64, // exported images are scaled by this before tonemapping
+    tonemap: bool, // exported images are reinhard tonemapped, otherwise values are clipped
+    denoise: bool, // headless renders are denoised before export, guided by albedo and normal AOVs traced alongside
+    sampler: SamplingStrategy, // how pixel offsets, lens positions and times are chosen for each sample
+    fps_cap: u32, // windows sleep so frames take at least 1/fps_cap seconds, 0 doesn't sleep
+    keyframes: Option<String>, // image sequences fly the camera through the keyframes in this file over their frames
+    interpolation: KeyframeInterpolation, // how the camera moves between keyframes
+    accel: Acceleration, // structure rays are traced through the world with, scenes are rebuilt into it when not a bvh
 }
 
 impl Config {
@@ -119,6 +198,48 @@ impl Config {
             realtime: true,
             max_depth: 10,
             spp: 1,
+            spp_per_pass: 4,
+            filter: ReconstructionFilter::Box,
+            gamma: 2.2,
+            transfer: TransferFunction::Gamma,
+            output_path: None,
+            write_stats: false,
+            max_seconds: None,
+            tile_order: TileOrder::RowMajor,
+            aa_edges: false,
+            num_threads: None,
+            num_frames: 1,
+            orbit: false,
+            shutter: 1.0,
+            background: BackgroundMode::Black,
+            seed: None,
+            bloom: None,
+            aberration: 0.0,
+            vignette: 0.0,
+            nee: false,
+            min_bounces: 3,
+            termination: Termination::RussianRoulette,
+            ray_epsilon: 0.001,
+            clear_colour: [0.0, 0.0, 0.0, 1.0],
+            scene_file: None,
+            render_mode: RenderMode::Beauty,
+            guides: false,
+            software: false,
+            max_accumulation: 1000,
+            focal_length: None,
+            sensor_size: 36.0,
+            info: false,
+            light_mix: LightMix::Fixed(0.5),
+            clay: false,
+            review: false,
+            exposure: 1.0,
+            tonemap: true,
+            denoise: false,
+            sampler: SamplingStrategy::Random,
+            fps_cap: 60,
+            keyframes: None,
+            interpolation: KeyframeInterpolation::CatmullRom,
+            accel: Acceleration::Bvh,
         }
     }
 
@@ -134,10 +255,136 @@ impl Config {
                 config.spp = 100;
             }
 
+            // applied before the other flags so they take precedence over the file
+            if let Some(scene_arg) = args.iter().find(|arg| arg.starts_with("-scene=")) {
+                let scene_path = &scene_arg[7..];
+                let settings = scene_file::SceneSettings::load(scene_path).unwrap();
+                if let Some(spp) = settings.spp {
+                    config.spp = spp;
+                }
+                if let Some(max_depth) = settings.max_depth {
+                    config.max_depth = max_depth;
+                }
+                if let Some(background) = settings.background {
+                    config.background = background;
+                }
+                config.scene_file = Some(String::from(scene_path));
+            }
+
             for arg in args {
                 if arg.starts_with("-spp=") {
                     let spp = &arg[5..];
                     config.spp = spp.parse().unwrap();
+                } else if arg.starts_with("-spp-per-pass=") {
+                    let spp_per_pass = &arg[14..];
+                    config.spp_per_pass = spp_per_pass.parse().unwrap();
+                } else if arg.starts_with("-filter=") {
+                    let filter = &arg[8..];
+                    config.filter = filter.parse().unwrap();
+                } else if arg.starts_with("-gamma=") {
+                    let gamma = &arg[7..];
+                    config.gamma = gamma.parse().unwrap();
+                } else if arg.starts_with("-transfer=") {
+                    let transfer = &arg[10..];
+                    config.transfer = transfer.parse().unwrap();
+                } else if arg.starts_with("-out=") {
+                    let output_path = &arg[5..];
+                    config.output_path = Some(String::from(output_path));
+                } else if arg == "-stats" {
+                    config.write_stats = true;
+                } else if arg.starts_with("-max-seconds=") {
+                    let max_seconds = &arg[13..];
+                    config.max_seconds = Some(max_seconds.parse().unwrap());
+                } else if arg.starts_with("-tile-order=") {
+                    let tile_order = &arg[12..];
+                    config.tile_order = tile_order.parse().unwrap();
+                } else if arg == "-aa-edges" {
+                    config.aa_edges = true;
+                } else if arg.starts_with("-threads=") {
+                    let num_threads = &arg[9..];
+                    config.num_threads = Some(num_threads.parse().unwrap());
+                } else if arg.starts_with("-frames=") {
+                    let num_frames = &arg[8..];
+                    config.num_frames = num_frames.parse().unwrap();
+                } else if arg == "-orbit" {
+                    config.orbit = true;
+                } else if arg.starts_with("-keyframes=") {
+                    let keyframes = &arg[11..];
+                    config.keyframes = Some(String::from(keyframes));
+                } else if arg.starts_with("-interpolation=") {
+                    let interpolation = &arg[15..];
+                    config.interpolation = interpolation.parse().unwrap();
+                } else if arg.starts_with("-shutter=") {
+                    let shutter = &arg[9..];
+                    config.shutter = shutter.parse().unwrap();
+                } else if arg.starts_with("-bg=") {
+                    let background = &arg[4..];
+                    config.background = background.parse().unwrap();
+                } else if arg.starts_with("-seed=") {
+                    let seed = &arg[6..];
+                    config.seed = Some(seed.parse().unwrap());
+                } else if arg.starts_with("-bloom=") {
+                    let bloom = &arg[7..];
+                    config.bloom = Some(bloom.parse().unwrap());
+                } else if arg.starts_with("-aberration=") {
+                    let aberration = &arg[12..];
+                    config.aberration = aberration.parse().unwrap();
+                } else if arg.starts_with("-vignette=") {
+                    let vignette = &arg[10..];
+                    config.vignette = vignette.parse().unwrap();
+                } else if arg == "-nee" {
+                    config.nee = true;
+                } else if arg.starts_with("-min-bounces=") {
+                    let min_bounces = &arg[13..];
+                    config.min_bounces = min_bounces.parse().unwrap();
+                } else if arg.starts_with("-termination=") {
+                    let termination = &arg[13..];
+                    config.termination = termination.parse().unwrap();
+                } else if arg.starts_with("-ray-epsilon=") {
+                    let ray_epsilon = &arg[13..];
+                    config.ray_epsilon = ray_epsilon.parse().unwrap();
+                } else if arg.starts_with("-clear=") {
+                    let clear_colour = &arg[7..];
+                    config.clear_colour = parse_clear_colour(clear_colour).unwrap();
+                } else if arg.starts_with("-mode=") {
+                    let render_mode = &arg[6..];
+                    config.render_mode = render_mode.parse().unwrap();
+                } else if arg == "-guides" {
+                    config.guides = true;
+                } else if arg == "-software" {
+                    config.software = true;
+                } else if arg.starts_with("-max-accumulation=") {
+                    let max_accumulation = &arg[18..];
+                    config.max_accumulation = max_accumulation.parse().unwrap();
+                } else if arg.starts_with("-focal=") {
+                    let focal_length = &arg[7..];
+                    config.focal_length = Some(focal_length.parse().unwrap());
+                } else if arg.starts_with("-sensor=") {
+                    let sensor_size = &arg[8..];
+                    config.sensor_size = sensor_size.parse().unwrap();
+                } else if arg == "-info" {
+                    config.info = true;
+                } else if arg.starts_with("-light-mix=") {
+                    let light_mix = &arg[11..];
+                    config.light_mix = light_mix.parse().unwrap();
+                } else if arg == "-clay" {
+                    config.clay = true;
+                } else if arg.starts_with("-sampler=") {
+                    let sampler = &arg[9..];
+                    config.sampler = sampler.parse().unwrap();
+                } else if arg.starts_with("-fps-cap=") {
+                    let fps_cap = &arg[9..];
+                    config.fps_cap = fps_cap.parse().unwrap();
+                } else if arg.starts_with("-accel=") {
+                    let accel = &arg[7..];
+                    config.accel = accel.parse().unwrap();
+                } else if arg == "-denoise" {
+                    config.denoise = true;
+                } else if arg == "-review" {
+                    config.review = true;
+                } else if arg.starts_with("-exposure=") {
+                    let exposure = &arg[10..];
+                    config.exposure = exposure.parse().unwrap();
                 }
             }
         }
@@ -146,6 +393,22 @@ impl Config {
     }
 }
 
+// r,g,b with alpha set to 1
+fn parse_clear_colour(s: &str) -> Result<[f32; 4], String> {
+    let colour = parse_vec3(s).ok_or_else(|| format!("Invalid clear colour '{}', expected r,g,b", s))?;
+    Ok([colour.x as f32, colour.y as f32, colour.z as f32, 1.0])
+}
+
+#[cfg(feature = "rendy")]
+fn clear_value(config: &Config) -> hal::command::ClearValue {
+    hal::command::ClearValue {
+        color: hal::command::ClearColor {
+            float32: config.clear_colour,
+        },
+    }
+}
+
+#[cfg(feature = "rendy")]
 #[derive(Default)]
 pub struct Aux<B: hal::Backend> {
     pub frames: usize,
@@ -154,9 +417,613 @@ pub struct Aux<B: hal::Backend> {
     pub source_buffer: Option<Escape<Buffer<B>>>
 }
 
-#[cfg(not(any(feature = "dx12", feature = "metal", feature = "vulkan")))]
+// Staging buffer the traced image is uploaded through every frame
+#[cfg(any(feature = "dx12", feature = "metal", feature = "vulkan"))]
+fn create_source_buffer(factory: &mut rendy::factory::Factory<Backend>, image_size: (u32, u32)) -> Result<Escape<Buffer<Backend>>, failure::Error> {
+    let source_buffer_size: u64 = (image_size.0 * image_size.1) as u64 * 4 * std::mem::size_of::<f32>() as u64;
+    factory
+        .create_buffer(
+            BufferInfo {
+                size: source_buffer_size,
+                usage: hal::buffer::Usage::TRANSFER_SRC
+            },
+            rendy::memory::Upload
+        )
+        .map_err(|_| failure::err_msg("Unable to create source buffer"))
+}
+
+// Copies aux.source_buffer into an image, tonemaps it and presents it to a new surface for the window
+#[cfg(any(feature = "dx12", feature = "metal", feature = "vulkan"))]
+fn build_frame_graph(
+    rendy: &mut rendy::init::Rendy<Backend>,
+    window: &winit::window::Window,
+    image_size: (u32, u32),
+    config: &Config,
+    aux: &mut Aux<Backend>)
+-> Result<rendy::graph::Graph<Backend, Aux<Backend>>, failure::Error> {
+
+    let surface = rendy.factory.create_surface(window).map_err(|_|failure::err_msg("Could create backbuffer surface"))?;
+    let mut graph_builder = GraphBuilder::<Backend, Aux<Backend>>::new();
+
+    let source_image = graph_builder.create_image(
+        hal::image::Kind::D2(image_size.0, image_size.1, 1, 1), 
+        1, 
+        hal::format::Format::Rgba32Sfloat, 
+        Some(clear_value(config)),
+    );
+
+    let color = graph_builder.create_image(
+        hal::image::Kind::D2(image_size.0, image_size.1, 1, 1),
+        1,
+        rendy.factory.get_surface_format(&surface),
+        Some(clear_value(config)),
+    );
+
+    let copy_texture_node = graph_builder.add_node(
+        node::copy_image::CopyToTexture::<Backend>::builder(
+            source_image
+        )
+    );
+
+    let tonemap_pass = graph_builder.add_node(
+        node::tonemap::Pipeline::builder()
+                .with_image(source_image)
+                .into_subpass()
+                .with_dependency(copy_texture_node)
+                .with_color(color)
+                .into_pass(),
+    );
+    graph_builder.add_node(PresentNode::builder(&rendy.factory, surface, color).with_dependency(tonemap_pass));
+
+    graph_builder
+        .with_frames_in_flight(FRAMES_IN_FLIGHT)
+        .build(&mut rendy.factory, &mut rendy.families, aux).map_err(|_|failure::err_msg("Could not build graph"))
+}
+
+#[cfg(not(any(feature = "dx12", feature = "metal", feature = "vulkan", feature = "cpu")))]
 pub fn run(config: Config) -> Result<(), failure::Error>{
-    Err(failure::err_msg("run with --feature dx/metal/vulkan"))
+    Err(failure::err_msg("run with --feature dx/metal/vulkan/cpu"))
+}
+
+#[cfg(all(feature = "cpu", not(any(feature = "dx12", feature = "metal", feature = "vulkan"))))]
+pub fn run(config: Config) -> Result<(), failure::Error>{
+    // -software only needs winit, e.g. --no-default-features --features cpu,winit
+    #[cfg(feature = "winit")]
+    {
+        if config.software && !config.info && config.num_frames <= 1 && config.keyframes.is_none() {
+            print_config(&config);
+            return run_software(config, (500, 500));
+        }
+    }
+    // cpu builds don't initialise a logger, so this goes to stdout with the rest of the render's output
+    #[cfg(not(feature = "winit"))]
+    {
+        if config.software {
+            println!("Warning: -software needs the winit feature, rendering offline instead");
+        }
+    }
+    run_headless(config)
+}
+
+fn print_config(config: &Config) {
+    println!("{:#?}", config);
+}
+
+// What a render of image_size would be set up with, printed by -info alongside the config so a render can be reproduced
+fn print_scene_info(config: &Config, image_size: (u32, u32)) {
+    let (task_dim_xy, num_tasks_xy) = tile_layout(image_size);
+    let (world, _) = cornell_box((image_size.0 as f64)/(image_size.1 as f64), config.shutter);
+    println!("Info:\nimage size={}x{}\ntile size={}x{}\ntiles={}x{}\nthreads={}\nprimitives={}\nlights={}",
+             image_size.0, image_size.1, task_dim_xy.0, task_dim_xy.1, num_tasks_xy.0, num_tasks_xy.1,
+             config.num_threads.unwrap_or_else(num_cpus::get), world.primitive_count(), collect_lights(world.as_ref()).len());
+}
+
+const TILES_PER_AXIS: u32 = 9;
+
+// Splits the image into tiles, returns the tile dimensions and the number of tiles along x and y. Tiles in the
+// last row and column are cropped to the image when the tile dimensions don't divide it exactly.
+fn tile_layout(image_size: (u32, u32)) -> ((u32, u32), (u32, u32)) {
+    let (nx, ny) = image_size;
+    let task_dim_xy = (nx.div_ceil(TILES_PER_AXIS), ny.div_ceil(TILES_PER_AXIS));
+    let num_tasks_xy = (nx.div_ceil(task_dim_xy.0), ny.div_ceil(task_dim_xy.1));
+    (task_dim_xy, num_tasks_xy)
+}
+
+// Printed once when a render is set up, realtime resizes lay the tiles out again without it
+fn print_tile_layout(image_size: (u32, u32)) {
+    let (task_dim_xy, _) = tile_layout(image_size);
+    println!("Task Dimensions = {}x{}", task_dim_xy.0, task_dim_xy.1);
+}
+
+// Trace jobs per tile, as batches for direct access and as jobs for dispatch
+type TileJobs = (Vec<Arc<RwLock<TraceSceneBatchJob>>>, Vec<Arc<RwLock<dyn JobTask + Send + Sync + 'static>>>);
+
+// Creates a trace job per tile, returned both as batches for direct access and as jobs for dispatch
+fn create_tile_jobs(
+    image_size: (u32, u32),
+    num_samples: u32,
+    config: &Config,
+    scene_state: &Arc<RwLock<SceneState>>,
+    scene_output: &Arc<SceneOutput>) 
+-> TileJobs {
+
+    let (task_dim_xy, num_tasks_xy) = tile_layout(image_size);
+    let mut batches = vec![];
+    let mut jobs: Vec<Arc<RwLock<dyn JobTask + Send + Sync + 'static>>>  = vec![];
+    // jobs are dispatched in the order they are pushed
+    for (task_x, task_y) in tiles::ordered_tiles(num_tasks_xy, config.tile_order) {
+        let start_xy = (task_dim_xy.0 * task_x, task_dim_xy.1 * task_y);
+        let end_xy = ((start_xy.0 + task_dim_xy.0).min(image_size.0), (start_xy.1 + task_dim_xy.1).min(image_size.1));
+        let mut batch = TraceSceneBatchJob::new(num_samples, 
+                                            start_xy, end_xy, 
+                                                image_size, 
+                                                scene_state.clone(), 
+                                                scene_output.clone(),
+                                                config.realtime);
+        if let Some(seed) = config.seed {
+            // indexed by position rather than dispatch order so the image doesn't depend on the tile order
+            batch.set_seed(seed, task_y * num_tasks_xy.0 + task_x);
+        }
+        let batch = Arc::new(RwLock::new(batch));
+        batches.push(batch.clone());
+        jobs.push(batch);
+    }
+    (batches, jobs)
+}
+
+// The image size a realtime window is traced at after being resized to new_frame_size, None while it is minimised
+fn resized_image_size(new_frame_size: (f32, f32)) -> Option<(u32, u32)> {
+    let (width, height) = (new_frame_size.0.round() as u32, new_frame_size.1.round() as u32);
+    if width == 0 || height == 0 {
+        return None;
+    }
+    Some((width, height))
+}
+
+// A cleared output buffer for image_size with the tile jobs tracing into it, used when a realtime window is resized
+fn create_scene_output_and_jobs(
+    image_size: (u32, u32),
+    num_samples: u32,
+    config: &Config,
+    scene_state: &Arc<RwLock<SceneState>>)
+-> (Arc<SceneOutput>, TileJobs) {
+
+    let (_, num_tasks_xy) = tile_layout(image_size);
+    let buffer = MultiSliceReadWriteLock::new(vec![0.0_f32; (image_size.0 * image_size.1 * 4) as usize]);
+    let remaining_tasks = AtomicUsize::new((num_tasks_xy.0 * num_tasks_xy.1) as usize);
+    let scene_output = Arc::new(SceneOutput::new(buffer, remaining_tasks, AtomicBool::new(false)));
+    let tile_jobs = create_tile_jobs(image_size, num_samples, config, scene_state, &scene_output);
+    (scene_output, tile_jobs)
+}
+
+// How long the main thread sleeps after a frame that took frame_duration so it runs at no more than fps_cap
+fn frame_sleep_time(fps_cap: u32, frame_duration: Duration) -> Option<Duration> {
+    if fps_cap == 0 {
+        return None;
+    }
+    Duration::from_micros(1_000_000 / fps_cap as u64).checked_sub(frame_duration)
+}
+
+// Offline renders are progressive, tracing spp over several passes so there is always an image to preview.
+// Returns the samples for the first pass, the samples for every later pass and the number of passes.
+fn samples_per_pass(config: &Config) -> (u32, u32, u32) {
+    let ns: u32 = if config.realtime {config.spp} else {config.spp_per_pass.min(config.spp).max(1)};
+    // edge anti-aliasing traces a 1spp pass first to find the edges, the remaining samples go to edge pixels only
+    let first_pass_ns: u32 = if edge_aa_enabled(config) {1} else {ns};
+    let num_passes: u32 = if config.realtime {1} else {1 + (config.spp - first_pass_ns + ns - 1) / ns};
+    (first_pass_ns, ns, num_passes)
+}
+
+// Samples traced by pass, the last pass only traces what is left of spp when it isn't a multiple of the samples per pass
+fn samples_for_pass(config: &Config, pass: u32) -> u32 {
+    let (first_pass_ns, ns, _) = samples_per_pass(config);
+    if pass == 0 {first_pass_ns} else {ns.min(config.spp - first_pass_ns - (pass - 1) * ns)}
+}
+
+fn edge_aa_enabled(config: &Config) -> bool {
+    !config.realtime && config.aa_edges && config.spp > 1
+}
+
+// Offline windows go from tracing to done once the last pass finishes. With -review they then move on to reviewing,
+// where the finished image's exposure and tonemapping can be changed and it re-saved. The camera stays put as moving
+// it would need a re-render.
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum OfflineState {
+    Tracing,
+    Done,
+    Reviewing,
+}
+
+impl OfflineState {
+    fn next(self, trace_completed: bool, review: bool) -> OfflineState {
+        match self {
+            OfflineState::Tracing if trace_completed => OfflineState::Done,
+            OfflineState::Done if review => OfflineState::Reviewing,
+            state => state,
+        }
+    }
+}
+
+const REVIEW_EXPOSURE_STEP: f64 = 0.1;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum ReviewAction {
+    IncreaseExposure,
+    DecreaseExposure,
+    ToggleTonemap,
+    Save,
+}
+
+impl ReviewAction {
+    // Updates the export settings, saving is left to the caller
+    fn apply(self, config: &mut Config) {
+        match self {
+            ReviewAction::IncreaseExposure => config.exposure += REVIEW_EXPOSURE_STEP,
+            ReviewAction::DecreaseExposure => config.exposure = (config.exposure - REVIEW_EXPOSURE_STEP).max(0.0),
+            ReviewAction::ToggleTonemap => config.tonemap = !config.tonemap,
+            ReviewAction::Save => {},
+        }
+    }
+}
+
+#[cfg(feature = "winit")]
+const REVIEW_CONTROLS: &str = "Decrease/Increase Exposure = R/T | Toggle Tonemap = L | Save = S";
+
+#[cfg(feature = "winit")]
+fn review_actions(user_input: &input::UserInput) -> Vec<ReviewAction> {
+    [(VirtualKeyCode::T, ReviewAction::IncreaseExposure),
+     (VirtualKeyCode::R, ReviewAction::DecreaseExposure),
+     (VirtualKeyCode::L, ReviewAction::ToggleTonemap),
+     (VirtualKeyCode::S, ReviewAction::Save)]
+        .iter()
+        .filter(|(key, _)| user_input.keys_pressed.contains(key))
+        .map(|(_, action)| *action)
+        .collect()
+}
+
+// Directory sequences write their frames to, the one -out= names a file in or the working directory
+fn sequence_output_dir(config: &Config) -> std::path::PathBuf {
+    match config.output_path.as_ref().and_then(|output_path| std::path::Path::new(output_path).parent()) {
+        Some(output_dir) if !output_dir.as_os_str().is_empty() => output_dir.to_path_buf(),
+        _ => std::path::PathBuf::from("."),
+    }
+}
+
+// Image path for offline renders, either the requested path or the oldest of the rotating output{n}.ppm files
+fn output_image_path(config: &Config) -> String {
+    match config.output_path {
+        Some(ref output_path) => output_path.clone(),
+        None => {
+            // save up to 10 versions so we can have some sort of local history for comparisons
+            let image_file_name = "output";
+            let image_file_ext = ".ppm";
+            let mut oldest_file_version = 0;
+            let mut oldest_file_time = std::time::SystemTime::now();
+            for i in 0..10 {
+                let image_path_string = [image_file_name, &(i as u32).to_string(), image_file_ext].concat();
+                let image_path = std::path::Path::new(&image_path_string);
+                if !image_path.exists() {
+                    oldest_file_version = i;
+                    break;
+                } else {
+                    let file_time = image_path.metadata().unwrap().modified().unwrap();
+                    if oldest_file_time > file_time {
+                        oldest_file_time = file_time;
+                        oldest_file_version = i;
+                    } 
+                }
+            }
+            [image_file_name, &(oldest_file_version as u32).to_string(), image_file_ext].concat()
+        }
+    }
+}
+
+fn print_render_stats(scene_output: &SceneOutput, config: &Config, duration: Duration) {
+    println!("{}", scene_output.stats.summary(duration));
+    if config.write_stats {
+        let stats_path = std::path::Path::new("stats.json");
+        match scene_output.stats.write_json(&stats_path, duration) {
+            Ok(_) => println!("stats.json saved"),
+            Err(e) => log::error!("Could not write stats.json: {}", e),
+        }
+    }
+}
+
+// Traces an offline render of the world on the renderer's threads, returning the RGBA buffer
+fn render_to_buffer(renderer: &Renderer, world: Box<ThreadsafeHitable>, cam: Camera, config: &Config, image_size: (u32, u32)) -> Vec<f32> {
+    let (nx, ny) = image_size;
+    let (first_pass_ns, _, num_passes) = samples_per_pass(config);
+
+    let mut scene_state = SceneState::new(cam, world, 0.0, 1.0, 1.0, false, config.clone());
+    scene_state.deadline = config.max_seconds.map(|max_seconds| Instant::now() + Duration::from_secs_f64(max_seconds));
+    let scene_state = Arc::new(RwLock::new(scene_state));
+    let rgba_texture = MultiSliceReadWriteLock::new(vec![0.0_f32; (nx*ny*4) as usize]);
+    let scene_output = Arc::new(SceneOutput::new(rgba_texture, AtomicUsize::new(0), AtomicBool::new(false)));
+    let (batches, jobs) = create_tile_jobs(image_size, first_pass_ns, config, &scene_state, &scene_output);
+
+    let start_timer = Instant::now();
+    for pass in 0..num_passes {
+        if pass > 0 {
+            if scene_state.read().deadline.is_some_and(|deadline| Instant::now() >= deadline) {
+                println!("Time budget reached.. stopped after {} of {} passes", pass, num_passes);
+                break;
+            }
+            if pass == 1 && edge_aa_enabled(config) {
+                let sample_mask = edges::sobel_edge_mask(scene_output.buffer.read(), image_size, edges::EDGE_THRESHOLD);
+                println!("Edge anti-aliasing {} of {} pixels", sample_mask.iter().filter(|is_edge| **is_edge).count(), sample_mask.len());
+                scene_state.write().sample_mask = Some(sample_mask);
+            }
+            batches.iter().for_each(|batch| batch.write().set_num_samples(samples_for_pass(config, pass)));
+        }
+        scene_output.remaining_tasks.store(jobs.len(), Ordering::Release);
+        renderer.wait(&renderer.dispatch(&jobs));
+        println!("Tracing... {} x {} {}spp. Pass {}/{} done", nx, ny, config.spp, pass + 1, num_passes);
+    }
+
+    print_render_stats(&scene_output, config, start_timer.elapsed());
+    let image = scene_output.buffer.read().clone();
+    image
+}
+
+// Traces an offline render on a thread pool without opening a window then writes the image.
+// Used by cpu builds which have no graphics dependencies.
+pub fn run_headless(config: Config) -> Result<(), failure::Error> {
+    // there's no window to interact with so always render offline
+    let mut config = config;
+    config.realtime = false;
+    print_config(&config);
+
+    let image_size = (500, 500);
+    if config.info {
+        print_scene_info(&config, image_size);
+        return Ok(());
+    }
+    print_tile_layout(image_size);
+    if config.num_frames > 1 || config.keyframes.is_some() {
+        render_sequence(&config, image_size, &sequence_output_dir(&config))?;
+        return Ok(());
+    }
+
+    let (world, mut cam) = cornell_box((image_size.0 as f64)/(image_size.1 as f64), config.shutter);
+    apply_focal_length(&mut cam, &config);
+    let renderer = Renderer::new(config.num_threads.unwrap_or_else(num_cpus::get));
+    let mut image = render_to_buffer(&renderer, world, cam, &config, image_size);
+
+    let image_path_string = output_image_path(&config);
+    let image_path = std::path::Path::new(&image_path_string);
+
+    let mut guides = vec![];
+    if config.guides || (config.denoise && config.render_mode == RenderMode::Beauty) {
+        for render_mode in [RenderMode::Albedo, RenderMode::Normal].iter() {
+            let mut guide_config = config.clone();
+            guide_config.render_mode = *render_mode;
+            let (world, mut cam) = cornell_box((image_size.0 as f64)/(image_size.1 as f64), config.shutter);
+            apply_focal_length(&mut cam, &config);
+            let guide = render_to_buffer(&renderer, world, cam, &guide_config, image_size);
+            if config.guides {
+                let guide_path = aov_image_path(&image_path, *render_mode);
+                save_image(&guide_path, &guide, image_size, &guide_config)?;
+                println!("{} saved", guide_path.display());
+            }
+            guides.push(guide);
+        }
+    }
+
+    // AOVs are already noise free
+    if config.denoise && config.render_mode == RenderMode::Beauty {
+        let guides = denoise::DenoiseGuides { albedo: Some(&guides[0]), normal: Some(&guides[1]) };
+        image = denoise::denoise(&image, image_size, &guides);
+    }
+
+    save_image(&image_path, &image, image_size, &config)?;
+    println!("{} saved", image_path_string);
+    Ok(())
+}
+
+// -focal= overrides the scene camera's field of view
+fn apply_focal_length(cam: &mut Camera, config: &Config) {
+    if let Some(focal_length) = config.focal_length {
+        cam.set_vfov(camera::focal_length_to_vfov(focal_length, config.sensor_size));
+    }
+}
+
+// image.png becomes image_albedo.png for the albedo AOV
+fn aov_image_path(image_path: &std::path::Path, render_mode: RenderMode) -> std::path::PathBuf {
+    let stem = image_path.file_stem().map_or(String::from("output"), |stem| stem.to_string_lossy().into_owned());
+    let mut file_name = format!("{}_{}", stem, format!("{:?}", render_mode).to_lowercase());
+    if let Some(extension) = image_path.extension() {
+        file_name = format!("{}.{}", file_name, extension.to_string_lossy());
+    }
+    image_path.with_file_name(file_name)
+}
+
+// Renders the crop (start, size) of a built in scene on the calling thread with a fixed seed, so the same config
+// always gives the same RGBA buffer. Used by the golden image tests.
+pub fn render_scene_crop(scene_name: &str, config: &Config, image_size: (u32, u32), crop: ((u32, u32), (u32, u32))) -> Result<Vec<f32>, failure::Error> {
+    let aspect = (image_size.0 as f64)/(image_size.1 as f64);
+    let (world, mut cam) = match scene_name {
+        "cornell_box" => cornell_box(aspect, config.shutter),
+        "two_spheres" => two_spheres(aspect, config.shutter),
+        _ => return Err(failure::err_msg(format!("Unknown scene '{}', expected cornell_box or two_spheres", scene_name))),
+    };
+    apply_focal_length(&mut cam, config);
+    Ok(render_single_threaded_crop(world, cam, config, image_size, crop))
+}
+
+// Renders config.num_frames offline images to output_dir/frame_0000.png.., flying the camera from the first to the
+// last keyframe when given keyframes or turning it around its look at point when orbiting. Returns the camera origin
+// used for each frame.
+pub fn render_sequence(config: &Config, image_size: (u32, u32), output_dir: &std::path::Path) -> Result<Vec<Vec3>, failure::Error> {
+    let mut config = config.clone();
+    config.realtime = false;
+    let keyframes = match config.keyframes {
+        Some(ref keyframes_path) => Some(keyframes::CameraKeyframes::load(keyframes_path).map_err(failure::err_msg)?),
+        None => None,
+    };
+    if !config.orbit && keyframes.is_none() {
+        log::warn!("Rendering {} frames without -orbit, the camera is static", config.num_frames);
+    }
+    if keyframes.is_some() && config.num_frames <= 1 {
+        log::warn!("Rendering 1 frame of the keyframed camera, set -frames= to render the whole path");
+    }
+
+    let renderer = Renderer::new(config.num_threads.unwrap_or_else(num_cpus::get));
+    let mut camera_origins = Vec::with_capacity(config.num_frames as usize);
+    for frame in 0..config.num_frames {
+        let (world, mut cam) = cornell_box((image_size.0 as f64)/(image_size.1 as f64), config.shutter);
+        apply_focal_length(&mut cam, &config);
+        if let Some(ref keyframes) = keyframes {
+            // the first and last frames land on the first and last keyframes
+            let progress = if config.num_frames > 1 {frame as f64 / (config.num_frames - 1) as f64} else {0.0};
+            let time = keyframes.start_time() + (keyframes.end_time() - keyframes.start_time()) * progress;
+            keyframes.sample(time, config.interpolation).apply(&mut cam);
+        } else if config.orbit {
+            cam.orbit(360.0 * frame as f64 / config.num_frames as f64);
+        }
+        camera_origins.push(cam.get_origin());
+
+        println!("Frame {}/{}", frame + 1, config.num_frames);
+        let image = render_to_buffer(&renderer, world, cam, &config, image_size);
+        let frame_path = output_dir.join(format!("frame_{:04}.png", frame));
+        save_image(&frame_path, &image, image_size, &config)?;
+    }
+    Ok(camera_origins)
+}
+
+// Traces into a window without rendy, the image is tonemapped on the CPU and presented with
+// winit_utils::SoftwarePresenter. Only the camera controls are supported.
+#[cfg(feature = "winit")]
+fn run_software(config: Config, image_size: (u32, u32)) -> Result<(), failure::Error> {
+    let (nx, ny) = image_size;
+    print_tile_layout(image_size);
+    let (first_pass_ns, _, num_passes) = samples_per_pass(&config);
+    let aa_edges = edge_aa_enabled(&config);
+    let buffer_size_elements = (nx*ny*4) as usize;
+
+    let mut events_loop = winit::event_loop::EventLoop::new();
+    let mut window = WindowBuilder::new()
+        .with_inner_size(LogicalSize{width: nx as f64, height: ny as f64})
+        .build(&events_loop)
+        .map_err(|_| failure::err_msg("Could not create window"))?;
+    update_window_title_status(&window, &format!("Starting.. image size ({} x {})", nx, ny));
+    let mut presenter = winit_utils::SoftwarePresenter::new(&window)?;
+
+    let (world, mut cam) = cornell_box((nx as f64)/(ny as f64), config.shutter);
+    apply_focal_length(&mut cam, &config);
+    let renderer = Renderer::new(config.num_threads.unwrap_or_else(num_cpus::get));
+    let (_, num_tasks_xy) = tile_layout(image_size);
+    let num_tasks = num_tasks_xy.0 * num_tasks_xy.1;
+
+    let mut scene_state = SceneState::new(cam, world, 0.0, 1.0/60.0, 1.0, false, config.clone());
+    if !config.realtime {
+        scene_state.deadline = config.max_seconds.map(|max_seconds| Instant::now() + Duration::from_secs_f64(max_seconds));
+    }
+    let scene_state = Arc::new(RwLock::new(scene_state));
+    let rgba_texture = MultiSliceReadWriteLock::new(vec![0.0_f32; buffer_size_elements]);
+    let mut scene_output = SceneOutput::new(rgba_texture, AtomicUsize::new(num_tasks as usize), AtomicBool::new(false));
+    if !config.realtime {
+        // offline tiles finish one at a time so only those are blitted, realtime redraws every tile each frame
+        scene_output = scene_output.with_completed_tiles();
+    }
+    let scene_output = Arc::new(scene_output);
+    let mut app_user_input_state: input::AppUserInputState = Default::default();
+    let (batches, jobs) = create_tile_jobs(image_size, first_pass_ns, &config, &scene_state, &scene_output);
+
+    if !config.realtime {
+        renderer.dispatch(&jobs);
+    }
+
+    let mut frame_time = 1.0 / 60.0;
+    let app_start_timer = Instant::now();
+    let mut trace_completed = false;
+    let mut passes_completed = 0;
+    let mut offline_state = OfflineState::Tracing;
+    let mut review_config = config.clone();
+
+    loop {
+        let start_timer = Instant::now();
+        let user_input = input::UserInput::poll_events_loop(&mut events_loop, &mut window, &mut app_user_input_state);
+        let mut redraw = false;
+
+        if config.realtime {
+            {
+                let mut scene_state_writable = scene_state.write();
+                scene_state_writable.time0 = scene_state_writable.time1;
+                scene_state_writable.time1 += frame_time;
+                if app_user_input_state.grabbed {
+                    let cam = &mut scene_state_writable.cam;
+                    if cam.update_from_input(&user_input, frame_time) {
+                        cam.update();
+                        batches.iter().for_each(|batch| batch.write().clear_buffer());
+                        *scene_output.buffer.write() = vec![0.0_f32; buffer_size_elements];
+                    }
+                }
+            }
+            renderer.wait(&renderer.dispatch(&jobs));
+        } else if !trace_completed && scene_output.remaining_tasks.load(Ordering::Acquire) == 0 {
+            let out_of_time = scene_state.read().deadline.is_some_and(|deadline| Instant::now() >= deadline);
+            if passes_completed + 1 < num_passes && !out_of_time {
+                if aa_edges && passes_completed == 0 {
+                    let sample_mask = edges::sobel_edge_mask(scene_output.buffer.read(), image_size, edges::EDGE_THRESHOLD);
+                    scene_state.write().sample_mask = Some(sample_mask);
+                }
+                passes_completed += 1;
+                batches.iter().for_each(|batch| batch.write().set_num_samples(samples_for_pass(&config, passes_completed)));
+                scene_output.remaining_tasks.store(num_tasks as usize, Ordering::Release);
+                renderer.dispatch(&jobs);
+            } else {
+                trace_completed = true;
+                let duration = app_start_timer.elapsed();
+                update_window_title_status(&window, &format!("Done.. in {}s.", duration.as_secs_f64()));
+                print_render_stats(&scene_output, &config, duration);
+            }
+        }
+
+        if !config.realtime {
+            let previous_state = offline_state;
+            offline_state = offline_state.next(trace_completed, config.review);
+            if offline_state == OfflineState::Reviewing {
+                if previous_state != OfflineState::Reviewing {
+                    update_window_title_status(&window, &format!("Reviewing.. {}", REVIEW_CONTROLS));
+                }
+                for action in review_actions(&user_input) {
+                    if action == ReviewAction::Save {
+                        let image_path_string = output_image_path(&review_config);
+                        save_image(std::path::Path::new(&image_path_string), scene_output.buffer.read(), image_size, &review_config)?;
+                    } else {
+                        action.apply(&mut review_config);
+                        redraw = true;
+                    }
+                }
+            }
+        }
+
+        if config.realtime || redraw {
+            let rgb_buffer = convert_to_rgb_u8_and_gamma_correct(scene_output.buffer.read(), image_size, &review_config);
+            let bgr_buffer = winit_utils::convert_rgb_to_bgr_dib(&rgb_buffer, image_size);
+            presenter.present(&window, &bgr_buffer, image_size)?;
+        } else {
+            for (tile_pos, tile_size) in scene_output.take_completed_tiles() {
+                let rgb_buffer = convert_tile_to_rgb_u8(scene_output.buffer.read(), image_size, (tile_pos, tile_size), &config);
+                let bgr_buffer = winit_utils::convert_rgb_to_bgr_dib(&rgb_buffer, tile_size);
+                presenter.present_rect(&window, &bgr_buffer, tile_pos, tile_size, image_size)?;
+            }
+        }
+
+        if let Some(sleep_time) = frame_sleep_time(config.fps_cap, start_timer.elapsed()) {
+            std::thread::sleep(sleep_time);
+        }
+        frame_time = start_timer.elapsed().as_secs_f64();
+
+        if user_input.exit_requested {
+            println!("Exit requested");
+            if OUTPUT_IMAGE_ON_CLOSE || !config.realtime {
+                let image_path_string = output_image_path(&review_config);
+                save_image(std::path::Path::new(&image_path_string), scene_output.buffer.read(), image_size, &review_config)?;
+            }
+            return Ok(());
+        }
+    }
 }
 
 #[cfg(any(feature = "dx12", feature = "metal", feature = "vulkan"))]
@@ -168,17 +1035,31 @@ pub fn run(config: Config) -> Result<(), failure::Error>{
         .filter_module("path-tracer", log::LevelFilter::Trace)
         .init();
 
-    println!("Config:\nrealtime={}\nspp={}\nmax depth={}", config.realtime, config.spp, config.max_depth);
+    print_config(&config);
 
     let nx: u32 = 500;
     let ny: u32 = 500;
-    let ns: u32 = config.spp;
-    let image_size = (nx,ny);
+    if config.info {
+        print_scene_info(&config, (nx, ny));
+        return Ok(());
+    }
+    if config.num_frames > 1 || config.keyframes.is_some() {
+        // sequences are always rendered offline without a window
+        render_sequence(&config, (nx, ny), &sequence_output_dir(&config))?;
+        return Ok(());
+    }
+    if config.software {
+        return run_software(config, (nx, ny));
+    }
+    let (first_pass_ns, _, num_passes) = samples_per_pass(&config);
+    let aa_edges = edge_aa_enabled(&config);
+    let mut image_size = (nx,ny);
+    print_tile_layout(image_size);
 
     let window_width = nx as f64;
     let window_height = ny as f64;
 
-    let buffer_size_elements = (nx*ny*4) as usize;
+    let mut buffer_size_elements = (nx*ny*4) as usize;
     let rgba_texture = MultiSliceReadWriteLock::new(vec![0.0_f32; buffer_size_elements]);
 
     if false {
@@ -209,20 +1090,10 @@ pub fn run(config: Config) -> Result<(), failure::Error>{
         rendy::init::Rendy::<Backend>::init(&config).map_err(|_|failure::err_msg("Could not initialise rendy"))?
       //  AnyWindowedRendy::init_auto(&config, window, &events_loop).unwrap()
     };
-    let surface = rendy.factory.create_surface(&window).map_err(|_|failure::err_msg("Could create backbuffer surface"))?;
     let hw_alignment = hal::adapter::PhysicalDevice::limits(rendy.factory.physical())
         .min_uniform_buffer_offset_alignment;
 
-    let source_buffer_size: u64 = (image_size.0 * image_size.1) as u64 * 4 * std::mem::size_of::<f32>() as u64;
-    let mut source_buffer = rendy.factory
-        .create_buffer(
-            BufferInfo {
-                size: source_buffer_size,
-                usage: hal::buffer::Usage::TRANSFER_SRC
-            },
-            rendy::memory::Upload
-        )
-        .map_err(|_| failure::err_msg("Unable to create source buffer"))?;
+    let mut source_buffer = create_source_buffer(&mut rendy.factory, image_size)?;
 
     let source_buffer_size = source_buffer.size();
     let mut mapped_buffer = source_buffer
@@ -238,58 +1109,17 @@ pub fn run(config: Config) -> Result<(), failure::Error>{
         writer.write(buffer.as_slice());
     }
 
-    let mut graph_builder = GraphBuilder::<Backend, Aux<Backend>>::new();
-
-    let source_image = graph_builder.create_image(
-        hal::image::Kind::D2(image_size.0, image_size.1, 1, 1), 
-        1, 
-        hal::format::Format::Rgba32Sfloat, 
-        Some(hal::command::ClearValue {
-            color: hal::command::ClearColor {
-                float32: [1.0, 1.0, 1.0, 1.0],
-            },
-        }),
-    );
-
-    let color = graph_builder.create_image(
-        hal::image::Kind::D2(image_size.0, image_size.1, 1, 1),
-        1,
-        rendy.factory.get_surface_format(&surface),
-        Some(hal::command::ClearValue {
-            color: hal::command::ClearColor {
-                float32: [1.0, 1.0, 1.0, 1.0],
-            },
-        }),
-    );
-
-    let copy_texture_node = graph_builder.add_node(
-        node::copy_image::CopyToTexture::<Backend>::builder(
-            source_image
-        )
-    );
-
-    let tonemap_pass = graph_builder.add_node(
-        node::tonemap::Pipeline::builder()
-                .with_image(source_image)
-                .into_subpass()
-                .with_dependency(copy_texture_node)
-                .with_color(color)
-                .into_pass(),
-    );
-    graph_builder.add_node(PresentNode::builder(&rendy.factory, surface, color).with_dependency(tonemap_pass));
-    
     let mut aux = Aux {
         frames: FRAMES_IN_FLIGHT as usize,
         hw_alignment,
         tonemapper_args: node::tonemap::TonemapperArgs {
             exposure_numframes_xx: [1.3, 1.0, 0.0, 0.0],
+            passthrough_xxx: [0, 0, 0, 0],
         },
         source_buffer: Some(source_buffer)
     };
 
-    let frame_graph = graph_builder
-        .with_frames_in_flight(FRAMES_IN_FLIGHT)
-        .build(&mut rendy.factory, &mut rendy.families, &mut aux).map_err(|_|failure::err_msg("Could not build graph"))?;
+    let frame_graph = build_frame_graph(&mut rendy, &window, image_size, &config, &mut aux)?;
 
     let mut frame_graph = Some(frame_graph);
     //- Rendy integration
@@ -299,11 +1129,11 @@ pub fn run(config: Config) -> Result<(), failure::Error>{
     //let world = two_spheres();
     //let world = four_spheres();
     //let world = random_scene(0.0, 1000.0);
-    //let world = two_perlin_spheres();
+    //let world = two_perlin_spheres(config.seed);
     //let world = textured_sphere();
-    //let world = simple_light();
-    //let world = cornell_smoke();
-    //let world = final_book_two();
+    //let world = simple_light(config.seed);
+    //let world = cornell_smoke(aspect, config.shutter);
+    //let world = final_book_two(config.seed);
 
     //let lookfrom = Vec3::new(-2.0,2.0,1.0);
     //let lookfrom = Vec3::new(26.0,2.0,3.0);
@@ -318,36 +1148,21 @@ pub fn run(config: Config) -> Result<(), failure::Error>{
 
     //let dist_to_focus = 10.0;
     //let aperture = 0.0;
-    let aspect: f64 = (nx as f64)/(ny as f64);
+    let mut aspect: f64 = (nx as f64)/(ny as f64);
     //let fov = 20.0;
     //let fov = 40.0;
 
-   // let cam = Arc::new(RwLock::new(Camera::new(lookfrom, lookat, Vec3::new(0.0,1.0,0.0), 20.0, aspect, aperture, dist_to_focus, 0.0, 1.0)));
-    //let cam = Camera::new(lookfrom, lookat, Vec3::new(0.0,1.0,0.0), fov, aspect, aperture, dist_to_focus, 0.0, 1.0);
+   // let cam = Arc::new(RwLock::new(Camera::new(lookfrom, lookat, Vec3::new(0.0,1.0,0.0), 20.0, aspect, aperture, dist_to_focus, 0.0, config.shutter)));
+    //let cam = Camera::new(lookfrom, lookat, Vec3::new(0.0,1.0,0.0), fov, aspect, aperture, dist_to_focus, 0.0, config.shutter);
 
-    let (world, cam) = cornell_box(aspect);
-
-    let convert_to_rgb_u8_and_gamma_correct = |buffer: &Vec<f32>| -> Vec<u8>{
-        let mut output = Vec::with_capacity(buffer.len());
-         buffer.chunks(4).map(|chunk| {
-            let colour = Vec3::new(chunk[0] as f64,chunk[1] as f64,chunk[2] as f64);
-            reinhard_tonemap(&colour)
-        }).for_each(|colour|{   output.push((255.99 * colour.x.sqrt()) as u8);
-                                output.push((255.99 * colour.y.sqrt()) as u8);
-                                output.push((255.99 * colour.z.sqrt()) as u8);});
-
-        output
-    };
+    let (world, mut cam) = cornell_box(aspect, config.shutter);
+    apply_focal_length(&mut cam, &config);
 
     let num_cores = num_cpus::get();
     println!("Running on {} cores", num_cores);
+    let renderer = Renderer::new(config.num_threads.unwrap_or(num_cores));
 
-    let task_dim_xy = (nx / 9, ny / 9);
-    println!("Task Dimensions = {}x{}", task_dim_xy.0, task_dim_xy.1);
-    // sanitize so num tasks divides exactly into image
-    let task_dim_xy = (round_down_to_closest_factor(task_dim_xy.0, nx), round_down_to_closest_factor(task_dim_xy.1, ny));
-    println!("Task Dimensions fitted to image size = {}x{}", task_dim_xy.0, task_dim_xy.1);
-    let num_tasks_xy = (nx / task_dim_xy.0, ny / task_dim_xy.1);
+    let (_, num_tasks_xy) = tile_layout(image_size);
     let num_tasks = num_tasks_xy.0 * num_tasks_xy.1;
     let window_lock = AtomicBool::new(false);
     let remaining_tasks = AtomicUsize::new((num_tasks) as usize);
@@ -356,8 +1171,12 @@ pub fn run(config: Config) -> Result<(), failure::Error>{
 
     let default_disable_emissive = false;//config.realtime; // Disable emissive for realtime by default as it's noisy
     let default_sky_brightness = 0.0;
-    let scene_state = Arc::new(RwLock::new(SceneState::new(cam, world, 0.0, 1.0/60.0, default_sky_brightness, default_disable_emissive, config)));
-    let scene_output = Arc::new(SceneOutput::new(rgba_texture, remaining_tasks, window_lock));
+    let mut scene_state = SceneState::new(cam, world, 0.0, 1.0/60.0, default_sky_brightness, default_disable_emissive, config.clone());
+    if !config.realtime {
+        scene_state.deadline = config.max_seconds.map(|max_seconds| Instant::now() + Duration::from_secs_f64(max_seconds));
+    }
+    let scene_state = Arc::new(RwLock::new(scene_state));
+    let mut scene_output = Arc::new(SceneOutput::new(rgba_texture, remaining_tasks, window_lock));
     let mut app_user_input_state: input::AppUserInputState = Default::default();
 
 
@@ -373,29 +1192,13 @@ pub fn run(config: Config) -> Result<(), failure::Error>{
         batch.run();
     }
     
-    let controls_string = "Decrease/Increase Sky Brightness = O/P | Toggle Emissive = B | Decrease/Increase Exposure = R/T";
+    let controls_string = "Decrease/Increase Sky Brightness = O/P | Toggle Emissive = B | Decrease/Increase Exposure = R/T | Auto Exposure = X | Toggle Tonemap = L | Frame Scene = F";
 
-    let mut batches = vec![];
-    let mut jobs: Vec<Arc<RwLock<dyn JobTask + Send + Sync + 'static>>>  = vec![];
-    for task_y in 0..num_tasks_xy.1 {
-        for task_x in 0..num_tasks_xy.0 {
-            let start_xy = (task_dim_xy.0 * task_x, task_dim_xy.1 * task_y);
-            let end_xy = (start_xy.0 + task_dim_xy.0, start_xy.1 + task_dim_xy.1);
-            let batch = TraceSceneBatchJob::new(ns, 
-                                                start_xy, end_xy, 
-                                                    image_size, 
-                                                    scene_state.clone(), 
-                                                    scene_output.clone(),
-                                                    config.realtime);
-            let batch = Arc::new(RwLock::new(batch));
-            batches.push(batch.clone());
-            jobs.push(batch);
-        }
-    }
+    let (mut batches, mut jobs) = create_tile_jobs(image_size, first_pass_ns, &config, &scene_state, &scene_output);
 
     // if offline just kick off straight away
     if !config.realtime {
-        Jobs::dispatch_jobs(&jobs);
+        renderer.dispatch(&jobs);
     }
 
     let mut fps = 0.0;
@@ -403,6 +1206,11 @@ pub fn run(config: Config) -> Result<(), failure::Error>{
     let mut frame_counter = 0;
     let app_start_timer = Instant::now();
     let mut trace_completed = false;
+    let mut passes_completed = 0;
+    let mut auto_exposure = false;
+    let mut offline_state = OfflineState::Tracing;
+    let mut review_config = config.clone();
+    let review_base_exposure = aux.tonemapper_args.exposure_numframes_xx[0];
     
     loop {
 
@@ -420,6 +1228,29 @@ pub fn run(config: Config) -> Result<(), failure::Error>{
 
         let user_input = input::UserInput::poll_events_loop(&mut events_loop, &mut window, &mut app_user_input_state);  
 
+        // realtime traces at the window size, offline keeps the size it was started with so the saved image is as requested
+        if config.realtime {
+            if let Some(new_image_size) = user_input.new_frame_size.and_then(resized_image_size) {
+                if new_image_size != image_size {
+                    image_size = new_image_size;
+                    buffer_size_elements = (image_size.0 * image_size.1 * 4) as usize;
+                    let (resized_output, (resized_batches, resized_jobs)) = create_scene_output_and_jobs(image_size, first_pass_ns, &config, &scene_state);
+                    scene_output = resized_output;
+                    batches = resized_batches;
+                    jobs = resized_jobs;
+
+                    // the graph images are sized when it is built so it is rebuilt around a new source buffer and surface
+                    frame_graph.take().unwrap().dispose(&mut rendy.factory, &mut aux);
+                    aux.source_buffer = Some(create_source_buffer(&mut rendy.factory, image_size)?);
+                    frame_graph = Some(build_frame_graph(&mut rendy, &window, image_size, &config, &mut aux)?);
+                    aux.tonemapper_args.exposure_numframes_xx[1] = 1.0;
+
+                    aspect = (image_size.0 as f64)/(image_size.1 as f64);
+                    scene_state.write().cam.set_aspect(aspect);
+                }
+            }
+        }
+
         if app_user_input_state.grabbed {
             if config.realtime {
                 if user_input.keys_pressed.contains(&VirtualKeyCode::T) {
@@ -428,6 +1259,15 @@ pub fn run(config: Config) -> Result<(), failure::Error>{
                     aux.tonemapper_args.exposure_numframes_xx[0] -= 0.1;
                 }
 
+                if user_input.keys_pressed.contains(&VirtualKeyCode::X) {
+                    auto_exposure = !auto_exposure;
+                }
+
+                if user_input.keys_pressed.contains(&VirtualKeyCode::L) {
+                    let passthrough = &mut aux.tonemapper_args.passthrough_xxx[0];
+                    *passthrough = if *passthrough == 0 {1} else {0};
+                }
+
                 if user_input.keys_pressed.contains(&VirtualKeyCode::O) {
                     clear_scene = true;
                     let mut scene_state_writable = scene_state.write();
@@ -446,6 +1286,13 @@ pub fn run(config: Config) -> Result<(), failure::Error>{
                     clear_scene = true;
                 }
 
+                if user_input.keys_pressed.contains(&VirtualKeyCode::F) {
+                    clear_scene = true;
+                    let mut scene_state_writable = scene_state.write();
+                    let scene_bounds = scene_state_writable.world.bounding_box(scene_state_writable.time0, scene_state_writable.time1);
+                    scene_state_writable.cam.frame_scene(&scene_bounds, aspect);
+                }
+
                 if user_input.keys_pressed.contains(&VirtualKeyCode::K) {
                     let mut scene_state_writable = scene_state.write();
                     let cam = &mut scene_state_writable.cam;
@@ -474,25 +1321,70 @@ pub fn run(config: Config) -> Result<(), failure::Error>{
         
         // if realtime we wait for all jobs to finish, else we poll.
         if config.realtime {
-            let job_counter = Jobs::dispatch_jobs(&jobs);
-            Jobs::wait_for_counter(&job_counter, 0);
+            let job_counter = renderer.dispatch(&jobs);
+            renderer.wait(&job_counter);
         } else {
             // poll completion 
             if !trace_completed {
-                if scene_output.remaining_tasks.compare_and_swap(0, 1, Ordering::Acquire) == 0 {
+                let out_of_time = scene_state.read().deadline.is_some_and(|deadline| Instant::now() >= deadline);
+                if scene_output.remaining_tasks.load(Ordering::Acquire) == 0 && passes_completed + 1 < num_passes && !out_of_time {
+                    if aa_edges && passes_completed == 0 {
+                        let sample_mask = edges::sobel_edge_mask(scene_output.buffer.read(), image_size, edges::EDGE_THRESHOLD);
+                        println!("Edge anti-aliasing {} of {} pixels", sample_mask.iter().filter(|is_edge| **is_edge).count(), sample_mask.len());
+                        scene_state.write().sample_mask = Some(sample_mask);
+                    }
+                    // kick off the next pass, accumulating into the existing image
+                    passes_completed += 1;
+                    batches.iter().for_each(|batch| batch.write().set_num_samples(samples_for_pass(&config, passes_completed)));
+                    scene_output.remaining_tasks.store(num_tasks as usize, Ordering::Release);
+                    renderer.dispatch(&jobs);
+                } else if scene_output.remaining_tasks.compare_and_swap(0, 1, Ordering::Acquire) == 0 {
                     trace_completed = true;
                         // stats taken to complete
                     let duration = app_start_timer.elapsed();
                     let duration_in_secs = duration.as_secs() as f64 + duration.subsec_nanos() as f64 * 1e-9;
-                    update_window_title_status(&window, &format!("Done.. in {}s.", duration_in_secs));
+                    if out_of_time {
+                        update_window_title_status(&window, &format!("Time budget reached.. stopped after {}s.", duration_in_secs));
+                    } else {
+                        update_window_title_status(&window, &format!("Done.. in {}s.", duration_in_secs));
+                    }
+                    print_render_stats(&scene_output, &config, duration);
                 } else if frame_counter % 50 == 0 {
-                    let percent_done = ((num_tasks - scene_output.remaining_tasks.load(Ordering::Relaxed) as u32) as f32 / num_tasks as f32) * 100.0;
-                    update_window_title_status(&window, &format!("Tracing... {} tasks, {} x {} {}spp. {}% done",  num_tasks, nx, ny, ns,percent_done));
+                    let tasks_done = passes_completed * num_tasks + (num_tasks - scene_output.remaining_tasks.load(Ordering::Relaxed) as u32);
+                    let percent_done = (tasks_done as f32 / (num_tasks * num_passes) as f32) * 100.0;
+                    update_window_title_status(&window, &format!("Tracing... {} tasks, {} x {} {}spp. Pass {}/{}. {}% done",  num_tasks, nx, ny, config.spp, passes_completed + 1, num_passes, percent_done));
                 }
             }
+
+            let previous_state = offline_state;
+            offline_state = offline_state.next(trace_completed, config.review);
+            if offline_state == OfflineState::Reviewing {
+                if previous_state != OfflineState::Reviewing {
+                    update_window_title_status(&window, &format!("Reviewing.. {}", REVIEW_CONTROLS));
+                }
+                for action in review_actions(&user_input) {
+                    if action == ReviewAction::Save {
+                        let image_path_string = output_image_path(&review_config);
+                        save_image(std::path::Path::new(&image_path_string), scene_output.buffer.read(), image_size, &review_config)?;
+                    } else {
+                        action.apply(&mut review_config);
+                    }
+                }
+                // the presented image follows the settings the image will be saved with
+                aux.tonemapper_args.exposure_numframes_xx[0] = review_base_exposure * review_config.exposure as f32;
+                aux.tonemapper_args.passthrough_xxx[0] = if review_config.tonemap {0} else {1};
+            }
         }
         let scene_state_readable = scene_state.read();
 
+        if auto_exposure {
+            let log_average = log_average_luminance(scene_output.buffer.read(), image_size);
+            let target_exposure = AUTO_EXPOSURE_KEY / log_average;
+            let exposure = aux.tonemapper_args.exposure_numframes_xx[0] as f64;
+            let blend = (AUTO_EXPOSURE_SPEED * frame_time).min(1.0);
+            aux.tonemapper_args.exposure_numframes_xx[0] = (exposure + (target_exposure - exposure) * blend) as f32;
+        }
+
         let source_buffer_size = aux.source_buffer.as_ref().unwrap().size();
         let mut mapped_buffer = aux.source_buffer
             .as_mut()
@@ -515,10 +1407,8 @@ pub fn run(config: Config) -> Result<(), failure::Error>{
         }
         frame_counter += 1;
 
-        // throttle main thread to 60fps
-        const SIXTY_HZ: Duration = Duration::from_micros(1_000_000 / 60);
         let frame_duration = start_timer.elapsed();
-        match SIXTY_HZ.checked_sub(frame_duration) {
+        match frame_sleep_time(config.fps_cap, frame_duration) {
             Some(sleep_time) => {
                // println!("frame time {:?} sleeping for {:?}", frame_time, sleep_time);
                  std::thread::sleep(sleep_time);
@@ -540,33 +1430,16 @@ pub fn run(config: Config) -> Result<(), failure::Error>{
         if user_input.exit_requested {
 
             // write image 
+            let mut save_result = Ok(());
             if OUTPUT_IMAGE_ON_CLOSE || !config.realtime {
-                // save up to 10 versions so we can have some sort of local history for comparisons
-                let image_file_name = "output";
-                let image_file_ext = ".ppm";
-                let mut oldest_file_version = 0;
-                let mut oldest_file_time = std::time::SystemTime::now();
-                for i in 0..10 {
-                    let image_path_string = [image_file_name, &(i as u32).to_string(), image_file_ext].concat();
-                    let image_path = std::path::Path::new(&image_path_string);
-                    if !image_path.exists() {
-                        oldest_file_version = i;
-                        break;
-                    } else {
-                        let file_time = image_path.metadata().unwrap().modified().unwrap();
-                        if oldest_file_time > file_time {
-                            oldest_file_time = file_time;
-                            oldest_file_version = i;
-                        } 
-                    }
-                }
-                let image_path_string = [image_file_name, &(oldest_file_version as u32).to_string(), image_file_ext].concat();
+                let image_path_string = output_image_path(&review_config);
                 let image_path = std::path::Path::new(&image_path_string);
-                save_rgb_texture_as_ppm(&image_path, &convert_to_rgb_u8_and_gamma_correct(scene_output.buffer.read()), image_size);
+                save_result = save_image(&image_path, scene_output.buffer.read(), image_size, &review_config);
             }
 
             frame_graph.take().unwrap().dispose(&mut rendy.factory, &mut aux);
             println!("Exit requested");
+            save_result?;
             break;
         }
     }
@@ -574,6 +1447,55 @@ pub fn run(config: Config) -> Result<(), failure::Error>{
     Ok(())
 }
 
+fn convert_to_rgb_u8_and_gamma_correct(buffer: &Vec<f32>, image_size: (u32, u32), config: &Config) -> Vec<u8> {
+    // post-processing operates on linear values so runs before tonemapping
+    let bloomed_buffer;
+    let buffer = match config.bloom {
+        Some(ref bloom) => {
+            bloomed_buffer = bloom.apply(buffer, image_size);
+            &bloomed_buffer
+        },
+        None => buffer,
+    };
+
+    let mut output = Vec::with_capacity(buffer.len());
+    buffer.chunks(4).enumerate().for_each(|(pixel_index, chunk)| {
+        let (x, y) = (pixel_index as u32 % image_size.0, pixel_index as u32 / image_size.0);
+        output.extend_from_slice(&tonemap_pixel_to_rgb_u8(chunk, (x, y), image_size, config));
+    });
+
+    output
+}
+
+// Converts just the tile's pixels, bloom spreads light across the whole image so isn't applied
+fn convert_tile_to_rgb_u8(buffer: &Vec<f32>, image_size: (u32, u32), tile: TileRect, config: &Config) -> Vec<u8> {
+    let ((tile_x, tile_y), (tile_width, tile_height)) = tile;
+    let mut output = Vec::with_capacity((tile_width * tile_height * 3) as usize);
+    for y in tile_y..tile_y + tile_height {
+        for x in tile_x..tile_x + tile_width {
+            let index = ((y * image_size.0 + x) * 4) as usize;
+            output.extend_from_slice(&tonemap_pixel_to_rgb_u8(&buffer[index..index + 4], (x, y), image_size, config));
+        }
+    }
+    output
+}
+
+fn tonemap_pixel_to_rgb_u8(pixel: &[f32], pixel_xy: (u32, u32), image_size: (u32, u32), config: &Config) -> [u8; 3] {
+    let (transfer, gamma) = (config.transfer, config.gamma);
+    let colour = Vec3::new(pixel[0] as f64, pixel[1] as f64, pixel[2] as f64);
+    let colour = if config.vignette != 0.0 {
+        colour * vignette(pixel_xy.0, pixel_xy.1, image_size, config.vignette)
+    } else {
+        colour
+    };
+    let colour = colour * config.exposure;
+    let colour = if config.tonemap {reinhard_tonemap(&colour)} else {colour};
+    [(255.99 * transfer.encode(colour.x, gamma)) as u8,
+     (255.99 * transfer.encode(colour.y, gamma)) as u8,
+     (255.99 * transfer.encode(colour.z, gamma)) as u8]
+}
+
+#[cfg(feature = "winit")]
 fn update_window_title_status(window: &winit::window::Window, status: &str) {
     println!("{}", status);
     window.set_title(&format!("Path Tracer: {}", status));
@@ -584,37 +1506,121 @@ fn save_bgr_texture_as_ppm(filename: &str, bgr_buffer: &Vec<u8>, buffer_size: (u
     
     let timer = Instant::now();
     
-    // convert to rgb buffer and flip horizontally as (0,0) is bottom left for ppm
-    let buffer_length = bgr_buffer.len();
-    let mut rgb_buffer = vec![0; buffer_length];
-    for j in 0..buffer_size.1 {
-        let j_flipped = buffer_size.1 - j - 1;
-        for i in 0..buffer_size.0 {
-            let offset_x = i * 3;
-            let rgb_offset = (j * buffer_size.0 * 3 + offset_x) as usize;
-            let bgr_offset = (j_flipped * buffer_size.0  * 3 + offset_x) as usize;
-            rgb_buffer[rgb_offset]   = bgr_buffer[bgr_offset+2];
-            rgb_buffer[rgb_offset+1] = bgr_buffer[bgr_offset+1];
-            rgb_buffer[rgb_offset+2] = bgr_buffer[bgr_offset];
-        }
-    }
-    
-    let mut output_image = File::create(filename).expect("Could not open file for write");
-    let header = format!("P6 {} {} 255\n", buffer_size.0, buffer_size.1);
-    output_image.write(header.as_bytes()).expect("failed to write to image file");
-    output_image.write(&rgb_buffer).expect("failed to write to image");
+    let mut output_image = std::io::BufWriter::new(File::create(filename).expect("Could not open file for write"));
+    write_texture_as_ppm(&mut output_image, bgr_buffer, buffer_size, 3, |bgr| [bgr[2], bgr[1], bgr[0]])
+        .and_then(|_| output_image.flush())
+        .expect("failed to write to image");
 
     let duration = timer.elapsed();
     let duration_in_secs = duration.as_secs() as f64 + duration.subsec_nanos() as f64 * 1e-9;
     println!("{} saved in {}s", filename, duration_in_secs);
 }
 
-#[allow(dead_code)]
-fn save_rgb_texture_as_ppm(filename: &std::path::Path, buffer: &Vec<u8>, buffer_size: (u32,u32)) {
-    
-    let timer = Instant::now();
-    
-    // convert to rgb buffer and flip horizontally as (0,0) is bottom left for ppm
+// Writes the linear RGBA buffer as OpenEXR when the path has an .exr extension, otherwise post-processes,
+// tonemaps and encodes it as an 8 bit image. AOVs skip post-processing and are normalised instead.
+fn save_image(path: &std::path::Path, buffer: &Vec<f32>, buffer_size: (u32,u32), config: &Config) -> Result<(), failure::Error> {
+    let is_exr = path.extension().map_or(false, |ext| ext.to_string_lossy().eq_ignore_ascii_case("exr"));
+    if is_exr {
+        save_exr(path, buffer, buffer_size)
+    } else {
+        let rgb_buffer = match config.render_mode {
+            RenderMode::Beauty => convert_to_rgb_u8_and_gamma_correct(buffer, buffer_size, config),
+            RenderMode::Depth => convert_depth_to_rgb_u8(buffer),
+            RenderMode::Position => convert_position_to_rgb_u8(buffer),
+            RenderMode::Albedo => convert_albedo_to_rgb_u8(buffer, config),
+            RenderMode::Normal => convert_normal_to_rgb_u8(buffer),
+        };
+        save_rgb_texture(path, &rgb_buffer, buffer_size)
+    }
+}
+
+// Albedo is already in [0, 1] so is only encoded, not tonemapped
+fn convert_albedo_to_rgb_u8(buffer: &Vec<f32>, config: &Config) -> Vec<u8> {
+    buffer.chunks(4).flat_map(|pixel| {
+        (0..3).map(move |channel| (255.99 * config.transfer.encode((pixel[channel] as f64).min(1.0).max(0.0), config.gamma)) as u8)
+    }).collect()
+}
+
+// Normals mapped from [-1, 1] to [0, 1], misses stay black
+fn convert_normal_to_rgb_u8(buffer: &Vec<f32>) -> Vec<u8> {
+    buffer.chunks(4).flat_map(|pixel| {
+        (0..3).map(move |axis| if pixel[3] > 0.0 {(255.99 * (pixel[axis] * 0.5 + 0.5).min(1.0).max(0.0)) as u8} else {0})
+    }).collect()
+}
+
+// Each axis scaled to the range of positions hit, misses stay black
+fn convert_position_to_rgb_u8(buffer: &Vec<f32>) -> Vec<u8> {
+    let (mut min, mut max) = ([std::f32::MAX; 3], [std::f32::MIN; 3]);
+    for pixel in buffer.chunks(4).filter(|pixel| pixel[3] > 0.0) {
+        for axis in 0..3 {
+            min[axis] = min[axis].min(pixel[axis]);
+            max[axis] = max[axis].max(pixel[axis]);
+        }
+    }
+    let mut output = Vec::with_capacity(buffer.len() / 4 * 3);
+    for pixel in buffer.chunks(4) {
+        for axis in 0..3 {
+            let range = max[axis] - min[axis];
+            let value = if pixel[3] <= 0.0 {0.0} else if range > 0.0 {(pixel[axis] - min[axis]) / range} else {1.0};
+            output.push((255.99 * value) as u8);
+        }
+    }
+    output
+}
+
+// Depth scaled so the furthest hit is white, misses stay black
+fn convert_depth_to_rgb_u8(buffer: &Vec<f32>) -> Vec<u8> {
+    let max_depth = buffer.chunks(4).fold(0.0_f32, |max_depth, pixel| max_depth.max(pixel[0]));
+    let scale = if max_depth > 0.0 {1.0 / max_depth} else {0.0};
+    buffer.chunks(4).flat_map(|pixel| {
+        let value = (255.99 * (pixel[0] * scale).min(1.0)) as u8;
+        vec![value, value, value]
+    }).collect()
+}
+
+// Writes the raw linear buffer as 32 bit float RGBA for compositing, alpha is the traced coverage
+fn save_exr(path: &std::path::Path, buffer: &Vec<f32>, buffer_size: (u32,u32)) -> Result<(), failure::Error> {
+    create_parent_directories(path)?;
+
+    let (width, height) = (buffer_size.0 as usize, buffer_size.1 as usize);
+    exr::prelude::write_rgba_file(path, width, height, |x, y| {
+        // (0,0) is bottom left in our buffer but top left in the file
+        let index = ((height - 1 - y) * width + x) * 4;
+        (buffer[index], buffer[index + 1], buffer[index + 2], buffer[index + 3])
+    }).map_err(|e| failure::err_msg(format!("Could not write image {}: {}", path.display(), e)))
+}
+
+// Creates any missing directories above path so the image written there doesn't fail
+fn create_parent_directories(path: &std::path::Path) -> Result<(), failure::Error> {
+    match path.parent() {
+        Some(parent) if !parent.as_os_str().is_empty() => std::fs::create_dir_all(parent)
+            .map_err(|e| failure::err_msg(format!("Could not create output directory {}: {}", parent.display(), e))),
+        _ => Ok(()),
+    }
+}
+
+// Writes an rgb buffer to path, inferring the format from the extension (ppm if there is none).
+// Parent directories are created if missing and any existing file is overwritten.
+fn save_rgb_texture(path: &std::path::Path, buffer: &Vec<u8>, buffer_size: (u32,u32)) -> Result<(), failure::Error> {
+    create_parent_directories(path)?;
+
+    let is_ppm = match path.extension() {
+        Some(ext) => ext.to_string_lossy().eq_ignore_ascii_case("ppm"),
+        None => true,
+    };
+
+    let result = if is_ppm {
+        save_rgb_texture_as_ppm(path, buffer, buffer_size)
+    } else {
+        // (0,0) is bottom left in our buffer but top left for the image encoders
+        let flipped_buffer = flip_rgb_texture_vertically(buffer, buffer_size);
+        image::save_buffer(path, &flipped_buffer, buffer_size.0, buffer_size.1, image::ColorType::RGB(8))
+    };
+
+    result.map_err(|e| failure::err_msg(format!("Could not write image {}: {}", path.display(), e)))
+}
+
+fn flip_rgb_texture_vertically(buffer: &Vec<u8>, buffer_size: (u32,u32)) -> Vec<u8> {
     let buffer_length = buffer.len();
     let mut rgb_buffer = vec![0; buffer_length];
     for j in 0..buffer_size.1 {
@@ -628,15 +1634,47 @@ fn save_rgb_texture_as_ppm(filename: &std::path::Path, buffer: &Vec<u8>, buffer_
             rgb_buffer[rgb_offset+2] = buffer[buffer_offset+2];
         }
     }
-    
-    let mut output_image = File::create(filename).expect("Could not open file for write");
+    rgb_buffer
+}
+
+// Streams the rgb buffer as a binary ppm, rows are written last to first as (0,0) is bottom left for ppm
+// so no flipped copy of the image is needed
+fn write_rgb_texture_as_ppm<W: Write>(output: &mut W, buffer: &[u8], buffer_size: (u32,u32)) -> std::io::Result<()> {
+    let header = format!("P6 {} {} 255\n", buffer_size.0, buffer_size.1);
+    output.write_all(header.as_bytes())?;
+    let row_length = buffer_size.0 as usize * 3;
+    for row in buffer[..row_length * buffer_size.1 as usize].chunks(row_length).rev() {
+        output.write_all(row)?;
+    }
+    Ok(())
+}
+
+// Streams a buffer in another pixel layout as a binary ppm, to_rgb converts each pixel's bytes.
+// Pixels are written one at a time so the output should be buffered.
+fn write_texture_as_ppm<W: Write, F: Fn(&[u8]) -> [u8; 3]>(output: &mut W, buffer: &[u8], buffer_size: (u32,u32), bytes_per_pixel: usize, to_rgb: F) -> std::io::Result<()> {
     let header = format!("P6 {} {} 255\n", buffer_size.0, buffer_size.1);
-    output_image.write(header.as_bytes()).expect("failed to write to image file");
-    output_image.write(&rgb_buffer).expect("failed to write to image");
+    output.write_all(header.as_bytes())?;
+    let row_length = buffer_size.0 as usize * bytes_per_pixel;
+    for row in buffer[..row_length * buffer_size.1 as usize].chunks(row_length).rev() {
+        for pixel in row.chunks(bytes_per_pixel) {
+            output.write_all(&to_rgb(pixel))?;
+        }
+    }
+    Ok(())
+}
+
+fn save_rgb_texture_as_ppm(filename: &std::path::Path, buffer: &Vec<u8>, buffer_size: (u32,u32)) -> std::io::Result<()> {
+    
+    let timer = Instant::now();
+    
+    let mut output_image = std::io::BufWriter::new(File::create(filename)?);
+    write_rgb_texture_as_ppm(&mut output_image, buffer, buffer_size)?;
+    output_image.flush()?;
 
     let duration = timer.elapsed();
     let duration_in_secs = duration.as_secs() as f64 + duration.subsec_nanos() as f64 * 1e-9;
     println!("{} saved in {}s", filename.file_name().unwrap().to_str().unwrap(), duration_in_secs);
+    Ok(())
 }
 
 #[allow(dead_code)]
@@ -644,34 +1682,17 @@ fn save_rgba_texture_as_ppm(filename: &str, rgba_buffer: &Vec<u8>, buffer_size:
     
     let timer = Instant::now();
     
-    // convert to rgb buffer and flip horizontally as (0,0) is bottom left for ppm
-    let buffer_length = rgba_buffer.len();
-    let mut rgb_buffer = vec![0; buffer_length];
-    for j in 0..buffer_size.1 {
-        let j_flipped = buffer_size.1 - j - 1;
-        for i in 0..buffer_size.0 {
-            let rgb_offset_x = i * 3;
-            let rgba_offset_x = i * 4;
-            let rgb_offset = (j * buffer_size.0 * 3 + rgb_offset_x) as usize;
-            let rgba_offset = (j_flipped * buffer_size.0  * 3 + rgba_offset_x) as usize;
-            rgb_buffer[rgb_offset]   = rgba_buffer[rgba_offset];
-            rgb_buffer[rgb_offset+1] = rgba_buffer[rgba_offset+1];
-            rgb_buffer[rgb_offset+2] = rgba_buffer[rgba_offset+2];
-        }
-    }
-    
-    let mut output_image = File::create(filename).expect("Could not open file for write");
-    let header = format!("P6 {} {} 255\n", buffer_size.0, buffer_size.1);
-    output_image.write(header.as_bytes()).expect("failed to write to image file");
-    output_image.write(&rgb_buffer).expect("failed to write to image");
+    let mut output_image = std::io::BufWriter::new(File::create(filename).expect("Could not open file for write"));
+    write_texture_as_ppm(&mut output_image, rgba_buffer, buffer_size, 4, |rgba| [rgba[0], rgba[1], rgba[2]])
+        .and_then(|_| output_image.flush())
+        .expect("failed to write to image");
 
     let duration = timer.elapsed();
     let duration_in_secs = duration.as_secs() as f64 + duration.subsec_nanos() as f64 * 1e-9;
     println!("{} saved in {}s", filename, duration_in_secs);
 }
 
-#[allow(dead_code)]
-fn two_spheres() -> Box<dyn Hitable + Send + Sync + 'static> {
+fn two_spheres(aspect: f64, shutter: f64) -> (Box<ThreadsafeHitable>, Camera) {
     let red_material = Arc::new(Lambertian::new(Arc::new(ConstantTexture::new(Vec3::new(1.0, 0.0, 0.0))), 0.0));
     let blue_material = Arc::new(Lambertian::new(Arc::new(ConstantTexture::new(Vec3::new(0.0, 0.0, 1.0))), 0.0));
 
@@ -680,7 +1701,12 @@ fn two_spheres() -> Box<dyn Hitable + Send + Sync + 'static> {
         Arc::new(Sphere::new(Vec3::new(0.0,  10.0, 0.0), 10.0, blue_material)),
     ];
 
-    Box::new(HitableList::new(list))
+    let lookfrom = Vec3::new(0.0, -1.0, 3.0);
+    let lookat = Vec3::new(0.0, 0.0, -1.0);
+    let cam = Camera::new(lookfrom, lookat, Vec3::new(0.0, 1.0, 0.0),
+                        40.0, aspect, 0.0, 10.0, 0.0, shutter);
+
+    (Box::new(HitableList::new(list)), cam)
 }
 
 #[allow(dead_code)]
@@ -766,8 +1792,8 @@ fn random_scene(t_min: f64, t_max: f64) -> Box<dyn Hitable + Send + Sync + 'stat
 
 pub static EARTH_TEXTURE_BYTES: &[u8] = include_bytes!("../assets/textures/earthmap.jpg");
 
-fn two_perlin_spheres() -> Box<dyn Hitable + Send + Sync + 'static> {
-    let perlin_texture = Arc::new(texture::NoiseTexture::new(4.0));
+fn two_perlin_spheres(seed: Option<u64>) -> Box<dyn Hitable + Send + Sync + 'static> {
+    let perlin_texture = Arc::new(texture::NoiseTexture::new(4.0, seed));
     let mut list: Vec<Arc<dyn Hitable + Send + Sync + 'static>> = vec![];
     list.push(Arc::new(Sphere::new(Vec3::new(0.0, -1000.0, 0.0), 1000.0, Arc::new(Lambertian::new(perlin_texture.clone(), 0.0)))));
     list.push(Arc::new(Sphere::new(Vec3::new(0.0, 2.0, 0.0), 2.0, Arc::new(Lambertian::new(perlin_texture.clone(), 0.0)))));
@@ -781,8 +1807,8 @@ fn textured_sphere() -> Box<dyn Hitable + Send + Sync + 'static> {
     Box::new(BvhNode::from_list(list, 0.0, 1.0))
 }
 
-fn simple_light() -> Box<dyn Hitable + Send + Sync + 'static> {
-    let perlin_texture = Arc::new(texture::NoiseTexture::new(4.0));
+fn simple_light(seed: Option<u64>) -> Box<dyn Hitable + Send + Sync + 'static> {
+    let perlin_texture = Arc::new(texture::NoiseTexture::new(4.0, seed));
     let mut list: Vec<Arc<dyn Hitable + Send + Sync + 'static>> = vec![];
     list.push(Arc::new(Sphere::new(Vec3::new(0.0, -1000.0, 0.0), 1000.0, Arc::new(Lambertian::new(perlin_texture.clone(), 0.0)))));
     list.push(Arc::new(Sphere::new(Vec3::new(0.0, 2.0, 0.0), 2.0, Arc::new(Lambertian::new(perlin_texture.clone(), 0.0)))));
@@ -794,7 +1820,7 @@ fn simple_light() -> Box<dyn Hitable + Send + Sync + 'static> {
     Box::new(BvhNode::from_list(list, 0.0, 1.0))
 }
 
-fn cornell_box(aspect: f64) -> (Box<ThreadsafeHitable>, Camera) {
+fn cornell_box(aspect: f64, shutter: f64) -> (Box<ThreadsafeHitable>, Camera) {
 
     let mut material_builder = MaterialBuilder::new();
 
@@ -885,13 +1911,13 @@ fn cornell_box(aspect: f64) -> (Box<ThreadsafeHitable>, Camera) {
     let aperture = 0.0;
     let vfov = 40.0;
     let cam = Camera::new(lookfrom, lookat, Vec3::new(0.0, 1.0, 0.0),
-                        vfov, aspect, aperture, dist_to_focus, 0.0, 1.0);
+                        vfov, aspect, aperture, dist_to_focus, 0.0, shutter);
 
 
     (scene_builder.as_bvh(), cam)
 }
 
-fn cornell_smoke(aspect: f64) -> (Box<ThreadsafeHitable>, Camera) {
+fn cornell_smoke(aspect: f64, shutter: f64) -> (Box<ThreadsafeHitable>, Camera) {
 
     let mut material_builder = MaterialBuilder::new();
 
@@ -981,13 +2007,13 @@ fn cornell_smoke(aspect: f64) -> (Box<ThreadsafeHitable>, Camera) {
     let aperture = 0.0;
     let vfov = 40.0;
     let cam = Camera::new(lookfrom, lookat, Vec3::new(0.0, 1.0, 0.0),
-                      vfov, aspect, aperture, dist_to_focus, 0.0, 1.0);
+                      vfov, aspect, aperture, dist_to_focus, 0.0, shutter);
 
 
     (scene_builder.as_bvh(), cam)
 }
 
-fn final_book_two() -> Box<ThreadsafeHitable> {
+fn final_book_two(seed: Option<u64>) -> Box<ThreadsafeHitable> {
 
     let mut material_builder = MaterialBuilder::new();
     
@@ -1058,7 +2084,7 @@ fn final_book_two() -> Box<ThreadsafeHitable> {
         Arc::new(Sphere::new(Vec3::new(400.0, 200.0, 400.0), 100.0, earth_material))
     );
     scene_builder.add_hitable(
-        Arc::new(Sphere::new(Vec3::new(220.0, 280.0, 300.0), 80.0, Arc::new(Lambertian::new(Arc::new(NoiseTexture::new(0.1)), 0.0))))
+        Arc::new(Sphere::new(Vec3::new(220.0, 280.0, 300.0), 80.0, Arc::new(Lambertian::new(Arc::new(NoiseTexture::new(0.1, seed)), 0.0))))
     );
 
     let ns = 1000;
@@ -1074,4 +2100,355 @@ fn final_book_two() -> Box<ThreadsafeHitable> {
         .translate(Vec3::new(-100.0, 270.0, 395.0));
 
     scene_builder.as_bvh()
+}
+
+#[cfg(test)]
+mod tests {
+
+    use super::*;
+
+    fn assert_send_sync<T: Send + Sync>() {}
+
+    // Everything shared between the main thread and the job threads. Not included on purpose are ScatterResult
+    // and the PDFs (Arc<dyn PDF>), which are created and used within a single sample on one thread.
+    #[test]
+    fn shared_render_types_are_send_and_sync() {
+        assert_send_sync::<Config>();
+        assert_send_sync::<SceneState>();
+        assert_send_sync::<SceneOutput>();
+        assert_send_sync::<TraceSceneBatchJob>();
+        assert_send_sync::<jobs::MultiSliceReadWriteLock<Vec<f32>>>();
+        assert_send_sync::<Renderer>();
+        assert_send_sync::<Camera>();
+        assert_send_sync::<hitable::HitRecord>();
+        assert_send_sync::<BvhNode>();
+        assert_send_sync::<grid::UniformGrid>();
+        assert_send_sync::<environment::EnvironmentMap>();
+        assert_send_sync::<material::Lambertian>();
+        assert_send_sync::<material::Metal>();
+        assert_send_sync::<material::Dielectric>();
+        assert_send_sync::<material::ThinDielectric>();
+        assert_send_sync::<material::DiffuseLight>();
+        assert_send_sync::<material::Isotropic>();
+        assert_send_sync::<material::AlphaMasked>();
+        assert_send_sync::<texture::ImageTexture>();
+        assert_send_sync::<texture::NoiseTexture>();
+    }
+
+    #[test]
+    fn output_path_is_written_and_overwritten() {
+        let path = std::env::temp_dir().join("path_tracer_tests").join("out").join("output.png");
+        let _ = std::fs::remove_file(&path);
+
+        for value in [64_u8, 192_u8].iter() {
+            let buffer = vec![*value; 2 * 2 * 3];
+            save_rgb_texture(&path, &buffer, (2, 2)).unwrap();
+            let image = image::open(&path).unwrap().to_rgb();
+            assert_eq!(image.dimensions(), (2, 2));
+            assert!(image.pixels().all(|pixel| pixel.0 == [*value; 3]));
+        }
+    }
+
+    #[test]
+    fn streamed_ppm_matches_flipped_buffer() {
+        let image_size = (5, 3);
+        let buffer: Vec<u8> = (0..image_size.0 * image_size.1 * 3).map(|i| i as u8).collect();
+        let mut expected = b"P6 5 3 255\n".to_vec();
+        expected.extend_from_slice(&flip_rgb_texture_vertically(&buffer, image_size));
+
+        let mut streamed = vec![];
+        write_rgb_texture_as_ppm(&mut streamed, &buffer, image_size).unwrap();
+        assert_eq!(streamed, expected);
+
+        let path = std::env::temp_dir().join("path_tracer_tests").join("out").join("streamed.ppm");
+        save_rgb_texture(&path, &buffer, image_size).unwrap();
+        assert_eq!(std::fs::read(&path).unwrap(), expected);
+    }
+
+    #[test]
+    fn bgr_and_rgba_ppm_match_rgb_ppm() {
+        let image_size = (4, 3);
+        let rgb_buffer: Vec<u8> = (0..image_size.0 * image_size.1 * 3).map(|i| (i * 7) as u8).collect();
+        let bgr_buffer: Vec<u8> = rgb_buffer.chunks(3).flat_map(|rgb| vec![rgb[2], rgb[1], rgb[0]]).collect();
+        let rgba_buffer: Vec<u8> = rgb_buffer.chunks(3).flat_map(|rgb| vec![rgb[0], rgb[1], rgb[2], 255]).collect();
+
+        let directory = std::env::temp_dir().join("path_tracer_tests").join("out");
+        save_rgb_texture(&directory.join("rgb.ppm"), &rgb_buffer, image_size).unwrap();
+        save_bgr_texture_as_ppm(directory.join("bgr.ppm").to_str().unwrap(), &bgr_buffer, image_size);
+        save_rgba_texture_as_ppm(directory.join("rgba.ppm").to_str().unwrap(), &rgba_buffer, image_size);
+
+        let expected = std::fs::read(directory.join("rgb.ppm")).unwrap();
+        assert_eq!(std::fs::read(directory.join("bgr.ppm")).unwrap(), expected);
+        assert_eq!(std::fs::read(directory.join("rgba.ppm")).unwrap(), expected);
+    }
+
+    #[test]
+    fn exr_round_trips_linear_values() {
+        let path = std::env::temp_dir().join("path_tracer_tests").join("out").join("output.exr");
+        let image_size = (3, 2);
+        let buffer: Vec<f32> = (0..image_size.0 * image_size.1 * 4).map(|i| if i % 4 == 3 {(i / 4) as f32 / 6.0} else {i as f32 * 0.37}).collect();
+        save_image(&path, &buffer, image_size, &Config::new()).unwrap();
+
+        let image = exr::prelude::read_first_rgba_layer_from_file(&path,
+            |resolution, _| vec![(0.0_f32, 0.0_f32, 0.0_f32, 0.0_f32); resolution.width() * resolution.height()],
+            |pixels: &mut Vec<(f32, f32, f32, f32)>, position, pixel: (f32, f32, f32, f32)| pixels[position.y() * 3 + position.x()] = pixel).unwrap();
+        let pixels = &image.layer_data.channel_data.pixels;
+        for y in 0..image_size.1 as usize {
+            for x in 0..image_size.0 as usize {
+                let index = ((image_size.1 as usize - 1 - y) * image_size.0 as usize + x) * 4;
+                let (r, g, b, a) = pixels[y * 3 + x];
+                assert!((r - buffer[index]).abs() < 1e-5 && (g - buffer[index + 1]).abs() < 1e-5 && (b - buffer[index + 2]).abs() < 1e-5);
+                assert!((a - buffer[index + 3]).abs() < 1e-5);
+            }
+        }
+    }
+
+    #[test]
+    fn scene_file_settings_are_overridden_by_flags() {
+        let directory = std::env::temp_dir().join("path_tracer_tests");
+        std::fs::create_dir_all(&directory).unwrap();
+        let scene_path = directory.join("settings.scene");
+        std::fs::write(&scene_path, "spp = 8\nmax_depth = 20\n").unwrap();
+        let scene_arg = format!("-scene={}", scene_path.display());
+
+        let args = vec![String::from("path_tracer"), scene_arg.clone()];
+        let config = Config::from_cmdline(&args);
+        assert_eq!(config.spp, 8);
+        assert_eq!(config.max_depth, 20);
+
+        // flags win regardless of their position relative to -scene=
+        let args = vec![String::from("path_tracer"), String::from("-spp=2"), scene_arg];
+        let config = Config::from_cmdline(&args);
+        assert_eq!(config.spp, 2);
+        assert_eq!(config.max_depth, 20);
+    }
+
+    #[test]
+    fn aov_images_are_named_after_the_beauty_image() {
+        let image_path = std::path::Path::new("renders/cornell.png");
+        assert_eq!(aov_image_path(image_path, RenderMode::Albedo), std::path::Path::new("renders/cornell_albedo.png"));
+        assert_eq!(aov_image_path(image_path, RenderMode::Normal), std::path::Path::new("renders/cornell_normal.png"));
+    }
+
+    #[test]
+    fn window_clears_to_black_by_default() {
+        let mut config = Config::new();
+        assert_eq!(config.clear_colour, [0.0, 0.0, 0.0, 1.0]);
+        config.clear_colour = parse_clear_colour("0.5, 0.25,1").unwrap();
+        assert_eq!(config.clear_colour, [0.5, 0.25, 1.0, 1.0]);
+        assert!(parse_clear_colour("0.5,0.25").is_err());
+    }
+
+    #[cfg(feature = "rendy")]
+    #[test]
+    fn graph_images_use_configured_clear_value() {
+        let clear_colour = unsafe { clear_value(&Config::new()).color.float32 };
+        assert_eq!(clear_colour, [0.0, 0.0, 0.0, 1.0]);
+    }
+
+    #[test]
+    fn vignette_darkens_corners_more_than_centre() {
+        let image_size = (9, 9);
+        let buffer = vec![0.5_f32; (image_size.0 * image_size.1 * 4) as usize];
+        let mut config = Config::new();
+        let centre = ((4 * image_size.0 + 4) * 3) as usize;
+        let corner = 0;
+
+        let plain = convert_to_rgb_u8_and_gamma_correct(&buffer, image_size, &config);
+        assert!(plain.iter().all(|value| *value == plain[0]));
+
+        config.vignette = 0.8;
+        let vignetted = convert_to_rgb_u8_and_gamma_correct(&buffer, image_size, &config);
+        assert_eq!(vignetted[centre], plain[centre]);
+        assert!(vignetted[corner] < vignetted[centre - 3]);
+        assert!(vignetted[centre - 3] <= vignetted[centre]);
+    }
+
+    #[test]
+    fn offline_window_reviews_once_done() {
+        let mut state = OfflineState::Tracing;
+        state = state.next(false, true);
+        assert_eq!(state, OfflineState::Tracing);
+        state = state.next(true, true);
+        assert_eq!(state, OfflineState::Done);
+        assert_eq!(state.next(true, false), OfflineState::Done);
+        state = state.next(true, true);
+        assert_eq!(state, OfflineState::Reviewing);
+        assert_eq!(state.next(true, true), OfflineState::Reviewing);
+
+        // reviewing changes what the finished image is saved with
+        let image_size = (2, 2);
+        let buffer = vec![0.5_f32; (image_size.0 * image_size.1 * 4) as usize];
+        let mut config = Config::new();
+        let traced = convert_to_rgb_u8_and_gamma_correct(&buffer, image_size, &config);
+        ReviewAction::Save.apply(&mut config);
+        assert_eq!(convert_to_rgb_u8_and_gamma_correct(&buffer, image_size, &config), traced);
+        ReviewAction::IncreaseExposure.apply(&mut config);
+        let brighter = convert_to_rgb_u8_and_gamma_correct(&buffer, image_size, &config);
+        assert!(brighter[0] > traced[0]);
+        ReviewAction::DecreaseExposure.apply(&mut config);
+        ReviewAction::ToggleTonemap.apply(&mut config);
+        let untonemapped = convert_to_rgb_u8_and_gamma_correct(&buffer, image_size, &config);
+        assert!(untonemapped[0] > traced[0]);
+    }
+
+    #[test]
+    fn frame_sleep_tops_frames_up_to_the_cap() {
+        assert_eq!(frame_sleep_time(60, Duration::from_millis(10)), Some(Duration::from_micros(6_666)));
+        assert_eq!(frame_sleep_time(144, Duration::from_millis(2)), Some(Duration::from_micros(4_944)));
+        // slow frames and an uncapped loop don't sleep
+        assert_eq!(frame_sleep_time(60, Duration::from_millis(20)), None);
+        assert_eq!(frame_sleep_time(0, Duration::from_millis(1)), None);
+        assert_eq!(Config::new().fps_cap, 60);
+    }
+
+    #[test]
+    fn resized_window_recomputes_tiles_for_the_new_size() {
+        // minimised windows have nothing to trace
+        assert_eq!(resized_image_size((0.0, 300.0)), None);
+        assert_eq!(resized_image_size((4.0, 300.4)), Some((4, 300)));
+
+        let mut config = Config::new();
+        config.realtime = false;
+        config.spp = 1;
+        config.max_depth = 2;
+        let (world, cam) = cornell_box(1.0, config.shutter);
+        let scene_state = Arc::new(RwLock::new(SceneState::new(cam, world, 0.0, 1.0, 0.0, false, config.clone())));
+        let renderer = Renderer::new(2);
+        for window_size in [(45.0, 27.0), (64.0, 36.0), (4.0, 23.0)].iter() {
+            let image_size = resized_image_size(*window_size).unwrap();
+            // the tiles reach the new edges without a whole tile hanging off them
+            let (task_dim_xy, num_tasks_xy) = tile_layout(image_size);
+            assert!(task_dim_xy.0 * num_tasks_xy.0 >= image_size.0 && task_dim_xy.0 * (num_tasks_xy.0 - 1) < image_size.0);
+            assert!(task_dim_xy.1 * num_tasks_xy.1 >= image_size.1 && task_dim_xy.1 * (num_tasks_xy.1 - 1) < image_size.1);
+
+            let (scene_output, (batches, jobs)) = create_scene_output_and_jobs(image_size, config.spp, &config, &scene_state);
+            let num_tasks = (num_tasks_xy.0 * num_tasks_xy.1) as usize;
+            assert_eq!(scene_output.buffer.read().len(), (image_size.0 * image_size.1 * 4) as usize);
+            assert_eq!((batches.len(), jobs.len()), (num_tasks, num_tasks));
+            renderer.wait(&renderer.dispatch(&jobs));
+            assert_eq!(scene_output.remaining_tasks.load(Ordering::Acquire), 0);
+        }
+    }
+
+    #[test]
+    fn deadline_stops_render_after_a_whole_pass() {
+        let image_size = (16, 16);
+        let mut config = Config::new();
+        config.realtime = false;
+        config.max_depth = 1;
+        // far too many passes to finish, the budget runs out during the first
+        config.spp = 1_000_000;
+        config.spp_per_pass = 1;
+        config.max_seconds = Some(0.0);
+
+        // the camera sits inside a glowing sphere so every pixel of a whole pass is lit
+        let world: Box<ThreadsafeHitable> = Box::new(Sphere::new(Vec3::new_zero_vector(), 10.0,
+            Arc::new(Lambertian::new(Arc::new(ConstantTexture::new(Vec3::from_float(0.5))), 1.0))));
+        let cam = Camera::new(Vec3::new_zero_vector(), Vec3::new(0.0, 0.0, -1.0), Vec3::new(0.0, 1.0, 0.0), 40.0, 1.0, 0.0, 1.0, 0.0, 1.0);
+        let image = render_to_buffer(&Renderer::new(2), world, cam, &config, image_size);
+        assert!(image.chunks(4).all(|pixel| pixel[0] > 0.0 && pixel[3] == 1.0));
+    }
+
+    #[test]
+    fn completed_tiles_cover_the_image_once() {
+        let image_size = (45, 27);
+        let mut config = Config::new();
+        config.realtime = false;
+        config.spp = 1;
+        config.max_depth = 2;
+        let (world, cam) = cornell_box((image_size.0 as f64)/(image_size.1 as f64), config.shutter);
+        let scene_state = Arc::new(RwLock::new(SceneState::new(cam, world, 0.0, 1.0, 0.0, false, config.clone())));
+        let rgba_texture = MultiSliceReadWriteLock::new(vec![0.0_f32; (image_size.0 * image_size.1 * 4) as usize]);
+        let (_, num_tasks_xy) = tile_layout(image_size);
+        let num_tasks = (num_tasks_xy.0 * num_tasks_xy.1) as usize;
+        let scene_output = Arc::new(SceneOutput::new(rgba_texture, AtomicUsize::new(num_tasks), AtomicBool::new(false)).with_completed_tiles());
+        let (_, jobs) = create_tile_jobs(image_size, config.spp, &config, &scene_state, &scene_output);
+        let renderer = Renderer::new(2);
+        renderer.wait(&renderer.dispatch(&jobs));
+
+        let tiles = scene_output.take_completed_tiles();
+        assert_eq!(tiles.len(), num_tasks);
+        let mut coverage = vec![0; (image_size.0 * image_size.1) as usize];
+        for ((tile_x, tile_y), (tile_width, tile_height)) in tiles {
+            for y in tile_y..tile_y + tile_height {
+                for x in tile_x..tile_x + tile_width {
+                    coverage[(y * image_size.0 + x) as usize] += 1;
+                }
+            }
+        }
+        assert!(coverage.iter().all(|count| *count == 1));
+        // taking the tiles clears them for the next frame
+        assert!(scene_output.take_completed_tiles().is_empty());
+    }
+
+    #[test]
+    fn last_pass_only_traces_the_remaining_samples() {
+        let mut config = Config::new();
+        config.realtime = false;
+        config.spp = 10;
+        config.spp_per_pass = 4;
+        let passes = |config: &Config| (0..samples_per_pass(config).2).map(|pass| samples_for_pass(config, pass)).collect::<Vec<u32>>();
+        assert_eq!(passes(&config), vec![4, 4, 2]);
+
+        // the 1spp edge detection pass comes out of spp too
+        config.aa_edges = true;
+        assert_eq!(passes(&config), vec![1, 4, 4, 1]);
+
+        config.spp = 8;
+        config.aa_edges = false;
+        assert_eq!(passes(&config), vec![4, 4]);
+    }
+
+    #[test]
+    fn single_threaded_cornell_render_is_deterministic() {
+        let image_size = (16, 16);
+        let mut config = Config::new();
+        config.realtime = false;
+        config.spp = 4;
+        config.max_depth = 4;
+        // russian roulette off so the render matches the recorded value
+        config.termination = Termination::FixedDepth;
+
+        let render = || {
+            let (world, cam) = cornell_box(1.0, config.shutter);
+            render_single_threaded(world, cam, &config, image_size)
+        };
+        let image = render();
+        assert_eq!(image, render());
+
+        let pixel_index = ((image_size.1 / 2) * image_size.0 + image_size.0 / 2) as usize * 4;
+        // centre of the back wall, value recorded from a known good render
+        for channel in 0..3 {
+            assert!((image[pixel_index + channel] - 0.30407336).abs() < 1e-5, "pixel = {:?}", &image[pixel_index..pixel_index + 3]);
+        }
+    }
+
+    #[test]
+    fn orbit_sequence_moves_camera_between_frames() {
+        let output_dir = std::env::temp_dir().join("path_tracer_tests").join("sequence");
+        let mut config = Config::new();
+        config.spp = 1;
+        config.max_depth = 2;
+        config.num_threads = Some(2);
+        config.num_frames = 2;
+        config.orbit = true;
+
+        let camera_origins = render_sequence(&config, (18, 18), &output_dir).unwrap();
+        assert_eq!(camera_origins.len(), 2);
+        assert!((&camera_origins[0] - &camera_origins[1]).length() > 1.0);
+        for frame in 0..2 {
+            assert!(output_dir.join(format!("frame_{:04}.png", frame)).exists());
+        }
+    }
+
+    #[test]
+    fn sequences_are_written_next_to_the_output_path() {
+        let mut config = Config::new();
+        assert_eq!(sequence_output_dir(&config), std::path::PathBuf::from("."));
+        config.output_path = Some(String::from("render.png"));
+        assert_eq!(sequence_output_dir(&config), std::path::PathBuf::from("."));
+        config.output_path = Some(String::from("renders/orbit/render.png"));
+        assert_eq!(sequence_output_dir(&config), std::path::PathBuf::from("renders/orbit"));
+    }
 }
\ No newline at end of file
@@ -3,50 +3,10 @@ use math::*;
 use hitable::HitRecord;
 use texture::{Texture, ConstantTexture, ThreadsafeTexture};
 use std::sync::Arc;
-use std::f64::consts::{FRAC_1_PI, PI};
+use std::f64::consts::FRAC_1_PI;
 use crate::onb::ONB;
 use hitable::ThreadsafeHitable;
 
-fn random_cosine_direction() -> Vec3 {
-    let r1 = random::rand();
-    let r2 = random::rand();
-    let z = (1.0 - r2).sqrt();
-    let phi = 2.0 * PI * r1;
-    let r2_sqrt = r2.sqrt();
-    let x = phi.cos() * r2_sqrt;
-    let y = phi.sin() * r2_sqrt;
-    Vec3::new(x, y, z)
-}
-
-fn random_in_unit_sphere() -> Vec3 {
-    let mut p: Vec3;
-    loop  {
-        p = 2.0 * Vec3::new(random::rand(), random::rand(), random::rand()) - Vec3::new(1.0, 1.0, 1.0);
-        if p.squared_length() < 1.0 {
-            break
-        }
-    }
-
-    p
-}
-
-fn random_on_unit_sphere() -> Vec3 {
-    let mut p: Vec3;
-    loop  {
-        p = 2.0 * Vec3::new(random::rand(), random::rand(), random::rand()) - Vec3::new(1.0, 1.0, 1.0);
-        if p.squared_length() < 1.0 {
-            break
-        }
-    }
-
-    p.normalise();
-    p
-}
-
-fn unit_sphere_pdf() -> f64{
-    1.0 / (4.0 * std::f64::consts::PI)
-}
-
 fn reflect(v: &Vec3, n: &Vec3) -> Vec3 {
     v - &(2.0*vec3::dot(v, n)*n)
 }
@@ -69,12 +29,88 @@ fn schlick(cosine: f64, ref_idx: f64) -> f64 {
     r0 + (1.0-r0)*(1.0-cosine).powf(5.0)
 }
 
+// Deepest nesting of dielectrics a path tracks, boundaries of media nested any deeper are ignored
+const MAX_NESTED_MEDIA: usize = 4;
+
+// Refraction indices of the dielectrics a path is inside, innermost last, so nested dielectrics like ice in water
+// refract by the index of the medium on each side of a boundary rather than assuming the other side is air.
+// Fixed size so color() can copy it down the recursion without allocating.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct MediumStack {
+    refraction_indices: [f64; MAX_NESTED_MEDIA],
+    len: usize,
+}
+
+impl MediumStack {
+    // outside every dielectric, in air
+    pub fn new() -> Self {
+        Self {
+            refraction_indices: [1.0; MAX_NESTED_MEDIA],
+            len: 0,
+        }
+    }
+
+    // index of the medium the ray is travelling through
+    pub fn current(&self) -> f64 {
+        if self.len > 0 {self.refraction_indices[self.len - 1]} else {1.0}
+    }
+
+    // index of the medium a ray leaving a dielectric of refraction_index moves into
+    pub fn outside(&self, refraction_index: f64) -> f64 {
+        self.exited(refraction_index).current()
+    }
+
+    pub fn entered(&self, refraction_index: f64) -> Self {
+        let mut stack = *self;
+        if stack.len < MAX_NESTED_MEDIA {
+            stack.refraction_indices[stack.len] = refraction_index;
+            stack.len += 1;
+        }
+        stack
+    }
+
+    // removes the innermost medium of refraction_index, which need not be the innermost medium when boundaries overlap
+    pub fn exited(&self, refraction_index: f64) -> Self {
+        let mut stack = *self;
+        if let Some(idx) = stack.refraction_indices[..stack.len].iter().rposition(|&ri| ri == refraction_index) {
+            stack.refraction_indices.copy_within(idx + 1..stack.len, idx);
+            stack.len -= 1;
+            // unused slots stay at 1.0 so equal stacks compare equal
+            stack.refraction_indices[stack.len] = 1.0;
+        }
+        stack
+    }
+
+    // The stack after a ray hit the boundary of a dielectric with refraction_index, unchanged if it was reflected
+    pub fn after_scatter(&self, refraction_index: f64, r_in: &Ray, scattered: &Ray, normal: &Vec3) -> Self {
+        let incoming = vec3::dot(&r_in.direction, normal);
+        let outgoing = vec3::dot(&scattered.direction, normal);
+        if incoming < 0.0 && outgoing < 0.0 {
+            self.entered(refraction_index)
+        } else if incoming > 0.0 && outgoing > 0.0 {
+            self.exited(refraction_index)
+        } else {
+            *self
+        }
+    }
+}
+
+// How color() should choose the next direction after a hit
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum SamplingHint {
+    LightMis, // mix light sampling with the material's pdf
+    Bsdf, // sample the material's pdf only, ignoring lights
+    Specular, // follow the material's specular ray
+}
+
 pub struct MaterialBuilder {
     texture: Arc<dyn Texture + Send + Sync + 'static>,
     albedo: Vec3,
     emissive: f64,
     fuzz: f64,
-    refraction_index: f64
+    refraction_index: f64,
+    roughness: f64,
+    sampling_hint: SamplingHint,
 }
 
 impl MaterialBuilder {
@@ -85,6 +121,8 @@ impl MaterialBuilder {
             albedo: Vec3::from_float(0.0),
             fuzz: 0.0,
             refraction_index: 1.0,
+            roughness: 0.0,
+            sampling_hint: SamplingHint::LightMis,
         }
     }
 
@@ -113,8 +151,20 @@ impl MaterialBuilder {
         self
     }
 
+    // how frosted dielectrics are, 0 is smooth glass
+    pub fn set_roughness<'a>(&'a mut self, roughness: f64) -> &'a mut MaterialBuilder {
+        self.roughness = roughness;
+        self
+    }
+
+    // only used by materials that support more than one way of sampling, i.e. lambertian
+    pub fn set_sampling_hint<'a>(&'a mut self, sampling_hint: SamplingHint) -> &'a mut MaterialBuilder {
+        self.sampling_hint = sampling_hint;
+        self
+    }
+
     pub fn lambertian(&self) -> Arc<dyn Material + Send + Sync + 'static> {
-        Arc::new(Lambertian::new(self.texture.clone(), self.emissive))
+        Arc::new(Lambertian::new(self.texture.clone(), self.emissive).with_sampling_hint(self.sampling_hint))
     }
 
     pub fn diffuse_light(&self) -> Arc<dyn Material + Send + Sync + 'static> {
@@ -126,7 +176,11 @@ impl MaterialBuilder {
     }
 
     pub fn dielectric(&self) -> Arc<dyn Material + Send + Sync + 'static> {
-        Arc::new(Dielectric::new(self.refraction_index))
+        Arc::new(Dielectric::new(self.refraction_index).with_roughness(self.roughness))
+    }
+
+    pub fn thin_dielectric(&self) -> Arc<dyn Material + Send + Sync + 'static> {
+        Arc::new(ThinDielectric::new(self.refraction_index))
     }
 }  
 
@@ -139,12 +193,35 @@ pub struct ScatterResult {
 
 pub trait Material {
     fn scatter(&self, r_in: &Ray, rec: &HitRecord) -> Option<ScatterResult>;
+    // As scatter but knowing the dielectrics the ray is inside, only materials that refract need them
+    fn scatter_in_medium(&self, r_in: &Ray, rec: &HitRecord, _medium: &MediumStack) -> Option<ScatterResult> {
+        self.scatter(r_in, rec)
+    }
+    // Index of the medium the surface bounds, rays transmitted through it enter or leave that medium
+    fn refraction_index(&self) -> Option<f64> {
+        None
+    }
     fn scattering_pdf(&self, _r_in: &Ray, _rec: &HitRecord, _scattered: &Ray) -> f64 {
         0.0
     }
     fn emitted(&self, _ray: &Ray, _rec: &HitRecord, _u: f64, _v: f64, _point: &Vec3) -> Vec3 {
         Vec3::from_float(0.0)
     }
+    // radiance leaving the front of the surface at uv whichever way it is seen from, lights use it to importance
+    // sample their emission when it varies across the surface
+    fn emitted_radiance(&self, _u: f64, _v: f64, _point: &Vec3) -> Vec3 {
+        Vec3::from_float(0.0)
+    }
+    fn is_emissive(&self) -> bool {
+        false
+    }
+    fn sampling_hint(&self) -> SamplingHint {
+        SamplingHint::LightMis
+    }
+    // Cut away at uv so rays pass straight through, primitives check it before reporting a hit
+    fn is_cut_out(&self, _u: f64, _v: f64, _point: &Vec3) -> bool {
+        false
+    }
 }
 
 pub type ThreadsafeMaterial = dyn Material + Send + Sync;
@@ -162,19 +239,42 @@ impl Material for DummyMaterial {
 }
 
 pub struct Dielectric {
-    ref_idx: f64
+    ref_idx: f64,
+    roughness: f64,
 }
 
 impl Dielectric {
     pub fn new(ri: f64) -> Dielectric {
         Dielectric {
-            ref_idx: ri
+            ref_idx: ri,
+            roughness: 0.0,
+        }
+    }
+
+    // Frosted glass, both the reflected and refracted rays are perturbed like Metal's fuzz. Clamped to 1
+    pub fn with_roughness(mut self, roughness: f64) -> Dielectric {
+        self.roughness = roughness.min(1.0);
+        self
+    }
+
+    // None when the perturbed direction crosses to the other side of the surface from side, the ray is absorbed like
+    // Metal's fuzzed reflections would be rather than reflecting through the surface or refracting back out of it
+    fn roughen(&self, direction: Vec3, side: &Vec3) -> Option<Vec3> {
+        if self.roughness > 0.0 {
+            let roughened = Vec3::new_unit_vector(&direction) + self.roughness*random_in_unit_sphere();
+            if vec3::dot(&roughened, side) > 0.0 { Some(roughened) } else { None }
+        } else {
+            Some(direction)
         }
     }
 }
 
 impl Material for Dielectric {
     fn scatter(&self, r_in: &Ray, rec: &HitRecord) -> Option<ScatterResult> {
+        self.scatter_in_medium(r_in, rec, &MediumStack::new())
+    }
+
+    fn scatter_in_medium(&self, r_in: &Ray, rec: &HitRecord, medium: &MediumStack) -> Option<ScatterResult> {
         let outward_normal: Vec3;
         let reflected = reflect(&r_in.direction(), &rec.normal);
         let ni_over_nt: f64;
@@ -182,19 +282,23 @@ impl Material for Dielectric {
         let mut refracted = Vec3::new_zero_vector();
         let reflect_prob: f64;
         let cosine: f64;
+        // this dielectric's index relative to the medium on the other side of the boundary
+        let relative_idx: f64;
 
         if vec3::dot(&r_in.direction(), &rec.normal) > 0.0 {
             outward_normal = -(rec.normal.clone());
-            ni_over_nt = self.ref_idx;
-            cosine = self.ref_idx * vec3::dot(&r_in.direction, &rec.normal) / r_in.direction().length();
+            relative_idx = self.ref_idx / medium.outside(self.ref_idx);
+            ni_over_nt = relative_idx;
+            cosine = relative_idx * vec3::dot(&r_in.direction, &rec.normal) / r_in.direction().length();
         } else {
             outward_normal = rec.normal.clone();
-            ni_over_nt = 1.0 / self.ref_idx;
+            relative_idx = self.ref_idx / medium.current();
+            ni_over_nt = 1.0 / relative_idx;
             cosine = -vec3::dot(&r_in.direction, &rec.normal) / r_in.direction().length();
         }
 
         if refract(&r_in.direction(), &outward_normal, ni_over_nt, &mut refracted) {
-            reflect_prob = schlick(cosine, self.ref_idx);
+            reflect_prob = schlick(cosine, relative_idx);
         } else {
              //  scattered = ray(rec.p, reflected);
              reflect_prob = 1.0;
@@ -202,14 +306,122 @@ impl Material for Dielectric {
 
         let specular_ray;
         let is_specular = true;
+        // reflections stay on the side of outward_normal the ray arrived from, refractions carry on through
         if random::rand() < reflect_prob {
-            specular_ray = Ray::new(rec.p.clone(), reflected, r_in.time());
+            specular_ray = Ray::new(rec.p.clone(), self.roughen(reflected, &outward_normal)?, r_in.time());
         } else {
-            specular_ray = Ray::new(rec.p.clone(), refracted, r_in.time());
+            specular_ray = Ray::new(rec.p.clone(), self.roughen(refracted, &-outward_normal)?, r_in.time());
         }
 
         Some(ScatterResult{is_specular, specular_ray, albedo, pdf: Arc::new(DummyPDF{})})
     }
+
+    fn refraction_index(&self) -> Option<f64> {
+        Some(self.ref_idx)
+    }
+
+    fn sampling_hint(&self) -> SamplingHint {
+        SamplingHint::Specular
+    }
+}
+
+// Glass thin enough that light leaves it where it entered, like a window pane, so a single surface stands in for
+// both faces. Rays are reflected or carried straight through with air on both sides and never enter a medium.
+pub struct ThinDielectric {
+    ref_idx: f64
+}
+
+impl ThinDielectric {
+    pub fn new(ri: f64) -> ThinDielectric {
+        ThinDielectric {
+            ref_idx: ri
+        }
+    }
+
+    // Fraction of light reflected, light passing the first face bounces between the two faces so the pane
+    // reflects R + T^2 R / (1 - R^2) = 2R / (1 + R) of it in total
+    pub fn reflectance(&self, cosine: f64) -> f64 {
+        let single_face = schlick(cosine, self.ref_idx);
+        2.0 * single_face / (1.0 + single_face)
+    }
+}
+
+impl Material for ThinDielectric {
+    fn scatter(&self, r_in: &Ray, rec: &HitRecord) -> Option<ScatterResult> {
+        let cosine = vec3::dot(&r_in.direction, &rec.normal).abs() / r_in.direction().length();
+        let direction = if random::rand() < self.reflectance(cosine) {
+            reflect(&r_in.direction, &rec.normal)
+        } else {
+            r_in.direction
+        };
+        let specular_ray = Ray::new(rec.p, direction, r_in.time());
+        Some(ScatterResult{is_specular: true, specular_ray, albedo: Vec3::new(1.0, 1.0, 1.0), pdf: Arc::new(DummyPDF{})})
+    }
+
+    fn sampling_hint(&self) -> SamplingHint {
+        SamplingHint::Specular
+    }
+}
+
+// Cutout for foliage and fences, the material is cut away wherever the alpha texture's first channel is below
+// the threshold and otherwise behaves exactly like the wrapped material
+pub struct AlphaMasked {
+    material: Arc<ThreadsafeMaterial>,
+    alpha: Arc<ThreadsafeTexture>,
+    threshold: f64,
+}
+
+impl AlphaMasked {
+    pub fn new(material: Arc<ThreadsafeMaterial>, alpha: Arc<ThreadsafeTexture>) -> AlphaMasked {
+        AlphaMasked {
+            material,
+            alpha,
+            threshold: 0.5,
+        }
+    }
+
+    pub fn with_threshold(mut self, threshold: f64) -> AlphaMasked {
+        self.threshold = threshold;
+        self
+    }
+}
+
+impl Material for AlphaMasked {
+    fn scatter(&self, r_in: &Ray, rec: &HitRecord) -> Option<ScatterResult> {
+        self.material.scatter(r_in, rec)
+    }
+
+    fn scatter_in_medium(&self, r_in: &Ray, rec: &HitRecord, medium: &MediumStack) -> Option<ScatterResult> {
+        self.material.scatter_in_medium(r_in, rec, medium)
+    }
+
+    fn refraction_index(&self) -> Option<f64> {
+        self.material.refraction_index()
+    }
+
+    fn scattering_pdf(&self, r_in: &Ray, rec: &HitRecord, scattered: &Ray) -> f64 {
+        self.material.scattering_pdf(r_in, rec, scattered)
+    }
+
+    fn emitted(&self, ray: &Ray, rec: &HitRecord, u: f64, v: f64, point: &Vec3) -> Vec3 {
+        self.material.emitted(ray, rec, u, v, point)
+    }
+
+    fn emitted_radiance(&self, u: f64, v: f64, point: &Vec3) -> Vec3 {
+        self.material.emitted_radiance(u, v, point)
+    }
+
+    fn is_emissive(&self) -> bool {
+        self.material.is_emissive()
+    }
+
+    fn sampling_hint(&self) -> SamplingHint {
+        self.material.sampling_hint()
+    }
+
+    fn is_cut_out(&self, u: f64, v: f64, point: &Vec3) -> bool {
+        self.alpha.value(u, v, point).x < self.threshold || self.material.is_cut_out(u, v, point)
+    }
 }
 
 pub struct Metal {
@@ -248,20 +460,31 @@ impl Material for Metal{
       //      None
       //  }
     }
+    fn sampling_hint(&self) -> SamplingHint {
+        SamplingHint::Specular
+    }
+
 }
 
 pub struct Lambertian {
     albedo: Arc<dyn Texture + Send + Sync + 'static>,
-    emissive: f64
+    emissive: f64,
+    sampling_hint: SamplingHint,
 }
 
 impl Lambertian {
     pub fn new(albedo: Arc<dyn Texture + Send + Sync + 'static>, emissive: f64) -> Lambertian {
         Lambertian {
             albedo,
-            emissive
+            emissive,
+            sampling_hint: SamplingHint::LightMis,
         }
     }
+
+    pub fn with_sampling_hint(mut self, sampling_hint: SamplingHint) -> Lambertian {
+        self.sampling_hint = sampling_hint;
+        self
+    }
 }
 
 impl Material for Lambertian {
@@ -301,8 +524,20 @@ impl Material for Lambertian {
     }
 
     fn emitted(&self, _ray: &Ray, _rec: &HitRecord, u: f64, v: f64, point: &Vec3) -> Vec3 {
+        self.emitted_radiance(u, v, point)
+    }
+
+    fn emitted_radiance(&self, u: f64, v: f64, point: &Vec3) -> Vec3 {
         if self.emissive > 0.0 {self.albedo.value(u, v, point) * self.emissive} else {Vec3::from_float(0.0)}
     }
+
+    fn is_emissive(&self) -> bool {
+        self.emissive > 0.0
+    }
+
+    fn sampling_hint(&self) -> SamplingHint {
+        self.sampling_hint
+    }
 }
 
 pub struct DiffuseLight {
@@ -329,6 +564,14 @@ impl Material for DiffuseLight {
             Vec3::new_zero_vector()
         }
     }
+
+    fn emitted_radiance(&self, u: f64, v: f64, point: &Vec3) -> Vec3 {
+        self.texture.value(u, v, point)
+    }
+
+    fn is_emissive(&self) -> bool {
+        true
+    }
 }
 
 pub struct Isotropic {
@@ -345,7 +588,7 @@ impl Isotropic {
 
 impl Material for Isotropic {
     fn scatter(&self, r_in: &Ray, rec: &HitRecord) -> Option<ScatterResult>{
-        let specular_ray = Ray::new(rec.p, random_in_unit_sphere(), r_in.time);
+        let specular_ray = Ray::new(rec.p, random_unit_vector(), r_in.time);
         let albedo = self.albedo.value(rec.u, rec.v, &rec.p);
         Some(ScatterResult{is_specular: false, specular_ray, albedo, pdf: Arc::new(DummyPDF{})})
     } 
@@ -409,23 +652,30 @@ impl PDF for HittablePDF {
 }
 
 pub struct MixturePDF {
-    pdfs: [Arc<dyn PDF>; 2]
+    pdfs: [Arc<dyn PDF>; 2],
+    weight: f64, // chance of generating from pdf0
 }
 
 impl MixturePDF {
     pub fn new(pdf0: Arc<dyn PDF>, pdf1: Arc<dyn PDF> ) -> Self {
         Self {
-            pdfs: [pdf0, pdf1]
+            pdfs: [pdf0, pdf1],
+            weight: 0.5,
         }
     }
+
+    pub fn with_weight(mut self, weight: f64) -> Self {
+        self.weight = weight;
+        self
+    }
 }
 
 impl PDF for MixturePDF {
     fn value(&self,direction: &Vec3) -> f64 {
-        0.5 * self.pdfs[0].value(direction) + 0.5*self.pdfs[1].value(direction)
+        self.weight * self.pdfs[0].value(direction) + (1.0 - self.weight)*self.pdfs[1].value(direction)
     }
     fn generate(&self) -> Vec3 {
-        if random::rand() < 0.5 {
+        if random::rand() < self.weight {
             self.pdfs[0].generate()
         } else {
             self.pdfs[1].generate()
@@ -443,4 +693,133 @@ impl PDF for DummyPDF {
     fn generate(&self) -> Vec3 {
         Vec3::new_zero_vector()
     }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use rect::{AxisAlignedRect, AxisAlignedRectAxis};
+    use hitable::Hitable;
+
+    #[test]
+    fn thin_dielectric_reflects_a_fraction_and_transmits_the_rest_unbent() {
+        let material = MaterialBuilder::new().set_refraction_index(1.5).thin_dielectric();
+        let pane = AxisAlignedRect::new(-1.0, 1.0, -1.0, 1.0, 0.0, AxisAlignedRectAxis::Z, material);
+        let direction = Vec3::new(0.0, -1.0, -1.0);
+        let ray = Ray::new(Vec3::new(0.0, 0.5, 0.5), direction, 0.0);
+        let hit_record = pane.hit(&ray, 0.001, std::f64::MAX).unwrap();
+
+        random::seed_thread_rng(1414);
+        let num_samples = 10000;
+        let mut num_reflected = 0;
+        for _ in 0..num_samples {
+            let scattered = hit_record.mat.scatter(&ray, &hit_record).unwrap().specular_ray.direction;
+            if scattered.z > 0.0 {
+                num_reflected += 1;
+                assert_eq!(scattered, Vec3::new(0.0, -1.0, 1.0));
+            } else {
+                assert_eq!(scattered, direction);
+            }
+        }
+        random::clear_thread_rng_seed();
+
+        let expected = ThinDielectric::new(1.5).reflectance(std::f64::consts::FRAC_1_SQRT_2);
+        let reflected = num_reflected as f64 / num_samples as f64;
+        assert!(expected > 0.05 && expected < 0.2, "expected reflectance = {}", expected);
+        assert!((reflected - expected).abs() < 0.02, "reflected {} of rays, expected {}", reflected, expected);
+        assert!(hit_record.mat.refraction_index().is_none());
+    }
+
+    // always generates the same direction, which has the given pdf
+    struct FixedPDF {
+        direction: Vec3,
+        pdf: f64,
+    }
+
+    impl PDF for FixedPDF {
+        fn value(&self, direction: &Vec3) -> f64 {
+            if *direction == self.direction {self.pdf} else {0.0}
+        }
+        fn generate(&self) -> Vec3 {
+            self.direction
+        }
+    }
+
+    #[test]
+    fn mixture_weight_shifts_samples_towards_the_first_pdf() {
+        let up = Vec3::new(0.0, 1.0, 0.0);
+        let down = Vec3::new(0.0, -1.0, 0.0);
+        random::seed_thread_rng(1419);
+        for weight in [0.5, 0.8, 0.1].iter() {
+            let mixture = MixturePDF::new(Arc::new(FixedPDF{direction: up, pdf: 2.0}), Arc::new(FixedPDF{direction: down, pdf: 4.0}))
+                .with_weight(*weight);
+            let num_samples = 10000;
+            let num_up = (0..num_samples).filter(|_| mixture.generate() == up).count();
+            let fraction_up = num_up as f64 / num_samples as f64;
+            assert!((fraction_up - weight).abs() < 0.02, "weight {} generated {} from the first pdf", weight, fraction_up);
+            assert!((mixture.value(&up) - weight * 2.0).abs() < 1e-12);
+            assert!((mixture.value(&down) - (1.0 - weight) * 4.0).abs() < 1e-12);
+        }
+        random::clear_thread_rng_seed();
+    }
+
+    // mean angle in radians between the rays transmitted into a glass rect and the smooth refraction direction
+    fn transmitted_spread(roughness: f64) -> f64 {
+        let material = MaterialBuilder::new().set_refraction_index(1.5).set_roughness(roughness).dielectric();
+        let slab = AxisAlignedRect::new(-1.0, 1.0, -1.0, 1.0, 0.0, AxisAlignedRectAxis::Z, material);
+        let ray = Ray::new(Vec3::new(0.0, 0.5, 0.5), Vec3::new(0.0, -1.0, -1.0), 0.0);
+        let hit_record = slab.hit(&ray, 0.001, std::f64::MAX).unwrap();
+        // sin 45 / 1.5 = 1 / sqrt(4.5)
+        let sin_t = 1.0 / 4.5_f64.sqrt();
+        let smooth = Vec3::new(0.0, -sin_t, -(1.0 - sin_t * sin_t).sqrt());
+
+        let smooth_reflected = Vec3::new_unit_vector(&Vec3::new(0.0, -1.0, 1.0));
+
+        random::seed_thread_rng(1415);
+        let mut angles = Vec::new();
+        for _ in 0..4000 {
+            let direction = match hit_record.mat.scatter(&ray, &hit_record) {
+                Some(scatter_result) => Vec3::new_unit_vector(&scatter_result.specular_ray.direction),
+                None => continue,
+            };
+            // every ray leaves on the side of the surface of the smooth direction it was perturbed from
+            let transmitted = vec3::dot(&direction, &smooth) > vec3::dot(&direction, &smooth_reflected);
+            assert_eq!(transmitted, direction.z < 0.0, "roughness {} scattered {:?} across the surface", roughness, direction);
+            if transmitted {
+                angles.push(vec3::dot(&direction, &smooth).min(1.0).acos());
+            }
+        }
+        random::clear_thread_rng_seed();
+        angles.iter().sum::<f64>() / angles.len() as f64
+    }
+
+    #[test]
+    fn dielectric_roughness_widens_transmitted_directions() {
+        let smooth = transmitted_spread(0.0);
+        let frosted = transmitted_spread(0.1);
+        let rough = transmitted_spread(0.5);
+        assert!(smooth < 1e-6, "smooth glass spread = {}", smooth);
+        assert!(frosted > 0.01, "frosted glass spread = {}", frosted);
+        assert!(rough > 2.0 * frosted, "spread {} should be wider than {}", rough, frosted);
+    }
+
+    #[test]
+    fn rough_dielectric_reflections_stay_inside() {
+        // grazing the top of a glass rect from inside so every ray is totally internally reflected
+        let material = MaterialBuilder::new().set_refraction_index(1.5).set_roughness(1.0).dielectric();
+        let slab = AxisAlignedRect::new(-1.0, 1.0, -1.0, 1.0, 0.0, AxisAlignedRectAxis::Z, material);
+        let ray = Ray::new(Vec3::new(0.0, -0.5, -0.05), Vec3::new(0.0, 1.0, 0.1), 0.0);
+        let hit_record = slab.hit(&ray, 0.001, std::f64::MAX).unwrap();
+
+        random::seed_thread_rng(1415);
+        let mut absorbed = 0;
+        for _ in 0..4000 {
+            match hit_record.mat.scatter(&ray, &hit_record) {
+                Some(scatter_result) => assert!(scatter_result.specular_ray.direction.z < 0.0, "reflected {:?} out of the glass", scatter_result.specular_ray.direction),
+                None => absorbed += 1,
+            }
+        }
+        random::clear_thread_rng_seed();
+        assert!(absorbed > 0 && absorbed < 4000, "absorbed {} of 4000", absorbed);
+    }
 }
\ No newline at end of file
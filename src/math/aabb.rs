@@ -1,8 +1,8 @@
-use super::vec3::Vec3;
+use super::vec3::{self, Vec3};
 use super::ray::Ray;
 use std::mem;
 
-#[derive(Clone)]
+#[derive(Clone, Debug)]
 pub struct AABB {
     min: Vec3,
     max: Vec3
@@ -59,6 +59,36 @@ impl AABB {
         self.max += translation;
         self.min += translation;
     }
+
+    // Box around the 8 transformed corners. Exact for translations, a loose fit around rotated boxes
+    pub fn transformed_by<F: Fn(&Vec3) -> Vec3>(&self, transform: F) -> AABB {
+        let mut min = Vec3::from_float(std::f64::MAX);
+        let mut max = Vec3::from_float(-std::f64::MAX);
+        for i in 0..2 {
+            for j in 0..2 {
+                for k in 0..2 {
+                    let corner = Vec3::new(
+                        if i == 0 {self.min.x} else {self.max.x},
+                        if j == 0 {self.min.y} else {self.max.y},
+                        if k == 0 {self.min.z} else {self.max.z});
+                    let corner = transform(&corner);
+                    min = vec3::min(&corner, &min);
+                    max = vec3::max(&corner, &max);
+                }
+            }
+        }
+        AABB::new(min, max)
+    }
+
+    // float tolerant comparison, exact equality is too strict once boxes have been unioned or transformed
+    pub fn approx_eq(&self, other: &AABB, eps: f64) -> bool {
+        for i in 0..3 {
+            if (self.min[i] - other.min[i]).abs() > eps || (self.max[i] - other.max[i]).abs() > eps {
+                return false;
+            }
+        }
+        true
+    }
 }
 
 
@@ -69,4 +99,33 @@ fn ffmax(a: f64, b :f64) -> f64 {
 
 fn ffmin(a: f64, b :f64) -> f64 {
     if a < b {a} else {b}
-}
\ No newline at end of file
+}
+
+#[cfg(test)]
+mod tests {
+
+    use super::*;
+
+    #[test]
+    fn union_matches_hand_computed_box() {
+        let box0 = AABB::new(Vec3::new(-1.0, 0.0, 0.5), Vec3::new(0.1, 2.0, 1.0));
+        let box1 = AABB::new(Vec3::new(0.2, -0.3, 0.0), Vec3::new(0.7, 1.0, 3.0));
+        let expected = AABB::new(Vec3::new(-1.0, -0.3, 0.0), Vec3::new(0.7, 2.0, 3.0));
+        let union = AABB::get_union(&box0, &box1);
+        assert!(union.approx_eq(&expected, 1e-9), "{:?} != {:?}", union, expected);
+        assert!(!union.approx_eq(&box0, 1e-9));
+    }
+
+    #[test]
+    fn transformed_box_contains_rotated_corners() {
+        let aabb = AABB::new(Vec3::new(-1.0, 0.0, -2.0), Vec3::new(1.0, 3.0, 2.0));
+        // quarter turn about y swaps the x and z extents
+        let rotated = aabb.transformed_by(|p| Vec3::new(p.z, p.y, -p.x));
+        assert!(rotated.approx_eq(&AABB::new(Vec3::new(-2.0, 0.0, -1.0), Vec3::new(2.0, 3.0, 1.0)), 1e-9), "{:?}", rotated);
+
+        let translation = Vec3::new(5.0, -1.0, 0.5);
+        let mut translated = aabb.clone();
+        translated.add_translation(translation);
+        assert!(translated.approx_eq(&aabb.transformed_by(|p| p + &translation), 1e-9));
+    }
+}
@@ -5,12 +5,15 @@ pub mod ray;
 pub mod random;
 pub mod aabb;
 pub mod noise;
+pub mod sampling;
+pub mod spline;
 extern crate rand;
 
 pub use self::vec3::*;
 pub use self::ray::*;
 pub use self::random::*;
 pub use self::aabb::*;
+pub use self::sampling::*;
 
 pub fn lerp<T>(a: &T, b: &T, t: f64) -> T
 where for<'a> &'a T: std::ops::Mul<f64, Output = T>,
@@ -35,6 +38,33 @@ pub fn round_up_to_closest_factor (factor_to_round: u32, factor_of: u32) -> u32
     factor_of / factor.round() as u32
 }
 
+// Three comma separated components such as 0.5,0.25,1 for a colour or a position, callers report what they expected
+pub fn parse_vec3(s: &str) -> Option<Vec3> {
+    let components: Vec<f64> = s.split(',').map(|component| component.trim().parse::<f64>()).collect::<Result<_, _>>().ok()?;
+    match components.as_slice() {
+        [x, y, z] => Some(Vec3::new(*x, *y, *z)),
+        _ => None,
+    }
+}
+
+// Encodes a linear value using the piecewise sRGB curve (linear segment near black, 2.4 power elsewhere)
+pub fn srgb_oetf(value: f64) -> f64 {
+    if value <= 0.0031308 {
+        12.92 * value
+    } else {
+        1.055 * value.powf(1.0 / 2.4) - 0.055
+    }
+}
+
+// Decodes an sRGB encoded value back to linear, the inverse of srgb_oetf
+pub fn srgb_eotf(value: f64) -> f64 {
+    if value <= 0.04045 {
+        value / 12.92
+    } else {
+        ((value + 0.055) / 1.055).powf(2.4)
+    }
+}
+
 #[cfg(test)]
 mod tests {
     // TODO(SS): Added unit tests for round_to_closest_factor functions
@@ -1,6 +1,7 @@
 use math::vec3::Vec3;
-use crate::random;
 use crate::vec3;
+use super::rand::prelude::*;
+use super::rand::rngs::StdRng;
 
 fn hermite_cubic(x: f64) -> f64 {
     x * x * (3.0 - 2.0 * x)
@@ -44,49 +45,72 @@ fn perlin_interpolate(c: &[[[Vec3; 2]; 2]; 2], u: f64, v: f64, w: f64) -> f64 {
 }
 
 
-fn perlin_generate() -> [Vec3;256] {
+fn perlin_generate(rng: &mut StdRng) -> [Vec3;256] {
     let mut p = [Vec3::from_float(0.0); 256];
     for elem in p.iter_mut() {
-        let x_random = 2.0 * random::rand() - 1.0;
-        let y_random = 2.0 * random::rand() - 1.0;
-        let z_random = 2.0 * random::rand() - 1.0;
+        let x_random = 2.0 * rng.gen::<f64>() - 1.0;
+        let y_random = 2.0 * rng.gen::<f64>() - 1.0;
+        let z_random = 2.0 * rng.gen::<f64>() - 1.0;
         *elem = Vec3::new_unit_vector(&Vec3::new(x_random, y_random, z_random));
     }
 
     p
 }
 
-fn permute(p: &mut [i32]) {
+fn permute(p: &mut [i32], rng: &mut StdRng) {
     let n = p.len();
     for i in (0..n).rev() {
-        let target = (random::rand() * (i + 1) as f64) as usize;
+        let target = (rng.gen::<f64>() * (i + 1) as f64) as usize;
         let tmp = p[i as usize];
         p[i as usize] = p[target];
         p[target] = tmp;
     }
 }
 
-fn perlin_generate_perm() -> [i32; 256] {
+fn perlin_generate_perm(rng: &mut StdRng) -> [i32; 256] {
     let mut p = [0; 256];
     for (i, elem) in p.iter_mut().enumerate() {
         *elem = i as i32;
     }
-    permute(&mut p);
+    permute(&mut p, rng);
     p
 }
 
-lazy_static::lazy_static!{
-    static ref RAN_VEC: [Vec3; 256] = perlin_generate();
-    static ref PERM_X: [i32; 256] = perlin_generate_perm();
-    static ref PERM_Y: [i32; 256] = perlin_generate_perm();
-    static ref PERM_Z: [i32; 256] = perlin_generate_perm();
+// Gradient and permutation tables, generated from a seed so noise textures can be reproduced
+pub struct Perlin {
+    ran_vec: [Vec3; 256],
+    perm_x: [i32; 256],
+    perm_y: [i32; 256],
+    perm_z: [i32; 256],
 }
 
+impl Perlin {
+    // without a seed the tables differ every run
+    pub fn new(seed: Option<u64>) -> Self {
+        let mut rng = match seed {
+            Some(seed) => StdRng::seed_from_u64(seed),
+            None => StdRng::from_entropy(),
+        };
+        Perlin {
+            ran_vec: perlin_generate(&mut rng),
+            perm_x: perlin_generate_perm(&mut rng),
+            perm_y: perlin_generate_perm(&mut rng),
+            perm_z: perlin_generate_perm(&mut rng),
+        }
+    }
 
+    pub fn noise(&self, p: &Vec3) -> f64 {
+        self.lattice_noise(p, None)
+    }
 
-pub struct Perlin;
-impl Perlin {
-    pub fn noise(p: &Vec3) -> f64 {
+    // Noise that repeats every period units along each axis, so textures using it tile seamlessly. Panics unless
+    // period is positive
+    pub fn noise_tiled(&self, p: &Vec3, period: i32) -> f64 {
+        assert!(period > 0, "noise period must be positive, got {}", period);
+        self.lattice_noise(p, Some(period))
+    }
+
+    fn lattice_noise(&self, p: &Vec3, period: Option<i32>) -> f64 {
         let i = p.x.floor() as i32;
         let j = p.y.floor() as i32;
         let k = p.z.floor() as i32;
@@ -94,17 +118,23 @@ impl Perlin {
         let v = p.y - j as f64;
         let w = p.z - k as f64;
 
+        // tiled noise wraps the lattice coordinates so opposite edges of a period share gradients
+        let wrap = |lattice: i32| match period {
+            Some(period) => lattice.rem_euclid(period),
+            None => lattice,
+        };
+
         let mut c = [[[Vec3::from_float(0.0); 2]; 2]; 2];
         for di in 0..2 {
             for dj in 0..2 {
                 for dk in 0..2 {
-                    let di_i32 = di as i32;
-                    let dj_i32 = dj as i32;
-                    let dk_i32 = dk as i32;
-                    c[di][dj][dk] = RAN_VEC[
-                        (PERM_X[(i+di_i32 & 255) as usize] ^ 
-                         PERM_Y[(j+dj_i32 & 255) as usize] ^ 
-                         PERM_Z[(k+dk_i32 & 255) as usize]) as usize
+                    let lattice_i = wrap(i + di as i32);
+                    let lattice_j = wrap(j + dj as i32);
+                    let lattice_k = wrap(k + dk as i32);
+                    c[di][dj][dk] = self.ran_vec[
+                        (self.perm_x[(lattice_i & 255) as usize] ^ 
+                         self.perm_y[(lattice_j & 255) as usize] ^ 
+                         self.perm_z[(lattice_k & 255) as usize]) as usize
                     ]
                 }
             }
@@ -112,16 +142,66 @@ impl Perlin {
         perlin_interpolate(&c, u, v, w)
     }
 
-    pub fn turb(p: &Vec3, depth: i32) ->f64 {
+    pub fn turb(&self, p: &Vec3, depth: i32) ->f64 {
+        self.lattice_turb(p, depth, None)
+    }
+
+    // each octave doubles the frequency so its period is doubled too
+    pub fn turb_tiled(&self, p: &Vec3, depth: i32, period: i32) -> f64 {
+        assert!(period > 0, "noise period must be positive, got {}", period);
+        self.lattice_turb(p, depth, Some(period))
+    }
+
+    fn lattice_turb(&self, p: &Vec3, depth: i32, period: Option<i32>) -> f64 {
         let mut accum = 0.0;
         let mut temp_p = p.clone();
+        let mut temp_period = period;
         let mut weight = 1.0;
         for _ in 0..depth {
-            accum += weight * Perlin::noise(&temp_p);
+            accum += weight * self.lattice_noise(&temp_p, temp_period);
             weight *= 0.5;
             temp_p *= 2.0;
+            temp_period = temp_period.map(|period| period * 2);
         }
 
         accum.abs()
     }
 }
+
+#[cfg(test)]
+mod tests {
+
+    use super::*;
+
+    #[test]
+    fn same_seed_gives_same_noise() {
+        let (a, b, c) = (Perlin::new(Some(1391)), Perlin::new(Some(1391)), Perlin::new(Some(1392)));
+        let points = [Vec3::new(0.5, 1.25, -3.75), Vec3::new(10.1, 0.2, 7.3), Vec3::new(-4.6, 2.2, 0.9)];
+        for point in points.iter() {
+            assert_eq!(a.noise(point), b.noise(point));
+            assert_eq!(a.turb(point, 7), b.turb(point, 7));
+        }
+        assert!(points.iter().any(|point| a.noise(point) != c.noise(point)));
+    }
+
+    #[test]
+    fn tiled_noise_repeats_every_period() {
+        let perlin = Perlin::new(Some(1392));
+        let period = 4;
+        let p = Vec3::new(0.75, 1.25, -2.5);
+        let offsets = [Vec3::new(period as f64, 0.0, 0.0), Vec3::new(0.0, period as f64, 0.0), Vec3::new(0.0, 0.0, period as f64)];
+        for offset in offsets.iter() {
+            let shifted = &p + offset;
+            assert!((perlin.noise_tiled(&p, period) - perlin.noise_tiled(&shifted, period)).abs() < 1e-12);
+            assert!((perlin.turb_tiled(&p, 7, period) - perlin.turb_tiled(&shifted, 7, period)).abs() < 1e-12);
+        }
+        // matches untiled noise inside the first period
+        assert_eq!(perlin.noise_tiled(&Vec3::new(0.75, 1.25, 2.5), period), perlin.noise(&Vec3::new(0.75, 1.25, 2.5)));
+    }
+
+    #[test]
+    #[should_panic(expected = "noise period must be positive")]
+    fn tiled_noise_rejects_zero_period() {
+        Perlin::new(Some(1392)).turb_tiled(&Vec3::new(0.5, 0.5, 0.5), 7, 0);
+    }
+}
@@ -1,6 +1,36 @@
 use super::rand::prelude::*;
+use super::rand::rngs::StdRng;
+use std::cell::RefCell;
+
+thread_local! {
+    // when set, rand() on this thread draws from a seeded generator so renders can be reproduced
+    static SEEDED_RNG: RefCell<Option<StdRng>> = RefCell::new(None);
+}
 
 pub fn rand() -> f64 {
-    let mut rng = rand::thread_rng();
-    rng.gen()
-}
\ No newline at end of file
+    SEEDED_RNG.with(|seeded_rng| {
+        match *seeded_rng.borrow_mut() {
+            Some(ref mut rng) => rng.gen(),
+            None => {
+                let mut rng = rand::thread_rng();
+                rng.gen()
+            }
+        }
+    })
+}
+
+pub fn seed_thread_rng(seed: u64) {
+    SEEDED_RNG.with(|seeded_rng| *seeded_rng.borrow_mut() = Some(StdRng::seed_from_u64(seed)));
+}
+
+pub fn clear_thread_rng_seed() {
+    SEEDED_RNG.with(|seeded_rng| *seeded_rng.borrow_mut() = None);
+}
+
+// Derives a well mixed seed for an independent random stream, e.g. a tile or a pass, from a base seed (splitmix64)
+pub fn stream_seed(base_seed: u64, stream: u64) -> u64 {
+    let mut z = (base_seed ^ stream).wrapping_add(0x9e3779b97f4a7c15);
+    z = (z ^ (z >> 30)).wrapping_mul(0xbf58476d1ce4e5b9);
+    z = (z ^ (z >> 27)).wrapping_mul(0x94d049bb133111eb);
+    z ^ (z >> 31)
+}
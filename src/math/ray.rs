@@ -27,9 +27,23 @@ impl Ray {
         self.time
     }
 
-    pub fn point_at_parameter(&self, t: f64) -> Vec3 {
+    /// Point along the ray at parameter `t`, i.e. `origin + t*direction`.
+    ///
+    /// ```
+    /// use path_tracer::math::{Ray, Vec3};
+    ///
+    /// let ray = Ray::new(Vec3::new(1.0, 2.0, 3.0), Vec3::new(0.0, 1.0, -1.0), 0.0);
+    /// let point = ray.at(2.0);
+    /// assert_eq!((point.x, point.y, point.z), (1.0, 4.0, 1.0));
+    /// ```
+    pub fn at(&self, t: f64) -> Vec3 {
         &self.origin + &(&self.direction * t)
     }
+
+    // kept for existing callers, prefer at
+    pub fn point_at_parameter(&self, t: f64) -> Vec3 {
+        self.at(t)
+    }
 }
 
 impl Default for Ray {
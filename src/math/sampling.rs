@@ -0,0 +1,382 @@
+use std::f64::consts::PI;
+use super::vec3::Vec3;
+use super::random;
+
+// Uniform point inside the unit sphere by rejection sampling the enclosing cube
+pub fn random_in_unit_sphere() -> Vec3 {
+    let mut p: Vec3;
+    loop  {
+        p = 2.0 * Vec3::new(random::rand(), random::rand(), random::rand()) - Vec3::new(1.0, 1.0, 1.0);
+        if p.squared_length() < 1.0 {
+            break
+        }
+    }
+
+    p
+}
+
+// Uniform point on the unit sphere, a point inside the sphere projected onto its surface
+pub fn random_on_unit_sphere() -> Vec3 {
+    let mut p = random_in_unit_sphere();
+    p.normalise();
+    p
+}
+
+// Uniform direction on the unit sphere without rejection, z is uniform in [-1, 1] and phi in [0, 2pi)
+pub fn random_unit_vector() -> Vec3 {
+    let z = 1.0 - 2.0 * random::rand();
+    let phi = 2.0 * PI * random::rand();
+    let r = (1.0 - z * z).max(0.0).sqrt();
+    Vec3::new(r * phi.cos(), r * phi.sin(), z)
+}
+
+pub fn unit_sphere_pdf() -> f64 {
+    1.0 / (4.0 * PI)
+}
+
+// Direction on the hemisphere around +z with pdf cos(theta) / pi
+pub fn random_cosine_direction() -> Vec3 {
+    let r1 = random::rand();
+    let r2 = random::rand();
+    let z = (1.0 - r2).sqrt();
+    let phi = 2.0 * PI * r1;
+    let r2_sqrt = r2.sqrt();
+    let x = phi.cos() * r2_sqrt;
+    let y = phi.sin() * r2_sqrt;
+    Vec3::new(x, y, z)
+}
+
+// Direction around +z uniformly distributed over the cone subtended by a sphere of the given radius
+// whose centre is distance_squared away along +z
+pub fn random_to_sphere(radius: f64, distance_squared: f64) -> Vec3 {
+    let r1 = random::rand();
+    let r2 = random::rand();
+    let z = 1.0 + r2*((1.0-radius*radius/distance_squared).sqrt()-1.0);
+    let phi = 2.0*PI*r1;
+    let c = (1.0-z*z).sqrt();
+    let x = phi.cos()*c;
+    let y = phi.sin()*c;
+    Vec3::new(x, y, z)
+}
+
+// first index i where cdf[i + 1] > value, cdf starts at 0 and ends at 1
+fn sample_cdf(cdf: &[f64], value: f64) -> usize {
+    let num_entries = cdf.len() - 1;
+    let index = match cdf[1..].binary_search_by(|probe| probe.partial_cmp(&value).unwrap()) {
+        Ok(index) => index + 1,
+        Err(index) => index,
+    };
+    index.min(num_entries - 1)
+}
+
+// Piecewise constant distribution over a width x height grid of non-negative weights, laid out row by row. A row is
+// picked from the marginal cdf over rows then a column from the row's conditional cdf. Grids and rows without any
+// weight keep a uniform cdf so they stay valid, but are never picked when anything else has weight.
+pub struct Distribution2D {
+    width: usize,
+    height: usize,
+    row_cdf: Vec<f64>, // marginal cdf over rows, height + 1 entries
+    column_cdfs: Vec<f64>, // conditional cdf over the columns of each row, width + 1 entries per row
+    total_weight: f64,
+}
+
+impl Distribution2D {
+    pub fn new(width: usize, height: usize, weights: &[f64]) -> Self {
+        assert_eq!(weights.len(), width * height, "distribution weights don't match its size");
+
+        let mut column_cdfs = vec![0.0; height * (width + 1)];
+        let mut row_weights = vec![0.0; height];
+        for row in 0..height {
+            let cdf = &mut column_cdfs[row * (width + 1)..(row + 1) * (width + 1)];
+            for column in 0..width {
+                cdf[column + 1] = cdf[column] + weights[row * width + column];
+            }
+            row_weights[row] = cdf[width];
+            if cdf[width] > 0.0 {
+                let row_weight = cdf[width];
+                cdf.iter_mut().for_each(|value| *value /= row_weight);
+            } else {
+                cdf.iter_mut().enumerate().for_each(|(column, value)| *value = column as f64 / width as f64);
+            }
+        }
+
+        let mut row_cdf = vec![0.0; height + 1];
+        for row in 0..height {
+            row_cdf[row + 1] = row_cdf[row] + row_weights[row];
+        }
+        let total_weight = row_cdf[height];
+        if total_weight > 0.0 {
+            row_cdf.iter_mut().for_each(|value| *value /= total_weight);
+        } else {
+            row_cdf.iter_mut().enumerate().for_each(|(row, value)| *value = row as f64 / height as f64);
+        }
+
+        Self {
+            width,
+            height,
+            row_cdf,
+            column_cdfs,
+            total_weight,
+        }
+    }
+
+    pub fn total_weight(&self) -> f64 {
+        self.total_weight
+    }
+
+    // (column, row) of a cell picked with probability proportional to its weight
+    pub fn sample(&self) -> (usize, usize) {
+        let row = sample_cdf(&self.row_cdf, random::rand());
+        let column_cdf = &self.column_cdfs[row * (self.width + 1)..(row + 1) * (self.width + 1)];
+        let column = sample_cdf(column_cdf, random::rand());
+        (column, row)
+    }
+
+    // chance of sample picking the cell, zero everywhere when the grid has no weight
+    pub fn probability(&self, column: usize, row: usize) -> f64 {
+        if self.total_weight <= 0.0 {
+            return 0.0;
+        }
+        let row_probability = self.row_cdf[row + 1] - self.row_cdf[row];
+        let column_cdf = &self.column_cdfs[row * (self.width + 1)..];
+        let column_probability = column_cdf[column + 1] - column_cdf[column];
+        row_probability * column_probability
+    }
+}
+
+// Uniform point inside the unit disk in the xy plane
+pub fn random_in_unit_disk() -> Vec3 {
+    let mut new_vector = Vec3::new(random::rand(), random::rand(), 0.0)*2.0 - Vec3::new(1.0,1.0,0.0);
+    while super::vec3::dot(&new_vector,&new_vector) >= 1.0 {
+        new_vector = Vec3::new(random::rand(), random::rand(), 0.0)*2.0 - Vec3::new(1.0,1.0,0.0);
+    } 
+
+    new_vector
+}
+
+// Maps a point in the unit square to the unit disk in the xy plane, keeping it stratified (Shirley's concentric mapping)
+pub fn concentric_sample_disk(u: f64, v: f64) -> Vec3 {
+    let (a, b) = (2.0 * u - 1.0, 2.0 * v - 1.0);
+    if a == 0.0 && b == 0.0 {
+        return Vec3::new_zero_vector();
+    }
+    let (radius, theta) = if a.abs() > b.abs() {
+        (a, (PI / 4.0) * (b / a))
+    } else {
+        (b, PI / 2.0 - (PI / 4.0) * (a / b))
+    };
+    Vec3::new(radius * theta.cos(), radius * theta.sin(), 0.0)
+}
+
+// Low discrepancy sequences have this many dimensions, see SamplingStrategy
+pub const LOW_DISCREPANCY_DIMENSIONS: usize = 5;
+
+const HALTON_BASES: [u64; LOW_DISCREPANCY_DIMENSIONS] = [2, 3, 5, 7, 11];
+
+// index's digits in base mirrored about the radix point
+pub fn radical_inverse(base: u64, index: u64) -> f64 {
+    let inverse_base = 1.0 / base as f64;
+    let mut index = index;
+    let mut scale = inverse_base;
+    let mut result = 0.0;
+    while index > 0 {
+        result += (index % base) as f64 * scale;
+        index /= base;
+        scale *= inverse_base;
+    }
+    result
+}
+
+pub fn halton(index: u64, dimension: usize) -> f64 {
+    radical_inverse(HALTON_BASES[dimension], index)
+}
+
+// Primitive polynomial degree, coefficients and initial direction numbers of each dimension after the first
+// (Joe and Kuo's new-joe-kuo-6.21201 table)
+const SOBOL_POLYNOMIALS: [(usize, u32, [u32; 3]); LOW_DISCREPANCY_DIMENSIONS - 1] = [
+    (1, 0, [1, 0, 0]),
+    (2, 1, [1, 3, 0]),
+    (3, 1, [1, 3, 1]),
+    (3, 2, [1, 1, 1]),
+];
+
+lazy_static::lazy_static! {
+    static ref SOBOL_DIRECTIONS: [[u32; 32]; LOW_DISCREPANCY_DIMENSIONS] = {
+        let mut directions = [[0; 32]; LOW_DISCREPANCY_DIMENSIONS];
+        // the first dimension is the van der corput sequence
+        for (bit, direction) in directions[0].iter_mut().enumerate() {
+            *direction = 1 << (31 - bit);
+        }
+        for (dimension, &(degree, coefficients, initial)) in SOBOL_POLYNOMIALS.iter().enumerate() {
+            let v = &mut directions[dimension + 1];
+            for bit in 0..32 {
+                v[bit] = if bit < degree {
+                    initial[bit] << (31 - bit)
+                } else {
+                    let mut direction = v[bit - degree] ^ (v[bit - degree] >> degree);
+                    for k in 1..degree {
+                        direction ^= ((coefficients >> (degree - 1 - k)) & 1) * v[bit - k];
+                    }
+                    direction
+                };
+            }
+        }
+        directions
+    };
+}
+
+pub fn sobol(index: u32, dimension: usize) -> f64 {
+    let directions = &SOBOL_DIRECTIONS[dimension];
+    let mut result = 0;
+    let mut index = index;
+    let mut bit = 0;
+    while index > 0 {
+        if index & 1 == 1 {
+            result ^= directions[bit];
+        }
+        index >>= 1;
+        bit += 1;
+    }
+    result as f64 / (1u64 << 32) as f64
+}
+
+#[cfg(test)]
+mod tests {
+
+    use super::*;
+
+    const NUM_SAMPLES: usize = 100000;
+
+    fn mean_of<F: FnMut() -> Vec3>(mut sample: F) -> (Vec3, f64) {
+        let mut mean = Vec3::new_zero_vector();
+        let mut mean_squared_length = 0.0;
+        for _ in 0..NUM_SAMPLES {
+            let p = sample();
+            mean += p;
+            mean_squared_length += p.squared_length();
+        }
+        (mean / NUM_SAMPLES as f64, mean_squared_length / NUM_SAMPLES as f64)
+    }
+
+    #[test]
+    fn unit_sphere_moments() {
+        // in the sphere E[r^2] = 3/5, on the sphere every point has length 1
+        let (mean, mean_squared_length) = mean_of(random_in_unit_sphere);
+        assert!(mean.length() < 0.01, "mean = {}", mean);
+        assert!((mean_squared_length - 0.6).abs() < 0.01);
+
+        let (mean, mean_squared_length) = mean_of(random_on_unit_sphere);
+        assert!(mean.length() < 0.01, "mean = {}", mean);
+        assert!((mean_squared_length - 1.0).abs() < 1e-9);
+    }
+
+    #[test]
+    fn unit_vector_uniform_over_octants() {
+        random::seed_thread_rng(1363);
+        let mut octant_counts = [0; 8];
+        for _ in 0..NUM_SAMPLES {
+            let p = random_unit_vector();
+            assert!((p.length() - 1.0).abs() < 1e-9);
+            let octant = (p.x > 0.0) as usize | ((p.y > 0.0) as usize) << 1 | ((p.z > 0.0) as usize) << 2;
+            octant_counts[octant] += 1;
+        }
+        random::clear_thread_rng_seed();
+
+        let expected = NUM_SAMPLES as f64 / 8.0;
+        let chi_square: f64 = octant_counts.iter().map(|count| {
+            let difference = *count as f64 - expected;
+            difference * difference / expected
+        }).sum();
+        // critical value for 7 degrees of freedom at p = 0.001
+        assert!(chi_square < 24.32, "chi square = {}, counts = {:?}", chi_square, octant_counts);
+    }
+
+    #[test]
+    fn cosine_direction_moments() {
+        // E[cos(theta)] = 2/3 for a cosine weighted hemisphere
+        let (mean, mean_squared_length) = mean_of(random_cosine_direction);
+        assert!(mean.x.abs() < 0.01 && mean.y.abs() < 0.01, "mean = {}", mean);
+        assert!((mean.z - 2.0 / 3.0).abs() < 0.01, "mean = {}", mean);
+        assert!((mean_squared_length - 1.0).abs() < 1e-9);
+    }
+
+    #[test]
+    fn to_sphere_moments() {
+        // z is uniform over [cos(theta_max), 1]
+        let (radius, distance) = (1.0, 2.0);
+        let cos_theta_max = (1.0 - radius * radius / (distance * distance) as f64).sqrt();
+        let (mean, _) = mean_of(|| random_to_sphere(radius, distance * distance));
+        assert!(mean.x.abs() < 0.01 && mean.y.abs() < 0.01, "mean = {}", mean);
+        assert!((mean.z - (1.0 + cos_theta_max) / 2.0).abs() < 0.01, "mean = {}", mean);
+    }
+
+    #[test]
+    fn distribution_2d_samples_cells_by_weight() {
+        // one empty row, the other row weighted 1:3
+        let distribution = Distribution2D::new(2, 2, &[0.0, 0.0, 1.0, 3.0]);
+        assert_eq!(distribution.total_weight(), 4.0);
+        assert_eq!(distribution.probability(0, 0), 0.0);
+        assert_eq!(distribution.probability(0, 1), 0.25);
+        assert_eq!(distribution.probability(1, 1), 0.75);
+
+        random::seed_thread_rng(1420);
+        let mut counts = [0; 4];
+        for _ in 0..NUM_SAMPLES {
+            let (column, row) = distribution.sample();
+            counts[row * 2 + column] += 1;
+        }
+        random::clear_thread_rng_seed();
+        assert_eq!(counts[0] + counts[1], 0);
+        assert!((counts[3] as f64 / NUM_SAMPLES as f64 - 0.75).abs() < 0.01, "counts = {:?}", counts);
+
+        let empty = Distribution2D::new(2, 2, &[0.0; 4]);
+        assert_eq!(empty.probability(1, 1), 0.0);
+    }
+
+    #[test]
+    fn halton_matches_known_points() {
+        let base_2: Vec<f64> = (0..5).map(|i| halton(i, 0)).collect();
+        assert_eq!(base_2, vec![0.0, 0.5, 0.25, 0.75, 0.125]);
+        let base_3: Vec<f64> = (0..5).map(|i| halton(i, 1)).collect();
+        for (value, expected) in base_3.iter().zip([0.0, 1.0 / 3.0, 2.0 / 3.0, 1.0 / 9.0, 4.0 / 9.0].iter()) {
+            assert!((value - expected).abs() < 1e-12, "{:?}", base_3);
+        }
+    }
+
+    #[test]
+    fn sobol_matches_known_points() {
+        let first: Vec<f64> = (0..8).map(|i| sobol(i, 0)).collect();
+        assert_eq!(first, vec![0.0, 0.5, 0.25, 0.75, 0.125, 0.625, 0.375, 0.875]);
+        // generated in index order rather than gray code order
+        let second: Vec<f64> = (0..8).map(|i| sobol(i, 1)).collect();
+        assert_eq!(second, vec![0.0, 0.5, 0.75, 0.25, 0.625, 0.125, 0.375, 0.875]);
+        // every dimension puts one of the first 2^k points in each interval of width 2^-k
+        for dimension in 0..LOW_DISCREPANCY_DIMENSIONS {
+            let mut intervals: Vec<u32> = (0..16).map(|i| (sobol(i, dimension) * 16.0) as u32).collect();
+            intervals.sort();
+            assert_eq!(intervals, (0..16).collect::<Vec<u32>>(), "dimension {}", dimension);
+        }
+    }
+
+    #[test]
+    fn concentric_disk_stays_inside_and_covers_the_disk() {
+        let mut mean_squared_length = 0.0;
+        let n = 64;
+        for i in 0..n {
+            for j in 0..n {
+                let point = concentric_sample_disk((i as f64 + 0.5) / n as f64, (j as f64 + 0.5) / n as f64);
+                assert!(point.length() <= 1.0 + 1e-12);
+                mean_squared_length += point.squared_length() / (n * n) as f64;
+            }
+        }
+        assert!((mean_squared_length - 0.5).abs() < 0.01);
+    }
+
+    #[test]
+    fn unit_disk_moments() {
+        // E[r^2] = 1/2 over the unit disk
+        let (mean, mean_squared_length) = mean_of(random_in_unit_disk);
+        assert!(mean.length() < 0.01, "mean = {}", mean);
+        assert!((mean_squared_length - 0.5).abs() < 0.01);
+    }
+}
@@ -0,0 +1,100 @@
+use super::vec3::Vec3;
+
+// Uniform Catmull-Rom spline from p1 at t = 0 to p2 at t = 1, p0 and p3 set the tangents at either end.
+// Generic like lerp so scalars such as a field of view can follow the same curve as positions
+pub fn catmull_rom<T>(p0: &T, p1: &T, p2: &T, p3: &T, t: f64) -> T
+where for<'a> &'a T: std::ops::Mul<f64, Output = T>,
+      for<'a> T: std::ops::Add<T, Output = T>,
+{
+    let (t2, t3) = (t * t, t * t * t);
+    p0 * (0.5 * (-t + 2.0 * t2 - t3)) + p1 * (0.5 * (2.0 - 5.0 * t2 + 3.0 * t3)) + p2 * (0.5 * (t + 4.0 * t2 - 3.0 * t3)) + p3 * (0.5 * (t3 - t2))
+}
+
+// Smooth path through a list of control points, e.g. a camera or object motion path. The end points are
+// repeated to give the first and last segments a neighbour.
+pub struct CatmullRomPath {
+    points: Vec<Vec3>,
+    // chord length from the first point to each point, so the path can be walked at a roughly even speed
+    distances: Vec<f64>,
+}
+
+impl CatmullRomPath {
+    pub fn new(points: Vec<Vec3>) -> CatmullRomPath {
+        assert!(!points.is_empty(), "a path needs at least one control point");
+        let mut distances = Vec::with_capacity(points.len());
+        distances.push(0.0);
+        for pair in points.windows(2) {
+            let distance = distances[distances.len() - 1] + (pair[1] - pair[0]).length();
+            distances.push(distance);
+        }
+        CatmullRomPath { points, distances }
+    }
+
+    pub fn num_segments(&self) -> usize {
+        self.points.len() - 1
+    }
+
+    pub fn length(&self) -> f64 {
+        self.distances[self.distances.len() - 1]
+    }
+
+    // Point at t in 0..num_segments, passing through control point i at t = i
+    pub fn point(&self, t: f64) -> Vec3 {
+        if self.num_segments() == 0 {
+            return self.points[0];
+        }
+        let t = t.clamp(0.0, self.num_segments() as f64);
+        let segment = (t.floor() as usize).min(self.num_segments() - 1);
+        let last = self.points.len() - 1;
+        catmull_rom(&self.points[segment.saturating_sub(1)], &self.points[segment], &self.points[segment + 1],
+                    &self.points[(segment + 2).min(last)], t - segment as f64)
+    }
+
+    // Point the fraction 0..1 of the way along the path, measured by chord length so long segments take as long
+    // to cross as short ones at the same speed
+    pub fn point_at_fraction(&self, fraction: f64) -> Vec3 {
+        let length = self.length();
+        if length <= 0.0 {
+            return self.points[0];
+        }
+        let distance = fraction.clamp(0.0, 1.0) * length;
+        let segment = self.distances.iter().rposition(|start| *start <= distance).unwrap_or(0).min(self.num_segments() - 1);
+        let segment_length = self.distances[segment + 1] - self.distances[segment];
+        let segment_t = if segment_length > 0.0 {(distance - self.distances[segment]) / segment_length} else {0.0};
+        self.point(segment as f64 + segment_t)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+
+    use super::*;
+
+    fn path() -> CatmullRomPath {
+        CatmullRomPath::new(vec![Vec3::new(0.0, 0.0, 0.0), Vec3::new(1.0, 2.0, 0.0), Vec3::new(4.0, 2.0, 1.0), Vec3::new(5.0, -1.0, 3.0), Vec3::new(2.0, 0.0, 6.0)])
+    }
+
+    #[test]
+    fn path_passes_through_control_points_with_a_continuous_tangent() {
+        let path = path();
+        for (i, point) in path.points.iter().enumerate() {
+            assert!(path.point(i as f64).approx_eq(point, 1e-12), "control point {}", i);
+        }
+
+        // the tangents leaving a control point from either side agree at every join
+        let h = 1e-6;
+        for i in 1..path.num_segments() {
+            let t = i as f64;
+            let before = (path.point(t) - path.point(t - h)) / h;
+            let after = (path.point(t + h) - path.point(t)) / h;
+            assert!(before.approx_eq(&after, 1e-4), "tangent at {} is {} before and {} after", t, before, after);
+        }
+
+        // the ends and fractions along the path
+        assert!(path.point_at_fraction(0.0).approx_eq(&path.points[0], 1e-12));
+        assert!(path.point_at_fraction(1.0).approx_eq(&path.points[4], 1e-12));
+        let fraction = path.distances[2] / path.length();
+        assert!(path.point_at_fraction(fraction).approx_eq(&path.points[2], 1e-9));
+        assert_eq!(catmull_rom(&1.0, &2.0, &4.0, &8.0, 0.0), 2.0);
+    }
+}
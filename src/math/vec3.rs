@@ -63,6 +63,52 @@ impl Vec3 {
         self.x == rhs.x && self.y == rhs.y && self.z == rhs.z
     }
 
+    // true when every component is within eps, for comparing computed results where == is too strict
+    pub fn approx_eq(&self, other: &Vec3, eps: f64) -> bool {
+        (self.x - other.x).abs() <= eps && (self.y - other.y).abs() <= eps && (self.z - other.z).abs() <= eps
+    }
+
+    // returns (theta, phi) for a unit vector, theta is the elevation from the xz plane in [-pi/2, pi/2]
+    // and phi the angle around the y axis from +x towards +z in [-pi, pi]
+    pub fn to_spherical(&self) -> (f64, f64) {
+        let theta = self.y.asin();
+        let phi = self.z.atan2(self.x);
+        (theta, phi)
+    }
+
+    pub fn from_spherical(theta: f64, phi: f64) -> Vec3 {
+        let cos_theta = theta.cos();
+        Vec3::new(cos_theta * phi.cos(), theta.sin(), cos_theta * phi.sin())
+    }
+
+    pub fn lerp(&self, to: &Vec3, t: f64) -> Vec3 {
+        Vec3::new(self.x + (to.x - self.x) * t, self.y + (to.y - self.y) * t, self.z + (to.z - self.z) * t)
+    }
+
+    pub fn abs(&self) -> Vec3 {
+        Vec3::new(self.x.abs(), self.y.abs(), self.z.abs())
+    }
+
+    pub fn min_component(&self) -> f64 {
+        self.x.min(self.y).min(self.z)
+    }
+
+    pub fn max_component(&self) -> f64 {
+        self.x.max(self.y).max(self.z)
+    }
+
+    // Rec. 709 luminance of a linear colour
+    pub fn luminance(&self) -> f64 {
+        0.2126 * self.x + 0.7152 * self.y + 0.0722 * self.z
+    }
+
+    // mean of the vectors, zero for an empty iterator
+    pub fn mean<I: Iterator<Item = Vec3>>(iter: I) -> Vec3 {
+        let mut count = 0;
+        let sum: Vec3 = iter.inspect(|_| count += 1).sum();
+        if count > 0 {sum / count as f64} else {sum}
+    }
+
     fn add_vec(&self, rhs: &Vec3) -> Vec3 {
          Vec3 {
             x: self.x + rhs.x,
@@ -128,6 +174,18 @@ impl Vec3 {
     }
 }
 
+impl std::iter::Sum for Vec3 {
+    fn sum<I: Iterator<Item = Vec3>>(iter: I) -> Vec3 {
+        iter.fold(Vec3::new_zero_vector(), |sum, v| sum + v)
+    }
+}
+
+impl<'a> std::iter::Sum<&'a Vec3> for Vec3 {
+    fn sum<I: Iterator<Item = &'a Vec3>>(iter: I) -> Vec3 {
+        iter.fold(Vec3::new_zero_vector(), |sum, v| sum + v)
+    }
+}
+
 impl std::fmt::Display for Vec3 {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
         write!(f, "({}, {}, {})", self.x, self.y, self.z)
@@ -492,6 +550,50 @@ mod tests {
 
     use super::*;
 
+    #[test]
+    fn lerp_between_endpoints() {
+        let from = Vec3::new(0.0, 2.0, -4.0);
+        let to = Vec3::new(1.0, 4.0, 4.0);
+        assert_eq!(from.lerp(&to, 0.0), from);
+        assert_eq!(from.lerp(&to, 0.5), Vec3::new(0.5, 3.0, 0.0));
+        assert_eq!(from.lerp(&to, 1.0), to);
+    }
+
+    #[test]
+    fn abs_of_negative_components() {
+        assert_eq!(Vec3::new(-1.0, 2.0, -0.5).abs(), Vec3::new(1.0, 2.0, 0.5));
+        assert_eq!(Vec3::new(-0.0, -3.0, 0.0).abs(), Vec3::new(0.0, 3.0, 0.0));
+    }
+
+    #[test]
+    fn min_and_max_component() {
+        let v = Vec3::new(3.0, -2.0, 7.5);
+        assert_eq!(v.min_component(), -2.0);
+        assert_eq!(v.max_component(), 7.5);
+        assert_eq!(Vec3::from_float(1.0).min_component(), Vec3::from_float(1.0).max_component());
+    }
+
+    #[test]
+    fn sum_and_mean_of_iterator() {
+        let vectors = vec![Vec3::new(1.0, 2.0, 3.0), Vec3::new(-1.0, 0.5, 0.0), Vec3::new(3.0, 0.5, 6.0)];
+        assert_eq!(vectors.iter().sum::<Vec3>(), Vec3::new(3.0, 3.0, 9.0));
+        assert_eq!(vectors.iter().cloned().sum::<Vec3>(), Vec3::new(3.0, 3.0, 9.0));
+        assert_eq!((0..4).map(|i| Vec3::from_float(i as f64)).sum::<Vec3>() / 4.0, Vec3::from_float(1.5));
+        assert_eq!(Vec3::mean(vectors.into_iter()), Vec3::new(1.0, 1.0, 3.0));
+        assert_eq!(Vec3::mean(std::iter::empty()), Vec3::new_zero_vector());
+    }
+
+    #[test]
+    fn spherical_round_trip() {
+        let directions = [Vec3::new(1.0, 2.0, 3.0), Vec3::new(-0.5, -1.0, 0.25), Vec3::new(0.0, 1.0, 0.0), Vec3::new(0.0, 0.0, -1.0)];
+        for direction in directions.iter() {
+            let v = Vec3::new_unit_vector(direction);
+            let (theta, phi) = v.to_spherical();
+            let round_trip = Vec3::from_spherical(theta, phi);
+            assert!(round_trip.approx_eq(&v, 1e-9), "{} != {}", round_trip, v);
+        }
+    }
+
     #[test]
     fn test() {
 
@@ -573,6 +675,14 @@ mod tests {
         let mut v = Vec3::new(1.0, 1.0, 1.0);
         let length = 3.0_f64.sqrt();
         v.normalise();
-        assert_eq!(v, Vec3::new(1.0/length, 1.0/length, 1.0/length));
+        assert!(v.approx_eq(&Vec3::new(1.0/length, 1.0/length, 1.0/length), 1e-12));
+    }
+
+    #[test]
+    fn approx_eq_within_epsilon() {
+        let v = Vec3::new(1.0, -2.0, 3.0);
+        assert!(v.approx_eq(&Vec3::new(1.0 + 1e-12, -2.0, 3.0 - 1e-12), 1e-6));
+        assert!(!v.approx_eq(&Vec3::new(1.0, -2.0 + 1e-3, 3.0), 1e-6));
+        assert!(!v.approx_eq(&Vec3::new(1.0, -2.0, 3.0 + 1e-3), 1e-6));
     }
 }
\ No newline at end of file
@@ -41,6 +41,7 @@ lazy_static::lazy_static! {
 #[repr(C)]
 pub struct TonemapperArgs {
     pub exposure_numframes_xx: [f32; 4],
+    pub passthrough_xxx: [u32; 4], // non zero shows the linear buffer clamped to [0, 1] instead of tonemapping
 }
 
 #[derive(Debug, Clone, Copy)]
@@ -336,4 +337,28 @@ where
 
     fn dispose(self, _factory: &mut Factory<B>, _aux: &Aux<B>) {
     }
-}
\ No newline at end of file
+}
+
+#[cfg(test)]
+mod tests {
+
+    use super::*;
+
+    #[test]
+    fn passthrough_flag_is_written_after_exposure() {
+        let args = UniformArgs {
+            tonemapper: TonemapperArgs {
+                exposure_numframes_xx: [1.3, 1.0, 0.0, 0.0],
+                passthrough_xxx: [1, 0, 0, 0],
+            },
+        };
+        let bytes = unsafe {
+            std::slice::from_raw_parts(&args as *const UniformArgs as *const u8, size_of::<UniformArgs>())
+        };
+        // std140 places each vec4 on a 16 byte boundary
+        assert_eq!(bytes.len(), 32);
+        assert_eq!(&bytes[0..4], &1.3_f32.to_ne_bytes());
+        assert_eq!(&bytes[16..20], &1_u32.to_ne_bytes());
+        assert!(bytes[20..32].iter().all(|byte| *byte == 0));
+    }
+}
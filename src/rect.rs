@@ -3,7 +3,9 @@ use math::*;
 use material::Material;
 use hitable::*;
 use std::sync::Arc;
+use stats;
 
+#[derive(Clone)]
 pub enum AxisAlignedRectAxis {
     X,
     Y,
@@ -21,9 +23,18 @@ pub struct AxisAlignedRect {
     b_size: f64,
     area: f64,
     plane_axis: AxisAlignedRectAxis,
+    emission_distribution: Option<Distribution2D>, // set when the emission varies across the rect, over EMISSION_GRID_SIZE cells
 }
 
 const FLT_TOLERANCE: f64 = 0.0000001;
+// Rects are flat so their boxes are padded along the plane axis by this fraction of their larger side, a fixed pad
+// is lost on large rects far from the origin and swamps tiny ones
+const BOX_PAD_SCALE: f64 = 0.0001;
+// ..but never less than this many ulps of the plane coordinate, or small rects far from the origin get boxes with no
+// thickness at all
+const BOX_PAD_ULPS: f64 = 4.0;
+// Cells across each side of the grid textured lights tabulate their emission over for importance sampling
+const EMISSION_GRID_SIZE: usize = 32;
 
 impl AxisAlignedRect {
     pub fn new(
@@ -46,7 +57,7 @@ impl AxisAlignedRect {
         let a_size = amax - amin;
         let b_size = bmax - bmin;
 
-        Self {
+        let mut rect = Self {
             material,
             amin,
             amax, 
@@ -57,6 +68,35 @@ impl AxisAlignedRect {
             b_size,
             area: a_size * b_size,
             plane_axis,
+            emission_distribution: None,
+        };
+        if rect.material.is_emissive() {
+            rect.emission_distribution = rect.build_emission_distribution();
+        }
+        rect
+    }
+
+    // Luminance of the emission at the centre of each grid cell, rows along b and columns along a. None when every
+    // cell is equally bright as uniform sampling already matches it.
+    fn build_emission_distribution(&self) -> Option<Distribution2D> {
+        let cell_centre = |index: usize| (index as f64 + 0.5) / EMISSION_GRID_SIZE as f64;
+        let weights: Vec<f64> = (0..EMISSION_GRID_SIZE * EMISSION_GRID_SIZE).map(|index| {
+            let (u, v) = (cell_centre(index % EMISSION_GRID_SIZE), cell_centre(index / EMISSION_GRID_SIZE));
+            let point = self.get_point(self.amin + u * self.a_size, self.bmin + v * self.b_size);
+            self.material.emitted_radiance(u, v, &point).luminance().max(0.0)
+        }).collect();
+        if weights.iter().all(|weight| *weight == weights[0]) {
+            None
+        } else {
+            Some(Distribution2D::new(EMISSION_GRID_SIZE, EMISSION_GRID_SIZE, &weights))
+        }
+    }
+
+    pub fn get_point(&self, a: f64, b: f64) -> Vec3 {
+        match self.plane_axis {
+            AxisAlignedRectAxis::X => Vec3::new(self.c, a, b),
+            AxisAlignedRectAxis::Y => Vec3::new(a, self.c, b),
+            AxisAlignedRectAxis::Z => Vec3::new(a, b, self.c),
         }
     }
 
@@ -95,6 +135,7 @@ impl AxisAlignedRect {
 
 impl Hitable for AxisAlignedRect {
     fn hit(&self, ray: &Ray, t_min: f64, t_max: f64) -> Option<HitRecord> {
+        stats::record_primitive_hit_test();
         let t = self.get_plane_intersection(ray);
         if t < t_min || t > t_max {
             return None;
@@ -103,43 +144,154 @@ impl Hitable for AxisAlignedRect {
         if a < self.amin || a > self.amax || b < self.bmin || b > self.bmax {
             return None;
         }
+        let (u, v) = ((a - self.amin) / self.a_size, (b - self.bmin) / self.b_size);
+        let point = ray.at(t);
+        if self.material.is_cut_out(u, v, &point) {
+            return None;
+        }
         Some(HitRecord::new(
             t, 
-            (a - self.amin) / self.a_size,
-            (b - self.bmin) / self.b_size,
-            ray.point_at_parameter(t),
+            u,
+            v,
+            point,
             self.get_plane_normal(),
             self.material.clone()
         ))
     }
 
     fn bounding_box(&self, _t0: f64, _t1: f64) -> AABB {
+        let ulp = (self.c.abs() * f64::EPSILON).max(f64::MIN_POSITIVE);
+        let pad = (BOX_PAD_SCALE * self.a_size.abs().max(self.b_size.abs())).max(BOX_PAD_ULPS * ulp);
         match self.plane_axis {
-            AxisAlignedRectAxis::X => AABB::new(Vec3::new(self.c-0.0001, self.amin, self.bmin), Vec3::new(self.c+0.0001, self.amax, self.bmax)),
-            AxisAlignedRectAxis::Y => AABB::new(Vec3::new( self.amin, self.c-0.0001, self.bmin), Vec3::new(self.amax, self.c+0.0001, self.bmax)),
-            AxisAlignedRectAxis::Z => AABB::new(Vec3::new(self.amin, self.bmin, self.c-0.0001), Vec3::new(self.amax, self.bmax, self.c + 0.0001)),
+            AxisAlignedRectAxis::X => AABB::new(Vec3::new(self.c-pad, self.amin, self.bmin), Vec3::new(self.c+pad, self.amax, self.bmax)),
+            AxisAlignedRectAxis::Y => AABB::new(Vec3::new( self.amin, self.c-pad, self.bmin), Vec3::new(self.amax, self.c+pad, self.bmax)),
+            AxisAlignedRectAxis::Z => AABB::new(Vec3::new(self.amin, self.bmin, self.c-pad), Vec3::new(self.amax, self.bmax, self.c + pad)),
         }
     }
 
+    fn is_emissive(&self) -> bool {
+        self.material.is_emissive()
+    }
+
     fn pdf_value(&self, origin: &Vec3, v: &Vec3) -> f64 {
         if let Some(rec) = self.hit(&Ray::new(*origin, *v, 0.0), 0.001, std::f64::MAX) {
             let v_sq_length = v.squared_length();
             let distance_sq = rec.t * rec.t * v_sq_length;
             let cosine = (dot(v, &rec.normal) / v_sq_length.sqrt()).abs();
-            distance_sq / (cosine * self.area)
+            let uniform_pdf = distance_sq / (cosine * self.area);
+            match self.emission_distribution {
+                Some(ref distribution) => {
+                    // the chance of the cell scaled to a density over the rect's uv
+                    let cell = |uv: f64| ((uv * EMISSION_GRID_SIZE as f64) as usize).min(EMISSION_GRID_SIZE - 1);
+                    let num_cells = (EMISSION_GRID_SIZE * EMISSION_GRID_SIZE) as f64;
+                    uniform_pdf * distribution.probability(cell(rec.u), cell(rec.v)) * num_cells
+                },
+                None => uniform_pdf,
+            }
         } else {
             0.0
         }
     }
 
+    // uniform over the rect unless its emission varies, when brighter cells are picked more often
     fn random(&self, origin: &Vec3) -> Vec3 {
-        let random_a = self.amin + random::rand()*(self.a_size);
-        let random_b = self.bmin + random::rand()*(self.b_size);
-        let random_point = match self.plane_axis {
-            AxisAlignedRectAxis::X => Vec3::new(self.c, random_a, random_b),
-            AxisAlignedRectAxis::Y => Vec3::new(random_a, self.c, random_b),
-            AxisAlignedRectAxis::Z => Vec3::new(random_a, random_b, self.c),
+        let (random_a, random_b) = match self.emission_distribution {
+            Some(ref distribution) => {
+                let (column, row) = distribution.sample();
+                let u = (column as f64 + random::rand()) / EMISSION_GRID_SIZE as f64;
+                let v = (row as f64 + random::rand()) / EMISSION_GRID_SIZE as f64;
+                (self.amin + u*self.a_size, self.bmin + v*self.b_size)
+            },
+            None => (self.amin + random::rand()*(self.a_size), self.bmin + random::rand()*(self.b_size)),
         };
-        random_point - origin
+        self.get_point(random_a, random_b) - origin
     }
-}
\ No newline at end of file
+}
+
+#[cfg(test)]
+mod tests {
+
+    use super::*;
+    use material::{AlphaMasked, DiffuseLight, DummyMaterial};
+    use texture::{Texture, ConstantTexture};
+
+    // three times as bright for u < 0.5
+    struct HalfBrightTexture;
+
+    impl Texture for HalfBrightTexture {
+        fn value(&self, u: f64, _v: f64, _point: &Vec3) -> Vec3 {
+            Vec3::from_float(if u < 0.5 {3.0} else {1.0})
+        }
+    }
+
+    // 2x2 checkerboard alpha, opaque where u and v fall in the same half
+    struct CheckerAlphaTexture;
+
+    impl Texture for CheckerAlphaTexture {
+        fn value(&self, u: f64, v: f64, _point: &Vec3) -> Vec3 {
+            Vec3::from_float(if (u < 0.5) == (v < 0.5) {1.0} else {0.0})
+        }
+    }
+
+    #[test]
+    fn alpha_mask_lets_rays_through_transparent_cells() {
+        let alpha = Arc::new(CheckerAlphaTexture);
+        let masked = Arc::new(AlphaMasked::new(Arc::new(DummyMaterial::new()), alpha));
+        let rect = Arc::new(AxisAlignedRect::new(0.0, 1.0, 0.0, 1.0, 0.0, AxisAlignedRectAxis::Z, masked));
+        let backdrop = Arc::new(AxisAlignedRect::new(-1.0, 2.0, -1.0, 2.0, -1.0, AxisAlignedRectAxis::Z, Arc::new(DummyMaterial::new())));
+        let world = HitableList::new(vec![rect.clone(), backdrop]);
+
+        for &(x, y, opaque) in [(0.25, 0.25, true), (0.75, 0.25, false), (0.25, 0.75, false), (0.75, 0.75, true)].iter() {
+            let ray = Ray::new(Vec3::new(x, y, 1.0), Vec3::new(0.0, 0.0, -1.0), 0.0);
+            assert_eq!(rect.hit(&ray, 0.001, std::f64::MAX).is_some(), opaque, "cell at ({}, {})", x, y);
+            // rays through a hole carry on to whatever is behind
+            let hit = world.hit(&ray, 0.001, std::f64::MAX).unwrap();
+            assert_eq!(hit.p.z, if opaque {0.0} else {-1.0});
+        }
+    }
+
+    #[test]
+    fn bounding_box_contains_rect_across_scales() {
+        // the last is small enough next to its distance from the origin that a relative pad rounds away
+        for &(size, offset) in [(1e-4, 1e-3), (1.0, 10.0), (555.0, 5550.0), (1e6, 1e7), (1e-6, 1e7)].iter() {
+            for axis in [AxisAlignedRectAxis::X, AxisAlignedRectAxis::Y, AxisAlignedRectAxis::Z].iter() {
+                let rect = AxisAlignedRect::new(offset, offset + size, -size, size, offset, axis.clone(), Arc::new(DummyMaterial::new()));
+                let bounding_box = rect.bounding_box(0.0, 1.0);
+                let (min, max) = (bounding_box.min(), bounding_box.max());
+                for &(a, b) in [(offset, -size), (offset + size, size)].iter() {
+                    let corner = rect.get_point(a, b);
+                    assert!(corner.x >= min.x && corner.y >= min.y && corner.z >= min.z, "{:?} outside {:?}", corner, bounding_box);
+                    assert!(corner.x <= max.x && corner.y <= max.y && corner.z <= max.z, "{:?} outside {:?}", corner, bounding_box);
+                }
+                // the pad stays small next to the rect, or within a few ulps of the plane
+                let thickness = (max - min).min_component();
+                assert!(thickness > 0.0, "size {} at {} has a flat box", size, offset);
+                assert!(thickness < (0.01 * size).max(1e-14 * offset), "size {} at {} thickness = {}", size, offset, thickness);
+                // a ray through the middle of the rect isn't culled by its box
+                let centre = rect.get_point(offset + 0.5 * size, 0.0);
+                let ray = Ray::new(centre - rect.get_plane_normal() * size, rect.get_plane_normal(), 0.0);
+                assert!(bounding_box.hit(&ray, 0.0, std::f64::MAX) && rect.hit(&ray, 0.0, std::f64::MAX).is_some());
+            }
+        }
+    }
+
+    #[test]
+    fn textured_light_samples_its_bright_half() {
+        let light = AxisAlignedRect::new(-1.0, 1.0, -1.0, 1.0, 2.0, AxisAlignedRectAxis::Y, Arc::new(DiffuseLight::new(Arc::new(HalfBrightTexture))));
+        let origin = Vec3::new_zero_vector();
+        random::seed_thread_rng(1420);
+        let num_samples = 10000;
+        let num_bright = (0..num_samples).filter(|_| light.random(&origin).x < 0.0).count();
+        random::clear_thread_rng_seed();
+        let fraction_bright = num_bright as f64 / num_samples as f64;
+        assert!((fraction_bright - 0.75).abs() < 0.02, "{} of samples on the bright half", fraction_bright);
+
+        // mirrored points are the same distance and angle away so only the emission changes the pdf
+        let uniform = AxisAlignedRect::new(-1.0, 1.0, -1.0, 1.0, 2.0, AxisAlignedRectAxis::Y, Arc::new(DiffuseLight::new(Arc::new(ConstantTexture::new(Vec3::from_float(2.0))))));
+        let (bright, dim) = (Vec3::new(-0.5, 2.0, 0.3), Vec3::new(0.5, 2.0, 0.3));
+        let uniform_pdf = uniform.pdf_value(&origin, &bright);
+        assert!((uniform.pdf_value(&origin, &dim) - uniform_pdf).abs() < 1e-12);
+        assert!((light.pdf_value(&origin, &bright) - 1.5 * uniform_pdf).abs() < 1e-9);
+        assert!((light.pdf_value(&origin, &dim) - 0.5 * uniform_pdf).abs() < 1e-9);
+    }
+}
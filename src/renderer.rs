@@ -0,0 +1,69 @@
+use std::sync::Arc;
+use parking_lot::RwLock;
+use jobs::{JobCounter, JobTask, Jobs, ThreadPool};
+
+// Owns the thread pool used to trace a render so the thread count can be chosen per render and
+// the threads are shut down when the renderer is dropped
+pub struct Renderer {
+    thread_pool: ThreadPool,
+}
+
+impl Renderer {
+    pub fn new(num_threads: usize) -> Self {
+        Self {
+            thread_pool: ThreadPool::new(num_threads.max(1)),
+        }
+    }
+
+    pub fn num_threads(&self) -> usize {
+        self.thread_pool.num_threads()
+    }
+
+    pub fn dispatch(&self, job_tasks: &Vec<Arc<RwLock<dyn JobTask + Send + Sync + 'static>>>) -> Arc<JobCounter> {
+        self.thread_pool.push_job_array(job_tasks)
+    }
+
+    // blocks until every job dispatched with the counter has completed
+    pub fn wait(&self, job_counter: &JobCounter) {
+        Jobs::wait_for_counter(job_counter, 0);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+
+    use super::*;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+
+    struct CountingJob {
+        count: Arc<AtomicUsize>,
+    }
+
+    impl JobTask for CountingJob {
+        fn run(&mut self) {
+            self.count.fetch_add(1, Ordering::SeqCst);
+        }
+    }
+
+    #[test]
+    fn renderers_with_different_thread_counts() {
+        let count = Arc::new(AtomicUsize::new(0));
+        let jobs: Vec<Arc<RwLock<dyn JobTask + Send + Sync + 'static>>> = (0..32).map(|_| {
+            let job: Arc<RwLock<dyn JobTask + Send + Sync + 'static>> = Arc::new(RwLock::new(CountingJob { count: count.clone() }));
+            job
+        }).collect();
+
+        let single = Renderer::new(1);
+        let multiple = Renderer::new(3);
+        assert_eq!(single.num_threads(), 1);
+        assert_eq!(multiple.num_threads(), 3);
+
+        single.wait(&single.dispatch(&jobs));
+        assert_eq!(count.load(Ordering::SeqCst), 32);
+        multiple.wait(&multiple.dispatch(&jobs));
+        assert_eq!(count.load(Ordering::SeqCst), 64);
+
+        drop(single);
+        drop(multiple);
+    }
+}
@@ -3,7 +3,9 @@
 use crate::hitable::*;
 use std::sync::Arc;
 use crate::bvh::BvhNode;
+use crate::instance::{Instance, Transform};
 use crate::math::vec3::*;
+use crate::material::ThreadsafeMaterial;
 
 pub struct SceneBuilder {
     scene: Vec<Arc<dyn Hitable + Send + Sync + 'static>>,
@@ -33,11 +35,22 @@ impl SceneBuilder {
         last_hitable.unwrap()
     }
 
+    // hitables added so far with emissive materials, to be used as the importance sampling list
+    pub fn collect_lights(&self) -> Vec<Arc<ThreadsafeHitable>> {
+        collect_lights(&HitableList::new(self.scene.clone()))
+    }
+
     pub fn add_hitable(&mut self, hitable: Arc<dyn Hitable + Send + Sync>) -> &mut Self {
         self.scene.push(hitable);
         self
     }
 
+    // places a shared hitable with its own transform without copying it
+    pub fn add_instance(&mut self, hitable: Arc<ThreadsafeHitable>, transform: Transform) -> &mut Self {
+        self.scene.push(Arc::new(Instance::new(hitable, transform)));
+        self
+    }
+
     pub fn flip_normals(&mut self) -> &mut Self {
         let last_hitable = self.scene.pop();
         if let Some(hitable) = last_hitable {
@@ -54,6 +67,14 @@ impl SceneBuilder {
         self
     }
 
+    pub fn override_material(&mut self, mat: Arc<ThreadsafeMaterial>) -> &mut Self {
+        let last_hitable = self.scene.pop();
+        if let Some(hitable) = last_hitable {
+            self.scene.push(Arc::new(MaterialOverride::new(hitable, mat)));
+        }
+        self
+    }
+
     pub fn rotate_y(&mut self, angle: f64) -> &mut Self {
         let last_hitable = self.scene.pop();
         if let Some(hitable) = last_hitable {
@@ -63,3 +84,48 @@ impl SceneBuilder {
     }
 }
 
+#[cfg(test)]
+mod tests {
+
+    use super::*;
+    use crate::material::MaterialBuilder;
+    use crate::math::Ray;
+    use crate::rect::{AxisAlignedRect, AxisAlignedRectAxis};
+    use crate::sphere::Sphere;
+    use crate::texture::ConstantTexture;
+
+    #[test]
+    fn collect_lights_finds_diffuse_light_rect() {
+        let mut material_builder = MaterialBuilder::new();
+        let white = material_builder.with_texture(Arc::new(ConstantTexture::new(Vec3::from_float(0.73)))).lambertian();
+        let light = material_builder.with_texture(Arc::new(ConstantTexture::new(Vec3::from_float(15.0)))).diffuse_light();
+
+        let light_rect: Arc<ThreadsafeHitable> = Arc::new(AxisAlignedRect::new(213.0, 343.0, 227.0, 332.0, 554.0, AxisAlignedRectAxis::Y, light));
+        let mut scene_builder = SceneBuilder::new();
+        scene_builder.add_hitable(Arc::new(AxisAlignedRect::new(0.0, 555.0, 0.0, 555.0, 0.0, AxisAlignedRectAxis::Y, white.clone())));
+        scene_builder.add_hitable(light_rect.clone());
+        scene_builder.add_hitable(Arc::new(Sphere::new(Vec3::new(190.0, 90.0, 190.0), 90.0, white)));
+
+        let lights = scene_builder.collect_lights();
+        assert_eq!(lights.len(), 1);
+        assert!(Arc::ptr_eq(&lights[0], &light_rect));
+    }
+
+    #[test]
+    fn override_material_replaces_hit_record_material() {
+        let mut material_builder = MaterialBuilder::new();
+        let white = material_builder.with_texture(Arc::new(ConstantTexture::new(Vec3::from_float(0.73)))).lambertian();
+        let red = material_builder.with_texture(Arc::new(ConstantTexture::new(Vec3::new(0.65, 0.05, 0.05)))).lambertian();
+
+        let mut scene_builder = SceneBuilder::new();
+        scene_builder.add_hitable(Arc::new(Sphere::new(Vec3::new(0.0, 0.0, -2.0), 0.5, white.clone())))
+            .override_material(red.clone());
+        let sphere = scene_builder.as_hitable();
+
+        let ray = Ray::new(Vec3::new_zero_vector(), Vec3::new(0.0, 0.0, -1.0), 0.0);
+        let hit_record = sphere.hit(&ray, 0.001, std::f64::MAX).unwrap();
+        assert!(Arc::ptr_eq(&hit_record.mat, &red));
+        assert!(!Arc::ptr_eq(&hit_record.mat, &white));
+        assert!((hit_record.t - 1.5).abs() < 1e-9);
+    }
+}
@@ -0,0 +1,101 @@
+use trace::BackgroundMode;
+use std::path::Path;
+
+// Render settings stored alongside a scene, loaded with -scene=. One `key = value` per line, # starts a comment.
+// Every key is optional and anything set here is overridden by the matching command line flag.
+//
+//   spp = 8
+//   max_depth = 20
+//   background = 0.5,0.7,1.0
+//
+// Relative paths, like an .exr background, are relative to the scene file rather than the working directory.
+#[derive(Debug, Clone, PartialEq, Default)]
+pub struct SceneSettings {
+    pub spp: Option<u32>,
+    pub max_depth: Option<i32>,
+    pub background: Option<BackgroundMode>,
+}
+
+impl SceneSettings {
+    pub fn load(path: &str) -> Result<Self, String> {
+        let contents = std::fs::read_to_string(path)
+            .map_err(|e| format!("Could not read scene file {}: {}", path, e))?;
+        SceneSettings::parse(&contents, Path::new(path).parent().unwrap_or_else(|| Path::new("")))
+    }
+
+    // directory is the one relative paths in s are resolved against
+    fn parse(s: &str, directory: &Path) -> Result<Self, String> {
+        let mut settings = SceneSettings::default();
+        for (line_index, line) in s.lines().enumerate() {
+            let line = line.split('#').next().unwrap().trim();
+            if line.is_empty() {
+                continue;
+            }
+            let mut key_value = line.splitn(2, '=');
+            let key = key_value.next().unwrap().trim();
+            let value = key_value.next()
+                .ok_or_else(|| format!("Invalid scene file line {}: '{}', expected key = value", line_index + 1, line))?
+                .trim();
+            let invalid_value = || format!("Invalid {} '{}' on scene file line {}", key, value, line_index + 1);
+            match key {
+                "spp" => settings.spp = Some(value.parse().map_err(|_| invalid_value())?),
+                "max_depth" => settings.max_depth = Some(value.parse().map_err(|_| invalid_value())?),
+                "background" => settings.background = Some(resolve_path(value, directory).parse().map_err(|_| invalid_value())?),
+                _ => return Err(format!("Unknown scene file setting '{}' on line {}", key, line_index + 1)),
+            }
+        }
+        Ok(settings)
+    }
+}
+
+// Joins value to directory when it's the relative path of an .exr, other values are returned as they are
+fn resolve_path(value: &str, directory: &Path) -> String {
+    if value.ends_with(".exr") && Path::new(value).is_relative() {
+        directory.join(value).to_string_lossy().into_owned()
+    } else {
+        String::from(value)
+    }
+}
+
+// relative paths are resolved against the working directory
+impl std::str::FromStr for SceneSettings {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        SceneSettings::parse(s, Path::new(""))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+
+    use super::*;
+    use math::Vec3;
+
+    #[test]
+    fn parses_optional_settings_and_comments() {
+        let settings: SceneSettings = "# cornell box\nspp = 8\n\nbackground = 0.5,0.25,1 # sky\n".parse().unwrap();
+        assert_eq!(settings, SceneSettings {
+            spp: Some(8),
+            max_depth: None,
+            background: Some(BackgroundMode::SolidColour(Vec3::new(0.5, 0.25, 1.0))),
+        });
+        assert!("spp = eight".parse::<SceneSettings>().is_err());
+        assert!("exposure = 2".parse::<SceneSettings>().is_err());
+    }
+
+    #[test]
+    fn exr_background_is_found_next_to_the_scene_file() {
+        let directory = std::env::temp_dir().join("path_tracer_tests").join("scene_with_environment");
+        std::fs::create_dir_all(&directory).unwrap();
+        exr::prelude::write_rgba_file(directory.join("sky.exr"), 2, 2, |_, _| (2.0_f32, 2.0_f32, 2.0_f32, 1.0_f32)).unwrap();
+        let scene_path = directory.join("settings.scene");
+        std::fs::write(&scene_path, "background = sky.exr\n").unwrap();
+
+        // the working directory is the crate root, which has no sky.exr
+        let settings = SceneSettings::load(scene_path.to_str().unwrap()).unwrap();
+        let environment = settings.background.unwrap();
+        assert_eq!(environment.environment().unwrap().value(&Vec3::new(0.0, 1.0, 0.0)), Vec3::from_float(2.0));
+        assert!("background = sky.exr".parse::<SceneSettings>().is_err());
+    }
+}
@@ -2,28 +2,18 @@ use math::*;
 use material::Material;
 use hitable::*;
 use std::sync::Arc;
+use stats;
 use std::f64::consts::{PI, FRAC_PI_2};
 use onb::ONB;
 
+// uv of a point on the unit sphere, v runs from 0 at the south pole to 1 at the north pole
 fn get_sphere_uv(point: &Vec3) -> (f64, f64) {
-    let phi = point.z.atan2(point.x);
-    let theta = point.y.asin();
+    let (theta, phi) = point.to_spherical();
     let u = 1.0 - (phi + PI) / (PI * 2.0); // convert from [-pi, pi] to [1, 0]
     let v = (theta + FRAC_PI_2) / PI; // convert from [-pi/2, pi/2] tp [0, 1]
     (u, v)
 }
 
-fn random_to_sphere(radius: f64, distance_squared: f64) -> Vec3 {
-    let r1 = random::rand();
-    let r2 = random::rand();
-    let z = 1.0 + r2*((1.0-radius*radius/distance_squared).sqrt()-1.0);
-    let phi = 2.0*PI*r1;
-    let c = (1.0-z*z).sqrt();
-    let x = phi.cos()*c;
-    let y = phi.sin()*c;
-    Vec3::new(x, y, z)
-}
-
 pub struct Sphere {
     center: Vec3,
     radius: f64,
@@ -42,6 +32,7 @@ impl Sphere {
 
 impl Hitable for Sphere {
     fn hit(&self, ray: &Ray, t_min: f64, t_max: f64) -> Option<HitRecord> {
+        stats::record_primitive_hit_test();
         // SS: A lot of 2s cancelled out here
         let oc = ray.origin() - &self.center;
         let a = vec3::dot(&ray.direction(), &ray.direction());
@@ -52,28 +43,35 @@ impl Hitable for Sphere {
 
             let temp = (-b - (b*b-a*c).sqrt()) / a;
             if temp < t_max && temp > t_min {
-                let point = ray.point_at_parameter(temp);
-                let (u, v) = get_sphere_uv(&((&self.center - &point)/self.radius));
-                return Some(HitRecord::new(
-                    temp,
-                    u, v,
-                    point.clone(),
-                    (point - &self.center) / self.radius,
-                    Arc::clone(&self.material))
-                );
+                let point = ray.at(temp);
+                let outward_normal = (&point - &self.center) / self.radius;
+                let (u, v) = get_sphere_uv(&outward_normal);
+                // the far side shows through cut outs
+                if !self.material.is_cut_out(u, v, &point) {
+                    return Some(HitRecord::new(
+                        temp,
+                        u, v,
+                        point,
+                        outward_normal,
+                        Arc::clone(&self.material))
+                    );
+                }
             }
 
             let temp = (-b + (b*b-a*c).sqrt()) / a;
             if temp < t_max && temp > t_min {
-                let point = ray.point_at_parameter(temp);
-                let (u, v) = get_sphere_uv(&(&self.center - &point));
-                return Some(HitRecord::new(
-                    temp,
-                    u, v,
-                    point.clone(),
-                    (point - &self.center) / self.radius,
-                    Arc::clone(&self.material))
-                );
+                let point = ray.at(temp);
+                let outward_normal = (&point - &self.center) / self.radius;
+                let (u, v) = get_sphere_uv(&outward_normal);
+                if !self.material.is_cut_out(u, v, &point) {
+                    return Some(HitRecord::new(
+                        temp,
+                        u, v,
+                        point,
+                        outward_normal,
+                        Arc::clone(&self.material))
+                    );
+                }
             }
         } 
 
@@ -84,6 +82,10 @@ impl Hitable for Sphere {
         AABB::new(&self.center - Vec3::from_float(self.radius), &self.center + Vec3::from_float(self.radius))
     }
 
+    fn is_emissive(&self) -> bool {
+        self.material.is_emissive()
+    }
+
     fn pdf_value(&self, origin: &Vec3, direction: &Vec3) -> f64 {
         if let Some(_) = self.hit(&Ray::new(*origin, *direction, 0.0), 0.001, std::f64::MAX) {
             let cos_theta_max = (1.0 - self.radius*self.radius/(self.center-origin).squared_length()).sqrt();
@@ -135,6 +137,7 @@ impl MovingSphere {
 
 impl Hitable for MovingSphere {
     fn hit(&self, ray: &Ray, t_min: f64, t_max: f64) -> Option<HitRecord> {
+        stats::record_primitive_hit_test();
         // SS: A lot of 2s cancelled out here
         let center = self.center(ray.time());
         let oc = ray.origin() - &center;
@@ -146,28 +149,34 @@ impl Hitable for MovingSphere {
 
             let temp = (-b - (b*b-a*c).sqrt()) / a;
             if temp < t_max && temp > t_min {
-                let point = ray.point_at_parameter(temp);
-                let (u, v) = get_sphere_uv(&(&center - &point));
-                return Some(HitRecord::new(
-                    temp,
-                    u, v,
-                    point.clone(),
-                    (point - &center) / self.radius,
-                    Arc::clone(&self.material))
-                );
+                let point = ray.at(temp);
+                let outward_normal = (&point - &center) / self.radius;
+                let (u, v) = get_sphere_uv(&outward_normal);
+                if !self.material.is_cut_out(u, v, &point) {
+                    return Some(HitRecord::new(
+                        temp,
+                        u, v,
+                        point,
+                        outward_normal,
+                        Arc::clone(&self.material))
+                    );
+                }
             }
 
             let temp = (-b + (b*b-a*c).sqrt()) / a;
             if temp < t_max && temp > t_min {
-                let point = ray.point_at_parameter(temp);
-                let (u, v) = get_sphere_uv(&(&center - &point));
-                return Some(HitRecord::new(
-                    temp,
-                    u, v,
-                    point.clone(),
-                    (point - &center) / self.radius,
-                    Arc::clone(&self.material))
-                );
+                let point = ray.at(temp);
+                let outward_normal = (&point - &center) / self.radius;
+                let (u, v) = get_sphere_uv(&outward_normal);
+                if !self.material.is_cut_out(u, v, &point) {
+                    return Some(HitRecord::new(
+                        temp,
+                        u, v,
+                        point,
+                        outward_normal,
+                        Arc::clone(&self.material))
+                    );
+                }
             }
         } 
 
@@ -181,5 +190,58 @@ impl Hitable for MovingSphere {
         let box1 = AABB::new(&center1 - Vec3::from_float(self.radius), &center1 + Vec3::from_float(self.radius));
         AABB::get_union(&box0, &box1)
     }
+
+    fn is_emissive(&self) -> bool {
+        self.material.is_emissive()
+    }
 }
 
+#[cfg(test)]
+mod tests {
+
+    use super::*;
+    use material::DummyMaterial;
+    use texture::{ImageTexture, Texture};
+
+    fn offset_sphere() -> Sphere {
+        Sphere::new(Vec3::new(1.0, 2.0, 3.0), 2.0, Arc::new(DummyMaterial::new()))
+    }
+
+    #[test]
+    fn uvs_match_from_outside_and_inside() {
+        let sphere = offset_sphere();
+        for direction in [Vec3::new(1.0, 0.3, -0.2), Vec3::new(-0.4, -1.0, 0.6), Vec3::new(0.1, 0.9, 1.0)].iter() {
+            // a ray from the centre hits the far side, one from outside coming back hits the near side at the same point
+            let from_centre = sphere.hit(&Ray::new(Vec3::new(1.0, 2.0, 3.0), *direction, 0.0), 0.001, std::f64::MAX).unwrap();
+            let from_outside = sphere.hit(&Ray::new(&from_centre.p + &(direction * 10.0), -*direction, 0.0), 0.001, std::f64::MAX).unwrap();
+            assert!(from_centre.p.approx_eq(&from_outside.p, 1e-9));
+            assert!((from_centre.u - from_outside.u).abs() < 1e-9 && (from_centre.v - from_outside.v).abs() < 1e-9);
+        }
+    }
+
+    #[test]
+    fn uvs_are_continuous_and_north_is_up() {
+        let sphere = offset_sphere();
+        // walk from the south pole to the north pole along a meridian away from the u seam
+        let mut previous: Option<(f64, f64)> = None;
+        for step in 1..100 {
+            let theta = -FRAC_PI_2 + PI * step as f64 / 100.0;
+            let direction = Vec3::from_spherical(theta, 0.5);
+            let hit = sphere.hit(&Ray::new(&Vec3::new(1.0, 2.0, 3.0) + &(&direction * 10.0), -direction, 0.0), 0.001, std::f64::MAX).unwrap();
+            if let Some((u, v)) = previous {
+                assert!((hit.u - u).abs() < 1e-9 && hit.v > v && hit.v - v < 0.02, "jump from ({}, {}) to ({}, {})", u, v, hit.u, hit.v);
+            }
+            previous = Some((hit.u, hit.v));
+        }
+
+        // top row of the image is the north pole like the earth map
+        let image = image::RgbImage::from_raw(1, 2, vec![255, 0, 0, 0, 0, 255]).unwrap();
+        let mut bytes = vec![];
+        image::DynamicImage::ImageRgb8(image).write_to(&mut bytes, image::ImageOutputFormat::PNG).unwrap();
+        let texture = ImageTexture::new(&bytes);
+        let north = sphere.hit(&Ray::new(Vec3::new(1.0, 10.0, 3.0), Vec3::new(0.0, -1.0, 0.0), 0.0), 0.001, std::f64::MAX).unwrap();
+        let south = sphere.hit(&Ray::new(Vec3::new(1.0, -10.0, 3.0), Vec3::new(0.0, 1.0, 0.0), 0.0), 0.001, std::f64::MAX).unwrap();
+        assert_eq!(texture.value(north.u, north.v, &north.p), Vec3::new(1.0, 0.0, 0.0));
+        assert_eq!(texture.value(south.u, south.v, &south.p), Vec3::new(0.0, 0.0, 1.0));
+    }
+}
@@ -0,0 +1,130 @@
+use std::fs::File;
+use std::io::Write;
+use std::time::Duration;
+use std::sync::atomic::{AtomicUsize, Ordering};
+#[cfg(any(test, feature = "hit-counters"))]
+use std::cell::Cell;
+use parking_lot::Mutex;
+
+// Primitive intersection tests made on each thread, so tests can check how much work an acceleration
+// structure saves. Only counted in tests or with the hit-counters feature as every ray would pay for it.
+#[cfg(any(test, feature = "hit-counters"))]
+thread_local! {
+    static PRIMITIVE_HIT_TESTS: Cell<usize> = Cell::new(0);
+}
+
+#[inline]
+pub fn record_primitive_hit_test() {
+    #[cfg(any(test, feature = "hit-counters"))]
+    PRIMITIVE_HIT_TESTS.with(|count| count.set(count.get() + 1));
+}
+
+#[cfg(any(test, feature = "hit-counters"))]
+pub fn reset_primitive_hit_tests() {
+    PRIMITIVE_HIT_TESTS.with(|count| count.set(0));
+}
+
+// tests made on the calling thread since the last reset, trace jobs reset it per tile and add it to their RenderStats
+#[cfg(any(test, feature = "hit-counters"))]
+pub fn primitive_hit_tests() -> usize {
+    PRIMITIVE_HIT_TESTS.with(|count| count.get())
+}
+
+fn duration_in_secs(duration: &Duration) -> f64 {
+    duration.as_secs() as f64 + duration.subsec_nanos() as f64 * 1e-9
+}
+
+// Timing and ray count statistics gathered by the trace jobs. Jobs record once per tile
+// so the shared state is only touched when a tile completes.
+pub struct RenderStats {
+    rays_traced: AtomicUsize,
+    tile_times: Mutex<Vec<Duration>>,
+    #[cfg(any(test, feature = "hit-counters"))]
+    primitive_hit_tests: AtomicUsize, // summed over the tiles of every thread
+}
+
+impl RenderStats {
+    pub fn new() -> Self {
+        Self {
+            rays_traced: AtomicUsize::new(0),
+            tile_times: Mutex::new(vec![]),
+            #[cfg(any(test, feature = "hit-counters"))]
+            primitive_hit_tests: AtomicUsize::new(0),
+        }
+    }
+
+    pub fn record_tile(&self, duration: Duration, rays_traced: usize) {
+        self.rays_traced.fetch_add(rays_traced, Ordering::Relaxed);
+        self.tile_times.lock().push(duration);
+    }
+
+    pub fn rays_traced(&self) -> usize {
+        self.rays_traced.load(Ordering::Relaxed)
+    }
+
+    #[cfg(any(test, feature = "hit-counters"))]
+    pub fn record_primitive_hit_tests(&self, primitive_hit_tests: usize) {
+        self.primitive_hit_tests.fetch_add(primitive_hit_tests, Ordering::Relaxed);
+    }
+
+    #[cfg(any(test, feature = "hit-counters"))]
+    pub fn primitive_hit_tests(&self) -> usize {
+        self.primitive_hit_tests.load(Ordering::Relaxed)
+    }
+
+    pub fn num_tiles(&self) -> usize {
+        self.tile_times.lock().len()
+    }
+
+    pub fn clear(&self) {
+        self.rays_traced.store(0, Ordering::Relaxed);
+        self.tile_times.lock().clear();
+        #[cfg(any(test, feature = "hit-counters"))]
+        self.primitive_hit_tests.store(0, Ordering::Relaxed);
+    }
+
+    // returns (min, mean, max) tile time in seconds
+    pub fn tile_time_range(&self) -> (f64, f64, f64) {
+        let tile_times = self.tile_times.lock();
+        if tile_times.is_empty() {
+            return (0.0, 0.0, 0.0);
+        }
+        let mut min = std::f64::MAX;
+        let mut max = 0.0_f64;
+        let mut total = 0.0;
+        for tile_time in tile_times.iter() {
+            let secs = duration_in_secs(tile_time);
+            min = min.min(secs);
+            max = max.max(secs);
+            total += secs;
+        }
+        (min, total / tile_times.len() as f64, max)
+    }
+
+    pub fn rays_per_second(&self, wall_time: Duration) -> f64 {
+        let secs = duration_in_secs(&wall_time);
+        if secs > 0.0 {self.rays_traced() as f64 / secs} else {0.0}
+    }
+
+    pub fn summary(&self, wall_time: Duration) -> String {
+        let (min, mean, max) = self.tile_time_range();
+        let summary = format!("Render stats:\nwall time={:.3}s\ntiles={}\ntile time min/mean/max={:.3}s/{:.3}s/{:.3}s\nrays={}\nrays/sec={:.0}",
+                duration_in_secs(&wall_time), self.num_tiles(), min, mean, max, self.rays_traced(), self.rays_per_second(wall_time));
+        #[cfg(any(test, feature = "hit-counters"))]
+        let summary = format!("{}\nprimitive hit tests={}", summary, self.primitive_hit_tests());
+        summary
+    }
+
+    pub fn write_json(&self, filename: &std::path::Path, wall_time: Duration) -> std::io::Result<()> {
+        let (min, mean, max) = self.tile_time_range();
+        let tile_times: Vec<String> = self.tile_times.lock().iter().map(|tile_time| format!("{}", duration_in_secs(tile_time))).collect();
+        #[cfg(any(test, feature = "hit-counters"))]
+        let hit_tests = format!("  \"primitive_hit_tests\": {},\n", self.primitive_hit_tests());
+        #[cfg(not(any(test, feature = "hit-counters")))]
+        let hit_tests = String::new();
+        let json = format!("{{\n  \"wall_time_secs\": {},\n  \"rays\": {},\n  \"rays_per_sec\": {},\n{}  \"tile_time_min_secs\": {},\n  \"tile_time_mean_secs\": {},\n  \"tile_time_max_secs\": {},\n  \"tile_times_secs\": [{}]\n}}\n",
+                           duration_in_secs(&wall_time), self.rays_traced(), self.rays_per_second(wall_time), hit_tests, min, mean, max, tile_times.join(", "));
+        let mut file = File::create(filename)?;
+        file.write_all(json.as_bytes())
+    }
+}
@@ -1,5 +1,8 @@
 use math::*;
-use std::sync::Arc;
+use std::sync::{Arc, Weak};
+use std::collections::HashMap;
+use std::hash::{Hash, Hasher};
+use parking_lot::Mutex;
 use crate::noise;
 use crate::math;
 
@@ -57,32 +60,58 @@ impl Texture for CheckerTexture {
 
 pub struct NoiseTexture {
     pub scale: f64,
+    perlin: noise::Perlin,
+    period: Option<i32>,
 }
 
 impl NoiseTexture {
-    pub fn new(scale: f64) -> Self {
+    // seed is normally Config.seed so seeded renders reproduce the same noise
+    pub fn new(scale: f64, seed: Option<u64>) -> Self {
         Self {
-            scale
+            scale,
+            perlin: noise::Perlin::new(seed),
+            period: None,
         }
     }
+
+    // Makes the turbulence repeat every period units, the stripes along z also tile when scale * period is a
+    // multiple of 2 pi. Panics unless period is positive
+    pub fn with_period(&mut self, period: i32) -> &mut Self {
+        assert!(period > 0, "noise period must be positive, got {}", period);
+        self.period = Some(period);
+        self
+    }
 }
 
 impl Texture for NoiseTexture {
     fn value(&self, _u: f64, _v: f64, point: &Vec3) -> Vec3 {
-        let noise = self.scale * point.z + 10.0 * noise::Perlin::turb(point, 7);
+        let turbulence = match self.period {
+            Some(period) => self.perlin.turb_tiled(point, 7, period),
+            None => self.perlin.turb(point, 7),
+        };
+        let noise = self.scale * point.z + 10.0 * turbulence;
         Vec3::from_float(1.0) * 0.5 * (1.0 + noise.sin())
     }
 }
 
-pub struct ImageTexture {
+// RGB8 pixels decoded from an encoded image, shared between every ImageTexture made from the same bytes
+pub struct DecodedImage {
     width: u32,
     height: u32,
     data: Vec<u8>
 }
 
-impl ImageTexture {
-    pub fn new(image_bytes: &[u8]) -> Self {
+// encoded bytes and the image decoded from them, by a hash of the bytes
+type DecodedImageCache = HashMap<u64, (Vec<u8>, Weak<DecodedImage>)>;
+
+lazy_static::lazy_static! {
+    // keyed by a hash of the encoded bytes, which are kept to tell apart images whose hashes collide. Entries only
+    // live while a texture still uses them
+    static ref DECODED_IMAGES: Mutex<DecodedImageCache> = Mutex::new(HashMap::new());
+}
 
+impl DecodedImage {
+    fn decode(image_bytes: &[u8]) -> Self {
         let image = image::load_from_memory(image_bytes)
                         .expect("Binary corrupted!")
                         .to_rgb();
@@ -96,19 +125,318 @@ impl ImageTexture {
             data,
         }
     }
+
+    fn cache_key(image_bytes: &[u8]) -> u64 {
+        let mut hasher = std::collections::hash_map::DefaultHasher::new();
+        image_bytes.hash(&mut hasher);
+        hasher.finish()
+    }
+
+    fn cached(decoded_images: &DecodedImageCache, key: u64, image_bytes: &[u8]) -> Option<Arc<DecodedImage>> {
+        decoded_images.get(&key)
+            .filter(|(bytes, _)| bytes.as_slice() == image_bytes)
+            .and_then(|(_, image)| image.upgrade())
+    }
+
+    // Returns the cached decode of image_bytes, decoding them only if no texture is using them already
+    pub fn load(image_bytes: &[u8]) -> Arc<DecodedImage> {
+        let key = DecodedImage::cache_key(image_bytes);
+        let cached = DecodedImage::cached(&DECODED_IMAGES.lock(), key, image_bytes);
+        if let Some(image) = cached {
+            return image;
+        }
+
+        // decoded without holding the lock so textures loading other images aren't kept waiting
+        let image = Arc::new(DecodedImage::decode(image_bytes));
+        let mut decoded_images = DECODED_IMAGES.lock();
+        // another thread may have decoded the same bytes in the meantime, share theirs
+        if let Some(image) = DecodedImage::cached(&decoded_images, key, image_bytes) {
+            return image;
+        }
+        decoded_images.retain(|_, (_, image)| image.strong_count() > 0);
+        decoded_images.insert(key, (image_bytes.to_vec(), Arc::downgrade(&image)));
+        image
+    }
+}
+
+// How texture coordinates outside [0, 1] are brought back onto the image
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum WrapMode {
+    Clamp,
+    Repeat,
+}
+
+impl WrapMode {
+    pub fn apply(&self, coordinate: f64) -> f64 {
+        match self {
+            WrapMode::Clamp => math::clamp(&coordinate, &0.0, &1.0),
+            WrapMode::Repeat => coordinate - coordinate.floor(),
+        }
+    }
+}
+
+lazy_static::lazy_static! {
+    // linear value of each 8 bit sRGB encoded channel
+    static ref SRGB_TO_LINEAR: Vec<f64> = (0..256).map(|byte| srgb_eotf(byte as f64 / 255.0)).collect();
+}
+
+pub struct ImageTexture {
+    image: Arc<DecodedImage>,
+    wrap_mode: WrapMode,
+    decode_srgb: bool, // colour images are sRGB encoded, data like normal maps are stored linear
+}
+
+impl ImageTexture {
+    pub fn new(image_bytes: &[u8]) -> Self {
+        Self {
+            image: DecodedImage::load(image_bytes),
+            wrap_mode: WrapMode::Clamp,
+            decode_srgb: true,
+        }
+    }
+
+    pub fn with_wrap_mode(&mut self, wrap_mode: WrapMode) -> &mut Self {
+        self.wrap_mode = wrap_mode;
+        self
+    }
+
+    pub fn with_decode_srgb(&mut self, decode_srgb: bool) -> &mut Self {
+        self.decode_srgb = decode_srgb;
+        self
+    }
+
+    fn channel(&self, byte: u8) -> f64 {
+        if self.decode_srgb {
+            SRGB_TO_LINEAR[byte as usize]
+        } else {
+            byte as f64 / 255.0
+        }
+    }
 }
 
 impl Texture for ImageTexture {
     fn value(&self, u: f64, v: f64, _point: &Vec3) -> Vec3 {
-        let (width_f64, height_f64) = (self.width as f64, self.height as f64);
-        let i = u * width_f64;
-        let j = v * height_f64 - 0.001;
-        let i = math::clamp(&i, &0.0, &(width_f64 - 1.0)) as usize;
-        let j = math::clamp(&j, &0.0, &(height_f64 - 1.0)) as usize;
-        let pixel_offset = 3 * i + 3 * self.width as usize * j;
-        let r = self.data[pixel_offset] as f64 / 255.0;
-        let g = self.data[pixel_offset + 1] as f64 / 255.0;
-        let b = self.data[pixel_offset + 2] as f64 / 255.0;
+        let image = &self.image;
+        let (width, height) = (image.width as usize, image.height as usize);
+        let (u, v) = (self.wrap_mode.apply(u), self.wrap_mode.apply(v));
+        // v runs up the image, rows are stored from the top. u or v of exactly 1 would be one texel past the edge
+        let i = ((u * width as f64) as usize).min(width - 1);
+        let j = (((1.0 - v) * height as f64) as usize).min(height - 1);
+        let pixel_offset = 3 * (j * width + i);
+        let r = self.channel(image.data[pixel_offset]);
+        let g = self.channel(image.data[pixel_offset + 1]);
+        let b = self.channel(image.data[pixel_offset + 2]);
         Vec3::new(r, g, b)
     }
-}
\ No newline at end of file
+}
+
+// Scales, rotates then offsets the texture coordinates before sampling the child so patterns
+// can be tiled or turned without a new texture type. The point's x and y are transformed like u and v
+// so solid textures such as CheckerTexture follow along, z is left as it is
+pub struct TransformedTexture {
+    child: Arc<ThreadsafeTexture>,
+    scale: (f64, f64),
+    sin_theta: f64,
+    cos_theta: f64,
+    offset: (f64, f64),
+}
+
+impl TransformedTexture {
+    pub fn new(child: Arc<ThreadsafeTexture>) -> Self {
+        Self {
+            child,
+            scale: (1.0, 1.0),
+            sin_theta: 0.0,
+            cos_theta: 1.0,
+            offset: (0.0, 0.0),
+        }
+    }
+
+    pub fn with_scale(&mut self, scale_u: f64, scale_v: f64) -> &mut Self {
+        self.scale = (scale_u, scale_v);
+        self
+    }
+
+    // rotation is anticlockwise about the uv origin
+    pub fn with_rotation(&mut self, angle: f64) -> &mut Self {
+        let radians = angle.to_radians();
+        self.sin_theta = radians.sin();
+        self.cos_theta = radians.cos();
+        self
+    }
+
+    pub fn with_offset(&mut self, offset_u: f64, offset_v: f64) -> &mut Self {
+        self.offset = (offset_u, offset_v);
+        self
+    }
+
+    pub fn transform_uv(&self, u: f64, v: f64) -> (f64, f64) {
+        let (u, v) = (u * self.scale.0, v * self.scale.1);
+        let (u, v) = (self.cos_theta * u - self.sin_theta * v, self.sin_theta * u + self.cos_theta * v);
+        (u + self.offset.0, v + self.offset.1)
+    }
+
+    pub fn transform_point(&self, point: &Vec3) -> Vec3 {
+        let (x, y) = self.transform_uv(point.x, point.y);
+        Vec3::new(x, y, point.z)
+    }
+}
+
+impl Texture for TransformedTexture {
+    fn value(&self, u: f64, v: f64, point: &Vec3) -> Vec3 {
+        let (u, v) = self.transform_uv(u, v);
+        self.child.value(u, v, &self.transform_point(point))
+    }
+}
+
+// How much of the second texture a MixTexture blends in
+pub enum MixFactor {
+    Constant(f64),
+    Mask(Arc<ThreadsafeTexture>), // the mask's first channel is used
+}
+
+// Blends between two textures, lerp(a, b, factor), e.g. rust over metal with a mask
+pub struct MixTexture {
+    a: Arc<ThreadsafeTexture>,
+    b: Arc<ThreadsafeTexture>,
+    factor: MixFactor,
+}
+
+impl MixTexture {
+    pub fn new(a: Arc<ThreadsafeTexture>, b: Arc<ThreadsafeTexture>, factor: MixFactor) -> Self {
+        Self {
+            a,
+            b,
+            factor,
+        }
+    }
+}
+
+impl Texture for MixTexture {
+    fn value(&self, u: f64, v: f64, point: &Vec3) -> Vec3 {
+        let factor = match self.factor {
+            MixFactor::Constant(factor) => factor,
+            MixFactor::Mask(ref mask) => mask.value(u, v, point).x,
+        };
+        lerp(&self.a.value(u, v, point), &self.b.value(u, v, point), factor)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+
+    use super::*;
+    use hitable::Hitable;
+    use rect::{AxisAlignedRect, AxisAlignedRectAxis};
+    use material::DummyMaterial;
+
+    // number of colour changes along a line across the rect at v = 0.5, the rect sits off z = 0 where the
+    // checker's sines would all be zero
+    fn checker_transitions_across_rect(texture: &ThreadsafeTexture) -> usize {
+        let rect = AxisAlignedRect::new(0.0, 1.0, 0.0, 1.0, 0.05, AxisAlignedRectAxis::Z, Arc::new(DummyMaterial::new()));
+        let colours: Vec<Vec3> = (0..200).map(|i| {
+            let x = (i as f64 + 0.5) / 200.0;
+            let ray = Ray::new(Vec3::new(x, 0.5, 1.0), Vec3::new(0.0, 0.0, -1.0), 0.0);
+            let hit_record = rect.hit(&ray, 0.001, std::f64::MAX).unwrap();
+            texture.value(hit_record.u, hit_record.v, &hit_record.p)
+        }).collect();
+        colours.windows(2).filter(|pair| pair[0] != pair[1]).count()
+    }
+
+    #[test]
+    fn uv_scale_doubles_checker_frequency() {
+        let checker: Arc<ThreadsafeTexture> = Arc::new(CheckerTexture::new(
+            Arc::new(ConstantTexture::new(Vec3::from_float(1.0))),
+            Arc::new(ConstantTexture::new(Vec3::from_float(0.0)))));
+        let mut scaled = TransformedTexture::new(checker.clone());
+        scaled.with_scale(2.0, 2.0);
+
+        // sin(10x) changes sign at multiples of pi/10, three of which fall inside the rect
+        assert_eq!(checker_transitions_across_rect(checker.as_ref()), 3);
+        assert_eq!(checker_transitions_across_rect(&scaled), 6);
+    }
+
+    #[test]
+    fn rotation_and_offset_applied_after_scale() {
+        let mut transformed = TransformedTexture::new(Arc::new(ConstantTexture::new(Vec3::from_float(1.0))));
+        transformed.with_scale(2.0, 1.0).with_rotation(90.0).with_offset(0.5, 0.0);
+        let (u, v) = transformed.transform_uv(1.0, 0.0);
+        assert!((u - 0.5).abs() < 1e-12 && (v - 2.0).abs() < 1e-12, "({}, {})", u, v);
+        let point = transformed.transform_point(&Vec3::new(1.0, 0.0, 3.0));
+        assert!(point.approx_eq(&Vec3::new(0.5, 2.0, 3.0), 1e-12), "{}", point);
+    }
+
+    #[test]
+    fn mix_factor_blends_between_textures() {
+        let a: Arc<ThreadsafeTexture> = Arc::new(ConstantTexture::new(Vec3::new(1.0, 0.0, 0.5)));
+        let b: Arc<ThreadsafeTexture> = Arc::new(ConstantTexture::new(Vec3::new(0.0, 1.0, 0.25)));
+        let point = Vec3::new_zero_vector();
+        let mix = |factor: MixFactor| MixTexture::new(a.clone(), b.clone(), factor).value(0.0, 0.0, &point);
+
+        assert_eq!(mix(MixFactor::Constant(0.0)), Vec3::new(1.0, 0.0, 0.5));
+        assert_eq!(mix(MixFactor::Constant(1.0)), Vec3::new(0.0, 1.0, 0.25));
+        assert_eq!(mix(MixFactor::Constant(0.5)), Vec3::new(0.5, 0.5, 0.375));
+        assert_eq!(mix(MixFactor::Mask(Arc::new(ConstantTexture::new(Vec3::from_float(0.5))))), Vec3::new(0.5, 0.5, 0.375));
+    }
+
+    fn encoded_png(pixels: &[u8], width: u32, height: u32) -> Vec<u8> {
+        let image = image::RgbImage::from_raw(width, height, pixels.to_vec()).unwrap();
+        let mut bytes = vec![];
+        image::DynamicImage::ImageRgb8(image).write_to(&mut bytes, image::ImageOutputFormat::PNG).unwrap();
+        bytes
+    }
+
+    #[test]
+    fn out_of_range_uvs_use_wrap_mode() {
+        // red, green, blue along u
+        let bytes = encoded_png(&[255, 0, 0, 0, 255, 0, 0, 0, 255], 3, 1);
+        let mut texture = ImageTexture::new(&bytes);
+        let point = Vec3::new_zero_vector();
+        assert_eq!(texture.value(-0.2, 0.5, &point), Vec3::new(1.0, 0.0, 0.0));
+        assert_eq!(texture.value(1.2, 0.5, &point), Vec3::new(0.0, 0.0, 1.0));
+        assert_eq!(texture.value(1.0, 1.0, &point), Vec3::new(0.0, 0.0, 1.0));
+        assert_eq!(texture.value(0.5, -3.0, &point), Vec3::new(0.0, 1.0, 0.0));
+
+        texture.with_wrap_mode(WrapMode::Repeat);
+        assert_eq!(texture.value(1.2, 0.5, &point), Vec3::new(1.0, 0.0, 0.0));
+        assert_eq!(texture.value(-0.2, 0.5, &point), Vec3::new(0.0, 0.0, 1.0));
+    }
+
+    #[test]
+    fn srgb_texels_decode_to_linear() {
+        let bytes = encoded_png(&[128, 128, 128], 1, 1);
+        let mut texture = ImageTexture::new(&bytes);
+        let point = Vec3::new_zero_vector();
+        let grey = texture.value(0.5, 0.5, &point);
+        assert!((grey.x - 0.216).abs() < 0.001, "grey = {}", grey);
+        assert_eq!(grey.x, grey.y);
+
+        texture.with_decode_srgb(false);
+        assert!((texture.value(0.5, 0.5, &point).x - 0.502).abs() < 0.001);
+    }
+
+    #[test]
+    fn image_textures_from_same_bytes_share_decode() {
+        let bytes = encoded_png(&[255, 0, 0, 0, 255, 0], 2, 1);
+        let a = ImageTexture::new(&bytes);
+        let b = ImageTexture::new(&bytes.clone());
+        assert!(Arc::ptr_eq(&a.image, &b.image));
+        assert_eq!(a.value(0.25, 0.5, &Vec3::new_zero_vector()), Vec3::new(1.0, 0.0, 0.0));
+
+        let other = ImageTexture::new(&encoded_png(&[0, 0, 255, 0, 255, 0], 2, 1));
+        assert!(!Arc::ptr_eq(&a.image, &other.image));
+        assert_eq!(other.value(0.25, 0.5, &Vec3::new_zero_vector()), Vec3::new(0.0, 0.0, 1.0));
+    }
+
+    #[test]
+    fn image_textures_with_colliding_hashes_decode_separately() {
+        let red = ImageTexture::new(&encoded_png(&[255, 0, 0], 1, 1));
+        let blue_bytes = encoded_png(&[0, 0, 255], 1, 1);
+        // file the red image under the blue bytes' hash as if the two collided
+        DECODED_IMAGES.lock().insert(DecodedImage::cache_key(&blue_bytes), (encoded_png(&[255, 0, 0], 1, 1), Arc::downgrade(&red.image)));
+
+        let blue = ImageTexture::new(&blue_bytes);
+        assert!(!Arc::ptr_eq(&red.image, &blue.image));
+        assert_eq!(blue.value(0.5, 0.5, &Vec3::new_zero_vector()), Vec3::new(0.0, 0.0, 1.0));
+    }
+}
@@ -0,0 +1,165 @@
+use std::cmp::Ordering;
+use trace::TileRect;
+
+// Channels per pixel in the render buffers
+const CHANNELS: usize = 4;
+
+// Order tiles are dispatched in. Rendering the centre first gives better perceived progress
+// while the hilbert curve keeps consecutive tiles close together for cache locality.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum TileOrder {
+    RowMajor,
+    Spiral,
+    Hilbert,
+}
+
+impl std::str::FromStr for TileOrder {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "rowmajor" => Ok(TileOrder::RowMajor),
+            "spiral" => Ok(TileOrder::Spiral),
+            "hilbert" => Ok(TileOrder::Hilbert),
+            _ => Err(format!("Unknown tile order '{}', expected rowmajor, spiral or hilbert", s)),
+        }
+    }
+}
+
+// Returns the (x, y) index of every tile in a num_tiles_xy grid in dispatch order
+pub fn ordered_tiles(num_tiles_xy: (u32, u32), order: TileOrder) -> Vec<(u32, u32)> {
+    let mut tiles = Vec::with_capacity((num_tiles_xy.0 * num_tiles_xy.1) as usize);
+    for tile_y in 0..num_tiles_xy.1 {
+        for tile_x in 0..num_tiles_xy.0 {
+            tiles.push((tile_x, tile_y));
+        }
+    }
+
+    match order {
+        TileOrder::RowMajor => {},
+        TileOrder::Spiral => {
+            // walk outwards ring by ring from the centre tile, sweeping each ring by angle
+            let centre = ((num_tiles_xy.0 / 2) as i64, (num_tiles_xy.1 / 2) as i64);
+            let spiral_key = |tile: &(u32, u32)| {
+                let (dx, dy) = (tile.0 as i64 - centre.0, tile.1 as i64 - centre.1);
+                let ring = dx.abs().max(dy.abs());
+                let angle = (dy as f64).atan2(dx as f64);
+                (ring, angle)
+            };
+            tiles.sort_by(|a, b| {
+                let (ring_a, angle_a) = spiral_key(a);
+                let (ring_b, angle_b) = spiral_key(b);
+                ring_a.cmp(&ring_b).then(angle_a.partial_cmp(&angle_b).unwrap_or(Ordering::Equal))
+            });
+        },
+        TileOrder::Hilbert => {
+            let size = num_tiles_xy.0.max(num_tiles_xy.1).next_power_of_two();
+            tiles.sort_by_key(|tile| hilbert_index(size, tile.0, tile.1));
+        },
+    }
+
+    tiles
+}
+
+// Distance along a hilbert curve filling a size x size grid, size must be a power of two
+fn hilbert_index(size: u32, x: u32, y: u32) -> u64 {
+    let (mut x, mut y) = (x, y);
+    let mut index = 0;
+    let mut s = size / 2;
+    while s > 0 {
+        let rx = if x & s > 0 {1} else {0};
+        let ry = if y & s > 0 {1} else {0};
+        index += s as u64 * s as u64 * ((3 * rx) ^ ry) as u64;
+        // rotate the quadrant so the sub-curve lines up
+        if ry == 0 {
+            if rx == 1 {
+                x = size - 1 - x;
+                y = size - 1 - y;
+            }
+            std::mem::swap(&mut x, &mut y);
+        }
+        s /= 2;
+    }
+    index
+}
+
+// Image held as separate per-tile RGBA buffers, each row-major from its bottom left pixel, so tiles
+// can be filled without sharing a buffer. assemble() gathers them into one contiguous image.
+pub struct TiledImageBuffer {
+    image_size: (u32, u32),
+    tiles: Vec<(TileRect, Vec<f32>)>,
+}
+
+impl TiledImageBuffer {
+    pub fn new(image_size: (u32, u32)) -> TiledImageBuffer {
+        TiledImageBuffer {
+            image_size,
+            tiles: vec![],
+        }
+    }
+
+    pub fn add_tile(&mut self, tile: TileRect, pixels: Vec<f32>) {
+        let ((tile_x, tile_y), (tile_width, tile_height)) = tile;
+        assert!(tile_x + tile_width <= self.image_size.0 && tile_y + tile_height <= self.image_size.1, "tile {:?} outside {:?} image", tile, self.image_size);
+        assert_eq!(pixels.len(), tile_width as usize * tile_height as usize * CHANNELS);
+        self.tiles.push((tile, pixels));
+    }
+
+    pub fn num_tiles(&self) -> usize {
+        self.tiles.len()
+    }
+
+    // Stitches the tiles into a row-major image the size of the whole buffer, pixels not covered by a tile are zero
+    pub fn assemble(&self) -> Vec<f32> {
+        let row_len = self.image_size.0 as usize * CHANNELS;
+        let mut image = vec![0.0; row_len * self.image_size.1 as usize];
+        for (((tile_x, tile_y), (tile_width, tile_height)), pixels) in self.tiles.iter() {
+            let tile_row_len = *tile_width as usize * CHANNELS;
+            for (tile_row, row_pixels) in pixels.chunks_exact(tile_row_len).take(*tile_height as usize).enumerate() {
+                let dst = (*tile_y as usize + tile_row) * row_len + *tile_x as usize * CHANNELS;
+                image[dst..dst + tile_row_len].copy_from_slice(row_pixels);
+            }
+        }
+        image
+    }
+}
+
+#[cfg(test)]
+mod tests {
+
+    use super::*;
+
+    #[test]
+    fn spiral_starts_at_centre_tile() {
+        // the image centre falls in tile (4, 4) of a 9x9 grid and on the corner of tile (5, 5) of a 10x10 grid
+        assert_eq!(ordered_tiles((9, 9), TileOrder::Spiral)[0], (4, 4));
+        assert_eq!(ordered_tiles((10, 10), TileOrder::Spiral)[0], (5, 5));
+    }
+
+    #[test]
+    fn assembling_tiles_reproduces_gradient() {
+        let image_size = (6, 4);
+        let gradient = |x: u32, y: u32| [x as f32 / 5.0, y as f32 / 3.0, (x + y) as f32, 1.0];
+        let expected: Vec<f32> = (0..image_size.1).flat_map(|y| (0..image_size.0).flat_map(move |x| gradient(x, y).to_vec())).collect();
+
+        // uneven quadrants added out of order
+        let mut buffer = TiledImageBuffer::new(image_size);
+        for tile in [((4, 1), (2, 3)), ((0, 0), (4, 1)), ((0, 1), (4, 3)), ((4, 0), (2, 1))].iter() {
+            let ((tile_x, tile_y), (tile_width, tile_height)) = *tile;
+            let pixels = (tile_y..tile_y + tile_height).flat_map(|y| (tile_x..tile_x + tile_width).flat_map(move |x| gradient(x, y).to_vec())).collect();
+            buffer.add_tile(*tile, pixels);
+        }
+        assert_eq!(buffer.num_tiles(), 4);
+        assert_eq!(buffer.assemble(), expected);
+    }
+
+    #[test]
+    fn every_order_visits_each_tile_once() {
+        for order in [TileOrder::RowMajor, TileOrder::Spiral, TileOrder::Hilbert].iter() {
+            let mut tiles = ordered_tiles((7, 5), *order);
+            tiles.sort();
+            tiles.dedup();
+            assert_eq!(tiles.len(), 35);
+        }
+    }
+}
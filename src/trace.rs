@@ -1,28 +1,252 @@
 use std::f64;
 use std::sync::Arc;
-use parking_lot::{RwLock};
+use parking_lot::{RwLock, Mutex};
 use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use std::time::Instant;
 
 use math::*;
 use hitable::*;
-use camera::Camera;
+use camera::{self, Camera};
 
 use jobs::JobTask;
 use jobs::MultiSliceReadWriteLock;
 use super::Config;
-use material::{PDF, HittablePDF, MixturePDF, DummyMaterial};
+use material::{PDF, HittablePDF, MixturePDF, DummyMaterial, Lambertian, SamplingHint, ScatterResult, MediumStack, ThreadsafeMaterial};
+use texture::ConstantTexture;
 use rect::{AxisAlignedRect, AxisAlignedRectAxis};
+use stats::{self, RenderStats};
+use grid;
+use environment::EnvironmentMap;
 
 // Number of lines to wait before updating the backbuffer. Smaller the number worse the performance.
 const RENDER_UPDATE_LATENCY: u32 = 20; 
 const ENABLE_RENDER: bool = true;
 const CHANCE_TO_SKIP_TASK_PER_FRAME: f64 = 0.0;
 const CHANCE_TO_SKIP_PIXEL_PER_FRAME: f64 = 0.8;
+// auto exposure reads at most this many pixels along each axis so its cost doesn't grow with resolution
+const LOG_AVERAGE_SAMPLES_PER_AXIS: u32 = 128;
+// distance new rays are moved off a surface, relative to the size of the hit point's coordinates
+const RAY_ORIGIN_OFFSET: f64 = 1e-7;
+// albedo of every surface but the lights in a clay render
+const CLAY_ALBEDO: f64 = 0.5;
+
+lazy_static::lazy_static! {
+    static ref CLAY_MATERIAL: Arc<ThreadsafeMaterial> = Arc::new(Lambertian::new(Arc::new(ConstantTexture::new(Vec3::from_float(CLAY_ALBEDO))), 0.0));
+}
+
+// Filter used to weight each sample by its offset from the pixel centre when resolving a pixel.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum ReconstructionFilter {
+    Box,
+    Tent,
+    Gaussian,
+}
+
+impl ReconstructionFilter {
+    // offsets are relative to the pixel centre in pixels, i.e. in the range [-0.5, 0.5]
+    pub fn weight(&self, offset_x: f64, offset_y: f64) -> f64 {
+        match self {
+            ReconstructionFilter::Box => 1.0,
+            // tent with a radius of one pixel so samples at the pixel edge still contribute
+            ReconstructionFilter::Tent => (1.0 - offset_x.abs()).max(0.0) * (1.0 - offset_y.abs()).max(0.0),
+            // gaussian with a standard deviation of half a pixel
+            ReconstructionFilter::Gaussian => (-2.0 * (offset_x*offset_x + offset_y*offset_y)).exp(),
+        }
+    }
+}
+
+impl std::str::FromStr for ReconstructionFilter {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "box" => Ok(ReconstructionFilter::Box),
+            "tent" => Ok(ReconstructionFilter::Tent),
+            "gaussian" => Ok(ReconstructionFilter::Gaussian),
+            _ => Err(format!("Unknown reconstruction filter '{}', expected box, tent or gaussian", s)),
+        }
+    }
+}
+
+// Where a pixel's samples land. Random draws each pixel offset, lens position and time independently, Halton and
+// Sobol follow low discrepancy sequences indexed by the pixel's accumulated sample count so they cover the pixel
+// more evenly and converge faster. Every pixel shifts the sequence by its own random rotation (Cranley-Patterson)
+// so neighbours don't repeat the same pattern.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum SamplingStrategy {
+    Random,
+    Halton,
+    Sobol,
+}
+
+impl SamplingStrategy {
+    // Pixel offset x and y, lens u and v and time of a pixel's sample_index'th sample, all in [0, 1).
+    // None for random sampling, which draws them from the thread's generator as it goes.
+    pub fn sample(&self, pixel_seed: u64, sample_index: u32) -> Option<[f64; LOW_DISCREPANCY_DIMENSIONS]> {
+        if *self == SamplingStrategy::Random {
+            return None;
+        }
+        let mut sample = [0.0; LOW_DISCREPANCY_DIMENSIONS];
+        for (dimension, value) in sample.iter_mut().enumerate() {
+            let point = match self {
+                SamplingStrategy::Halton => halton(sample_index as u64, dimension),
+                _ => sobol(sample_index, dimension),
+            };
+            let rotation = (random::stream_seed(pixel_seed, dimension as u64) >> 11) as f64 / (1u64 << 53) as f64;
+            *value = (point + rotation).fract();
+        }
+        Some(sample)
+    }
+}
+
+impl std::str::FromStr for SamplingStrategy {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "random" => Ok(SamplingStrategy::Random),
+            "halton" => Ok(SamplingStrategy::Halton),
+            "sobol" => Ok(SamplingStrategy::Sobol),
+            _ => Err(format!("Unknown sampler '{}', expected random, halton or sobol", s)),
+        }
+    }
+}
+
+// What each pixel records. Beauty is the traced radiance, the others are arbitrary output variables (AOVs)
+// of the camera ray's first hit for compositing, averaged over the samples that hit geometry.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum RenderMode {
+    Beauty,
+    Depth, // distance along the camera ray to the first hit, written to every channel
+    Position, // world space position of the first hit
+    Albedo, // albedo of the first non-specular hit, a denoiser guide
+    Normal, // shading normal of the first non-specular hit, a denoiser guide
+}
+
+impl std::str::FromStr for RenderMode {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "beauty" => Ok(RenderMode::Beauty),
+            "depth" => Ok(RenderMode::Depth),
+            "position" => Ok(RenderMode::Position),
+            "albedo" => Ok(RenderMode::Albedo),
+            "normal" => Ok(RenderMode::Normal),
+            _ => Err(format!("Unknown render mode '{}', expected beauty, depth, position, albedo or normal", s)),
+        }
+    }
+}
+
+// How paths end. Russian roulette randomly ends paths after Config.min_bounces, fixed depth always traces to
+// Config.max_depth so renders only vary with the sampling, for comparing against reference images.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Termination {
+    FixedDepth,
+    RussianRoulette,
+}
+
+impl std::str::FromStr for Termination {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "fixed-depth" => Ok(Termination::FixedDepth),
+            "russian-roulette" => Ok(Termination::RussianRoulette),
+            _ => Err(format!("Unknown termination '{}', expected fixed-depth or russian-roulette", s)),
+        }
+    }
+}
+
+// Chance of color() sampling a direction towards the importance sampled shapes rather than from the material's pdf.
+// By light count gives every shape the same chance as the material, n / (n + 1) for n shapes.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum LightMix {
+    Fixed(f64),
+    ByLightCount,
+}
+
+impl LightMix {
+    pub fn weight(&self, num_lights: usize) -> f64 {
+        match *self {
+            LightMix::Fixed(weight) => weight,
+            LightMix::ByLightCount => num_lights as f64 / (num_lights + 1) as f64,
+        }
+    }
+}
+
+impl std::str::FromStr for LightMix {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "lights" => Ok(LightMix::ByLightCount),
+            _ => match s.parse::<f64>() {
+                Ok(weight) if (0.0..=1.0).contains(&weight) => Ok(LightMix::Fixed(weight)),
+                _ => Err(format!("Unknown light mix '{}', expected a weight in 0..1 or lights", s)),
+            },
+        }
+    }
+}
+
+// Radiance returned by rays that miss all geometry
+#[derive(Debug, Clone, PartialEq)]
+pub enum BackgroundMode {
+    Black,
+    Gradient,
+    SolidColour(Vec3),
+    Environment(Arc<EnvironmentMap>), // lights the scene, importance sampled by next event estimation
+}
+
+impl BackgroundMode {
+    // sky_brightness scales the gradient sky only, the other backgrounds are given at the radiance they light the scene with
+    pub fn colour(&self, direction: &Vec3, sky_brightness: f64) -> Vec3 {
+        match self {
+            BackgroundMode::Black => Vec3::from_float(0.0),
+            BackgroundMode::Gradient => {
+                let unit_dir = Vec3::new_unit_vector(direction);
+                let t = 0.5*(unit_dir.y + 1.0);
+                let white = Vec3::from_float(1.0);
+                let sky = Vec3::new(0.5, 0.7, 1.0);
+                lerp(&white, &sky, t) * sky_brightness
+            },
+            BackgroundMode::SolidColour(colour) => *colour,
+            BackgroundMode::Environment(environment) => environment.value(direction),
+        }
+    }
+
+    pub fn environment(&self) -> Option<&EnvironmentMap> {
+        match self {
+            BackgroundMode::Environment(environment) => Some(environment),
+            _ => None,
+        }
+    }
+}
+
+// black, gradient, a solid colour given as r,g,b or the path of an .exr environment map
+impl std::str::FromStr for BackgroundMode {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "black" => Ok(BackgroundMode::Black),
+            "gradient" => Ok(BackgroundMode::Gradient),
+            _ if s.ends_with(".exr") => Ok(BackgroundMode::Environment(Arc::new(EnvironmentMap::load(s)?))),
+            _ => parse_vec3(s).map(BackgroundMode::SolidColour)
+                .ok_or_else(|| format!("Unknown background '{}', expected black, gradient, r,g,b or an .exr environment map", s)),
+        }
+    }
+}
+
+// Bottom left pixel and size of a tile in the output buffer
+pub type TileRect = ((u32, u32), (u32, u32));
 
 pub struct SceneOutput {
     pub buffer: MultiSliceReadWriteLock<Vec<f32>>,
     pub window_lock: AtomicBool, 
     pub remaining_tasks: AtomicUsize,
+    pub stats: RenderStats,
+    completed_tiles: Option<Mutex<Vec<TileRect>>>, // tiles finished since the last take_completed_tiles, when tracked
 }
 
 impl SceneOutput {
@@ -32,14 +256,45 @@ impl SceneOutput {
             buffer,
             window_lock,
             remaining_tasks,
+            stats: RenderStats::new(),
+            completed_tiles: None,
         }
     }
 
-    pub fn notify_task_completion(&self) {
+    // records finished tiles so only those need presenting, left off when nothing takes them as they would pile up
+    pub fn with_completed_tiles(mut self) -> Self {
+        self.completed_tiles = Some(Mutex::new(vec![]));
+        self
+    }
+
+    pub fn notify_task_completion(&self, tile: TileRect) {
+        if let Some(ref completed_tiles) = self.completed_tiles {
+            completed_tiles.lock().push(tile);
+        }
         self.remaining_tasks.fetch_sub(1, Ordering::SeqCst);
     }
+
+    pub fn take_completed_tiles(&self) -> Vec<TileRect> {
+        match self.completed_tiles {
+            Some(ref completed_tiles) => std::mem::replace(&mut *completed_tiles.lock(), vec![]),
+            None => vec![],
+        }
+    }
+}
+
+// Shapes the mixture pdf of LightMis bounces samples towards when next event estimation is off, their primitives
+// are counted once when the scene is built rather than on every bounce
+pub struct ShapeIntegrators {
+    shapes: Arc<ThreadsafeHitable>,
+    num_primitives: usize,
 }
 
+impl ShapeIntegrators {
+    pub fn new(shapes: Arc<ThreadsafeHitable>) -> ShapeIntegrators {
+        let num_primitives = shapes.primitive_count();
+        ShapeIntegrators { shapes, num_primitives }
+    }
+}
 
 pub struct SceneState {
     pub cam: Camera,
@@ -49,12 +304,27 @@ pub struct SceneState {
     pub sky_brightness: f64,
     pub disable_emissive: bool,
     pub config: Config,
+    pub deadline: Option<Instant>, // offline renders don't start another pass once this has passed, so stop on a whole pass
+    pub sample_mask: Option<Vec<bool>>, // when set only pixels flagged in the mask are traced, laid out like the output buffer
+    pub lights: Option<Arc<ThreadsafeHitable>>, // emissive hitables in the world, sampled directly for next event estimation
+    pub num_lights: usize, // emissive hitables collected into lights, weights the light mix of -light-mix=lights
+    pub shape_integrators: ShapeIntegrators,
 }
 
 impl SceneState {
     pub fn new(cam: Camera, world: Box<dyn Hitable + Send + Sync + 'static>, time0: f64, time1: f64, 
                sky_brightness: f64, disable_emissive: bool, config: Config) -> SceneState {
             
+        let world = grid::accelerate(world, config.accel, time0, time1);
+        let lights = collect_lights(world.as_ref());
+        let num_lights = lights.len();
+        let lights: Option<Arc<ThreadsafeHitable>> = if lights.is_empty() {None} else {Some(Arc::new(HitableList::new(lights)))};
+        let shape_integrators = {
+            let light_shape = AxisAlignedRect::new(213.0,343.0,227.0,332.0,554.0,AxisAlignedRectAxis::Y, Arc::new(DummyMaterial::new()));
+            let glass_sphere = crate::sphere::Sphere::new(Vec3::new(190.0, 90.0, 190.0), 90.0, Arc::new(DummyMaterial::new()));
+            let list: Vec<Arc<ThreadsafeHitable>> = vec![Arc::new(light_shape), Arc::new(glass_sphere)];
+            ShapeIntegrators::new(Arc::new(HitableList::new(list)))
+        };
         SceneState {
             cam,
             world,
@@ -62,7 +332,12 @@ impl SceneState {
             time1,
             sky_brightness,
             disable_emissive,
-            config
+            config,
+            deadline: None,
+            sample_mask: None,
+            lights,
+            num_lights,
+            shape_integrators,
         }
     }
 }
@@ -77,8 +352,9 @@ pub struct TraceSceneBatchJob {
     shared_scene_read_state: Arc<RwLock<SceneState>>,
     shared_scene_write_state: Arc<SceneOutput>,
     num_frames: i32,
-    num_frames_per_pixel: Vec<u32>,
+    num_samples_per_pixel: Vec<u32>,
     realtime: bool,
+    seed: Option<u64>, // when set each trace reseeds the thread's generator so the tile is reproducible
 }
 
 impl TraceSceneBatchJob {
@@ -104,28 +380,40 @@ impl TraceSceneBatchJob {
             shared_scene_read_state,
             shared_scene_write_state,
             num_frames: 0,
-            num_frames_per_pixel: vec![0; (num_pixels_xy.0*num_pixels_xy.1) as usize],
+            num_samples_per_pixel: vec![0; (num_pixels_xy.0*num_pixels_xy.1) as usize],
             realtime,
+            seed: None,
         }
     }
 
     pub fn clear_buffer(&mut self) {
         self.num_frames = 0;
-        self.num_frames_per_pixel = vec![0; (self.num_pixels_xy.0*self.num_pixels_xy.1) as usize];
+        self.num_samples_per_pixel = vec![0; (self.num_pixels_xy.0*self.num_pixels_xy.1) as usize];
+    }
+
+    pub fn set_num_samples(&mut self, num_samples: u32) {
+        self.num_samples = num_samples;
+    }
+
+    // Seeds the tile from base_seed ^ tile_index so tiles sharing a base seed don't repeat the same noise
+    pub fn set_seed(&mut self, base_seed: u64, tile_index: u32) {
+        self.seed = Some(random::stream_seed(base_seed, tile_index as u64));
     }
 
     fn trace(&mut self) {
 
+        let tile_timer = Instant::now();
+        let mut rays_traced = 0;
+        #[cfg(any(test, feature = "hit-counters"))]
+        stats::reset_primitive_hit_tests();
+
         //self.num_frames += if self.num_frames == 500 {0} else {1};
         self.num_frames += 1;//if self.num_frames == 500 {0} else {1};
+        if let Some(seed) = self.seed {
+            // a new stream per pass, otherwise every pass would trace the same samples
+            random::seed_thread_rng(random::stream_seed(seed, self.num_frames as u64));
+        }
         let read_state = self.shared_scene_read_state.read();
-        
-        let hlist: Arc<ThreadsafeHitable>  = {
-            let light_shape = AxisAlignedRect::new(213.0,343.0,227.0,332.0,554.0,AxisAlignedRectAxis::Y, Arc::new(DummyMaterial::new()));
-            let glass_sphere = crate::sphere::Sphere::new(Vec3::new(190.0, 90.0, 190.0), 90.0, Arc::new(DummyMaterial::new()));
-            let list: Vec<Arc<ThreadsafeHitable>> = vec![Arc::new(light_shape), Arc::new(glass_sphere)];
-            Arc::new(HitableList::new(list))
-        };
 
         //if read_state.config.realtime && random::rand() < CHANCE_TO_SKIP_TASK_PER_FRAME {
         //    self.shared_scene_write_state.notify_task_completion();
@@ -145,48 +433,110 @@ impl TraceSceneBatchJob {
                    continue;
                }
 
+                if let Some(ref sample_mask) = read_state.sample_mask {
+                    if !sample_mask[(j * self.image_size.0 + i) as usize] {
+                        continue;
+                    }
+                }
+
                 let local_pixel_idx = row_idx * self.num_pixels_xy.0 as usize + col_idx;
-                self.num_frames_per_pixel[local_pixel_idx] += if self.num_frames_per_pixel[local_pixel_idx] <= 1000 {1} else {0};
+                // low discrepancy sequences carry on from the samples already accumulated
+                let first_sample_index = self.num_samples_per_pixel[local_pixel_idx];
+                let pixel_seed = random::stream_seed(read_state.config.seed.unwrap_or(0), (j * self.image_size.0 + i) as u64);
+                // realtime pixels stop once converged, offline accumulates every pass
+                let max_samples = if read_state.config.realtime {Some(read_state.config.max_accumulation * self.num_samples)} else {None};
+                let weight = match accumulation_weight(&mut self.num_samples_per_pixel[local_pixel_idx], self.num_samples, max_samples) {
+                    Some(weight) => weight,
+                    None => continue,
+                };
 
-                let mut pixel_colour = Vec3::new_zero_vector();
-                for _ in 0..self.num_samples {
-                    let random = random::rand();
-                    let u: f64 = ((i as f64) + random) / (self.image_size.0 as f64);
-                    let random = random::rand();
-                    let v: f64 = ((j as f64) + random) / (self.image_size.1 as f64);
+                let render_mode = read_state.config.render_mode;
+                let trace_ray = |r: &Ray, lights: Option<&Arc<ThreadsafeHitable>>, rays_traced: &mut usize| match render_mode {
+                    RenderMode::Beauty => color(r, &TraceContext::new(read_state.world.as_ref(), &read_state.shape_integrators, &read_state.config, lights).with_num_lights(read_state.num_lights).with_sky_brightness(read_state.sky_brightness), None, MediumStack::new(), 0, rays_traced),
+                    _ => aov(r, read_state.world.as_ref(), &read_state.config, rays_traced),
+                };
 
-                    let r = read_state.cam.get_ray(u, v);
-                    pixel_colour += color(&r, &read_state.world,  &hlist, 0, read_state.config.max_depth);
+                let mut total_weight = 0.0;
+                let mut coverage = 0.0;
+                let mut pixel_colour: Vec3 = (0..self.num_samples).map(|sample| {
+                    let samples = read_state.config.sampler.sample(pixel_seed, first_sample_index + sample);
+                    let camera_ray = |s: f64, t: f64| match samples {
+                        Some(samples) => read_state.cam.get_ray_sampled(s, t, (samples[2], samples[3]), samples[4]),
+                        None => read_state.cam.get_ray(s, t),
+                    };
+                    let random_x = samples.map_or_else(random::rand, |samples| samples[0]);
+                    let u: f64 = ((i as f64) + random_x) / (self.image_size.0 as f64);
+                    let random_y = samples.map_or_else(random::rand, |samples| samples[1]);
+                    let v: f64 = ((j as f64) + random_y) / (self.image_size.1 as f64);
+                    let weight = read_state.config.filter.weight(random_x - 0.5, random_y - 0.5);
+                    total_weight += weight;
 
                     // SS: Debug uv image
-                    // col += Vec3::new(u, v, 0.0);
-                }
+                    // return Vec3::new(u, v, 0.0);
+                    let aberration = read_state.config.aberration;
+                    let lights = if read_state.config.nee {read_state.lights.as_ref()} else {None};
+                    if aberration != 0.0 {
+                        // each channel follows its own ray
+                        let mut channel_colour = |channel: usize| {
+                            let (channel_u, channel_v) = camera::channel_st(u, v, channel, aberration);
+                            let r = camera_ray(channel_u, channel_v);
+                            let (colour, hit) = trace_ray(&r, lights, &mut rays_traced);
+                            coverage += if hit {weight / 3.0} else {0.0};
+                            colour
+                        };
+                        let (red, green, blue) = (channel_colour(0).x, channel_colour(1).y, channel_colour(2).z);
+                        weight * Vec3::new(red, green, blue)
+                    } else {
+                        let r = camera_ray(u, v);
+                        let (colour, hit) = trace_ray(&r, lights, &mut rays_traced);
+                        coverage += if hit {weight} else {0.0};
+                        weight * colour
+                    }
+                }).sum();
 
                 // PDF
-                pixel_colour = pixel_colour / self.num_samples as f64;
+                if render_mode != RenderMode::Beauty && coverage > 0.0 {
+                    // misses would drag the AOV towards zero along silhouettes
+                    pixel_colour = pixel_colour / coverage;
+                    coverage = coverage / total_weight;
+                } else if total_weight > 0.0 {
+                    pixel_colour = pixel_colour / total_weight;
+                    coverage = coverage / total_weight;
+                }
 
                 let index = col_idx*4 as usize;
 
-                if read_state.config.realtime {
-                    let num_frames = self.num_frames_per_pixel[local_pixel_idx];
-                    let weight = 1.0 / num_frames as f32;
-                    let one_minus_weight: f32 = 1.0 - weight;
-
-                    dest_buffer_row_slice[index]     = (pixel_colour.x as f32) * weight + dest_buffer_row_slice[index    ] * one_minus_weight;
-                    dest_buffer_row_slice[index + 1] = (pixel_colour.y as f32) * weight + dest_buffer_row_slice[index + 1] * one_minus_weight;
-                    dest_buffer_row_slice[index + 2] = (pixel_colour.z as f32) * weight + dest_buffer_row_slice[index + 2] * one_minus_weight;
-                } else {
-                    dest_buffer_row_slice[index]     = pixel_colour.x as f32;
-                    dest_buffer_row_slice[index + 1] = pixel_colour.y as f32;
-                    dest_buffer_row_slice[index + 2] = pixel_colour.z as f32;
-                }
+                let one_minus_weight: f32 = 1.0 - weight;
 
+                dest_buffer_row_slice[index]     = (pixel_colour.x as f32) * weight + dest_buffer_row_slice[index    ] * one_minus_weight;
+                dest_buffer_row_slice[index + 1] = (pixel_colour.y as f32) * weight + dest_buffer_row_slice[index + 1] * one_minus_weight;
+                dest_buffer_row_slice[index + 2] = (pixel_colour.z as f32) * weight + dest_buffer_row_slice[index + 2] * one_minus_weight;
+                dest_buffer_row_slice[index + 3] = (coverage as f32) * weight + dest_buffer_row_slice[index + 3] * one_minus_weight;
             }
         }
 
+        if self.seed.is_some() {
+            random::clear_thread_rng_seed();
+        }
+
+        self.shared_scene_write_state.stats.record_tile(tile_timer.elapsed(), rays_traced);
+        #[cfg(any(test, feature = "hit-counters"))]
+        self.shared_scene_write_state.stats.record_primitive_hit_tests(stats::primitive_hit_tests());
+
         // notify completion by decrementing task counter
-        self.shared_scene_write_state.notify_task_completion();
+        self.shared_scene_write_state.notify_task_completion((self.start_xy, self.num_pixels_xy));
+    }
+}
+
+// Adds num_samples to a pixel's accumulated sample count and returns their weight in the running average over frames
+// (realtime) or passes (offline), the first frame overwrites the buffer. None once max_samples have been accumulated,
+// the pixel has converged and keeps its value until the accumulation is cleared.
+fn accumulation_weight(accumulated_samples: &mut u32, num_samples: u32, max_samples: Option<u32>) -> Option<f32> {
+    if max_samples.is_some_and(|max_samples| *accumulated_samples >= max_samples) {
+        return None;
     }
+    *accumulated_samples += num_samples;
+    Some(num_samples as f32 / *accumulated_samples as f32)
 }
 
 impl JobTask for TraceSceneBatchJob {
@@ -195,41 +545,316 @@ impl JobTask for TraceSceneBatchJob {
     }
 }
 
+// Seed used for single threaded renders so the same scene always produces the same image
+const SINGLE_THREADED_SEED: u64 = 0x5eed;
+
+// Traces the whole image as one batch on the calling thread with a seeded random number generator,
+// returning the RGBA buffer. Bypasses the window and thread pool so it can be used from tests.
+pub fn render_single_threaded(world: Box<ThreadsafeHitable>, cam: Camera, config: &Config, image_size: (u32, u32)) -> Vec<f32> {
+    render_single_threaded_crop(world, cam, config, image_size, ((0, 0), image_size))
+}
+
+// As render_single_threaded but only traces the crop (start, size) of the image, returning just its RGBA buffer
+pub fn render_single_threaded_crop(world: Box<ThreadsafeHitable>, cam: Camera, config: &Config, image_size: (u32, u32), crop: TileRect) -> Vec<f32> {
+    let (crop_start, crop_size) = crop;
+    let scene_output = trace_single_threaded(world, cam, config, image_size, crop);
+
+    let image = scene_output.buffer.read();
+    let mut cropped = Vec::with_capacity((crop_size.0 * crop_size.1 * 4) as usize);
+    for y in crop_start.1..crop_start.1 + crop_size.1 {
+        let row_start = ((y * image_size.0 + crop_start.0) * 4) as usize;
+        cropped.extend_from_slice(&image[row_start..row_start + (crop_size.0 * 4) as usize]);
+    }
+    cropped
+}
+
+// Traces the crop as one seeded batch on the calling thread, returning the full size output along with its stats
+pub fn trace_single_threaded(world: Box<ThreadsafeHitable>, cam: Camera, config: &Config, image_size: (u32, u32), crop: TileRect) -> Arc<SceneOutput> {
+    let (crop_start, crop_size) = crop;
+    let crop_end = (crop_start.0 + crop_size.0, crop_start.1 + crop_size.1);
+    assert!(crop_end.0 <= image_size.0 && crop_end.1 <= image_size.1, "crop must lie inside the image");
+
+    let buffer = MultiSliceReadWriteLock::new(vec![0.0_f32; (image_size.0 * image_size.1 * 4) as usize]);
+    let scene_state = Arc::new(RwLock::new(SceneState::new(cam, world, 0.0, 1.0, 1.0, false, config.clone())));
+    let scene_output = Arc::new(SceneOutput::new(buffer, AtomicUsize::new(1), AtomicBool::new(false)));
+
+    random::seed_thread_rng(SINGLE_THREADED_SEED);
+    let mut job = TraceSceneBatchJob::new(config.spp, crop_start, crop_end, image_size, scene_state, scene_output.clone(), config.realtime);
+    job.trace();
+    random::clear_thread_rng_seed();
+
+    scene_output
+}
+
+// Ray leaving the hit point, moved along the normal to the side it travels towards. Rounding error in the hit point
+// grows with its coordinates so the offset does too, otherwise the ray can hit the surface it left.
+fn spawn_ray(hit_record: &HitRecord, direction: Vec3, time: f64) -> Ray {
+    let p = &hit_record.p;
+    let offset = RAY_ORIGIN_OFFSET * (1.0 + p.x.abs().max(p.y.abs()).max(p.z.abs()));
+    let normal = if dot(&direction, &hit_record.normal) < 0.0 {-hit_record.normal} else {hit_record.normal};
+    Ray::new(p + &(normal * offset), direction, time)
+}
+
+// Smallest t a hit along r is accepted at. ray_epsilon is a world distance and t is in units of r's direction, which
+// camera rays and rays scattered from a pdf don't normalise
+fn min_hit_t(r: &Ray, ray_epsilon: f64) -> f64 {
+    ray_epsilon / r.direction().length()
+}
+
+// Power heuristic (beta = 2) weight for a sample drawn from the strategy with pdf_a when the other strategy has pdf_b
+fn power_heuristic(pdf_a: f64, pdf_b: f64) -> f64 {
+    let (a, b) = (pdf_a * pdf_a, pdf_b * pdf_b);
+    if a + b > 0.0 {a / (a + b)} else {0.0}
+}
+
+// Next event estimation: samples a point on one of the lights from the hit point, casts a shadow ray and returns
+// the direct light reflected back along r, MIS weighted against the chance of the BSDF sampling the same direction
+fn sample_direct_light(
+    r: &Ray,
+    hit_record: &HitRecord,
+    scatter_result: &ScatterResult,
+    world: &ThreadsafeHitable,
+    lights: &Arc<ThreadsafeHitable>,
+    ray_epsilon: f64,
+    rays_traced: &mut usize) -> Vec3 {
+
+    // unit length so hit distances are in world units like ray_epsilon
+    let shadow_ray = spawn_ray(hit_record, Vec3::new_unit_vector(&lights.random(&hit_record.p)), r.time);
+    let light_pdf = lights.pdf_value(&shadow_ray.origin, &shadow_ray.direction);
+    if light_pdf <= 0.0 {
+        return Vec3::new_zero_vector();
+    }
+    let light_record = match lights.hit(&shadow_ray, ray_epsilon, f64::MAX) {
+        Some(light_record) => light_record,
+        None => return Vec3::new_zero_vector(),
+    };
+
+    *rays_traced += 1;
+    // stop just short of the light so it doesn't occlude itself
+    if world.hit_any(&shadow_ray, ray_epsilon, light_record.t - ray_epsilon) {
+        return Vec3::new_zero_vector();
+    }
+
+    // light_pdf is per solid angle so already includes the geometry term distance^2 / (cosine at the light * area)
+    let emitted = light_record.mat.emitted(&shadow_ray, &light_record, light_record.u, light_record.v, &light_record.p);
+    let bsdf_pdf = scatter_result.pdf.value(&shadow_ray.direction);
+    let weight = power_heuristic(light_pdf, bsdf_pdf);
+    scatter_result.albedo * hit_record.mat.scattering_pdf(r, hit_record, &shadow_ray) * emitted * weight / light_pdf
+}
+
+// Next event estimation towards an environment map, picks a direction by the map's luminance and returns the
+// environment reflected back along r if nothing blocks it, MIS weighted against the BSDF like sample_direct_light
+fn sample_direct_environment(
+    r: &Ray,
+    hit_record: &HitRecord,
+    scatter_result: &ScatterResult,
+    world: &ThreadsafeHitable,
+    environment: &EnvironmentMap,
+    ray_epsilon: f64,
+    rays_traced: &mut usize) -> Vec3 {
+
+    let (direction, environment_pdf) = environment.sample_direction();
+    if environment_pdf <= 0.0 {
+        return Vec3::new_zero_vector();
+    }
+    let shadow_ray = spawn_ray(hit_record, direction, r.time);
+    *rays_traced += 1;
+    if world.hit_any(&shadow_ray, min_hit_t(&shadow_ray, ray_epsilon), f64::MAX) {
+        return Vec3::new_zero_vector();
+    }
+
+    let bsdf_pdf = scatter_result.pdf.value(&shadow_ray.direction);
+    let weight = power_heuristic(environment_pdf, bsdf_pdf);
+    scatter_result.albedo * hit_record.mat.scattering_pdf(r, hit_record, &shadow_ray) * environment.value(&direction) * weight / environment_pdf
+}
+
+// State of the render that every bounce of color() reads, bundled so the recursion only passes what changes per ray
+#[derive(Clone, Copy)]
+struct TraceContext<'a> {
+    world: &'a ThreadsafeHitable,
+    shape_integrators: &'a ShapeIntegrators,
+    config: &'a Config,
+    lights: Option<&'a Arc<ThreadsafeHitable>>,
+    num_lights: usize, // lights in the scene whether or not next event estimation samples them
+    sky_brightness: f64, // scales the gradient background
+}
+
+impl<'a> TraceContext<'a> {
+    fn new(world: &'a ThreadsafeHitable, shape_integrators: &'a ShapeIntegrators, config: &'a Config, lights: Option<&'a Arc<ThreadsafeHitable>>) -> Self {
+        TraceContext { world, shape_integrators, config, lights, num_lights: 0, sky_brightness: 1.0 }
+    }
+
+    fn with_num_lights(mut self, num_lights: usize) -> Self {
+        self.num_lights = num_lights;
+        self
+    }
+
+    fn with_sky_brightness(mut self, sky_brightness: f64) -> Self {
+        self.sky_brightness = sky_brightness;
+        self
+    }
+}
+
+// Paths end at config.max_depth, or earlier by russian roulette after config.min_bounces unless config.termination
+// is fixed depth.
+// When the context's lights are set diffuse bounces use next event estimation and bsdf_pdf is the pdf the BSDF sampled r with,
+// used to MIS weight any light it hits. Otherwise lights are found by the scattered rays alone.
+// An environment map background is sampled the same way when config.nee is set.
+// medium holds the dielectrics r is travelling inside, so refraction at nested dielectrics uses the right indices.
+// Also returns whether r hit geometry, which camera rays accumulate as the pixel's alpha coverage.
+// Hits closer than config.ray_epsilon are ignored so rays leaving a surface don't hit it again.
 fn color(
     r : &Ray, 
-    world: &Box<ThreadsafeHitable>,
-    shape_integrators: &Arc<ThreadsafeHitable>,
+    context: &TraceContext,
+    bsdf_pdf: Option<f64>,
+    medium: MediumStack,
     depth: i32, 
-    max_depth: i32) -> Vec3 {
-
-    if let Some(hit_record) = world.hit(r, 0.001, f64::MAX) {
-        let emissive = hit_record.mat.emitted(r, &hit_record, hit_record.u, hit_record.v, &hit_record.p);
-        if depth < max_depth {
-            if let Some(scatter_result) = hit_record.mat.scatter(r, &hit_record) {
-                if scatter_result.is_specular {
-                    return scatter_result.albedo *
-                        color(&scatter_result.specular_ray, world, shape_integrators, depth+1, max_depth);
-                } else {
-                    let hittable_pdf = HittablePDF::new(shape_integrators.clone(), hit_record.p);
-                    let pdf = MixturePDF::new(Arc::new(hittable_pdf), scatter_result.pdf.clone());
-                    let scattered = Ray::new(hit_record.p, pdf.generate(), r.time);
-                    let pdf_val = pdf.value(&scattered.direction);
-                    let colour = scatter_result.albedo 
-                                * hit_record.mat.scattering_pdf(r, &hit_record, &scattered)
-                                * color(&scattered, world, shape_integrators, depth+1, max_depth)
-                                / pdf_val;
-                    return colour + emissive;
+    rays_traced: &mut usize) -> (Vec3, bool) {
+
+    let TraceContext { world, shape_integrators, config, lights, num_lights, sky_brightness } = *context;
+    *rays_traced += 1;
+    let environment = if config.nee {config.background.environment()} else {None};
+    if let Some(mut hit_record) = world.hit(r, min_hit_t(r, config.ray_epsilon), f64::MAX) {
+        override_material(&mut hit_record, config);
+        // Radiance leaving the hit point back along the ray is
+        //   L_o = L_e + albedo * scattering_pdf * L_i / pdf
+        // Emission is added exactly once per hit whether or not the material scatters, so an emissive Lambertian
+        // and a DiffuseLight with the same emitted() are equally bright, the Lambertian only adds what it reflects.
+        let mut emissive = hit_record.mat.emitted(r, &hit_record, hit_record.u, hit_record.v, &hit_record.p);
+        if let (Some(lights), Some(bsdf_pdf)) = (lights, bsdf_pdf) {
+            // the light could also have been found by next event estimation at the previous bounce
+            if hit_record.mat.is_emissive() {
+                emissive = emissive * power_heuristic(bsdf_pdf, lights.pdf_value(&r.origin, &r.direction));
+            }
+        }
+        if depth < config.max_depth {
+            if let Some(mut scatter_result) = hit_record.mat.scatter_in_medium(r, &hit_record, &medium) {
+                // russian roulette once past the minimum bounces, paths through dark surfaces are likely to end
+                // and the survivors are boosted to compensate
+                if config.termination == Termination::RussianRoulette && depth >= config.min_bounces {
+                    let survival_probability = scatter_result.albedo.max_component().min(0.95);
+                    if random::rand() >= survival_probability {
+                        return (emissive, true);
+                    }
+                    scatter_result.albedo = scatter_result.albedo / survival_probability;
+                }
+                let sampling_hint = if scatter_result.is_specular {SamplingHint::Specular} else {hit_record.mat.sampling_hint()};
+                match (sampling_hint, lights) {
+                    (SamplingHint::Specular, _) => {
+                        let specular_ray = spawn_ray(&hit_record, scatter_result.specular_ray.direction, r.time);
+                        let medium = match hit_record.mat.refraction_index() {
+                            Some(refraction_index) => medium.after_scatter(refraction_index, r, &specular_ray, &hit_record.normal),
+                            None => medium,
+                        };
+                        return (scatter_result.albedo *
+                            color(&specular_ray, context, None, medium, depth+1, rays_traced).0
+                            + emissive, true);
+                    },
+                    (SamplingHint::LightMis, _) if lights.is_some() || environment.is_some() => {
+                        let light_direct = match lights {
+                            Some(lights) => sample_direct_light(r, &hit_record, &scatter_result, world, lights, config.ray_epsilon, rays_traced),
+                            None => Vec3::new_zero_vector(),
+                        };
+                        let environment_direct = match environment {
+                            Some(environment) => sample_direct_environment(r, &hit_record, &scatter_result, world, environment, config.ray_epsilon, rays_traced),
+                            None => Vec3::new_zero_vector(),
+                        };
+                        let direct = light_direct + environment_direct;
+                        let scattered = spawn_ray(&hit_record, scatter_result.pdf.generate(), r.time);
+                        let pdf_val = scatter_result.pdf.value(&scattered.direction);
+                        if pdf_val <= 0.0 {
+                            return (direct + emissive, true);
+                        }
+                        let indirect = scatter_result.albedo
+                                    * hit_record.mat.scattering_pdf(r, &hit_record, &scattered)
+                                    * color(&scattered, context, Some(pdf_val), medium, depth+1, rays_traced).0
+                                    / pdf_val;
+                        return (direct + indirect + emissive, true);
+                    },
+                    (SamplingHint::LightMis, _) | (SamplingHint::Bsdf, _) => {
+                        // without any shapes to importance sample only the material's pdf is left
+                        let mixture_pdf;
+                        let pdf: &dyn PDF = if sampling_hint == SamplingHint::LightMis && shape_integrators.num_primitives > 0 {
+                            let hittable_pdf = HittablePDF::new(shape_integrators.shapes.clone(), hit_record.p);
+                            mixture_pdf = MixturePDF::new(Arc::new(hittable_pdf), scatter_result.pdf.clone())
+                                .with_weight(config.light_mix.weight(num_lights));
+                            &mixture_pdf
+                        } else {
+                            scatter_result.pdf.as_ref()
+                        };
+                        let scattered = spawn_ray(&hit_record, pdf.generate(), r.time);
+                        let pdf_val = pdf.value(&scattered.direction);
+                        // a direction the pdf can't generate, dividing by it would give inf or NaN
+                        if pdf_val <= 0.0 {
+                            return (emissive, true);
+                        }
+                        let colour = scatter_result.albedo 
+                                    * hit_record.mat.scattering_pdf(r, &hit_record, &scattered)
+                                    * color(&scattered, context, None, medium, depth+1, rays_traced).0
+                                    / pdf_val;
+                        return (colour + emissive, true);
+                    },
                 }
             }
         }
-        return emissive;
+        return (emissive, true);
     } else {
-        return Vec3::from_float(0.0);
-        //let unit_dir = Vec3::new_unit_vector(&r.direction());
-        //let t = 0.5*(unit_dir.y + 1.0);
-        //let white = Vec3::from_float(1.0);
-        //let sky = Vec3::new(0.5, 0.7, 1.0);
-        //return lerp(&white, &sky, t) * sky_brightness;
+        // the background is not emissive geometry so it is returned even when emissive surfaces are disabled
+        let background = config.background.colour(&r.direction(), sky_brightness);
+        let weight = match (environment, bsdf_pdf) {
+            // the environment could also have been found by next event estimation at the previous bounce
+            (Some(environment), Some(bsdf_pdf)) => power_heuristic(bsdf_pdf, environment.pdf(r.direction())),
+            _ => 1.0,
+        };
+        return (background * weight, false);
+    }
+}
+
+// Swaps the material a hit is shaded with without touching the scene, clay renders shade everything but the lights
+// with a plain grey Lambertian so only the lighting and geometry show
+fn override_material(hit_record: &mut HitRecord, config: &Config) {
+    if config.clay && !hit_record.mat.is_emissive() {
+        hit_record.mat = CLAY_MATERIAL.clone();
+    }
+}
+
+// AOV of config.render_mode for a camera ray, rays that miss return zero
+fn aov(r: &Ray, world: &ThreadsafeHitable, config: &Config, rays_traced: &mut usize) -> (Vec3, bool) {
+    *rays_traced += 1;
+    match world.hit(r, min_hit_t(r, config.ray_epsilon), f64::MAX) {
+        Some(mut hit_record) => {
+            override_material(&mut hit_record, config);
+            let value = match config.render_mode {
+                // camera ray directions aren't normalised so t is scaled to a distance
+                RenderMode::Depth => Vec3::from_float(hit_record.t * r.direction().length()),
+                RenderMode::Position => hit_record.p,
+                RenderMode::Albedo | RenderMode::Normal => surface_aov(r, &hit_record, world, config, config.max_depth, rays_traced),
+                RenderMode::Beauty => unreachable!(),
+            };
+            (value, true)
+        },
+        None => (Vec3::new_zero_vector(), false),
+    }
+}
+
+// Albedo or normal of the first non-specular surface, following mirrors and glass for up to max_bounces.
+// Surfaces that don't scatter, like lights, have zero albedo.
+fn surface_aov(r: &Ray, hit_record: &HitRecord, world: &ThreadsafeHitable, config: &Config, max_bounces: i32, rays_traced: &mut usize) -> Vec3 {
+    match hit_record.mat.scatter(r, hit_record) {
+        Some(ref scatter_result) if scatter_result.is_specular && max_bounces > 0 => {
+            *rays_traced += 1;
+            let specular_ray = spawn_ray(hit_record, scatter_result.specular_ray.direction, r.time);
+            match world.hit(&specular_ray, min_hit_t(&specular_ray, config.ray_epsilon), f64::MAX) {
+                Some(mut next_record) => {
+                    override_material(&mut next_record, config);
+                    surface_aov(&specular_ray, &next_record, world, config, max_bounces - 1, rays_traced)
+                },
+                None => Vec3::new_zero_vector(),
+            }
+        },
+        Some(ref scatter_result) if config.render_mode == RenderMode::Albedo => scatter_result.albedo,
+        None if config.render_mode == RenderMode::Albedo => Vec3::new_zero_vector(),
+        _ => hit_record.normal,
     }
 }
 
@@ -239,4 +864,911 @@ pub fn reinhard_tonemap(colour: &Vec3) -> Vec3 {
     let colour = colour * EXPOSURE;
     //&colour / (vec3::dot(&colour, &luminance) + 1.0)
     &colour / (&colour + 1.0)
-}
\ No newline at end of file
+}
+
+// Geometric mean of the RGBA buffer's luminance (the scene key in Reinhard et al.), the small delta stops black
+// pixels sending it to zero. Large images are sampled on an evenly spaced grid.
+pub fn log_average_luminance(buffer: &[f32], image_size: (u32, u32)) -> f64 {
+    const DELTA: f64 = 1e-4;
+    let step_x = (image_size.0 / LOG_AVERAGE_SAMPLES_PER_AXIS).max(1);
+    let step_y = (image_size.1 / LOG_AVERAGE_SAMPLES_PER_AXIS).max(1);
+    let mut log_sum = 0.0;
+    let mut num_samples = 0;
+    for y in (0..image_size.1).step_by(step_y as usize) {
+        for x in (0..image_size.0).step_by(step_x as usize) {
+            let index = ((y * image_size.0 + x) * 4) as usize;
+            let luminance = 0.2126 * buffer[index] as f64 + 0.7152 * buffer[index + 1] as f64 + 0.0722 * buffer[index + 2] as f64;
+            log_sum += (DELTA + luminance.max(0.0)).ln();
+            num_samples += 1;
+        }
+    }
+    if num_samples == 0 {
+        return DELTA;
+    }
+    (log_sum / num_samples as f64).exp()
+}
+
+// Radial darkening for pixel (x, y), 1 at the image centre falling to 1 - strength in the corners
+pub fn vignette(x: u32, y: u32, image_size: (u32, u32), strength: f64) -> f64 {
+    let dx = (x as f64 + 0.5) / image_size.0 as f64 - 0.5;
+    let dy = (y as f64 + 0.5) / image_size.1 as f64 - 0.5;
+    // normalised so the squared distance to a corner is 1
+    let distance_squared = (dx*dx + dy*dy) * 2.0;
+    (1.0 - strength * distance_squared).max(0.0)
+}
+
+// Encodes a linear value for display using a simple power curve
+pub fn gamma_correct(value: f64, gamma: f64) -> f64 {
+    value.powf(1.0 / gamma)
+}
+
+// Transfer function used when encoding linear colour for export
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum TransferFunction {
+    Gamma,
+    Srgb,
+}
+
+impl TransferFunction {
+    // gamma is only used by the power curve
+    pub fn encode(&self, value: f64, gamma: f64) -> f64 {
+        match self {
+            TransferFunction::Gamma => gamma_correct(value, gamma),
+            TransferFunction::Srgb => srgb_oetf(value),
+        }
+    }
+}
+
+impl std::str::FromStr for TransferFunction {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "gamma" => Ok(TransferFunction::Gamma),
+            "srgb" => Ok(TransferFunction::Srgb),
+            _ => Err(format!("Unknown transfer function '{}', expected gamma or srgb", s)),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+
+    use super::*;
+    use jobs::JobTask;
+    use renderer::Renderer;
+    use std::time::Duration;
+    use material::{DiffuseLight, Dielectric, Isotropic};
+    use sphere::Sphere;
+    use axis_aligned_box::AxisAlignedBox;
+    use texture::Texture;
+
+    // camera at the centre of a diffuse sphere looking down -z
+    fn inside_sphere_scene(emissive: f64, config: Config) -> SceneState {
+        let world: Box<ThreadsafeHitable> = Box::new(Sphere::new(Vec3::new_zero_vector(), 10.0,
+            Arc::new(Lambertian::new(Arc::new(ConstantTexture::new(Vec3::from_float(0.5))), emissive))));
+        let cam = Camera::new(Vec3::new_zero_vector(), Vec3::new(0.0, 0.0, -1.0), Vec3::new(0.0, 1.0, 0.0), 40.0, 1.0, 0.0, 1.0, 0.0, 1.0);
+        SceneState::new(cam, world, 0.0, 1.0, 0.0, false, config)
+    }
+
+    fn new_scene_output(image_size: (u32, u32), num_tasks: usize) -> Arc<SceneOutput> {
+        let buffer = MultiSliceReadWriteLock::new(vec![0.0_f32; (image_size.0 * image_size.1 * 4) as usize]);
+        Arc::new(SceneOutput::new(buffer, AtomicUsize::new(num_tasks), AtomicBool::new(false)))
+    }
+
+    #[test]
+    fn tent_filter_weights_centre_over_corners() {
+        let filter = ReconstructionFilter::Tent;
+        let centre = filter.weight(0.0, 0.0);
+        let corner = filter.weight(0.5, 0.5);
+        assert!(centre > corner);
+        assert!(corner > 0.0);
+        assert_eq!(ReconstructionFilter::Box.weight(0.0, 0.0), ReconstructionFilter::Box.weight(0.5, 0.5));
+    }
+
+    #[test]
+    fn log_average_luminance_is_geometric_mean() {
+        // left half luminance 1, right half 4
+        let image_size = (8, 2);
+        let buffer: Vec<f32> = (0..image_size.0 * image_size.1).flat_map(|pixel| {
+            let value = if pixel % image_size.0 < image_size.0 / 2 {1.0} else {4.0};
+            vec![value, value, value, 1.0]
+        }).collect();
+        let log_average = log_average_luminance(&buffer, image_size);
+        assert!((log_average - 2.0).abs() < 1e-3, "log average = {}", log_average);
+
+        // sampled on a grid once past the sample limit, still the same mean for a regular pattern
+        let image_size = (1024, 4);
+        let buffer: Vec<f32> = (0..image_size.0 * image_size.1).flat_map(|pixel| {
+            let value = if pixel % image_size.0 < image_size.0 / 2 {1.0} else {4.0};
+            vec![value, value, value, 1.0]
+        }).collect();
+        let log_average = log_average_luminance(&buffer, image_size);
+        assert!((log_average - 2.0).abs() < 1e-3, "log average = {}", log_average);
+    }
+
+    #[test]
+    fn accumulation_stops_at_max_frames() {
+        let mut config = Config::new();
+        config.max_accumulation = 8;
+        let num_samples = 2;
+        let max_samples = Some(config.max_accumulation * num_samples);
+        let mut accumulated_samples = 0;
+        let weights: Vec<Option<f32>> = (0..12).map(|_| accumulation_weight(&mut accumulated_samples, num_samples, max_samples)).collect();
+        // 1/frame until the cap, then the pixel is frozen rather than averaged with a fixed weight
+        for (frame, weight) in weights[..8].iter().enumerate() {
+            assert_eq!(*weight, Some(1.0 / (frame + 1) as f32));
+        }
+        assert!(weights[8..].iter().all(|weight| weight.is_none()));
+        assert_eq!(accumulated_samples, 16);
+
+        // offline passes always accumulate
+        assert_eq!(accumulation_weight(&mut accumulated_samples, num_samples, None), Some(2.0 / 18.0));
+    }
+
+    #[test]
+    fn low_discrepancy_samplers_beat_random_coverage_error() {
+        random::seed_thread_rng(1429);
+        // a pixel a quarter disk covers pi/8 of, estimated with 64 samples for many pixels
+        let covered = |x: f64, y: f64| if x * x + y * y < 0.5 {1.0} else {0.0};
+        let expected = std::f64::consts::PI / 8.0;
+        let num_samples = 64;
+        let mean_squared_error = |sampler: SamplingStrategy| {
+            (0..256_u64).map(|pixel_seed| {
+                let estimate = (0..num_samples).map(|sample_index| match sampler.sample(pixel_seed, sample_index) {
+                    Some(sample) => {
+                        assert!(sample.iter().all(|value| *value >= 0.0 && *value < 1.0));
+                        covered(sample[0], sample[1])
+                    },
+                    None => covered(random::rand(), random::rand()),
+                }).sum::<f64>() / num_samples as f64;
+                (estimate - expected) * (estimate - expected)
+            }).sum::<f64>() / 256.0
+        };
+
+        let random_error = mean_squared_error(SamplingStrategy::Random);
+        for sampler in [SamplingStrategy::Halton, SamplingStrategy::Sobol].iter() {
+            let error = mean_squared_error(*sampler);
+            assert!(error < 0.5 * random_error, "{:?} error {} vs random {}", sampler, error, random_error);
+        }
+        random::clear_thread_rng_seed();
+    }
+
+    #[test]
+    fn gamma_correct_matches_linear_and_sqrt() {
+        for i in 0..=10 {
+            let value = i as f64 / 10.0;
+            assert!((gamma_correct(value, 1.0) - value).abs() < 1e-12);
+            assert!((gamma_correct(value, 2.0) - value.sqrt()).abs() < 1e-12);
+        }
+    }
+
+    #[test]
+    fn srgb_diverges_from_gamma_near_black() {
+        let srgb = TransferFunction::Srgb;
+        let gamma = TransferFunction::Gamma;
+
+        // dark values differ noticeably, mid-tones are close
+        assert!((srgb.encode(0.001, 2.2) - gamma.encode(0.001, 2.2)).abs() > 0.02);
+        assert!((srgb.encode(0.5, 2.2) - gamma.encode(0.5, 2.2)).abs() < 0.01);
+
+        // linear segment below the threshold
+        assert_eq!(srgb.encode(0.002, 2.2), 12.92 * 0.002);
+        assert!(srgb.encode(0.01, 2.2) != 12.92 * 0.01);
+    }
+
+    #[test]
+    fn ray_count_matches_one_bounce_paths() {
+        let image_size = (4, 4);
+        let num_samples = 3;
+        let mut config = Config::new();
+        config.realtime = false;
+        config.max_depth = 1;
+
+        // camera sits inside a diffuse sphere so every camera ray hits and scatters exactly once
+        let scene_state = Arc::new(RwLock::new(inside_sphere_scene(0.0, config)));
+        let scene_output = new_scene_output(image_size, 1);
+
+        let mut job = TraceSceneBatchJob::new(num_samples, (0, 0), image_size, image_size, scene_state, scene_output.clone(), false);
+        job.run();
+
+        let paths_per_sample = 2; // camera ray + one bounce
+        assert_eq!(scene_output.stats.rays_traced(), (image_size.0 * image_size.1 * num_samples * paths_per_sample) as usize);
+        assert_eq!(scene_output.stats.num_tiles(), 1);
+    }
+
+    #[test]
+    fn primitive_hit_tests_are_merged_across_threads() {
+        let image_size = (8, 8);
+        let mut config = Config::new();
+        config.realtime = false;
+        config.max_depth = 1;
+
+        // every path tests the one sphere the camera sits inside the same number of times, whichever thread traces it
+        let scene_state = Arc::new(RwLock::new(inside_sphere_scene(0.0, config)));
+        let whole_image_output = new_scene_output(image_size, 1);
+        TraceSceneBatchJob::new(2, (0, 0), image_size, image_size, scene_state.clone(), whole_image_output.clone(), false).run();
+        let expected_hit_tests = whole_image_output.stats.primitive_hit_tests();
+        assert!(expected_hit_tests >= whole_image_output.stats.rays_traced());
+
+        let scene_output = new_scene_output(image_size, 4);
+        let jobs: Vec<Arc<RwLock<dyn JobTask + Send + Sync + 'static>>> = [(0, 0), (4, 0), (0, 4), (4, 4)].iter().map(|start_xy: &(u32, u32)| {
+            let end_xy = (start_xy.0 + 4, start_xy.1 + 4);
+            let job: Arc<RwLock<dyn JobTask + Send + Sync + 'static>> = Arc::new(RwLock::new(
+                TraceSceneBatchJob::new(2, *start_xy, end_xy, image_size, scene_state.clone(), scene_output.clone(), false)));
+            job
+        }).collect();
+        let renderer = Renderer::new(4);
+        renderer.wait(&renderer.dispatch(&jobs));
+
+        assert_eq!(scene_output.stats.num_tiles(), 4);
+        assert_eq!(scene_output.stats.primitive_hit_tests(), expected_hit_tests);
+        assert!(scene_output.stats.summary(Duration::from_secs(1)).ends_with(&format!("primitive hit tests={}", expected_hit_tests)));
+
+        // the stats of another render start from zero
+        assert_eq!(new_scene_output(image_size, 1).stats.primitive_hit_tests(), 0);
+    }
+
+    #[test]
+    fn seeded_adjacent_tiles_have_different_noise() {
+        let image_size = (8, 4);
+        let mut config = Config::new();
+        config.realtime = false;
+        config.max_depth = 3;
+        // a tiny field of view makes every pixel trace practically the same ray so only the random samples differ,
+        // light sampling towards shapes outside the sphere makes those samples noisy
+        let material = Lambertian::new(Arc::new(ConstantTexture::new(Vec3::from_float(0.5))), 1.0);
+        let mut scene_state = inside_sphere_scene(1.0, config);
+        scene_state.world = Box::new(FlipNormals::new(Arc::new(Sphere::new(Vec3::new_zero_vector(), 10.0, Arc::new(material)))));
+        scene_state.cam = Camera::new(Vec3::new_zero_vector(), Vec3::new(0.0, 0.0, -1.0), Vec3::new(0.0, 1.0, 0.0), 1e-4, 1.0, 0.0, 1.0, 0.0, 1.0);
+        let scene_state = Arc::new(RwLock::new(scene_state));
+
+        let render_tiles = || {
+            let scene_output = new_scene_output(image_size, 2);
+            for tile_index in 0..2 {
+                let start_xy = (4 * tile_index, 0);
+                let mut job = TraceSceneBatchJob::new(1, start_xy, (start_xy.0 + 4, 4), image_size, scene_state.clone(), scene_output.clone(), false);
+                job.set_seed(1370, tile_index);
+                job.run();
+            }
+            let image = scene_output.buffer.read().clone();
+            image
+        };
+        let image = render_tiles();
+        assert_eq!(image, render_tiles());
+
+        let tile = |tile_index: usize| -> Vec<f32> {
+            image.chunks(8 * 4).flat_map(|row| row[tile_index * 16..(tile_index + 1) * 16].to_vec()).collect()
+        };
+        let mean_difference = tile(0).iter().zip(tile(1).iter()).map(|(a, b)| (a - b).abs()).sum::<f32>() / 64.0;
+        assert!(mean_difference > 1e-2, "adjacent tiles traced the same noise, mean difference = {}", mean_difference);
+    }
+
+    // rays traced from inside a nearly black diffuse sphere where russian roulette would end most paths early
+    fn dark_sphere_rays_traced(min_bounces: i32, termination: Termination) -> usize {
+        let mut config = Config::new();
+        config.realtime = false;
+        config.max_depth = 5;
+        config.min_bounces = min_bounces;
+        config.termination = termination;
+
+        let material = Lambertian::new(Arc::new(ConstantTexture::new(Vec3::from_float(0.01))), 0.0).with_sampling_hint(SamplingHint::Bsdf);
+        let world: Box<ThreadsafeHitable> = Box::new(FlipNormals::new(Arc::new(Sphere::new(Vec3::new_zero_vector(), 10.0, Arc::new(material)))));
+        let mut scene_state = inside_sphere_scene(0.0, config);
+        scene_state.world = world;
+        let image_size = (4, 4);
+        let scene_output = new_scene_output(image_size, 1);
+
+        let mut job = TraceSceneBatchJob::new(4, (0, 0), image_size, image_size, Arc::new(RwLock::new(scene_state)), scene_output.clone(), false);
+        job.run();
+        scene_output.stats.rays_traced()
+    }
+
+    #[test]
+    fn paths_reach_min_bounces_before_russian_roulette() {
+        let num_paths = 4 * 4 * 4;
+        // camera ray plus a ray for every bounce up to the max depth
+        assert_eq!(dark_sphere_rays_traced(5, Termination::RussianRoulette), num_paths * 6);
+        assert!(dark_sphere_rays_traced(1, Termination::RussianRoulette) < num_paths * 3);
+    }
+
+    #[test]
+    fn fixed_depth_always_reaches_max_depth() {
+        let num_paths = 4 * 4 * 4;
+        let termination: Termination = "fixed-depth".parse().unwrap();
+        assert_eq!(dark_sphere_rays_traced(0, termination), num_paths * 6);
+    }
+
+    #[test]
+    fn bsdf_only_diffuse_converges_to_analytic_radiance() {
+        let mut config = Config::new();
+        config.realtime = false;
+        config.max_depth = 3;
+
+        let material = Lambertian::new(Arc::new(ConstantTexture::new(Vec3::from_float(0.5))), 1.0).with_sampling_hint(SamplingHint::Bsdf);
+        // normals flipped to face the camera so scattered rays stay inside the sphere
+        let world: Box<ThreadsafeHitable> = Box::new(FlipNormals::new(Arc::new(Sphere::new(Vec3::new_zero_vector(), 10.0, Arc::new(material)))));
+        let mut scene_state = inside_sphere_scene(1.0, config);
+        scene_state.world = world;
+        let image_size = (4, 4);
+        let scene_output = new_scene_output(image_size, 1);
+
+        let mut job = TraceSceneBatchJob::new(16, (0, 0), image_size, image_size, Arc::new(RwLock::new(scene_state)), scene_output.clone(), false);
+        job.run();
+
+        // emission of 0.5 at every bounce, attenuated by the 0.5 albedo at each of the 3 scatters
+        let expected = 0.5 + 0.25 + 0.125 + 0.0625;
+        for pixel in scene_output.buffer.read().chunks(4) {
+            assert!((pixel[0] as f64 - expected).abs() < 1e-4, "pixel = {}, expected = {}", pixel[0], expected);
+        }
+    }
+
+    #[test]
+    fn sample_mask_limits_traced_pixels() {
+        let image_size = (8, 8);
+        let mut config = Config::new();
+        config.realtime = false;
+        config.max_depth = 0;
+
+        // only the centre column pair is flagged, as an edge between two halves would be
+        let mut scene_state = inside_sphere_scene(1.0, config);
+        scene_state.sample_mask = Some((0..image_size.0 * image_size.1).map(|pixel_index| {
+            let x = pixel_index % image_size.0;
+            x == 3 || x == 4
+        }).collect());
+        let scene_state = Arc::new(RwLock::new(scene_state));
+        let scene_output = new_scene_output(image_size, 1);
+
+        let mut job = TraceSceneBatchJob::new(4, (0, 0), image_size, image_size, scene_state, scene_output.clone(), false);
+        job.run();
+
+        assert_eq!(scene_output.stats.rays_traced(), (2 * image_size.1 * 4) as usize);
+        for (pixel_index, pixel) in scene_output.buffer.read().chunks(4).enumerate() {
+            let x = pixel_index as u32 % image_size.0;
+            assert_eq!(pixel[0] > 0.0, x == 3 || x == 4);
+        }
+    }
+
+    // emission that changes across each pixel, so every jittered sample of a pixel sees a different radiance
+    struct StripedTexture;
+
+    impl Texture for StripedTexture {
+        fn value(&self, u: f64, _v: f64, _point: &Vec3) -> Vec3 {
+            Vec3::from_float((u * 1000.0).fract())
+        }
+    }
+
+    #[test]
+    fn progressive_passes_match_single_pass() {
+        let image_size = (4, 4);
+        let num_passes = 8;
+        let mut config = Config::new();
+        config.realtime = false;
+        config.max_depth = 0; // emission only, the radiance depends on where in the pixel the sample lands
+        // samples are indexed from those already accumulated so both renders draw the same ones
+        config.sampler = SamplingStrategy::Sobol;
+
+        let render = |num_samples: u32, num_passes: u32| -> Vec<f32> {
+            let light = Arc::new(DiffuseLight::new(Arc::new(StripedTexture)));
+            let world: Box<ThreadsafeHitable> = Box::new(AxisAlignedRect::new(-10.0, 10.0, -10.0, 10.0, 0.0, AxisAlignedRectAxis::Z, light));
+            let cam = Camera::new(Vec3::new(0.0, 0.0, 1.0), Vec3::new_zero_vector(), Vec3::new(0.0, 1.0, 0.0), 40.0, 1.0, 0.0, 1.0, 0.0, 1.0);
+            let scene_state = Arc::new(RwLock::new(SceneState::new(cam, world, 0.0, 1.0, 0.0, false, config.clone())));
+            let scene_output = new_scene_output(image_size, num_passes as usize);
+            let mut job = TraceSceneBatchJob::new(num_samples, (0, 0), image_size, image_size, scene_state, scene_output.clone(), false);
+            for _ in 0..num_passes {
+                job.run();
+            }
+            scene_output.buffer.read().clone()
+        };
+
+        let progressive = render(1, num_passes);
+        let single_pass = render(num_passes, 1);
+        for (a, b) in progressive.iter().zip(single_pass.iter()) {
+            assert!((a - b).abs() < 1e-5, "progressive = {}, single pass = {}", a, b);
+        }
+
+        // the samples differ, so keeping only the first or last pass wouldn't match
+        let first_sample = render(1, 1);
+        assert!(first_sample.iter().zip(single_pass.iter()).any(|(a, b)| (a - b).abs() > 0.01));
+    }
+
+    // pixel radiance of a rect filling the view with nothing else in the scene to reflect
+    fn emitter_radiance(material: Arc<ThreadsafeMaterial>) -> Vec<f32> {
+        let world: Box<ThreadsafeHitable> = Box::new(AxisAlignedRect::new(-10.0, 10.0, -10.0, 10.0, 0.0, AxisAlignedRectAxis::Z, material));
+        let cam = Camera::new(Vec3::new(0.0, 0.0, 1.0), Vec3::new_zero_vector(), Vec3::new(0.0, 1.0, 0.0), 40.0, 1.0, 0.0, 1.0, 0.0, 1.0);
+        let mut config = Config::new();
+        config.realtime = false;
+        config.spp = 4;
+        config.max_depth = 4;
+        render_single_threaded(world, cam, &config, (4, 4))
+    }
+
+    #[test]
+    fn missed_rays_return_solid_background_colour() {
+        let background: BackgroundMode = "0.5,0.25,0.125".parse().unwrap();
+        assert_eq!(background, BackgroundMode::SolidColour(Vec3::new(0.5, 0.25, 0.125)));
+        let mut config = Config::new();
+        config.background = background;
+        assert!("0.5,0.25".parse::<BackgroundMode>().is_err());
+
+        let world: Box<ThreadsafeHitable> = Box::new(Sphere::new(Vec3::new(0.0, 0.0, -5.0), 1.0, Arc::new(DummyMaterial::new())));
+        let shape_integrators = ShapeIntegrators::new(Arc::new(HitableList::new(vec![])));
+        let mut rays_traced = 0;
+        for direction in [Vec3::new(0.0, 0.0, 1.0), Vec3::new(1.0, 0.0, 0.0), Vec3::new(0.0, -1.0, 0.0)].iter() {
+            let ray = Ray::new(Vec3::new_zero_vector(), *direction, 0.0);
+            let (colour, hit) = color(&ray, &TraceContext::new(world.as_ref(), &shape_integrators, &config, None), None, MediumStack::new(), 0, &mut rays_traced);
+            assert_eq!(colour, Vec3::new(0.5, 0.25, 0.125));
+            assert!(!hit);
+        }
+    }
+
+    #[test]
+    fn gradient_background_is_scaled_by_sky_brightness() {
+        let mut config = Config::new();
+        config.background = BackgroundMode::Gradient;
+
+        let world: Box<ThreadsafeHitable> = Box::new(HitableList::new(vec![]));
+        let shape_integrators = ShapeIntegrators::new(Arc::new(HitableList::new(vec![])));
+        let mut rays_traced = 0;
+        let ray = Ray::new(Vec3::new_zero_vector(), Vec3::new(0.0, 1.0, 0.0), 0.0);
+        let sky = |sky_brightness: f64, rays_traced: &mut usize| {
+            let context = TraceContext::new(world.as_ref(), &shape_integrators, &config, None).with_sky_brightness(sky_brightness);
+            color(&ray, &context, None, MediumStack::new(), 0, rays_traced).0
+        };
+        assert_eq!(sky(1.0, &mut rays_traced), Vec3::new(0.5, 0.7, 1.0));
+        assert_eq!(sky(0.5, &mut rays_traced), Vec3::new(0.25, 0.35, 0.5));
+        assert_eq!(sky(0.0, &mut rays_traced), Vec3::from_float(0.0));
+    }
+
+    // mean and variance of the radiance along a ray to the centre of the floor beneath a small light
+    fn small_light_radiance_statistics(next_event_estimation: bool) -> (f64, f64) {
+        let floor = Lambertian::new(Arc::new(ConstantTexture::new(Vec3::from_float(0.5))), 0.0);
+        let light = DiffuseLight::new(Arc::new(ConstantTexture::new(Vec3::from_float(50.0))));
+        let light_shape: Arc<ThreadsafeHitable> = Arc::new(FlipNormals::new(Arc::new(
+            AxisAlignedRect::new(-0.5, 0.5, -0.5, 0.5, 5.0, AxisAlignedRectAxis::Y, Arc::new(light)))));
+        let list: Vec<Arc<ThreadsafeHitable>> = vec![
+            Arc::new(AxisAlignedRect::new(-10.0, 10.0, -10.0, 10.0, 0.0, AxisAlignedRectAxis::Y, Arc::new(floor))),
+            light_shape.clone(),
+        ];
+        let world: Box<ThreadsafeHitable> = Box::new(HitableList::new(list));
+        let lights = collect_lights(world.as_ref());
+        assert_eq!(lights.len(), 1);
+        let lights: Arc<ThreadsafeHitable> = Arc::new(HitableList::new(lights));
+
+        let mut config = Config::new();
+        config.max_depth = 2;
+        random::seed_thread_rng(1375);
+        let ray = Ray::new(Vec3::new(0.0, 3.0, -3.0), Vec3::new(0.0, -1.0, 1.0), 0.0);
+        let num_samples = 4000;
+        let mut rays_traced = 0;
+        let shape_integrators = ShapeIntegrators::new(light_shape);
+        let samples: Vec<f64> = (0..num_samples).map(|_| {
+            let lights = if next_event_estimation {Some(&lights)} else {None};
+            color(&ray, &TraceContext::new(world.as_ref(), &shape_integrators, &config, lights), None, MediumStack::new(), 0, &mut rays_traced).0.x
+        }).collect();
+        random::clear_thread_rng_seed();
+
+        let mean = samples.iter().sum::<f64>() / num_samples as f64;
+        let variance = samples.iter().map(|sample| (sample - mean) * (sample - mean)).sum::<f64>() / num_samples as f64;
+        (mean, variance)
+    }
+
+    // mean radiance and rays traced for a ray glancing into a 2 unit high crack under a black ceiling, 500 units up
+    // where the floor would show acne if rays leaving it hit it again
+    fn crack_radiance(ray_epsilon: f64, with_ceiling: bool) -> (f64, usize) {
+        let floor = Lambertian::new(Arc::new(ConstantTexture::new(Vec3::from_float(0.5))), 0.0);
+        let ceiling = Lambertian::new(Arc::new(ConstantTexture::new(Vec3::from_float(0.0))), 0.0);
+        let mut list: Vec<Arc<ThreadsafeHitable>> = vec![
+            Arc::new(AxisAlignedRect::new(-555.0, 555.0, -10000.0, 10000.0, 500.0, AxisAlignedRectAxis::Y, Arc::new(floor))),
+        ];
+        if with_ceiling {
+            list.push(Arc::new(AxisAlignedRect::new(0.0, 555.0, -10000.0, 10000.0, 502.0, AxisAlignedRectAxis::Y, Arc::new(ceiling))));
+        }
+        let world: Box<ThreadsafeHitable> = Box::new(HitableList::new(list));
+        let shape_integrators = ShapeIntegrators::new(Arc::new(
+            AxisAlignedRect::new(-1.0, 1.0, -1.0, 1.0, 1000.0, AxisAlignedRectAxis::Y, Arc::new(DummyMaterial::new()))));
+
+        let mut config = Config::new();
+        config.max_depth = 1;
+        config.termination = Termination::FixedDepth;
+        config.background = BackgroundMode::SolidColour(Vec3::from_float(1.0));
+        config.ray_epsilon = ray_epsilon;
+        random::seed_thread_rng(1400);
+        // enters the crack from the open side and hits the floor 99 units in, 2 units under the ceiling
+        let ray = Ray::new(Vec3::new(-1.0, 501.0, 0.0), Vec3::new(1.0, -0.01, 0.0), 0.0);
+        let num_samples = 1000;
+        let mut rays_traced = 0;
+        let sum: f64 = (0..num_samples).map(|_| {
+            color(&ray, &TraceContext::new(world.as_ref(), &shape_integrators, &config, None), None, MediumStack::new(), 0, &mut rays_traced).0.x
+        }).sum();
+        random::clear_thread_rng_seed();
+        (sum / num_samples as f64, rays_traced)
+    }
+
+    #[test]
+    fn light_mix_parses_weights_and_light_count() {
+        assert_eq!("0.25".parse::<LightMix>(), Ok(LightMix::Fixed(0.25)));
+        assert!("1.5".parse::<LightMix>().is_err());
+        assert!("most".parse::<LightMix>().is_err());
+        let by_light_count: LightMix = "lights".parse().unwrap();
+        assert_eq!(by_light_count.weight(1), 0.5);
+        assert_eq!(by_light_count.weight(3), 0.75);
+        assert_eq!(LightMix::Fixed(0.5).weight(3), 0.5);
+
+        // the weight follows the scene's lights rather than the shapes the mixture samples towards
+        let light = Arc::new(DiffuseLight::new(Arc::new(ConstantTexture::new(Vec3::from_float(1.0)))));
+        let list: Vec<Arc<ThreadsafeHitable>> = (0..3)
+            .map(|i| Arc::new(Sphere::new(Vec3::new(i as f64 * 3.0, 0.0, 0.0), 1.0, light.clone())) as Arc<ThreadsafeHitable>)
+            .chain(std::iter::once(Arc::new(Sphere::new(Vec3::new(0.0, -100.0, 0.0), 99.0, CLAY_MATERIAL.clone())) as Arc<ThreadsafeHitable>))
+            .collect();
+        let cam = Camera::new(Vec3::new(0.0, 0.0, 10.0), Vec3::new_zero_vector(), Vec3::new(0.0, 1.0, 0.0), 40.0, 1.0, 0.0, 1.0, 0.0, 1.0);
+        let scene_state = SceneState::new(cam, Box::new(HitableList::new(list)), 0.0, 1.0, 0.0, false, Config::new());
+        assert_eq!(scene_state.num_lights, 3);
+        assert_eq!(by_light_count.weight(scene_state.num_lights), 0.75);
+    }
+
+    #[test]
+    fn clay_render_shades_surfaces_grey_and_keeps_lights() {
+        let floor = Lambertian::new(Arc::new(ConstantTexture::new(Vec3::new(0.8, 0.1, 0.1))), 0.0);
+        let light = DiffuseLight::new(Arc::new(ConstantTexture::new(Vec3::new(4.0, 4.0, 2.0))));
+        let list: Vec<Arc<ThreadsafeHitable>> = vec![
+            Arc::new(AxisAlignedRect::new(-10.0, 10.0, -10.0, 10.0, 0.0, AxisAlignedRectAxis::Y, Arc::new(floor))),
+            Arc::new(FlipNormals::new(Arc::new(AxisAlignedRect::new(-2.0, 2.0, -2.0, 2.0, 5.0, AxisAlignedRectAxis::Y, Arc::new(light))))),
+        ];
+        let world: Box<ThreadsafeHitable> = Box::new(HitableList::new(list));
+        let shape_integrators = ShapeIntegrators::new(Arc::new(HitableList::new(vec![])));
+        let to_floor = Ray::new(Vec3::new(0.0, 3.0, -3.0), Vec3::new(0.0, -1.0, 1.0), 0.0);
+        let to_light = Ray::new(Vec3::new(0.0, 3.0, 0.0), Vec3::new(0.0, 1.0, 0.0), 0.0);
+
+        let mut config = Config::new();
+        config.max_depth = 1;
+        config.termination = Termination::FixedDepth;
+        let mut rays_traced = 0;
+        let mut mean_radiance = |config: &Config, ray: &Ray| {
+            random::seed_thread_rng(1421);
+            let num_samples = 2000;
+            let sum: Vec3 = (0..num_samples)
+                .map(|_| color(ray, &TraceContext::new(world.as_ref(), &shape_integrators, config, None), None, MediumStack::new(), 0, &mut rays_traced).0)
+                .sum();
+            random::clear_thread_rng_seed();
+            sum / num_samples as f64
+        };
+
+        let coloured = mean_radiance(&config, &to_floor);
+        assert!(coloured.x > 4.0 * coloured.y, "floor radiance = {:?}", coloured);
+        let coloured_light = mean_radiance(&config, &to_light);
+
+        config.clay = true;
+        let clay = mean_radiance(&config, &to_floor);
+        assert!(clay.x > 0.0, "floor radiance = {:?}", clay);
+        // the light's colour is all that tints the floor
+        assert!((clay.x - clay.y).abs() < 1e-9 && clay.z < clay.x, "floor radiance = {:?}", clay);
+        assert_eq!(mean_radiance(&config, &to_light), coloured_light);
+    }
+
+    #[test]
+    fn zero_pdf_samples_return_finite_radiance() {
+        // isotropic scattering has no pdf to sample and there are no shapes to importance sample, so every
+        // scattered direction has zero pdf
+        let fog = Isotropic::new(Arc::new(ConstantTexture::new(Vec3::from_float(0.5))));
+        let light = DiffuseLight::new(Arc::new(ConstantTexture::new(Vec3::from_float(4.0))));
+        let list: Vec<Arc<ThreadsafeHitable>> = vec![
+            Arc::new(Sphere::new(Vec3::new(0.0, 0.0, -5.0), 1.0, Arc::new(fog))),
+            Arc::new(AxisAlignedRect::new(-1.0, 1.0, -6.0, -4.0, 3.0, AxisAlignedRectAxis::Y, Arc::new(light))),
+        ];
+        let world: Box<ThreadsafeHitable> = Box::new(HitableList::new(list));
+        let lights: Arc<ThreadsafeHitable> = Arc::new(HitableList::new(collect_lights(world.as_ref())));
+        let shape_integrators = ShapeIntegrators::new(Arc::new(HitableList::new(vec![])));
+
+        let mut config = Config::new();
+        config.background = BackgroundMode::SolidColour(Vec3::from_float(1.0));
+        random::seed_thread_rng(1418);
+        let ray = Ray::new(Vec3::new_zero_vector(), Vec3::new(0.0, 0.0, -1.0), 0.0);
+        let mut rays_traced = 0;
+        for lights in [None, Some(&lights)].iter() {
+            for _ in 0..100 {
+                let (colour, hit) = color(&ray, &TraceContext::new(world.as_ref(), &shape_integrators, &config, *lights), None, MediumStack::new(), 0, &mut rays_traced);
+                assert!(hit);
+                assert!(colour.x.is_finite() && colour.y.is_finite() && colour.z.is_finite(), "radiance = {:?}", colour);
+            }
+        }
+        random::clear_thread_rng_seed();
+    }
+
+    #[test]
+    fn ray_epsilon_is_small_enough_to_stop_light_leaks() {
+        let (leaking, _) = crack_radiance(5.0, true);
+        let (sealed, _) = crack_radiance(0.001, true);
+        assert!(leaking > 0.2, "epsilon larger than the crack lets light in, radiance = {}", leaking);
+        assert!(sealed < 0.01, "radiance = {}", sealed);
+
+        // every scattered ray leaves the floor, none hit it again
+        let (_, rays_traced) = crack_radiance(0.001, false);
+        assert_eq!(rays_traced, 1000 * 2);
+    }
+
+    #[test]
+    fn ray_epsilon_is_a_distance_whatever_the_ray_direction_length() {
+        let material: Arc<ThreadsafeMaterial> = Arc::new(Lambertian::new(Arc::new(ConstantTexture::new(Vec3::from_float(0.5))), 1.0));
+        let wall_at = |distance: f64| -> Box<ThreadsafeHitable> {
+            Box::new(AxisAlignedRect::new(-1.0, 1.0, -1.0, 1.0, -distance, AxisAlignedRectAxis::Z, material.clone()))
+        };
+        let mut config = Config::new();
+        config.render_mode = RenderMode::Depth;
+        let ray_epsilon = config.ray_epsilon;
+
+        // a wall just beyond the epsilon is always hit and one just inside it never is, however long the direction
+        let mut rays_traced = 0;
+        for length in [0.01, 1.0, 100.0].iter() {
+            let ray = Ray::new(Vec3::new_zero_vector(), Vec3::new(0.0, 0.0, -length), 0.0);
+            let (depth, hit) = aov(&ray, wall_at(2.0 * ray_epsilon).as_ref(), &config, &mut rays_traced);
+            assert!(hit, "direction length {}", length);
+            assert!((depth.x - 2.0 * ray_epsilon).abs() < 1e-12, "direction length {} depth {}", length, depth.x);
+            assert!(!aov(&ray, wall_at(0.5 * ray_epsilon).as_ref(), &config, &mut rays_traced).1, "direction length {}", length);
+
+            config.render_mode = RenderMode::Beauty;
+            let shape_integrators = ShapeIntegrators::new(Arc::new(HitableList::new(vec![])));
+            assert!(color(&ray, &TraceContext::new(wall_at(2.0 * ray_epsilon).as_ref(), &shape_integrators, &config, None), None, MediumStack::new(), config.max_depth, &mut rays_traced).1);
+            assert!(!color(&ray, &TraceContext::new(wall_at(0.5 * ray_epsilon).as_ref(), &shape_integrators, &config, None), None, MediumStack::new(), config.max_depth, &mut rays_traced).1);
+            config.render_mode = RenderMode::Depth;
+        }
+    }
+
+    #[test]
+    fn environment_next_event_estimation_matches_the_reflected_environment() {
+        use std::f64::consts::PI;
+
+        // dim sky with a small bright patch the BSDF alone would rarely find
+        let (width, height) = (64, 32);
+        let mut pixels = vec![Vec3::from_float(0.1); width * height];
+        for row in 23..26 {
+            for column in 39..42 {
+                pixels[row * width + column] = Vec3::from_float(20.0);
+            }
+        }
+        let environment = Arc::new(EnvironmentMap::new(width, height, pixels));
+
+        // reflected radiance of a 0.5 albedo floor is albedo / pi times the cosine weighted sky over the hemisphere
+        let (num_theta, num_phi) = (256, 1024);
+        let (d_theta, d_phi) = (0.5 * PI / num_theta as f64, 2.0 * PI / num_phi as f64);
+        let mut irradiance = 0.0;
+        for i in 0..num_theta {
+            let theta = (i as f64 + 0.5) * d_theta;
+            for j in 0..num_phi {
+                let direction = Vec3::from_spherical(theta, -PI + (j as f64 + 0.5) * d_phi);
+                irradiance += environment.value(&direction).x * direction.y * theta.cos() * d_theta * d_phi;
+            }
+        }
+        let expected = 0.5 * irradiance / PI;
+
+        let floor = Lambertian::new(Arc::new(ConstantTexture::new(Vec3::from_float(0.5))), 0.0);
+        let world: Box<ThreadsafeHitable> = Box::new(AxisAlignedRect::new(-10.0, 10.0, -10.0, 10.0, 0.0, AxisAlignedRectAxis::Y, Arc::new(floor)));
+        let shape_integrators = ShapeIntegrators::new(Arc::new(
+            AxisAlignedRect::new(-1.0, 1.0, -1.0, 1.0, 5000.0, AxisAlignedRectAxis::Y, Arc::new(DummyMaterial::new()))));
+        let mut config = Config::new();
+        config.max_depth = 1;
+        config.termination = Termination::FixedDepth;
+        config.nee = true;
+        config.background = BackgroundMode::Environment(environment);
+
+        random::seed_thread_rng(1379);
+        let ray = Ray::new(Vec3::new(0.0, 1.0, 0.0), Vec3::new(0.0, -1.0, 0.0), 0.0);
+        let mut rays_traced = 0;
+        let num_samples = 4000;
+        let total = (0..num_samples)
+            .map(|_| color(&ray, &TraceContext::new(world.as_ref(), &shape_integrators, &config, None), None, MediumStack::new(), 0, &mut rays_traced).0.x)
+            .sum::<f64>();
+        random::clear_thread_rng_seed();
+        let radiance = total / num_samples as f64;
+        assert!((radiance - expected).abs() < 0.05 * expected, "radiance = {}, expected = {}", radiance, expected);
+        // a shadow ray and a scattered ray per sample
+        assert_eq!(rays_traced, 3 * num_samples);
+    }
+
+    #[test]
+    fn contact_shadows_block_distant_lights() {
+        // a black shelf just above the floor hides it from a light across a Cornell box sized room
+        let floor = Lambertian::new(Arc::new(ConstantTexture::new(Vec3::from_float(0.5))), 0.0);
+        let shelf = Lambertian::new(Arc::new(ConstantTexture::new(Vec3::from_float(0.0))), 0.0);
+        let light = DiffuseLight::new(Arc::new(ConstantTexture::new(Vec3::from_float(50.0))));
+        let light_shape: Arc<ThreadsafeHitable> = Arc::new(FlipNormals::new(Arc::new(
+            AxisAlignedRect::new(-50.0, 50.0, -50.0, 50.0, 555.0, AxisAlignedRectAxis::Y, Arc::new(light)))));
+        let list: Vec<Arc<ThreadsafeHitable>> = vec![
+            Arc::new(AxisAlignedRect::new(-10.0, 10.0, -10.0, 10.0, 0.0, AxisAlignedRectAxis::Y, Arc::new(floor))),
+            Arc::new(AxisAlignedRect::new(-10.0, 10.0, -10.0, 10.0, 0.1, AxisAlignedRectAxis::Y, Arc::new(shelf))),
+            light_shape.clone(),
+        ];
+        let world: Box<ThreadsafeHitable> = Box::new(HitableList::new(list));
+        let lights: Arc<ThreadsafeHitable> = Arc::new(HitableList::new(collect_lights(world.as_ref())));
+
+        let mut config = Config::new();
+        config.max_depth = 1;
+        config.termination = Termination::FixedDepth;
+        random::seed_thread_rng(1400);
+        // starts under the shelf
+        let ray = Ray::new(Vec3::new(0.0, 0.05, -0.05), Vec3::new(0.0, -1.0, 1.0), 0.0);
+        let mut rays_traced = 0;
+        let shape_integrators = ShapeIntegrators::new(light_shape);
+        for _ in 0..200 {
+            let (colour, hit) = color(&ray, &TraceContext::new(world.as_ref(), &shape_integrators, &config, Some(&lights)), None, MediumStack::new(), 0, &mut rays_traced);
+            assert!(hit);
+            assert_eq!(colour, Vec3::new_zero_vector());
+        }
+        random::clear_thread_rng_seed();
+    }
+
+    #[test]
+    fn grazing_rays_on_distant_floor_do_not_hit_it_again() {
+        let floor = Lambertian::new(Arc::new(ConstantTexture::new(Vec3::from_float(0.5))), 0.0);
+        let world: Box<ThreadsafeHitable> = Box::new(
+            AxisAlignedRect::new(0.0, 20000.0, -10000.0, 10000.0, 1000.0, AxisAlignedRectAxis::Y, Arc::new(floor)));
+        let shape_integrators = ShapeIntegrators::new(Arc::new(
+            AxisAlignedRect::new(-1.0, 1.0, -1.0, 1.0, 5000.0, AxisAlignedRectAxis::Y, Arc::new(DummyMaterial::new()))));
+
+        let mut config = Config::new();
+        config.max_depth = 1;
+        config.termination = Termination::FixedDepth;
+        config.background = BackgroundMode::SolidColour(Vec3::from_float(1.0));
+        // only the origin offset keeps scattered rays off the floor
+        config.ray_epsilon = 0.0;
+        random::seed_thread_rng(1401);
+        // bands of hit points across the floor, each row further away and more grazing than the last
+        for row in 0..8 {
+            let slope = 0.01 / (1 << row) as f64;
+            let mut rays_traced = 0;
+            let num_samples = 200;
+            let sum: f64 = (0..num_samples).map(|sample| {
+                let ray = Ray::new(Vec3::new(10000.0 + sample as f64 * 7.3, 1000.5, 0.0), Vec3::new(1.0, -slope, 0.0), 0.0);
+                color(&ray, &TraceContext::new(world.as_ref(), &shape_integrators, &config, None), None, MediumStack::new(), 0, &mut rays_traced).0.x
+            }).sum();
+            assert_eq!(rays_traced, num_samples * 2, "row {} has rays hitting the floor again", row);
+            let mean = sum / num_samples as f64;
+            assert!((mean - 0.5).abs() < 0.1, "row {} radiance = {}", row, mean);
+        }
+        random::clear_thread_rng_seed();
+    }
+
+    // sine of the angle between direction and the line of the normal
+    fn sin_to_normal(direction: &Vec3, normal: &Vec3) -> f64 {
+        vec3::cross(&Vec3::new_unit_vector(direction), &Vec3::new_unit_vector(normal)).length()
+    }
+
+    #[test]
+    fn nested_dielectrics_refract_by_the_index_on_each_side() {
+        let list: Vec<Arc<ThreadsafeHitable>> = vec![
+            Arc::new(AxisAlignedBox::new(Vec3::from_float(-5.0), Vec3::from_float(5.0), Arc::new(Dielectric::new(1.1)))),
+            Arc::new(Sphere::new(Vec3::new_zero_vector(), 2.0, Arc::new(Dielectric::new(1.3)))),
+        ];
+        let world = HitableList::new(list);
+        let config = Config::new();
+        random::seed_thread_rng(1413);
+        // into the box, through the sphere off centre and back out of the box
+        let interfaces = [(1.0, 1.1), (1.1, 1.3), (1.3, 1.1), (1.1, 1.0)];
+        let mut ray = Ray::new(Vec3::new(-8.0, 4.5, 0.0), Vec3::new(1.0, -0.4, 0.0), 0.0);
+        let mut medium = MediumStack::new();
+        for (idx, &(ni, nt)) in interfaces.iter().enumerate() {
+            assert_eq!(medium.current(), ni, "interface {}", idx);
+            let hit_record = world.hit(&ray, config.ray_epsilon, f64::MAX).expect("ray should hit the next interface");
+            let incoming = dot(&ray.direction, &hit_record.normal);
+            // reflection is random, scatter until the ray is transmitted
+            let transmitted = (0..100)
+                .filter_map(|_| hit_record.mat.scatter_in_medium(&ray, &hit_record, &medium))
+                .map(|scatter_result| scatter_result.specular_ray)
+                .find(|scattered| dot(&scattered.direction, &hit_record.normal) * incoming > 0.0)
+                .expect("ray should be transmitted");
+            let sin_i = sin_to_normal(&ray.direction, &hit_record.normal);
+            let sin_t = sin_to_normal(&transmitted.direction, &hit_record.normal);
+            assert!(sin_i > 0.1, "interface {} should be hit at an angle", idx);
+            assert!((ni * sin_i - nt * sin_t).abs() < 1e-9, "interface {} refracts from {} to {}: {} != {}", idx, ni, nt, ni * sin_i, nt * sin_t);
+
+            medium = medium.after_scatter(hit_record.mat.refraction_index().unwrap(), &ray, &transmitted, &hit_record.normal);
+            ray = spawn_ray(&hit_record, transmitted.direction, ray.time);
+        }
+        random::clear_thread_rng_seed();
+        assert_eq!(medium, MediumStack::new());
+        assert!(world.hit(&ray, config.ray_epsilon, f64::MAX).is_none());
+    }
+
+    #[test]
+    fn next_event_estimation_reduces_variance_for_small_lights() {
+        let (mixture_mean, mixture_variance) = small_light_radiance_statistics(false);
+        let (nee_mean, nee_variance) = small_light_radiance_statistics(true);
+        // both estimate the same radiance
+        assert!((nee_mean - mixture_mean).abs() < 0.1 * mixture_mean, "mixture = {}, nee = {}", mixture_mean, nee_mean);
+        assert!(nee_variance < mixture_variance, "mixture variance = {}, nee variance = {}", mixture_variance, nee_variance);
+    }
+
+    #[test]
+    fn emissive_lambertian_matches_diffuse_light() {
+        let diffuse_light = emitter_radiance(Arc::new(DiffuseLight::new(Arc::new(ConstantTexture::new(Vec3::from_float(4.0))))));
+        let emissive_lambertian = emitter_radiance(Arc::new(Lambertian::new(Arc::new(ConstantTexture::new(Vec3::from_float(0.5))), 8.0)));
+        for (pixel, (light, lambertian)) in diffuse_light.chunks(4).zip(emissive_lambertian.chunks(4)).enumerate() {
+            for channel in 0..3 {
+                assert!((light[channel] - 4.0).abs() < 1e-5, "pixel {} = {:?}", pixel, light);
+                assert!((lambertian[channel] - light[channel]).abs() < 1e-5, "pixel {} = {:?} vs {:?}", pixel, lambertian, light);
+            }
+        }
+    }
+
+    #[test]
+    fn alpha_is_coverage_of_geometry() {
+        // sphere in the middle of the view, its silhouette doesn't reach the corners
+        let world: Box<ThreadsafeHitable> = Box::new(Sphere::new(Vec3::new(0.0, 0.0, -5.0), 1.0,
+            Arc::new(Lambertian::new(Arc::new(ConstantTexture::new(Vec3::from_float(0.5))), 0.0))));
+        let cam = Camera::new(Vec3::new_zero_vector(), Vec3::new(0.0, 0.0, -1.0), Vec3::new(0.0, 1.0, 0.0), 40.0, 1.0, 0.0, 1.0, 0.0, 1.0);
+        let mut config = Config::new();
+        config.realtime = false;
+        config.spp = 4;
+        let image_size = (9, 9);
+        let image = render_single_threaded(world, cam, &config, image_size);
+
+        let alpha = |x: u32, y: u32| image[((y * image_size.0 + x) * 4 + 3) as usize];
+        assert_eq!(alpha(4, 4), 1.0);
+        assert_eq!(alpha(0, 0), 0.0);
+        assert_eq!(alpha(8, 8), 0.0);
+    }
+
+    fn centre_depth(sphere_distance: f64) -> f32 {
+        let world: Box<ThreadsafeHitable> = Box::new(Sphere::new(Vec3::new(0.0, 0.0, -sphere_distance), 1.0, Arc::new(DummyMaterial::new())));
+        let cam = Camera::new(Vec3::new_zero_vector(), Vec3::new(0.0, 0.0, -1.0), Vec3::new(0.0, 1.0, 0.0), 40.0, 1.0, 0.0, 1.0, 0.0, 1.0);
+        let mut config = Config::new();
+        config.realtime = false;
+        config.spp = 4;
+        config.render_mode = "depth".parse().unwrap();
+        let image_size = (5, 5);
+        let image = render_single_threaded(world, cam, &config, image_size);
+        let centre = ((2 * image_size.0 + 2) * 4) as usize;
+        assert!(image[centre] == image[centre + 1] && image[centre] == image[centre + 2]);
+        assert_eq!(image[3], 0.0, "corner should miss the sphere");
+        image[centre]
+    }
+
+    #[test]
+    fn depth_aov_is_smaller_for_closer_sphere() {
+        let near = centre_depth(3.0);
+        let far = centre_depth(6.0);
+        assert!(near > 0.0 && near < far, "near = {}, far = {}", near, far);
+        // the centre pixel sees the front of the sphere, radius 1 in front of its centre
+        assert!((near - 2.0).abs() < 0.05, "near = {}", near);
+    }
+
+    #[test]
+    fn position_aov_is_front_of_head_on_sphere() {
+        let world: Box<ThreadsafeHitable> = Box::new(Sphere::new(Vec3::new(0.0, 0.0, -5.0), 1.0, Arc::new(DummyMaterial::new())));
+        // narrow field of view so the centre pixel's samples all land close to the front of the sphere
+        let cam = Camera::new(Vec3::new_zero_vector(), Vec3::new(0.0, 0.0, -1.0), Vec3::new(0.0, 1.0, 0.0), 1.0, 1.0, 0.0, 1.0, 0.0, 1.0);
+        let mut config = Config::new();
+        config.realtime = false;
+        config.spp = 4;
+        config.render_mode = "position".parse().unwrap();
+        let image_size = (5, 5);
+        let image = render_single_threaded(world, cam, &config, image_size);
+        let centre = ((2 * image_size.0 + 2) * 4) as usize;
+        let position = Vec3::new(image[centre] as f64, image[centre + 1] as f64, image[centre + 2] as f64);
+        assert!(position.approx_eq(&Vec3::new(0.0, 0.0, -4.0), 0.01), "position = {}", position);
+    }
+
+    #[test]
+    fn albedo_aov_ignores_lighting() {
+        let albedo = Vec3::new(0.8, 0.1, 0.1);
+        let centre_albedo = |background: &str| {
+            let world: Box<ThreadsafeHitable> = Box::new(Sphere::new(Vec3::new(0.0, 0.0, -5.0), 1.0,
+                Arc::new(Lambertian::new(Arc::new(ConstantTexture::new(albedo)), 0.0))));
+            let cam = Camera::new(Vec3::new_zero_vector(), Vec3::new(0.0, 0.0, -1.0), Vec3::new(0.0, 1.0, 0.0), 40.0, 1.0, 0.0, 1.0, 0.0, 1.0);
+            let mut config = Config::new();
+            config.realtime = false;
+            config.spp = 4;
+            config.background = background.parse().unwrap();
+            config.render_mode = "albedo".parse().unwrap();
+            let image = render_single_threaded(world, cam, &config, (5, 5));
+            let centre = ((2 * 5 + 2) * 4) as usize;
+            Vec3::new(image[centre] as f64, image[centre + 1] as f64, image[centre + 2] as f64)
+        };
+
+        // unlit and lit by a bright sky
+        for background in ["black", "4,4,4"].iter() {
+            let centre = centre_albedo(background);
+            assert!(centre.approx_eq(&albedo, 1e-6), "background = {}, albedo = {}", background, centre);
+        }
+    }
+}
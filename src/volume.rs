@@ -4,6 +4,22 @@ use hitable::*;
 use texture::ThreadsafeTexture;
 use std::sync::Arc;
 
+// Finds the parametric interval [t0, t1] the ray spends inside the boundary, clipped to [t_min, t_max].
+// Assumes the boundary is convex so the ray enters and exits at most once.
+fn boundary_interval(boundary: &Arc<ThreadsafeHitable>, r: &Ray, t_min: f64, t_max: f64) -> Option<(f64, f64)> {
+    if let Some(rec1) = boundary.hit(r, -std::f64::MAX, std::f64::MAX) {
+        if let Some(rec2) = boundary.hit(r, rec1.t+0.0001, std::f64::MAX) {
+            let t0 = rec1.t.max(t_min);
+            let t1 = rec2.t.min(t_max);
+            if t0 < t1 {
+                return Some((t0, t1));
+            }
+        }
+    }
+
+    None
+}
+
 pub struct ConstantMedium {
     boundary: Arc<ThreadsafeHitable>,
     density: f64, 
@@ -27,34 +43,89 @@ impl Hitable for ConstantMedium {
         const ENABLE_DEBUG: bool = false;
         let debugging: bool = ENABLE_DEBUG && (random::rand() < 0.00001);
 
-        if let Some(mut rec1) = self.boundary.hit(r, -std::f64::MAX, std::f64::MAX) {
-            if let Some(mut rec2) = self.boundary.hit(r, rec1.t+0.0001, std::f64::MAX) {
+        if let Some((t0, t1)) = boundary_interval(&self.boundary, r, t_min, t_max) {
+            if debugging {
+                println!("t0 {} t1 {}", t0, t1);
+            }
+            let ray_length = r.direction.length();
+            let distance_inside_boundary = (t1 - t0) * ray_length;
+            let hit_distance = -(1.0 / self.density) * random::rand().ln();
+            if hit_distance < distance_inside_boundary {
+                let time = t0 + hit_distance / ray_length;
+                let point = r.at(time);
                 if debugging {
-                    println!("t0 {} t1 {}", rec1.t, rec2.t);
+                    println!("hit_distance = {}", hit_distance);
+                    println!("time = {}", time);
+                    println!("point = {}", point);
                 }
-                rec1.t = rec1.t.max(t_min);
-                rec2.t = rec2.t.min(t_max);
-                if rec1.t >= rec2.t {
+                let normal = Vec3::new(1.0, 0.0, 0.0); // arbitary
+                return Some(HitRecord::new(
+                    time, 
+                    0.0, // u - no surface uvs for a volume, we could project on to boundary if required or support uvw for volumetric coords
+                    0.0, // v 
+                    point, 
+                    normal, 
+                    self.phase_function.clone(),
+                ));
+            }
+        }
+
+        None
+    }
+    fn bounding_box(&self, t0: f64, t1: f64) -> AABB {
+        self.boundary.bounding_box(t0, t1)
+    }
+}
+
+// Medium whose density varies through space. The density field is read from the red channel of a texture
+// (e.g. Perlin noise) and scaled by max_density, so texture values are expected to be in [0, 1].
+pub struct VariableDensityMedium {
+    boundary: Arc<ThreadsafeHitable>,
+    density: Arc<ThreadsafeTexture>,
+    max_density: f64,
+    phase_function: Arc<ThreadsafeMaterial>,
+}
+
+impl VariableDensityMedium {
+    pub fn new(boundary: Arc<ThreadsafeHitable>, density: Arc<ThreadsafeTexture>, max_density: f64, texture: Arc<ThreadsafeTexture>) -> Self {
+        let phase_function = Arc::new(Isotropic::new(texture));
+        Self {
+            boundary,
+            density,
+            max_density,
+            phase_function
+        }
+    }
+
+    fn density_at(&self, point: &Vec3) -> f64 {
+        let density = self.density.value(0.0, 0.0, point).x;
+        clamp(&density, &0.0, &1.0) * self.max_density
+    }
+}
+
+impl Hitable for VariableDensityMedium {
+    fn hit(&self, r: &Ray, t_min: f64, t_max: f64) -> Option<HitRecord>{
+        if let Some((t0, t1)) = boundary_interval(&self.boundary, r, t_min, t_max) {
+            // delta (woodcock) tracking: step through the volume as if it were uniformly max_density and
+            // accept each tentative collision with probability density / max_density.
+            let ray_length = r.direction.length();
+            let distance_inside_boundary = (t1 - t0) * ray_length;
+            let mut distance = 0.0;
+            loop {
+                distance -= (1.0 / self.max_density) * random::rand().ln();
+                if distance >= distance_inside_boundary {
                     return None;
                 }
-                let ray_length = r.direction.length();
-                let distance_inside_boundary = (rec2.t - rec1.t) * ray_length;
-                let hit_distance = -(1.0 / self.density) * random::rand().ln();
-                if hit_distance < distance_inside_boundary {
-                    let time = rec1.t + hit_distance / ray_length;
-                    let point = r.point_at_parameter(time);
-                    if debugging {
-                        println!("hit_distance = {}", hit_distance);
-                        println!("time = {}", time);
-                        println!("point = {}", point);
-                    }
+                let time = t0 + distance / ray_length;
+                let point = r.at(time);
+                if random::rand() * self.max_density < self.density_at(&point) {
                     let normal = Vec3::new(1.0, 0.0, 0.0); // arbitary
                     return Some(HitRecord::new(
-                        time, 
-                        0.0, // u - no surface uvs for a volume, we could project on to boundary if required or support uvw for volumetric coords
-                        0.0, // v 
-                        point, 
-                        normal, 
+                        time,
+                        0.0, // u
+                        0.0, // v
+                        point,
+                        normal,
                         self.phase_function.clone(),
                     ));
                 }
@@ -66,4 +137,38 @@ impl Hitable for ConstantMedium {
     fn bounding_box(&self, t0: f64, t1: f64) -> AABB {
         self.boundary.bounding_box(t0, t1)
     }
-}
\ No newline at end of file
+}
+
+#[cfg(test)]
+mod tests {
+
+    use super::*;
+    use texture::{Texture, ConstantTexture};
+    use material::DummyMaterial;
+    use axis_aligned_box::AxisAlignedBox;
+
+    // density ramps linearly from 0 at x=0 to 1 at x=1
+    struct GradientTexture;
+    impl Texture for GradientTexture {
+        fn value(&self, _u: f64, _v: f64, point: &Vec3) -> Vec3 {
+            Vec3::from_float(point.x)
+        }
+    }
+
+    #[test]
+    fn variable_density_scatters_more_in_dense_region() {
+        let boundary: Arc<ThreadsafeHitable> = Arc::new(AxisAlignedBox::new(Vec3::new_zero_vector(), Vec3::from_float(1.0), Arc::new(DummyMaterial::new())));
+        let medium = VariableDensityMedium::new(boundary, Arc::new(GradientTexture), 0.5, Arc::new(ConstantTexture::new(Vec3::from_float(1.0))));
+        let ray = Ray::new(Vec3::new(-1.0, 0.5, 0.5), Vec3::new(1.0, 0.0, 0.0), 0.0);
+
+        let (mut low, mut high) = (0, 0);
+        for _ in 0..20000 {
+            if let Some(hit_record) = medium.hit(&ray, 0.001, std::f64::MAX) {
+                assert!(hit_record.p.x >= 0.0 && hit_record.p.x <= 1.0);
+                if hit_record.p.x < 0.5 {low += 1} else {high += 1}
+            }
+        }
+
+        assert!(high > 2 * low, "low density hits = {}, high density hits = {}", low, high);
+    }
+}
@@ -1,3 +1,5 @@
+#[cfg(not(target_os = "windows"))]
+use std::num::NonZeroU32;
 
 #[allow(dead_code)]
 pub fn get_physical_window_size(window: &winit::window::Window) -> (f64, f64) {
@@ -8,7 +10,7 @@ pub fn get_physical_window_size(window: &winit::window::Window) -> (f64, f64) {
 #[allow(dead_code)]
 #[cfg(target_os = "windows")]
 pub fn update_window_framebuffer(window: &winit::window::Window, 
-                                 buffer: &mut Vec<u8>, 
+                                 buffer: &[u8], 
                                  buffer_size: (u32, u32)) {
     use winapi::shared::windef::HWND;
     use winapi::um::winuser::GetDC;
@@ -36,7 +38,7 @@ pub fn update_window_framebuffer(window: &winit::window::Window,
             biPlanes: 1,
             biBitCount: 24,
             biCompression:  BI_RGB,
-            biSizeImage: buffer_size.1 * buffer_size.0 * 3,
+            biSizeImage: buffer_size.1 * dib_row_stride(buffer_size.0),
             biXPelsPerMeter: 0,
             biYPelsPerMeter: 0,
             biClrUsed: 0,
@@ -55,7 +57,7 @@ pub fn update_window_framebuffer(window: &winit::window::Window,
                       0,
                       buffer_size.0 as i32,
                       buffer_size.1 as i32, 
-                      buffer.as_mut_ptr() as *mut c_void,
+                      buffer.as_ptr() as *const c_void,
                       &bitmap_info,
                       DIB_RGB_COLORS,
                       SRCCOPY);
@@ -64,20 +66,15 @@ pub fn update_window_framebuffer(window: &winit::window::Window,
 
 }
 
-#[allow(dead_code)]
-#[cfg(not(target_os = "windows"))]
-pub fn update_window_framebuffer_rect(_window: &winit::window::Window, 
-                                  _buffer: &mut Vec<u8>, 
-                                  _window_pos: (u32, u32), 
-                                  _buffer_size: (u32, u32)) {
-}
-
+// Blits one tile of the image, tile_buffer is a 24 bit DIB of just the tile (see convert_rgb_to_bgr_dib) and
+// tile_pos is its bottom left corner in the image
 #[allow(dead_code)]
 #[cfg(target_os = "windows")]
 pub fn update_window_framebuffer_rect(window: &winit::window::Window, 
-                                      buffer: &mut Vec<u8>, 
-                                      window_pos: (u32, u32), 
-                                      buffer_size: (u32, u32)) {
+                                      tile_buffer: &[u8], 
+                                      tile_pos: (u32, u32), 
+                                      tile_size: (u32, u32),
+                                      image_size: (u32, u32)) {
     use winapi::shared::windef::HWND;
     use winapi::um::winuser::GetDC;
     use winit::platform::windows::WindowExtWindows ;
@@ -85,8 +82,8 @@ pub fn update_window_framebuffer_rect(window: &winit::window::Window,
     use winapi::ctypes::c_void;
     
     let hwnd = window.hwnd() as HWND;
-
-    // Note(SS): Top left is (0,0).
+    let window_size = get_physical_window_size(&window);
+    let window_rect = tile_rect_to_window_rect(tile_pos, tile_size, image_size, (window_size.0 as u32, window_size.1 as u32));
 
     unsafe {
         let hdc = GetDC(hwnd);
@@ -98,12 +95,12 @@ pub fn update_window_framebuffer_rect(window: &winit::window::Window,
         }];
         let bitmap_header = BITMAPINFOHEADER {
             biSize: std::mem::size_of::<BITMAPINFO>() as u32,
-            biWidth: buffer_size.0 as i32,
-            biHeight: buffer_size.1 as i32,
+            biWidth: tile_size.0 as i32,
+            biHeight: tile_size.1 as i32,
             biPlanes: 1,
             biBitCount: 24,
             biCompression:  BI_RGB,
-            biSizeImage: buffer_size.1 * buffer_size.0 * 3,
+            biSizeImage: tile_size.1 * dib_row_stride(tile_size.0),
             biXPelsPerMeter: 0,
             biYPelsPerMeter: 0,
             biClrUsed: 0,
@@ -114,19 +111,284 @@ pub fn update_window_framebuffer_rect(window: &winit::window::Window,
             bmiColors: bmi_colors
         };
         let result = StretchDIBits(hdc,
-                      window_pos.0 as i32, 
-                      window_pos.1 as i32, 
-                      buffer_size.0 as i32,
-                      buffer_size.1 as i32,
+                      window_rect.0, 
+                      window_rect.1, 
+                      window_rect.2,
+                      window_rect.3,
                       0, 
                       0,
-                      buffer_size.0 as i32,
-                      buffer_size.1 as i32, 
-                      buffer.as_mut_ptr() as *mut c_void,
+                      tile_size.0 as i32,
+                      tile_size.1 as i32, 
+                      tile_buffer.as_ptr() as *const c_void,
                       &bitmap_info,
                       DIB_RGB_COLORS,
                       SRCCOPY);
         assert_ne!(result, 0);
     };
 
-}
\ No newline at end of file
+}
+
+// Presents -software frames to a window, with GDI on Windows
+#[cfg(target_os = "windows")]
+pub struct SoftwarePresenter;
+
+#[cfg(target_os = "windows")]
+impl SoftwarePresenter {
+    pub fn new(_window: &winit::window::Window) -> Result<Self, failure::Error> {
+        Ok(SoftwarePresenter)
+    }
+
+    pub fn present(&mut self, window: &winit::window::Window, buffer: &[u8], buffer_size: (u32, u32)) -> Result<(), failure::Error> {
+        update_window_framebuffer(window, buffer, buffer_size);
+        Ok(())
+    }
+
+    pub fn present_rect(&mut self,
+                        window: &winit::window::Window,
+                        tile_buffer: &[u8],
+                        tile_pos: (u32, u32),
+                        tile_size: (u32, u32),
+                        image_size: (u32, u32)) -> Result<(), failure::Error> {
+        update_window_framebuffer_rect(window, tile_buffer, tile_pos, tile_size, image_size);
+        Ok(())
+    }
+}
+
+// Raw handles of a winit window for softbuffer. winit 0.20 predates the raw-window-handle 0.6 softbuffer takes so
+// they are rebuilt from its platform extensions, and are only valid while the window is open
+#[cfg(not(target_os = "windows"))]
+#[derive(Clone, Copy)]
+struct WindowHandles {
+    window: raw_window_handle::RawWindowHandle,
+    display: raw_window_handle::RawDisplayHandle,
+}
+
+#[cfg(not(target_os = "windows"))]
+impl WindowHandles {
+    #[cfg(target_os = "macos")]
+    fn new(window: &winit::window::Window) -> Option<Self> {
+        use std::ptr::NonNull;
+        use raw_window_handle::{AppKitWindowHandle, AppKitDisplayHandle};
+        use winit::platform::macos::WindowExtMacOS;
+
+        let ns_view = NonNull::new(window.ns_view())?;
+        Some(WindowHandles {
+            window: AppKitWindowHandle::new(ns_view).into(),
+            display: AppKitDisplayHandle::new().into(),
+        })
+    }
+
+    #[cfg(not(target_os = "macos"))]
+    fn new(window: &winit::window::Window) -> Option<Self> {
+        use std::ptr::NonNull;
+        use raw_window_handle::{XlibWindowHandle, XlibDisplayHandle, WaylandWindowHandle, WaylandDisplayHandle};
+        use winit::platform::unix::WindowExtUnix;
+
+        if let (Some(xlib_window), Some(xlib_display)) = (window.xlib_window(), window.xlib_display()) {
+            return Some(WindowHandles {
+                window: XlibWindowHandle::new(xlib_window).into(),
+                display: XlibDisplayHandle::new(NonNull::new(xlib_display), window.xlib_screen_id().unwrap_or(0)).into(),
+            });
+        }
+        let surface = NonNull::new(window.wayland_surface()?)?;
+        let display = NonNull::new(window.wayland_display()?)?;
+        Some(WindowHandles {
+            window: WaylandWindowHandle::new(surface).into(),
+            display: WaylandDisplayHandle::new(display).into(),
+        })
+    }
+}
+
+#[cfg(not(target_os = "windows"))]
+impl raw_window_handle::HasWindowHandle for WindowHandles {
+    fn window_handle(&self) -> Result<raw_window_handle::WindowHandle<'_>, raw_window_handle::HandleError> {
+        // the presenter holding these is dropped before the window
+        Ok(unsafe { raw_window_handle::WindowHandle::borrow_raw(self.window) })
+    }
+}
+
+#[cfg(not(target_os = "windows"))]
+impl raw_window_handle::HasDisplayHandle for WindowHandles {
+    fn display_handle(&self) -> Result<raw_window_handle::DisplayHandle<'_>, raw_window_handle::HandleError> {
+        Ok(unsafe { raw_window_handle::DisplayHandle::borrow_raw(self.display) })
+    }
+}
+
+#[cfg(not(target_os = "windows"))]
+fn present_error(error: softbuffer::SoftBufferError) -> failure::Error {
+    failure::err_msg(format!("Software present failed: {}", error))
+}
+
+// Presents -software frames to an X11, Wayland or macOS window with softbuffer. Tiles are stretched into a copy of
+// the window's pixels which is presented whole, so tiles drawn earlier stay on screen
+#[cfg(not(target_os = "windows"))]
+pub struct SoftwarePresenter {
+    surface: softbuffer::Surface<WindowHandles, WindowHandles>,
+    frame: Vec<u32>, // 0RGB pixels of the window, top row first
+    frame_size: (u32, u32),
+}
+
+#[cfg(not(target_os = "windows"))]
+impl SoftwarePresenter {
+    pub fn new(window: &winit::window::Window) -> Result<Self, failure::Error> {
+        let handles = WindowHandles::new(window).ok_or_else(|| failure::err_msg("-software needs an X11, Wayland or macOS window"))?;
+        let context = softbuffer::Context::new(handles).map_err(present_error)?;
+        let surface = softbuffer::Surface::new(&context, handles).map_err(present_error)?;
+        Ok(SoftwarePresenter {
+            surface,
+            frame: vec![],
+            frame_size: (0, 0),
+        })
+    }
+
+    pub fn present(&mut self, window: &winit::window::Window, buffer: &[u8], buffer_size: (u32, u32)) -> Result<(), failure::Error> {
+        let frame_size = self.resize(window)?;
+        blit_dib_to_frame(&mut self.frame, frame_size, buffer, buffer_size, (0, 0, frame_size.0 as i32, frame_size.1 as i32));
+        self.present_frame()
+    }
+
+    pub fn present_rect(&mut self,
+                        window: &winit::window::Window,
+                        tile_buffer: &[u8],
+                        tile_pos: (u32, u32),
+                        tile_size: (u32, u32),
+                        image_size: (u32, u32)) -> Result<(), failure::Error> {
+        let frame_size = self.resize(window)?;
+        let window_rect = tile_rect_to_window_rect(tile_pos, tile_size, image_size, frame_size);
+        blit_dib_to_frame(&mut self.frame, frame_size, tile_buffer, tile_size, window_rect);
+        self.present_frame()
+    }
+
+    // follows the window's size, the frame is cleared when it changes
+    fn resize(&mut self, window: &winit::window::Window) -> Result<(u32, u32), failure::Error> {
+        let window_size = get_physical_window_size(window);
+        let frame_size = (window_size.0 as u32, window_size.1 as u32);
+        if frame_size != self.frame_size {
+            if let (Some(width), Some(height)) = (NonZeroU32::new(frame_size.0), NonZeroU32::new(frame_size.1)) {
+                self.surface.resize(width, height).map_err(present_error)?;
+            }
+            self.frame = vec![0; (frame_size.0 * frame_size.1) as usize];
+            self.frame_size = frame_size;
+        }
+        Ok(frame_size)
+    }
+
+    fn present_frame(&mut self) -> Result<(), failure::Error> {
+        // minimised windows have nothing to present to
+        if self.frame.is_empty() {
+            return Ok(());
+        }
+        let mut buffer = self.surface.buffer_mut().map_err(present_error)?;
+        buffer.copy_from_slice(&self.frame);
+        buffer.present().map_err(present_error)
+    }
+}
+
+// Stretches a 24 bit DIB (see convert_rgb_to_bgr_dib) over window_rect of a 0RGB frame whose top row comes first,
+// nearest neighbour like StretchDIBits. Parts of the rect outside the frame are skipped.
+pub fn blit_dib_to_frame(frame: &mut [u32], frame_size: (u32, u32), dib: &[u8], dib_size: (u32, u32), window_rect: (i32, i32, i32, i32)) {
+    let stride = dib_row_stride(dib_size.0) as usize;
+    let (left, top, width, height) = window_rect;
+    for y in top.max(0)..(top + height).min(frame_size.1 as i32) {
+        // DIB rows run bottom up
+        let dib_row = dib_size.1 as usize - 1 - (y - top) as usize * dib_size.1 as usize / height as usize;
+        for x in left.max(0)..(left + width).min(frame_size.0 as i32) {
+            let dib_column = (x - left) as usize * dib_size.0 as usize / width as usize;
+            let bgr = &dib[dib_row * stride + dib_column * 3..];
+            frame[y as usize * frame_size.0 as usize + x as usize] = (bgr[2] as u32) << 16 | (bgr[1] as u32) << 8 | bgr[0] as u32;
+        }
+    }
+}
+
+// Window rect (x, y, width, height) the tile is stretched to. The window's origin is the top left while the image's
+// is the bottom left so y is flipped. Edges are rounded down so neighbouring tiles meet without gaps or overlaps.
+pub fn tile_rect_to_window_rect(tile_pos: (u32, u32), tile_size: (u32, u32), image_size: (u32, u32), window_size: (u32, u32)) -> (i32, i32, i32, i32) {
+    let scale = |value: u32, from: u32, to: u32| (value as u64 * to as u64 / from as u64) as i32;
+    let left = scale(tile_pos.0, image_size.0, window_size.0);
+    let right = scale(tile_pos.0 + tile_size.0, image_size.0, window_size.0);
+    let top = scale(image_size.1 - (tile_pos.1 + tile_size.1), image_size.1, window_size.1);
+    let bottom = scale(image_size.1 - tile_pos.1, image_size.1, window_size.1);
+    (left, top, right - left, bottom - top)
+}
+
+// Bytes per row of a 24 bit DIB, rows are padded to a multiple of 4 bytes
+pub fn dib_row_stride(width: u32) -> u32 {
+    (width * 3 + 3) & !3
+}
+
+// Converts an RGB buffer to the BGR rows of a 24 bit DIB for update_window_framebuffer. Bottom-up DIBs start at the
+// bottom left like our buffers so rows keep their order.
+pub fn convert_rgb_to_bgr_dib(rgb_buffer: &[u8], buffer_size: (u32, u32)) -> Vec<u8> {
+    let stride = dib_row_stride(buffer_size.0) as usize;
+    let mut bgr_buffer = vec![0; stride * buffer_size.1 as usize];
+    for (rgb_row, bgr_row) in rgb_buffer.chunks(buffer_size.0 as usize * 3).zip(bgr_buffer.chunks_mut(stride)) {
+        for (rgb, bgr) in rgb_row.chunks(3).zip(bgr_row.chunks_mut(3)) {
+            bgr[0] = rgb[2];
+            bgr[1] = rgb[1];
+            bgr[2] = rgb[0];
+        }
+    }
+    bgr_buffer
+}
+
+#[cfg(test)]
+mod tests {
+
+    use super::*;
+
+    #[test]
+    fn bgr_dib_swaps_channels_and_pads_rows() {
+        // 3 x 2 image, 9 bytes per row padded to 12
+        let rgb_buffer: Vec<u8> = (1..=18).collect();
+        let bgr_buffer = convert_rgb_to_bgr_dib(&rgb_buffer, (3, 2));
+        assert_eq!(dib_row_stride(3), 12);
+        assert_eq!(bgr_buffer, vec![
+            3, 2, 1, 6, 5, 4, 9, 8, 7, 0, 0, 0,
+            12, 11, 10, 15, 14, 13, 18, 17, 16, 0, 0, 0,
+        ]);
+        // already aligned rows aren't padded
+        assert_eq!(convert_rgb_to_bgr_dib(&rgb_buffer[..12], (4, 1)), vec![3, 2, 1, 6, 5, 4, 9, 8, 7, 12, 11, 10]);
+    }
+
+    #[test]
+    fn dib_blits_are_flipped_and_stretched_into_the_frame() {
+        // 2 x 2 image, red and green along the bottom, blue and white along the top
+        let rgb_buffer = vec![255, 0, 0, 0, 255, 0, 0, 0, 255, 255, 255, 255];
+        let dib = convert_rgb_to_bgr_dib(&rgb_buffer, (2, 2));
+        let (red, green, blue, white) = (0xff0000, 0x00ff00, 0x0000ff, 0xffffff);
+
+        let mut frame = vec![0; 4 * 4];
+        blit_dib_to_frame(&mut frame, (4, 4), &dib, (2, 2), (0, 0, 4, 4));
+        assert_eq!(frame, vec![
+            blue, blue, white, white,
+            blue, blue, white, white,
+            red, red, green, green,
+            red, red, green, green,
+        ]);
+
+        // a tile in the bottom right corner only touches its rect, the part hanging off the frame is skipped
+        let mut frame = vec![0; 4 * 4];
+        blit_dib_to_frame(&mut frame, (4, 4), &dib, (2, 2), (3, 2, 2, 2));
+        assert_eq!(frame[..11].iter().filter(|pixel| **pixel != 0).count(), 0);
+        assert_eq!((frame[11], frame[15]), (blue, red));
+    }
+
+    #[test]
+    fn tile_rects_map_to_flipped_window_rects() {
+        // same size, the bottom left tile is drawn at the bottom of the window
+        assert_eq!(tile_rect_to_window_rect((0, 0), (100, 50), (500, 500), (500, 500)), (0, 450, 100, 50));
+        assert_eq!(tile_rect_to_window_rect((400, 450), (100, 50), (500, 500), (500, 500)), (400, 0, 100, 50));
+
+        // 3 tiles stretched over a window that isn't a multiple of them still meet exactly
+        let (image_size, window_size) = ((30, 30), (100, 70));
+        let rects: Vec<_> = (0..3).map(|tile| tile_rect_to_window_rect((tile * 10, tile * 10), (10, 10), image_size, window_size)).collect();
+        assert_eq!(rects[0].0, 0);
+        assert_eq!(rects[0].0 + rects[0].2, rects[1].0);
+        assert_eq!(rects[1].0 + rects[1].2, rects[2].0);
+        assert_eq!(rects[2].0 + rects[2].2, 100);
+        assert_eq!(rects[2].1, 0);
+        assert_eq!(rects[2].1 + rects[2].3, rects[1].1);
+        assert_eq!(rects[1].1 + rects[1].3, rects[0].1);
+        assert_eq!(rects[0].1 + rects[0].3, 70);
+    }
+}
@@ -0,0 +1,14 @@
+// CI check that the cpu feature builds without any of the graphics dependencies
+use std::process::Command;
+
+#[test]
+fn cpu_feature_builds_without_graphics_dependencies() {
+    let status = Command::new(env!("CARGO"))
+        .current_dir(env!("CARGO_MANIFEST_DIR"))
+        .args(&["check", "--no-default-features", "--features", "cpu"])
+        // separate target dir so we don't block on the build lock or invalidate the outer build
+        .args(&["--target-dir", "target/cpu-check"])
+        .status()
+        .expect("failed to run cargo");
+    assert!(status.success(), "cargo check --no-default-features --features cpu failed");
+}
@@ -0,0 +1,62 @@
+// Renders small crops of the built in scenes and compares them against committed reference buffers, so a change
+// that alters the image shows up as a test failure. References are raw little endian RGBA f32 in tests/golden.
+// Run with PATH_TRACER_UPDATE_GOLDEN=1 to regenerate them after an intended change.
+extern crate path_tracer;
+
+use path_tracer::Config;
+use path_tracer::image_diff::image_diff;
+use std::path::PathBuf;
+
+const IMAGE_SIZE: (u32, u32) = (64, 64);
+const CROP_SIZE: (u32, u32) = (16, 16);
+// the seed is fixed but allow for floating point differences between platforms
+const MAX_RMSE: f64 = 0.02;
+
+fn golden_path(name: &str) -> PathBuf {
+    PathBuf::from(env!("CARGO_MANIFEST_DIR")).join("tests").join("golden").join(format!("{}.f32", name))
+}
+
+fn read_buffer(path: &PathBuf) -> Vec<f32> {
+    let bytes = std::fs::read(path)
+        .unwrap_or_else(|e| panic!("Could not read {}: {}, run with PATH_TRACER_UPDATE_GOLDEN=1 to create it", path.display(), e));
+    bytes.chunks(4).map(|b| f32::from_bits(u32::from(b[0]) | u32::from(b[1]) << 8 | u32::from(b[2]) << 16 | u32::from(b[3]) << 24)).collect()
+}
+
+fn write_buffer(path: &PathBuf, buffer: &[f32]) {
+    std::fs::create_dir_all(path.parent().unwrap()).unwrap();
+    let mut bytes = Vec::with_capacity(buffer.len() * 4);
+    for value in buffer {
+        let bits = value.to_bits();
+        bytes.extend_from_slice(&[bits as u8, (bits >> 8) as u8, (bits >> 16) as u8, (bits >> 24) as u8]);
+    }
+    std::fs::write(path, bytes).unwrap();
+}
+
+fn check_against_golden(scene_name: &str, crop_start: (u32, u32), extra_args: &[&str]) {
+    let mut args: Vec<String> = vec!["golden", "-offline", "-spp=8"].into_iter().map(String::from).collect();
+    args.extend(extra_args.iter().map(|arg| String::from(*arg)));
+    let config = Config::from_cmdline(&args);
+    let image = path_tracer::render_scene_crop(scene_name, &config, IMAGE_SIZE, (crop_start, CROP_SIZE)).unwrap();
+
+    let path = golden_path(scene_name);
+    if std::env::var_os("PATH_TRACER_UPDATE_GOLDEN").is_some() {
+        write_buffer(&path, &image);
+        return;
+    }
+
+    let reference = read_buffer(&path);
+    let (rmse, _) = image_diff(&image, &reference, CROP_SIZE);
+    assert!(rmse < MAX_RMSE, "{} differs from {} with RMSE {}", scene_name, path.display(), rmse);
+}
+
+#[test]
+fn cornell_box_matches_golden() {
+    // the glass sphere and the light's reflection in it
+    check_against_golden("cornell_box", (16, 8), &[]);
+}
+
+#[test]
+fn two_spheres_matches_golden() {
+    // the sphere is unlit without a sky
+    check_against_golden("two_spheres", (24, 24), &["-bg=gradient"]);
+}